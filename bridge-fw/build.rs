@@ -3,6 +3,9 @@ use std::fs::File;
 use std::io::Write;
 use std::path::PathBuf;
 
+use flate2::write::GzEncoder;
+use flate2::Compression;
+
 fn main() {
     // Put `memory.x` in our output directory and ensure it's
     // on the linker search path.
@@ -19,6 +22,17 @@ fn main() {
     // `memory.x` is changed.
     println!("cargo:rerun-if-changed=memory.x");
 
+    // The dashboard is stored compressed and served as is, browsers decompress it.
+    let mut dashboard = GzEncoder::new(
+        File::create(out.join("dashboard.html.gz")).unwrap(),
+        Compression::best(),
+    );
+    dashboard
+        .write_all(include_bytes!("web/dashboard.html"))
+        .unwrap();
+    dashboard.finish().unwrap();
+    println!("cargo:rerun-if-changed=web/dashboard.html");
+
     println!("cargo:rustc-link-arg-bins=--nmagic");
     println!("cargo:rustc-link-arg-bins=-Tlink.x");
     println!("cargo:rustc-link-arg-bins=-Tdefmt.x");
@@ -1,5 +1,6 @@
 MEMORY
 {
-  FLASH : ORIGIN = 0x00027000, LENGTH = 868K
+  /* the last 8 KiB of the flash hold the configuration of the bridge, see src/settings.rs */
+  FLASH : ORIGIN = 0x00027000, LENGTH = 860K
   RAM : ORIGIN = 0x20005a08, LENGTH = 128K
 }
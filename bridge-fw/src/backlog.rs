@@ -0,0 +1,277 @@
+//! Store-and-forward of the readings received while the broker is unreachable.
+//!
+//! The readings are queued in RAM and replayed oldest first once the broker is back.
+//! With the `qspi-spill` feature, the oldest readings are moved to the on-board QSPI flash
+//! once the RAM queue is full, otherwise they are dropped.
+
+use core::mem;
+
+use embassy_sync::blocking_mutex::raw::ThreadModeRawMutex;
+use embassy_sync::mutex::Mutex;
+use heapless::{Deque, LinearMap};
+use serde::{Deserialize, Serialize};
+use shared::{AirQualityAdvertisement, SensorId, Sequence};
+
+use crate::registry::MAX_NODES;
+
+/// Number of readings kept in RAM
+const CAPACITY: usize = 128;
+
+pub type SharedBacklog = Mutex<ThreadModeRawMutex, Backlog>;
+
+/// Reading waiting for the broker
+#[derive(Clone, Copy, Serialize, Deserialize)]
+pub struct Queued {
+    pub measurement: AirQualityAdvertisement,
+    /// Time of the reception in ms since boot
+    pub received_ms: u64,
+}
+
+pub struct Backlog {
+    readings: Deque<Queued, CAPACITY>,
+    /// Sequence of the last queued reading of every node, as every reading is advertised repeatedly
+    sequences: LinearMap<SensorId, Sequence, MAX_NODES>,
+    #[cfg(feature = "qspi-spill")]
+    spill: spill::Spill,
+}
+
+impl Backlog {
+    pub fn new(#[cfg(feature = "qspi-spill")] spill: spill::Spill) -> Self {
+        Self {
+            readings: Deque::new(),
+            sequences: LinearMap::new(),
+            #[cfg(feature = "qspi-spill")]
+            spill,
+        }
+    }
+
+    /// Queues a reading, readings already queued are ignored
+    pub async fn push(&mut self, measurement: AirQualityAdvertisement, now_ms: u64) {
+        let id = measurement.sensor_id;
+        if self.sequences.get(&id) == Some(&measurement.sequence) {
+            return;
+        }
+        // the registry of the nodes can't hold more nodes either
+        let _ = self.sequences.insert(id, measurement.sequence);
+        self.enqueue(Queued {
+            measurement,
+            received_ms: now_ms,
+        })
+        .await;
+    }
+
+    /// Queues a reading again, which has been published but not acknowledged by the broker.
+    /// The reading is put in the order of the reception, so that it is replayed in order
+    /// with the readings queued meanwhile.
+    pub async fn requeue(&mut self, reading: Queued) {
+        self.make_room().await;
+        // the queue is ordered by the reception, the newer readings move one place back
+        let mut carried = reading;
+        for queued in self.readings.iter_mut() {
+            if queued.received_ms > carried.received_ms {
+                mem::swap(queued, &mut carried);
+            }
+        }
+        let _ = self.readings.push_back(carried);
+    }
+
+    async fn enqueue(&mut self, reading: Queued) {
+        self.make_room().await;
+        let _ = self.readings.push_back(reading);
+    }
+
+    /// Spills or drops the oldest reading if the queue is full
+    async fn make_room(&mut self) {
+        if self.readings.is_full() {
+            let oldest = self.readings.pop_front().unwrap();
+            #[cfg(feature = "qspi-spill")]
+            let spilled = self.spill.push(&oldest).await;
+            #[cfg(not(feature = "qspi-spill"))]
+            let spilled = false;
+            if !spilled {
+                defmt::warn!(
+                    "Backlog full, dropped reading {} of node {}",
+                    oldest.measurement.sequence,
+                    oldest.measurement.sensor_id
+                );
+            }
+        }
+    }
+
+    /// Returns the oldest queued reading, the spilled readings are older than the ones in RAM
+    pub async fn peek(&mut self) -> Option<Queued> {
+        #[cfg(feature = "qspi-spill")]
+        if let Some(queued) = self.spill.peek().await {
+            return Some(queued);
+        }
+        self.readings.front().copied()
+    }
+
+    /// Removes the reading returned by [`Backlog::peek`]
+    pub async fn pop(&mut self) {
+        #[cfg(feature = "qspi-spill")]
+        if self.spill.pop().await {
+            return;
+        }
+        self.readings.pop_front();
+    }
+}
+
+/// Queue of the spilled readings in the QSPI flash.
+///
+/// The readings are kept over a reset, the flash is only erased when the queue is corrupt.
+/// The reception times of the readings spilled before the boot are unknown, they are replayed
+/// as received at boot.
+#[cfg(feature = "qspi-spill")]
+pub mod spill {
+    use core::convert::Infallible;
+    use core::ops::Range;
+
+    use embassy_nrf::{peripherals, qspi};
+    use embedded_storage_async::nor_flash::NorFlash;
+    use sequential_storage::cache::NoCache;
+    use sequential_storage::queue;
+
+    use super::Queued;
+
+    pub type Flash = qspi::Qspi<'static, peripherals::QSPI>;
+
+    /// Part of the flash used for the spilled readings, 256 KiB hold over 10 000 readings
+    const RANGE: Range<u32> = 0..256 * 1024;
+
+    /// Configuration of the GD25Q flash on the board.
+    /// Single line opcodes are used, as the quad mode has to be enabled in the flash first.
+    pub fn config() -> qspi::Config {
+        let mut config = qspi::Config::default();
+        config.read_opcode = qspi::ReadOpcode::FASTREAD;
+        config.write_opcode = qspi::WriteOpcode::PP;
+        config.capacity = RANGE.end;
+        config
+    }
+
+    pub struct Spill {
+        flash: Flash,
+        /// Number of readings spilled before the boot, at the front of the queue
+        stale: usize,
+    }
+
+    impl Spill {
+        pub async fn new(flash: Flash) -> Self {
+            let mut spill = Self { flash, stale: 0 };
+            match spill.count().await {
+                Ok(stale) => {
+                    defmt::info!("{} readings spilled before the boot", stale);
+                    spill.stale = stale;
+                }
+                Err(e) => {
+                    defmt::error!("Spilled readings unreadable: {:?}, erasing them", e);
+                    spill.erase().await;
+                }
+            }
+            spill
+        }
+
+        async fn count(
+            &mut self,
+        ) -> Result<usize, sequential_storage::Error<Infallible, qspi::Error>> {
+            let mut readings = queue::peek_many(&mut self.flash, RANGE, NoCache::new()).await?;
+            let mut count = 0;
+            let mut buffer = [0u8; 32];
+            while readings.next(&mut buffer).await?.is_some() {
+                count += 1;
+            }
+            Ok(count)
+        }
+
+        async fn erase(&mut self) {
+            self.stale = 0;
+            if let Err(e) = NorFlash::erase(&mut self.flash, RANGE.start, RANGE.end).await {
+                defmt::error!("Failed to erase the spill flash: {:?}", e);
+            }
+        }
+
+        /// Appends a reading, returns whether it was stored
+        pub async fn push(&mut self, reading: &Queued) -> bool {
+            let mut buffer = [0u8; 32];
+            let Ok(data) = postcard::to_slice(reading, &mut buffer) else {
+                return false;
+            };
+            // the oldest readings are overwritten once the flash is full
+            match queue::push(&mut self.flash, RANGE, &mut NoCache::new(), data, true).await {
+                Ok(()) => true,
+                Err(e) => {
+                    defmt::error!("Failed to spill a reading: {:?}", e);
+                    false
+                }
+            }
+        }
+
+        pub async fn peek(&mut self) -> Option<Queued> {
+            loop {
+                let mut buffer = [0u8; 32];
+                let data =
+                    match queue::peek(&mut self.flash, RANGE, &mut NoCache::new(), &mut buffer)
+                        .await
+                    {
+                        Ok(Some(data)) => data,
+                        Ok(None) => return None,
+                        Err(e) => {
+                            defmt::error!("Failed to read a spilled reading: {:?}", e);
+                            return None;
+                        }
+                    };
+                match postcard::from_bytes::<Queued>(data) {
+                    Ok(mut queued) => {
+                        if self.stale > 0 {
+                            // the age is a lower bound
+                            queued.received_ms = 0;
+                        }
+                        return Some(queued);
+                    }
+                    // a malformed reading would block the readings behind it
+                    Err(_) => {
+                        defmt::warn!("Dropping a malformed spilled reading");
+                        match self.remove().await {
+                            Ok(true) => {}
+                            Ok(false) => return None,
+                            Err(e) => {
+                                defmt::error!(
+                                    "Failed to remove a spilled reading: {:?}, erasing them",
+                                    e
+                                );
+                                self.erase().await;
+                                return None;
+                            }
+                        }
+                    }
+                }
+            }
+        }
+
+        /// Removes the oldest reading, returns whether there was any.
+        /// The flash is erased if the reading can't be removed, as it would block the queue.
+        pub async fn pop(&mut self) -> bool {
+            match self.remove().await {
+                Ok(removed) => removed,
+                Err(e) => {
+                    defmt::error!("Failed to remove a spilled reading: {:?}, erasing them", e);
+                    self.erase().await;
+                    true
+                }
+            }
+        }
+
+        async fn remove(
+            &mut self,
+        ) -> Result<bool, sequential_storage::Error<Infallible, qspi::Error>> {
+            let mut buffer = [0u8; 32];
+            let removed = queue::pop(&mut self.flash, RANGE, &mut NoCache::new(), &mut buffer)
+                .await?
+                .is_some();
+            if removed {
+                self.stale = self.stale.saturating_sub(1);
+            }
+            Ok(removed)
+        }
+    }
+}
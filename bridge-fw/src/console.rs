@@ -0,0 +1,399 @@
+//! Serial console on the CDC-ACM port of the bridge.
+//!
+//! The port streams the readings and accepts the commands of [`shared::console::Command`].
+
+use core::cell::RefCell;
+use core::fmt::{self, Write};
+use core::mem;
+
+use embassy_futures::select::{select, Either};
+use embassy_sync::blocking_mutex::ThreadModeMutex;
+use embassy_sync::pubsub::WaitResult;
+use embassy_time::{with_timeout, Duration, Timer};
+use embassy_usb::class::cdc_acm::{self, CdcAcmClass, Sender};
+use embassy_usb::driver::EndpointError;
+use heapless::{LinearMap, Vec};
+use shared::config::{BridgeConfig, ConfigError, ConfigKey};
+use shared::console::{Command, StreamFormat, MAX_LINE_LEN};
+use shared::policy::Reading;
+use shared::sinks::{Sample, SinkConfig, CSV_HEADER};
+use shared::{SensorId, Sequence};
+
+use crate::frames::{Frame, FrameSubscriber};
+use crate::identity::Identity;
+use crate::registry::{Node, MAX_NODES};
+use crate::{node_labels, settings, AppState, UsbDriver};
+
+/// Maximum packet size of the bulk endpoints of the port
+pub const PACKET_SIZE: usize = 64;
+
+/// Time allowed for the host to read a packet, the output is dropped if nobody reads the port
+const WRITE_TIMEOUT: Duration = Duration::from_millis(500);
+
+/// Line written to the console
+pub type Line = heapless::String<256>;
+
+#[derive(Clone, Copy, Debug, defmt::Format, PartialEq, Eq)]
+pub enum WriteError {
+    Disconnected,
+    TimedOut,
+}
+
+impl From<EndpointError> for WriteError {
+    fn from(_: EndpointError) -> Self {
+        WriteError::Disconnected
+    }
+}
+
+/// Writes the data in packets, terminated by a short packet so the host doesn't wait for more
+pub async fn write(sender: &mut Sender<'static, UsbDriver>, data: &[u8]) -> Result<(), WriteError> {
+    for chunk in data.chunks(PACKET_SIZE) {
+        write_packet(sender, chunk).await?;
+    }
+    if data.len() % PACKET_SIZE == 0 {
+        write_packet(sender, &[]).await?;
+    }
+    Ok(())
+}
+
+async fn write_packet(
+    sender: &mut Sender<'static, UsbDriver>,
+    packet: &[u8],
+) -> Result<(), WriteError> {
+    match with_timeout(WRITE_TIMEOUT, sender.write_packet(packet)).await {
+        Ok(result) => Ok(result?),
+        Err(_) => Err(WriteError::TimedOut),
+    }
+}
+
+/// Splits the received bytes into command lines terminated by `\r` or `\n`.
+/// Lines longer than [`MAX_LINE_LEN`] and lines which aren't UTF-8 are discarded.
+#[derive(Default)]
+pub struct LineReader {
+    line: Vec<u8, MAX_LINE_LEN>,
+    /// The line has been returned and is cleared with the next byte
+    complete: bool,
+    /// The line is discarded until its end
+    overflow: bool,
+}
+
+impl LineReader {
+    /// Adds a received byte, returns the line once it's terminated
+    pub fn push(&mut self, byte: u8) -> Option<&str> {
+        if self.complete {
+            self.line.clear();
+            self.complete = false;
+        }
+        if byte != b'\r' && byte != b'\n' {
+            if self.line.push(byte).is_err() {
+                self.overflow = true;
+            }
+            return None;
+        }
+        self.complete = true;
+        if core::mem::take(&mut self.overflow) {
+            defmt::warn!("Discarded a console line longer than {}", MAX_LINE_LEN);
+            return None;
+        }
+        core::str::from_utf8(&self.line).ok()
+    }
+}
+
+/// Configuration shown and restored by the commands of the console
+#[derive(Clone, Copy)]
+pub struct ConsoleSettings {
+    /// Configuration the bridge was started with
+    pub boot: &'static BridgeConfig,
+    /// Configuration set at build time
+    pub defaults: &'static BridgeConfig,
+    pub sinks: SinkConfig<'static>,
+}
+
+type ConsoleSender = cdc_acm::Sender<'static, UsbDriver>;
+
+/// Streams the readings to the serial port while a terminal has it open
+/// and runs the commands received on it
+#[embassy_executor::task]
+pub async fn console_task(
+    class: CdcAcmClass<'static, UsbDriver>,
+    identity: &'static Identity,
+    console_settings: ConsoleSettings,
+    state: &'static ThreadModeMutex<RefCell<AppState>>,
+    mut frames: FrameSubscriber,
+) -> ! {
+    let (mut sender, mut receiver) = class.split();
+    let mut format = StreamFormat::default();
+    let mut packet = [0; PACKET_SIZE];
+    loop {
+        receiver.wait_connection().await;
+        defmt::info!("Console connected");
+        let mut lines = LineReader::default();
+        // the nodes repeat their readings in several advertisements
+        let mut streamed = LinearMap::<SensorId, Sequence, MAX_NODES>::new();
+        // written before the first CSV reading after the terminal opens the port
+        let mut csv_header = true;
+        loop {
+            let result = match select(receiver.read_packet(&mut packet), frames.next_message())
+                .await
+            {
+                Either::First(Ok(len)) => {
+                    let mut result = Ok(());
+                    for byte in &packet[..len] {
+                        let Some(line) = lines.push(*byte) else {
+                            continue;
+                        };
+                        let previous = format;
+                        result = run_command(
+                            &mut sender,
+                            identity,
+                            &console_settings,
+                            state,
+                            &mut format,
+                            line,
+                        )
+                        .await;
+                        csv_header |= format != previous;
+                        if result.is_err() {
+                            break;
+                        }
+                    }
+                    result
+                }
+                Either::First(Err(_)) => Err(WriteError::Disconnected),
+                Either::Second(WaitResult::Message(Frame::Measurement { measurement, .. })) => {
+                    let id = measurement.sensor_id;
+                    if streamed.get(&id) == Some(&measurement.sequence) {
+                        continue;
+                    }
+                    let _ = streamed.insert(id, measurement.sequence);
+                    if !sender.dtr() {
+                        csv_header = true;
+                        continue;
+                    }
+                    let (node, name) = node_labels(id);
+                    let sample = Sample {
+                        node: &node,
+                        name: &name,
+                        reading: Reading::from(&measurement),
+                    };
+                    stream_sample(&mut sender, format, &mut csv_header, &sample).await
+                }
+                Either::Second(WaitResult::Message(Frame::Diagnostics { .. })) => Ok(()),
+                Either::Second(WaitResult::Lagged(missed)) => {
+                    defmt::debug!("Console missed {} frames", missed);
+                    Ok(())
+                }
+            };
+            match result {
+                Ok(()) => {}
+                // nobody reads the port, the output is dropped
+                Err(WriteError::TimedOut) => {}
+                Err(WriteError::Disconnected) => break,
+            }
+        }
+        defmt::info!("Console disconnected");
+    }
+}
+
+async fn stream_sample(
+    sender: &mut ConsoleSender,
+    format: StreamFormat,
+    csv_header: &mut bool,
+    sample: &Sample<'_>,
+) -> Result<(), WriteError> {
+    let mut line = Line::new();
+    match format {
+        StreamFormat::Json => sample.write_json(&mut line).unwrap(),
+        StreamFormat::Csv => {
+            if mem::take(csv_header) {
+                line.push_str(CSV_HEADER).unwrap();
+            }
+            sample.write_csv(&mut line).unwrap();
+        }
+        StreamFormat::Off => return Ok(()),
+    }
+    write(sender, line.as_bytes()).await
+}
+
+/// Writes a line of a response, prefixed by `#` to tell it apart from the readings
+async fn respond(sender: &mut ConsoleSender, args: fmt::Arguments<'_>) -> Result<(), WriteError> {
+    let mut line = Line::new();
+    // longer lines are truncated
+    let _ = write!(line, "# {}", args);
+    if line.push('\n').is_err() {
+        line.pop();
+        line.push('\n').unwrap();
+    }
+    write(sender, line.as_bytes()).await
+}
+
+/// Runs a command line received by the console and writes its response
+async fn run_command(
+    sender: &mut ConsoleSender,
+    identity: &Identity,
+    console_settings: &ConsoleSettings,
+    state: &ThreadModeMutex<RefCell<AppState>>,
+    format: &mut StreamFormat,
+    line: &str,
+) -> Result<(), WriteError> {
+    let command = match Command::parse(line) {
+        None => return Ok(()),
+        Some(Ok(command)) => command,
+        Some(Err(e)) => return respond(sender, format_args!("error: {}", e.as_str())).await,
+    };
+    match command {
+        Command::Help => {
+            for help in shared::console::HELP.lines() {
+                respond(sender, format_args!("{}", help)).await?;
+            }
+        }
+        Command::Nodes => {
+            let nodes: Vec<Node, MAX_NODES> =
+                state.lock(|c| c.borrow().nodes.nodes().iter().copied().collect());
+            if nodes.is_empty() {
+                respond(sender, format_args!("no nodes heard yet")).await?;
+            }
+            for node in &nodes {
+                let m = &node.measurement;
+                respond(
+                    sender,
+                    format_args!(
+                        "{}: co2 {} ppm, temperature {:.1} °C, humidity {} %, rssi {} dBm, seen {} s ago",
+                        m.sensor_id,
+                        m.co2_concentration,
+                        m.temperature as f32 * 0.1,
+                        m.humidity,
+                        node.link.last,
+                        node.last_seen.elapsed().as_secs()
+                    ),
+                )
+                .await?;
+            }
+        }
+        Command::Config => {
+            let (config, connected) = state.lock(|c| {
+                let c = c.borrow();
+                (c.config.clone(), c.broker_connected)
+            });
+            respond(
+                sender,
+                format_args!("bridge: {} ({})", identity.name, identity.id),
+            )
+            .await?;
+            for key in ConfigKey::ALL {
+                let value = config.get(key).unwrap_or("not set");
+                let note =
+                    if key.needs_reboot() && config.get(key) != console_settings.boot.get(key) {
+                        " (applied after reboot)"
+                    } else {
+                        ""
+                    };
+                respond(sender, format_args!("{}: {}{}", key.as_str(), value, note)).await?;
+            }
+            let connected = if connected { "yes" } else { "no" };
+            respond(sender, format_args!("broker connected: {}", connected)).await?;
+            respond(
+                sender,
+                format_args!("outputs: {}", DisplaySinks(&console_settings.sinks)),
+            )
+            .await?;
+            respond(sender, format_args!("stream: {}", format.as_str())).await?;
+        }
+        Command::Set(key, value) => change_setting(sender, state, key, Some(value)).await?,
+        Command::Unset(key) => change_setting(sender, state, key, None).await?,
+        Command::Defaults => {
+            state.lock(|c| {
+                let mut c = c.borrow_mut();
+                c.config = console_settings.defaults.clone();
+                c.reconnect = true;
+            });
+            settings::REQUESTS.signal(settings::Request::Erase);
+            respond(
+                sender,
+                format_args!("defaults restored, the network and hostname after reboot"),
+            )
+            .await?;
+        }
+        Command::SetFormat(new_format) => {
+            *format = new_format;
+            respond(sender, format_args!("stream: {}", format.as_str())).await?;
+        }
+        Command::Reboot => {
+            respond(sender, format_args!("rebooting")).await?;
+            // lets the host read the response
+            Timer::after_millis(100).await;
+            cortex_m::peripheral::SCB::sys_reset();
+        }
+    }
+    Ok(())
+}
+
+/// Changes a setting of the stored configuration from the console, `None` unsets it
+async fn change_setting(
+    sender: &mut ConsoleSender,
+    state: &ThreadModeMutex<RefCell<AppState>>,
+    key: ConfigKey,
+    value: Option<&str>,
+) -> Result<(), WriteError> {
+    let result = state.lock(|c| {
+        let mut c = c.borrow_mut();
+        c.config.set(key, value)?;
+        c.reconnect |= key.needs_reconnect();
+        Ok::<_, ConfigError>(())
+    });
+    if let Err(e) = result {
+        return respond(sender, format_args!("error: {}", e.as_str())).await;
+    }
+    defmt::info!("Setting {} changed", key);
+    settings::REQUESTS.signal(settings::Request::Store);
+    let action = if value.is_some() { "set" } else { "unset" };
+    let applied = if key.needs_reboot() {
+        "applied after reboot"
+    } else if key.needs_reconnect() {
+        "reconnecting"
+    } else {
+        "applied once the scan restarts"
+    };
+    respond(
+        sender,
+        format_args!("{} {}, {}", key.as_str(), action, applied),
+    )
+    .await
+}
+
+/// Lists the enabled outputs
+struct DisplaySinks<'a>(&'a SinkConfig<'a>);
+
+impl fmt::Display for DisplaySinks<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let sinks = self.0;
+        if !sinks.is_enabled() {
+            return f.write_str("none");
+        }
+        let mut separator = "";
+        if let Some(endpoint) = sinks.influx_udp {
+            write!(f, "influxdb udp {}:{}", endpoint.host, endpoint.port)?;
+            separator = ", ";
+        }
+        if let Some(influx) = sinks.influx_http {
+            write!(
+                f,
+                "{}influxdb http {}:{}/{}",
+                separator, influx.endpoint.host, influx.endpoint.port, influx.database
+            )?;
+            separator = ", ";
+        }
+        if let Some(endpoint) = sinks.graphite {
+            write!(
+                f,
+                "{}graphite {}:{}",
+                separator, endpoint.host, endpoint.port
+            )?;
+            separator = ", ";
+        }
+        if let Some(port) = sinks.json_udp {
+            write!(f, "{}json broadcast port {}", separator, port)?;
+        }
+        Ok(())
+    }
+}
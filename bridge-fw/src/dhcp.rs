@@ -0,0 +1,60 @@
+//! DHCP server leasing the single address of the host on the USB network.
+
+use embassy_net::udp::{PacketMetadata, UdpSocket};
+use embassy_net::{Ipv4Address, Stack};
+use embassy_usb::class::cdc_ncm::embassy_net::Device;
+use shared::dhcp::{self, ClientMessage, Lease};
+
+use crate::MTU;
+
+/// Lease time of the address of the host in seconds
+pub const DHCP_LEASE_TIME: u32 = 3600;
+
+/// Answers the DHCP requests of the host with the single lease
+#[embassy_executor::task]
+pub async fn dhcp_server_task(stack: &'static Stack<Device<'static, MTU>>, lease: Lease) -> ! {
+    let mut rx_meta = [PacketMetadata::EMPTY; 2];
+    let mut tx_meta = [PacketMetadata::EMPTY; 2];
+    let mut rx_buffer = [0; 1024];
+    let mut tx_buffer = [0; 1024];
+    let mut socket = UdpSocket::new(
+        stack,
+        &mut rx_meta,
+        &mut rx_buffer,
+        &mut tx_meta,
+        &mut tx_buffer,
+    );
+    defmt::unwrap!(socket.bind(dhcp::SERVER_PORT));
+
+    let mut packet = [0; 576];
+    let mut reply = [0; dhcp::REPLY_LEN];
+    loop {
+        let Ok((len, _)) = socket.recv_from(&mut packet).await else {
+            defmt::warn!("DHCP message too long");
+            continue;
+        };
+        let message = match ClientMessage::parse(&packet[..len]) {
+            Ok(message) => message,
+            Err(e) => {
+                defmt::debug!("Invalid DHCP message: {:?}", e);
+                continue;
+            }
+        };
+        let Some(reply_type) = lease.reply_type(&message) else {
+            continue;
+        };
+        let len = lease.write_reply(&message, reply_type, &mut reply);
+        // the host has no address before the lease is acknowledged
+        let destination = match message.ciaddr {
+            [0, 0, 0, 0] => Ipv4Address::BROADCAST,
+            address => Ipv4Address(address),
+        };
+        match socket
+            .send_to(&reply[..len], (destination, dhcp::CLIENT_PORT))
+            .await
+        {
+            Ok(()) => defmt::info!("DHCP {:?} sent to {:02x}", reply_type, message.chaddr[..6]),
+            Err(e) => defmt::warn!("Failed to send DHCP {:?}: {:?}", reply_type, e),
+        }
+    }
+}
@@ -0,0 +1,159 @@
+//! Health telemetry of the bridge and the Home Assistant discovery of the diagnostic entities.
+
+use core::fmt::{self, Write};
+use core::ptr::{addr_of, addr_of_mut};
+
+use embassy_time::Instant;
+use nrf_softdevice::{raw, Softdevice};
+use shared::diagnostics::{self, MqttStats};
+use shared::discovery::{diagnostic, Sensor};
+use shared::identity::IdSource;
+use shared::watchdog::Task as _;
+
+use crate::scan::ScanHealth;
+use crate::watchdog::ResetInfo;
+
+pub const FIRMWARE_VERSION: &str = env!("CARGO_PKG_VERSION");
+
+/// Entities of the values in [`Diagnostics`]
+pub const SENSORS: [Sensor; 11] = [
+    diagnostic("uptime", "Uptime", Some("s"), Some("duration")),
+    diagnostic("id_source", "Bridge id source", None, None),
+    diagnostic("reset_reason", "Reset reason", None, None),
+    diagnostic("firmware", "Firmware version", None, None),
+    diagnostic("mqtt_reconnects", "MQTT reconnects", None, None),
+    diagnostic("mqtt_last_error", "MQTT last error", None, None),
+    diagnostic("mqtt_requeued", "MQTT requeued readings", None, None),
+    diagnostic("scan_failures", "Scan failures", None, None),
+    diagnostic("parse_errors", "Advertisement parse errors", None, None),
+    diagnostic(
+        "die_temperature",
+        "Die temperature",
+        Some("°C"),
+        Some("temperature"),
+    ),
+    diagnostic("stack_free", "Free stack", Some("B"), Some("data_size")),
+];
+
+/// Entities of the values in [`shared::NodeDiagnostics`] published for every node
+pub const NODE_SENSORS: [Sensor; 4] = [
+    diagnostic("uptime", "Uptime", Some("s"), Some("duration")),
+    diagnostic("reset_reason", "Reset reason", None, None),
+    diagnostic(
+        "die_temperature",
+        "Die temperature",
+        Some("°C"),
+        Some("temperature"),
+    ),
+    diagnostic("crash", "Last crash", None, None),
+];
+
+/// Entities of the link quality of every node, see [`crate::registry::Node::write_link_json`]
+pub const LINK_SENSORS: [Sensor; 7] = [
+    diagnostic(
+        "rssi",
+        "Signal strength",
+        Some("dBm"),
+        Some("signal_strength"),
+    ),
+    diagnostic(
+        "rssi_min",
+        "Minimum signal strength",
+        Some("dBm"),
+        Some("signal_strength"),
+    ),
+    diagnostic(
+        "rssi_max",
+        "Maximum signal strength",
+        Some("dBm"),
+        Some("signal_strength"),
+    ),
+    diagnostic(
+        "rssi_smoothed",
+        "Average signal strength",
+        Some("dBm"),
+        Some("signal_strength"),
+    ),
+    diagnostic("address", "Bluetooth address", None, None),
+    diagnostic("phy", "PHY", None, None),
+    diagnostic("bridge", "Receiving bridge", None, None),
+];
+
+pub struct Diagnostics {
+    /// `uicr` for a provisioned bridge id, `ficr` for an id derived from the device id
+    pub id_source: IdSource,
+    pub reset: ResetInfo,
+    pub mqtt: MqttStats,
+    pub scan: ScanHealth,
+    pub die_temperature: Option<f32>,
+    pub stack_free: usize,
+}
+
+impl Diagnostics {
+    pub fn write_json(&self, w: &mut impl Write) -> fmt::Result {
+        write!(
+            w,
+            r#"{{"uptime": {}, "firmware": "{}", "id_source": "{}", "reset_reason": "{}", "starved_task": "{}", "#,
+            Instant::now().as_secs(),
+            FIRMWARE_VERSION,
+            self.id_source.as_str(),
+            self.reset.reason.as_str(),
+            self.reset.starved_task.map_or("none", |task| task.as_str()),
+        )?;
+        write!(
+            w,
+            r#""mqtt_reconnects": {}, "mqtt_connect_failures": {}, "mqtt_auth_failures": {}, "mqtt_last_error": "{}", "mqtt_requeued": {}, "#,
+            self.mqtt.reconnects(),
+            self.mqtt.connect_failures,
+            self.mqtt.auth_failures,
+            self.mqtt
+                .last_failure
+                .map_or("none", |failure| failure.as_str()),
+            self.mqtt.requeued,
+        )?;
+        write!(
+            w,
+            r#""scan_running": {}, "scan_reports": {}, "scan_restarts": {}, "scan_failures": {}, "parse_errors": {}, "scan_last_end": "{}", "#,
+            self.scan.running,
+            self.scan.reports,
+            self.scan.restarts,
+            self.scan.failures,
+            self.scan.parse_errors,
+            self.scan.last_end.map_or("none", |end| end.as_str()),
+        )?;
+        match self.die_temperature {
+            Some(temperature) => write!(w, r#""die_temperature": {:.2}, "#, temperature)?,
+            None => w.write_str(r#""die_temperature": null, "#)?,
+        }
+        write!(w, r#""stack_free": {}}}"#, self.stack_free)
+    }
+}
+
+/// Reads the temperature of the chip
+pub fn die_temperature(_sd: &Softdevice) -> Option<f32> {
+    let mut temperature: i32 = 0;
+    let ret = unsafe { raw::sd_temp_get(&mut temperature) };
+    // the temperature is in 0.25 °C steps
+    (ret == raw::NRF_SUCCESS).then_some(temperature as f32 * 0.25)
+}
+
+extern "C" {
+    /// Start of the heap, which is the lowest address the stack can grow to
+    static mut __sheap: u32;
+    static mut _stack_start: u32;
+}
+
+/// Fills the unused part of the stack with a pattern, to later measure its high-water mark.
+/// Has to be called early after boot, while the stack is shallow.
+pub fn paint_stack() {
+    unsafe {
+        // leave a margin below the stack pointer for the frame of this function
+        let top = (cortex_m::register::msp::read() as *mut u32).sub(64);
+        diagnostics::paint_stack(addr_of_mut!(__sheap), top);
+    }
+}
+
+/// Returns the number of bytes of the stack that have never been used since boot
+pub fn stack_free() -> usize {
+    unsafe { diagnostics::stack_free(addr_of!(__sheap), addr_of!(_stack_start)) }
+}
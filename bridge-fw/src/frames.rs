@@ -0,0 +1,46 @@
+//! Frames decoded from the scan reports, distributed to any number of consumers.
+
+use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
+use embassy_sync::pubsub::{PubSubChannel, Publisher, Subscriber};
+use shared::{AirQualityAdvertisement, NodeDiagnostics};
+
+use crate::registry::{PeerAddress, Reception};
+
+/// Frame accepted by the node registry
+#[derive(Clone, Copy, defmt::Format)]
+pub enum Frame {
+    Measurement {
+        address: PeerAddress,
+        measurement: AirQualityAdvertisement,
+        reception: Reception,
+    },
+    /// Diagnostics from a scan response
+    Diagnostics {
+        address: PeerAddress,
+        diagnostics: NodeDiagnostics,
+    },
+}
+
+/// Frames kept for the slowest consumer, which misses the older frames once it lags behind
+const CAPACITY: usize = 8;
+/// Maximum number of consumers
+const SUBSCRIBERS: usize = 6;
+const PUBLISHERS: usize = 1;
+
+pub type FramePublisher =
+    Publisher<'static, CriticalSectionRawMutex, Frame, CAPACITY, SUBSCRIBERS, PUBLISHERS>;
+pub type FrameSubscriber =
+    Subscriber<'static, CriticalSectionRawMutex, Frame, CAPACITY, SUBSCRIBERS, PUBLISHERS>;
+
+static FRAMES: PubSubChannel<CriticalSectionRawMutex, Frame, CAPACITY, SUBSCRIBERS, PUBLISHERS> =
+    PubSubChannel::new();
+
+/// Returns the publisher of the frames, there is only one - the scan
+pub fn publisher() -> FramePublisher {
+    defmt::unwrap!(FRAMES.publisher())
+}
+
+/// Registers a new consumer of the frames, at most [`SUBSCRIBERS`] can be registered
+pub fn subscriber() -> FrameSubscriber {
+    defmt::unwrap!(FRAMES.subscriber())
+}
@@ -0,0 +1,113 @@
+//! Short in-RAM history of the measurements of the nodes, drawn as sparklines by the dashboard.
+
+use core::fmt::{self, Write};
+
+use embassy_time::Duration;
+use heapless::{HistoryBuffer, Vec};
+use shared::{AirQualityAdvertisement, SensorId};
+
+use crate::registry::{Node, MAX_NODES};
+
+/// Period of the samples of the history
+pub const HISTORY_PERIOD: Duration = Duration::from_secs(60);
+/// Number of samples kept for each node, an hour with the default period
+pub const HISTORY_LEN: usize = 60;
+
+#[derive(Clone, Copy)]
+struct Sample {
+    co2: u16,
+    /// Scaled by 0.1
+    temperature: i16,
+    humidity: u8,
+}
+
+impl From<&AirQualityAdvertisement> for Sample {
+    fn from(measurement: &AirQualityAdvertisement) -> Self {
+        Self {
+            co2: measurement.co2_concentration,
+            temperature: measurement.temperature,
+            humidity: measurement.humidity,
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct NodeHistory {
+    id: SensorId,
+    samples: HistoryBuffer<Sample, HISTORY_LEN>,
+}
+
+impl NodeHistory {
+    /// Writes the samples as a JSON object with an array for each quantity, oldest first
+    pub fn write_json(&self, w: &mut impl Write) -> fmt::Result {
+        write!(w, r#"{{"id": {}, "co2": ["#, self.id)?;
+        self.write_series(w, |w, sample| write!(w, "{}", sample.co2))?;
+        w.write_str(r#"], "temperature": ["#)?;
+        self.write_series(w, |w, sample| {
+            write!(w, "{:.1}", sample.temperature as f32 * 0.1)
+        })?;
+        w.write_str(r#"], "humidity": ["#)?;
+        self.write_series(w, |w, sample| write!(w, "{}", sample.humidity))?;
+        w.write_str("]}")
+    }
+
+    fn write_series<W: Write>(
+        &self,
+        w: &mut W,
+        mut write_value: impl FnMut(&mut W, &Sample) -> fmt::Result,
+    ) -> fmt::Result {
+        for (i, sample) in self.samples.oldest_ordered().enumerate() {
+            if i > 0 {
+                w.write_char(',')?;
+            }
+            write_value(w, sample)?;
+        }
+        Ok(())
+    }
+}
+
+#[derive(Default)]
+pub struct History {
+    nodes: Vec<NodeHistory, MAX_NODES>,
+}
+
+impl History {
+    pub const fn new() -> Self {
+        Self { nodes: Vec::new() }
+    }
+
+    /// Records a sample of each node heard during the last period,
+    /// the nodes which haven't been heard are skipped
+    pub fn record(&mut self, nodes: &[Node]) {
+        for node in nodes {
+            if node.last_seen.elapsed() > HISTORY_PERIOD {
+                continue;
+            }
+            let sample = Sample::from(&node.measurement);
+            match self
+                .nodes
+                .iter_mut()
+                .find(|history| history.id == node.id())
+            {
+                Some(history) => history.samples.write(sample),
+                None => {
+                    let mut history = NodeHistory {
+                        id: node.id(),
+                        samples: HistoryBuffer::new(),
+                    };
+                    history.samples.write(sample);
+                    // the registry holds at most as many nodes as the history
+                    let _ = self.nodes.push(history);
+                }
+            }
+        }
+    }
+
+    pub fn ids(&self) -> Vec<SensorId, MAX_NODES> {
+        self.nodes.iter().map(|history| history.id).collect()
+    }
+
+    pub fn get(&self, id: SensorId) -> Option<NodeHistory> {
+        self.nodes.iter().find(|history| history.id == id).cloned()
+    }
+}
@@ -0,0 +1,249 @@
+//! HTTP server of the bridge, serving the dashboard, the JSON API and the Prometheus metrics.
+
+use core::cell::RefCell;
+use core::fmt::{self, Write};
+
+use embassy_net::tcp;
+use embassy_net::Stack;
+use embassy_sync::blocking_mutex::ThreadModeMutex;
+use embassy_time::Instant;
+use embassy_usb::class::cdc_ncm::embassy_net::Device;
+use heapless::Vec;
+use shared::diagnostics::MqttStats;
+use shared::http::{self, Body, Content, Handler, Route, Status};
+use shared::prometheus::{self, Metric};
+use shared::SensorId;
+
+use crate::diagnostics;
+use crate::history::HISTORY_PERIOD;
+use crate::identity::Identity;
+use crate::registry::{Node, MAX_NODES};
+use crate::scan::ScanHealth;
+use crate::{AppState, MTU};
+
+/// The dashboard page, compressed by the build script
+const DASHBOARD: &[u8] = include_bytes!(concat!(env!("OUT_DIR"), "/dashboard.html.gz"));
+
+/// State of the bridge served by the health endpoint and in the metrics
+#[derive(Clone, Copy)]
+struct BridgeHealth {
+    broker_connected: bool,
+    scan: ScanHealth,
+    nodes: usize,
+    mqtt: MqttStats,
+}
+
+impl BridgeHealth {
+    fn new(state: &AppState) -> Self {
+        Self {
+            broker_connected: state.broker_connected,
+            scan: state.scan,
+            nodes: state.nodes.nodes().len(),
+            mqtt: state.mqtt,
+        }
+    }
+}
+
+/// Resource resolved from the state, before the response is written
+enum Resource {
+    Dashboard,
+    Nodes(Vec<Node, MAX_NODES>),
+    Node(Node),
+    Health(BridgeHealth),
+    /// Ids of the nodes with history, the history of each node is read while writing the response
+    History(Vec<SensorId, MAX_NODES>),
+    Metrics(Vec<Node, MAX_NODES>, BridgeHealth),
+}
+
+/// The dashboard, the JSON API and the metrics of the bridge
+struct Api {
+    identity: &'static Identity,
+    state: &'static ThreadModeMutex<RefCell<AppState>>,
+}
+
+impl Handler for Api {
+    type Resource = Resource;
+
+    fn route(&self, path: &str) -> Result<(Resource, Content), Status> {
+        self.state.lock(|c| {
+            let c = c.borrow();
+            match Route::parse(path) {
+                Some(Route::Dashboard) => Ok((Resource::Dashboard, Content::GzipHtml)),
+                Some(Route::Nodes) => Ok((
+                    Resource::Nodes(c.nodes.nodes().iter().copied().collect()),
+                    Content::Json,
+                )),
+                Some(Route::Node(id)) => match c.nodes.get(id) {
+                    Some(node) => Ok((Resource::Node(node), Content::Json)),
+                    None => Err(Status::NotFound),
+                },
+                Some(Route::Health) => Ok((Resource::Health(BridgeHealth::new(&c)), Content::Json)),
+                Some(Route::History) => Ok((Resource::History(c.history.ids()), Content::Json)),
+                Some(Route::Metrics) => Ok((
+                    Resource::Metrics(
+                        c.nodes.nodes().iter().copied().collect(),
+                        BridgeHealth::new(&c),
+                    ),
+                    Content::Prometheus,
+                )),
+                None => Err(Status::NotFound),
+            }
+        })
+    }
+
+    async fn write_body(
+        &self,
+        resource: Resource,
+        body: &mut Body<'_, '_>,
+    ) -> Result<(), tcp::Error> {
+        let identity = self.identity;
+        match resource {
+            Resource::Dashboard => body.write_all(DASHBOARD).await?,
+            Resource::Nodes(nodes) => {
+                body.write_char('[').unwrap();
+                for (i, node) in nodes.iter().enumerate() {
+                    if i > 0 {
+                        body.write_str(", ").unwrap();
+                    }
+                    body.send().await?;
+                    node.write_json(&identity.name, body).unwrap();
+                }
+                body.write_char(']').unwrap();
+            }
+            Resource::Node(node) => node.write_json(&identity.name, body).unwrap(),
+            Resource::Health(health) => write!(
+                body,
+                r#"{{"bridge": "{}", "uptime": {}, "firmware": "{}", "broker_connected": {}, "scan_running": {}, "nodes": {}, "mqtt_reconnects": {}, "mqtt_last_error": "{}"}}"#,
+                identity.name,
+                Instant::now().as_secs(),
+                diagnostics::FIRMWARE_VERSION,
+                health.broker_connected,
+                health.scan.running,
+                health.nodes,
+                health.mqtt.reconnects(),
+                health
+                    .mqtt
+                    .last_failure
+                    .map_or("none", |failure| failure.as_str()),
+            )
+            .unwrap(),
+            Resource::History(ids) => {
+                write!(
+                    body,
+                    r#"{{"period": {}, "nodes": ["#,
+                    HISTORY_PERIOD.as_secs()
+                )
+                .unwrap();
+                let mut first = true;
+                for id in ids {
+                    // the history is copied a node at a time, as all of it doesn't fit the stack
+                    let Some(history) = self.state.lock(|c| c.borrow().history.get(id)) else {
+                        continue;
+                    };
+                    if !first {
+                        body.write_str(", ").unwrap();
+                    }
+                    first = false;
+                    body.send().await?;
+                    history.write_json(body).unwrap();
+                }
+                body.write_str("]}").unwrap();
+            }
+            Resource::Metrics(nodes, health) => write_metrics(body, identity, &nodes, &health).await?,
+        }
+        Ok(())
+    }
+}
+
+/// Serves the dashboard, the JSON API and the metrics of the bridge over HTTP,
+/// one connection at a time
+#[embassy_executor::task]
+pub async fn http_task(
+    stack: &'static Stack<Device<'static, MTU>>,
+    identity: &'static Identity,
+    state: &'static ThreadModeMutex<RefCell<AppState>>,
+) -> ! {
+    http::serve(stack, &Api { identity, state }).await
+}
+
+/// Value of a metric of a node
+type NodeValue = fn(&Node) -> f64;
+
+/// Writes the metrics of the nodes and of the bridge in the Prometheus format
+async fn write_metrics(
+    body: &mut Body<'_, '_>,
+    identity: &Identity,
+    nodes: &[Node],
+    health: &BridgeHealth,
+) -> Result<(), tcp::Error> {
+    let node_metrics: [(Metric, NodeValue); 5] = [
+        (prometheus::CO2, |node| {
+            node.measurement.co2_concentration.into()
+        }),
+        (prometheus::TEMPERATURE, |node| {
+            f64::from(node.measurement.temperature) / 10.0
+        }),
+        (prometheus::HUMIDITY, |node| {
+            node.measurement.humidity.into()
+        }),
+        (prometheus::RSSI, |node| node.link.last.into()),
+        (prometheus::LAST_SEEN, |node| {
+            node.last_seen.elapsed().as_secs() as f64
+        }),
+    ];
+    for (metric, value) in node_metrics {
+        metric.write_header(body).unwrap();
+        for node in nodes {
+            write_node_sample(&metric, node, value(node), body).unwrap();
+            body.flush().await?;
+        }
+    }
+
+    let labels = [("node", "bridge"), ("name", identity.name.as_str())];
+    // the nodes report their uptime in the scan responses, received with active scanning only
+    prometheus::UPTIME.write_header(body).unwrap();
+    for node in nodes {
+        if let Some(diagnostics) = node.diagnostics {
+            write_node_sample(&prometheus::UPTIME, node, diagnostics.uptime, body).unwrap();
+            body.flush().await?;
+        }
+    }
+    prometheus::UPTIME
+        .write_sample(&labels, Instant::now().as_secs(), body)
+        .unwrap();
+
+    let bridge_metrics = [
+        (prometheus::BROKER_CONNECTED, health.broker_connected as u32),
+        (prometheus::NODES, health.nodes as u32),
+        (prometheus::MQTT_CONNECTIONS, health.mqtt.connections),
+        (
+            prometheus::MQTT_CONNECT_FAILURES,
+            health.mqtt.connect_failures,
+        ),
+        (prometheus::MQTT_AUTH_FAILURES, health.mqtt.auth_failures),
+        (prometheus::MQTT_REQUEUED, health.mqtt.requeued),
+        (prometheus::SCAN_REPORTS, health.scan.reports),
+        (prometheus::SCAN_RESTARTS, health.scan.restarts),
+        (prometheus::SCAN_FAILURES, health.scan.failures),
+        (prometheus::PARSE_ERRORS, health.scan.parse_errors),
+    ];
+    for (metric, value) in bridge_metrics {
+        metric.write(&labels, value, body).unwrap();
+        body.flush().await?;
+    }
+    Ok(())
+}
+
+/// Writes a sample of a node, labelled by its id and by its name in Home Assistant
+fn write_node_sample(
+    metric: &Metric,
+    node: &Node,
+    value: impl fmt::Display,
+    w: &mut impl Write,
+) -> fmt::Result {
+    let mut id = heapless::String::<8>::new();
+    write!(id, "{}", node.id())?;
+    let mut name = heapless::String::<16>::new();
+    write!(name, "AFO {}", node.id())?;
+    metric.write_sample(&[("node", &id), ("name", &name)], value, w)
+}
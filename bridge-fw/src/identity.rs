@@ -0,0 +1,70 @@
+//! Names derived from the bridge id, which distinguishes bridges sharing a broker.
+//!
+//! The id is resolved like the ids of the nodes, see [`shared::identity`].
+
+use core::fmt::{self, Write};
+
+use heapless::String;
+use shared::coordination::BridgeId;
+use shared::discovery::Device;
+use shared::identity::IdSource;
+use shared::topic::{Topic, TopicConfig};
+
+use crate::diagnostics::FIRMWARE_VERSION;
+
+/// Names derived from the bridge id
+pub struct Identity {
+    pub id: BridgeId,
+    /// Whether the id was provisioned or derived from the device id
+    pub id_source: IdSource,
+    /// MQTT client id, `afo-bridge-<id>`
+    pub name: String<24>,
+    /// USB serial number
+    pub serial_number: String<8>,
+    /// Name of the bridge in its topics, `bridge-<id>`
+    topic_name: String<16>,
+    device_id: String<24>,
+    device_name: String<24>,
+}
+
+impl Identity {
+    pub fn new(id: BridgeId, id_source: IdSource) -> Self {
+        let mut identity = Self {
+            id,
+            id_source,
+            name: String::new(),
+            serial_number: String::new(),
+            topic_name: String::new(),
+            device_id: String::new(),
+            device_name: String::new(),
+        };
+        write!(identity.name, "afo-bridge-{}", id).unwrap();
+        write!(identity.serial_number, "{:08x}", id).unwrap();
+        write!(identity.topic_name, "bridge-{}", id).unwrap();
+        write!(identity.device_id, "afo_bridge_{}", id).unwrap();
+        write!(identity.device_name, "AFO Bridge {}", id).unwrap();
+        identity
+    }
+
+    /// Writes a topic of the bridge, e.g. `<prefix>-bridge-<id>-diagnostics`.
+    /// The availability topic is retained `online`, replaced by the `offline` will
+    /// once the bridge disconnects.
+    pub fn write_topic(
+        &self,
+        topics: &TopicConfig,
+        topic: Topic,
+        w: &mut impl Write,
+    ) -> fmt::Result {
+        topics.write_topic(topic, &self.topic_name, w)
+    }
+
+    /// Home Assistant device of the bridge
+    pub fn device(&self) -> Device<'_> {
+        Device {
+            id: &self.device_id,
+            name: &self.device_name,
+            model: "bridge-fw",
+            sw_version: Some(FIRMWARE_VERSION),
+        }
+    }
+}
@@ -2,9 +2,27 @@
 #![no_main]
 #![feature(type_alias_impl_trait)]
 
+mod backlog;
+mod console;
+mod dhcp;
+mod diagnostics;
+mod frames;
+mod history;
+mod http;
+mod identity;
+mod mdns;
+mod mqtt;
+mod publish;
+mod registry;
+mod scan;
+mod settings;
+mod stats;
+mod watchdog;
+
 use defmt_rtt as _;
 
 use embassy_executor::Spawner;
+use embassy_futures::select::{select3, Either3};
 use embassy_net::tcp::TcpSocket;
 use embassy_net::{Ipv4Address, Ipv4Cidr, Stack, StackResources};
 use embassy_nrf::gpio::{AnyPin, Level, Output, OutputDrive, Pin as _};
@@ -12,20 +30,47 @@ use embassy_nrf::usb::vbus_detect::SoftwareVbusDetect;
 use embassy_nrf::usb::Driver;
 use embassy_nrf::{self as _, bind_interrupts, peripherals, usb};
 use embassy_sync::blocking_mutex::ThreadModeMutex;
-use embassy_time::Timer;
+use embassy_sync::pubsub::WaitResult;
+use embassy_time::{Duration, Instant, Timer};
+use embassy_usb::class::cdc_acm::{CdcAcmClass, State as AcmState};
 use embassy_usb::class::cdc_ncm::embassy_net::State as NetState;
 use embassy_usb::class::cdc_ncm::embassy_net::{Device, Runner};
 use embassy_usb::class::cdc_ncm::CdcNcmClass;
 use embassy_usb::UsbDevice;
 use nrf_softdevice::ble::central;
-use nrf_softdevice::{raw, SocEvent, Softdevice};
-
-use heapless::Vec;
-use rust_mqtt::client::client::MqttClient;
-use shared::AirQualityAdvertisement;
+use nrf_softdevice::{raw, Flash, SocEvent, Softdevice};
+
+use backlog::{Backlog, SharedBacklog};
+use frames::{Frame, FrameSubscriber};
+use heapless::{LinearMap, Vec};
+use history::{History, HISTORY_PERIOD};
+use identity::Identity;
+use publish::{
+    publish, publish_node, publish_periodic, receive_claims, record_claim, replay_backlog, Session,
+    TICK_PERIOD,
+};
+use registry::{NodeRegistry, Reception, MAX_NODES};
+use rust_mqtt::client::raw_client::RawMqttClient;
+use rust_mqtt::packet::v5::publish_packet::QualityOfService;
+use rust_mqtt::packet::v5::reason_codes::ReasonCode;
+use scan::{ScanEnd, ScanHealth};
+use shared::config::BridgeConfig;
+use shared::crash::{storage, CrashRecord};
+use shared::dhcp::Lease;
+use shared::diagnostics::{ConnectFailure, MqttStats};
+use shared::network::AddressConfig;
+use shared::policy::Reading;
+use shared::qos::{MessageClass, QosConfig, WillQos};
+use shared::resolve;
+use shared::sinks::{Outputs, Sample, SinkConfig, SocketBuffers};
+use shared::topic::Topic;
+use shared::{decode_advertisement, decode_scan_response, DecodeError, SensorId, Sequence};
 use static_cell::make_static;
+use stats::StatsRegistry;
+use watchdog::{ResetInfo, Task};
 
 use core::cell::RefCell;
+use core::convert::Infallible;
 use core::fmt::Write;
 use core::{mem, slice};
 
@@ -33,18 +78,25 @@ bind_interrupts!(struct Irqs {
     USBD => usb::InterruptHandler<peripherals::USBD>;
 });
 
+#[cfg(feature = "qspi-spill")]
+bind_interrupts!(struct QspiIrqs {
+    QSPI => embassy_nrf::qspi::InterruptHandler<peripherals::QSPI>;
+});
+
 #[cfg(feature = "dev")]
 use panic_probe as _;
 
 #[cfg(not(feature = "dev"))]
 #[panic_handler]
-fn panic(_info: &core::panic::PanicInfo) -> ! {
+fn panic(info: &core::panic::PanicInfo) -> ! {
     defmt::error!("panic!");
+    storage::record_panic(info);
     cortex_m::peripheral::SCB::sys_reset();
 }
 
 #[cortex_m_rt::exception]
-unsafe fn HardFault(_frame: &cortex_m_rt::ExceptionFrame) -> ! {
+unsafe fn HardFault(frame: &cortex_m_rt::ExceptionFrame) -> ! {
+    storage::record_hard_fault(frame);
     cortex_m::peripheral::SCB::sys_reset()
 }
 
@@ -52,14 +104,30 @@ type UsbDriver = Driver<'static, peripherals::USBD, &'static SoftwareVbusDetect>
 
 const MTU: usize = 1514;
 
-const MEASUREMENT_COUNT: usize = 2;
-
 struct AppState {
-    measurements: [AirQualityAdvertisement; MEASUREMENT_COUNT],
+    nodes: NodeRegistry,
+    scan: ScanHealth,
+    watchdog: watchdog::Monitor,
+    reset: ResetInfo,
+    /// Crash before the last reset, cleared once published
+    crash: Option<CrashRecord>,
+    mqtt: MqttStats,
+    /// Whether the broker is connected, the readings are queued in the backlog otherwise
+    broker_connected: bool,
+    stats: StatsRegistry,
+    /// Recent measurements shown by the dashboard
+    history: History,
+    /// Configuration as edited from the console, the network and the hostname
+    /// are applied after a reset
+    config: BridgeConfig,
+    /// Set when the MQTT settings change, the broker connection is closed and opened again
+    reconnect: bool,
 }
 
 #[embassy_executor::main]
 async fn main(spawner: Spawner) {
+    diagnostics::paint_stack();
+
     let mut config = embassy_nrf::config::Config::default();
     config.hfclk_source = embassy_nrf::config::HfclkSource::ExternalXtal;
     config.lfclk_source = embassy_nrf::config::LfclkSource::InternalRC;
@@ -70,24 +138,114 @@ async fn main(spawner: Spawner) {
 
     let p = embassy_nrf::init(config);
 
+    let reset = watchdog::take_reset_info();
+    defmt::info!("Reset reason: {:?}", reset);
+    let crash = storage::take();
+    if let Some(crash) = crash {
+        defmt::warn!(
+            "Crashed before reset: {:?}, pc: {:x}",
+            crash.kind(),
+            crash.pc()
+        );
+    }
+
+    let (id, id_source) = shared::identity::id();
+    let identity: &'static Identity = make_static!(Identity::new(id, id_source));
+    defmt::info!("Bridge id: {} from {}", identity.id, identity.id_source.as_str());
+    let publishing: &'static PublishSettings = make_static!(PublishSettings {
+        qos: QosConfig::from_env().unwrap_or_else(|e| {
+            defmt::error!(
+                "Invalid MQTT QoS configuration: {:?}, using the defaults",
+                e
+            );
+            QosConfig::DEFAULT
+        }),
+    });
+    let defaults: &'static BridgeConfig =
+        make_static!(BridgeConfig::from_env().unwrap_or_else(|e| {
+            defmt::error!("Invalid configuration: {:?}, using the defaults", e);
+            BridgeConfig::default()
+        }));
+    let sink_config = SinkConfig::from_env().unwrap_or_else(|e| {
+        defmt::error!("Invalid output configuration: {:?}, outputs disabled", e);
+        SinkConfig::DEFAULT
+    });
+    let stats_windows = shared::stats::windows_from_env().unwrap_or_else(|e| {
+        defmt::error!("Invalid statistics windows: {:?}, statistics disabled", e);
+        Vec::new()
+    });
+
     let config = softdevice_config();
 
     let software_vbus = make_static!(SoftwareVbusDetect::new(true, true));
 
     let state = make_static!(ThreadModeMutex::new(RefCell::new(AppState {
-        measurements: [AirQualityAdvertisement::default(); MEASUREMENT_COUNT],
+        nodes: NodeRegistry::new(),
+        scan: ScanHealth::default(),
+        watchdog: watchdog::Monitor::new(),
+        reset,
+        crash,
+        mqtt: MqttStats::default(),
+        broker_connected: false,
+        stats: StatsRegistry::new(stats_windows),
+        history: History::new(),
+        // replaced by the stored configuration once the flash is accessible
+        config: defaults.clone(),
+        reconnect: false,
     })));
 
+    let wdt = watchdog::start(p.WDT);
+    defmt::unwrap!(spawner.spawn(watchdog_task(wdt, state)));
+
+    #[cfg(feature = "qspi-spill")]
+    let backlog = {
+        use embassy_nrf::interrupt::{self, InterruptExt};
+        interrupt::QSPI.set_priority(interrupt::Priority::P2);
+        let flash = embassy_nrf::qspi::Qspi::new(
+            p.QSPI,
+            QspiIrqs,
+            p.P0_08,
+            p.P0_04,
+            p.P0_06,
+            p.P0_26,
+            p.P0_27,
+            p.P1_09,
+            backlog::spill::config(),
+        );
+        Backlog::new(backlog::spill::Spill::new(flash).await)
+    };
+    #[cfg(not(feature = "qspi-spill"))]
+    let backlog = Backlog::new();
+    let backlog: &'static SharedBacklog = make_static!(SharedBacklog::new(backlog));
+
     let sd = Softdevice::enable(&config);
     defmt::unwrap!(spawner.spawn(softdevice_task(sd, software_vbus)));
+
+    // the flash is accessed through the softdevice, which has to be running
+    let mut flash = Flash::take(sd);
+    if let Some(stored) = settings::load(&mut flash).await {
+        defmt::info!("Using the stored configuration");
+        state.lock(|c| c.borrow_mut().config = stored);
+    }
+    let boot_config: &'static BridgeConfig =
+        make_static!(state.lock(|c| c.borrow().config.clone()));
+    defmt::unwrap!(spawner.spawn(settings_task(flash, state)));
+    let hostname = boot_config
+        .hostname
+        .as_deref()
+        .unwrap_or(identity.name.as_str());
     defmt::unwrap!(spawner.spawn(scan_task(sd, state)));
+    defmt::unwrap!(spawner.spawn(log_frames_task(frames::subscriber())));
+    defmt::unwrap!(spawner.spawn(stats_task(state, frames::subscriber())));
+    defmt::unwrap!(spawner.spawn(backlog_task(state, backlog, frames::subscriber())));
+    defmt::unwrap!(spawner.spawn(history_task(state)));
 
     let driver = Driver::new(p.USBD, Irqs, &*software_vbus);
 
     let mut config = embassy_usb::Config::new(0xc0de, 0xcafe);
     config.manufacturer = Some("MatousHybl");
     config.product = Some("AFO-Bridge");
-    config.serial_number = Some("12345678");
+    config.serial_number = Some(&identity.serial_number);
     config.max_power = 100;
     config.max_packet_size_0 = 64;
 
@@ -101,7 +259,7 @@ async fn main(spawner: Spawner) {
         driver,
         config,
         make_static!([0; 256]),
-        make_static!([0; 256]),
+        make_static!([0; 512]),
         make_static!([0; 256]),
         make_static!([0; 128]),
         make_static!([0; 128]),
@@ -119,6 +277,12 @@ async fn main(spawner: Spawner) {
         64,
     );
 
+    let console = CdcAcmClass::new(
+        &mut builder,
+        make_static!(AcmState::new()),
+        console::PACKET_SIZE as u16,
+    );
+
     let usb = builder.build();
 
     defmt::unwrap!(spawner.spawn(usb_task(usb)));
@@ -129,11 +293,19 @@ async fn main(spawner: Spawner) {
     );
     defmt::unwrap!(spawner.spawn(usb_ncm_task(runner)));
 
-    let config = embassy_net::Config::ipv4_static(embassy_net::StaticConfigV4 {
-        address: Ipv4Cidr::new(Ipv4Address::new(10, 42, 0, 61), 24),
-        dns_servers: Vec::new(),
-        gateway: Some(Ipv4Address::new(10, 42, 0, 1)),
-    });
+    let address_config = boot_config.address();
+    let config = match address_config {
+        AddressConfig::Dhcp => embassy_net::Config::dhcpv4(Default::default()),
+        AddressConfig::Static {
+            address,
+            prefix_len,
+            gateway,
+        } => embassy_net::Config::ipv4_static(embassy_net::StaticConfigV4 {
+            address: Ipv4Cidr::new(Ipv4Address(address), prefix_len),
+            dns_servers: Vec::new(),
+            gateway: gateway.map(Ipv4Address),
+        }),
+    };
 
     // Generate random seed
     // wait for rnd to have enough entropy?
@@ -142,13 +314,54 @@ async fn main(spawner: Spawner) {
     nrf_softdevice::random_bytes(&sd, &mut raw_seed).unwrap();
     let seed = u64::from_le_bytes(raw_seed);
 
-    let resources = make_static!(StackResources::<2>::new());
+    // the MQTT connection, the DHCP client or server, the DNS and the mDNS queries, the mDNS responder,
+    // the HTTP server, the outputs and their mDNS queries
+    let resources = make_static!(StackResources::<9>::new());
     let stack = make_static!(Stack::<Device<'static, MTU>>::new(
         device, config, resources, seed,
     ));
 
     defmt::unwrap!(spawner.spawn(net_task(stack)));
-    defmt::unwrap!(spawner.spawn(send_measurements_task(sd, stack, state)));
+    // the host is the gateway of the link, it gets its address from the bridge
+    if let AddressConfig::Static {
+        address,
+        prefix_len,
+        gateway: Some(gateway),
+    } = address_config
+    {
+        let lease = Lease {
+            server: address,
+            client: gateway,
+            prefix_len,
+            lease_time: dhcp::DHCP_LEASE_TIME,
+        };
+        defmt::unwrap!(spawner.spawn(dhcp::dhcp_server_task(stack, lease)));
+    }
+    defmt::unwrap!(spawner.spawn(mdns::mdns_task(stack, state, hostname)));
+    defmt::unwrap!(spawner.spawn(http::http_task(stack, identity, state)));
+    defmt::unwrap!(spawner.spawn(console::console_task(
+        console,
+        identity,
+        console::ConsoleSettings {
+            boot: boot_config,
+            defaults,
+            sinks: sink_config,
+        },
+        state,
+        frames::subscriber()
+    )));
+    if sink_config.is_enabled() {
+        defmt::unwrap!(spawner.spawn(sinks_task(stack, sink_config, frames::subscriber())));
+    }
+    defmt::unwrap!(spawner.spawn(send_measurements_task(
+        sd,
+        identity,
+        stack,
+        state,
+        backlog,
+        publishing,
+        frames::subscriber()
+    )));
     defmt::unwrap!(spawner.spawn(blink_task(Output::new(
         p.P1_15.degrade(),
         Level::Low,
@@ -156,25 +369,64 @@ async fn main(spawner: Spawner) {
     ))));
 }
 
-/// Periodically sends the measurements over MQTT
+/// Sends the measurements over MQTT as the frames of new readings arrive
 #[embassy_executor::task]
 async fn send_measurements_task(
     sd: &'static Softdevice,
+    identity: &'static Identity,
     stack: &'static Stack<Device<'static, MTU>>,
     state: &'static ThreadModeMutex<RefCell<AppState>>,
+    backlog: &'static SharedBacklog,
+    publishing: &'static PublishSettings,
+    mut frames: FrameSubscriber,
 ) {
     let rx_buffer = make_static!([0; 512]);
     let tx_buffer = make_static!([0; 512]);
 
     loop {
+        state.lock(|c| watchdog::check_in(&mut c.borrow_mut().watchdog, Task::Publish));
+
+        // with DHCP, the address is leased once the host shares its connection
+        if !stack.is_config_up() {
+            defmt::info!("waiting for the network configuration");
+            Timer::after_secs(2).await;
+            continue;
+        }
+
+        // the MQTT settings may be changed from the console
+        let bridge_config = state.lock(|c| {
+            let mut c = c.borrow_mut();
+            c.reconnect = false;
+            c.config.clone()
+        });
+        let broker = bridge_config.broker();
+        let topics = bridge_config.topics();
+        let policies = &bridge_config.policies();
+        // the prefix is limited to MAX_PREFIX_LEN characters, so that the topics fit
+        let mut availability_topic = heapless::String::<64>::new();
+        identity
+            .write_topic(&topics, Topic::Availability, &mut availability_topic)
+            .unwrap();
+        let mut claim_filter = heapless::String::<64>::new();
+        topics
+            .write_topic(Topic::Claim, &"+", &mut claim_filter)
+            .unwrap();
+
+        // resolved with every connection, as the address of the broker may change
+        let Some(address) = resolve::resolve(stack, broker.host).await else {
+            let failure = ConnectFailure::Resolution;
+            let delay = state.lock(|c| c.borrow_mut().mqtt.record_failure(failure));
+            Timer::after_secs(delay).await;
+            continue;
+        };
+
         let mut socket = TcpSocket::new(stack, rx_buffer, tx_buffer);
-        if socket
-            .connect((Ipv4Address::new(10, 42, 0, 1), 1883))
-            .await
-            .is_err()
-        {
+        socket.set_timeout(Some(Duration::from_secs(10)));
+        if socket.connect((address, broker.port)).await.is_err() {
             defmt::error!("failed to connect to MQTT broker");
-            Timer::after_secs(2).await;
+            let failure = ConnectFailure::Network;
+            let delay = state.lock(|c| c.borrow_mut().mqtt.record_failure(failure));
+            Timer::after_secs(delay).await;
             continue;
         }
 
@@ -182,98 +434,409 @@ async fn send_measurements_task(
             rust_mqtt::client::client_config::MqttVersion::MQTTv5,
             SoftdeviceRng { sd },
         );
-        config.add_max_subscribe_qos(rust_mqtt::packet::v5::publish_packet::QualityOfService::QoS0);
-        config.add_client_id("afo-bridge");
-        config.max_packet_size = 100;
-        let mut recv_buffer = [0; 80];
-        let mut write_buffer = [0; 80];
+        config.add_max_subscribe_qos(QualityOfService::QoS0);
+        config.add_client_id(bridge_config.client_id.as_deref().unwrap_or(&identity.name));
+        config.add_will(&availability_topic, b"offline", true);
+        if let Some(username) = &bridge_config.mqtt_username {
+            config.add_username(username);
+        }
+        if let Some(password) = &bridge_config.mqtt_password {
+            config.add_password(password);
+        }
+        config.max_packet_size = 512;
+        let mut recv_buffer = [0; 512];
+        // the crash reports are the longest messages
+        let mut write_buffer = [0; 768];
+
+        let socket = mqtt::Socket::new(socket);
+        let raw = RawMqttClient::new(
+            WillQos::new(
+                socket.transport(),
+                publishing.qos.qos(MessageClass::Availability),
+            ),
+            &mut write_buffer,
+            768,
+            &mut recv_buffer,
+            512,
+            config,
+        );
+        let mut client = mqtt::Client::new(raw, &socket, publishing.qos, backlog, state);
+
+        if let Err(code) = client.connect().await {
+            let code = u8::from(code);
+            let failure = ConnectFailure::from_reason_code(code);
+            match failure {
+                ConnectFailure::Network => defmt::error!("failed to connect to MQTT broker"),
+                _ => defmt::error!(
+                    "MQTT broker refused the connection: {}, reason code {:#04x}",
+                    failure,
+                    code
+                ),
+            }
+            let delay = state.lock(|c| c.borrow_mut().mqtt.record_failure(failure));
+            Timer::after_secs(delay).await;
+            continue;
+        }
+        state.lock(|c| c.borrow_mut().mqtt.connections += 1);
 
-        let mut client =
-            MqttClient::<_, 5, _>::new(socket, &mut write_buffer, 80, &mut recv_buffer, 80, config);
+        if let Err(e) = client.subscribe(&claim_filter).await {
+            defmt::error!("failed to subscribe: {:?}", defmt::Debug2Format(&e));
+            Timer::after_secs(2).await;
+            continue;
+        }
 
-        if client.connect_to_broker().await.is_err() {
-            defmt::error!("failed to connect to MQTT broker");
+        if let Err(e) = publish(
+            &mut client,
+            MessageClass::Availability,
+            &availability_topic,
+            b"online",
+        )
+        .await
+        {
+            defmt::error!(
+                "failed to publish availability: {:?}",
+                defmt::Debug2Format(&e)
+            );
+            Timer::after_secs(2).await;
+            continue;
+        }
+
+        let mut session = Session::default();
+        // the retained claims are received before publishing for the first time
+        let deadline = Instant::now() + Duration::from_secs(2);
+        if let Err(e) = receive_claims(
+            &mut client,
+            &socket,
+            identity,
+            &topics,
+            &mut session,
+            deadline,
+        )
+        .await
+        {
+            defmt::error!(
+                "failed to receive MQTT message: {:?}",
+                defmt::Debug2Format(&e)
+            );
             Timer::after_secs(2).await;
             continue;
         }
 
-        for sensor_id in 0..MEASUREMENT_COUNT {
-            let mut json = heapless::String::<64>::new();
-
-            {
-                let s = state.lock(|c| c.borrow().measurements[sensor_id]);
-                write!(
-                    &mut json,
-                    r#"{{"co2": "{}", "temperature": "{:.1}", "humidity": "{}"}}"#,
-                    s.co2_concentration,
-                    s.temperature as f32 * 0.1,
-                    s.humidity
-                )
-                .unwrap();
+        state.lock(|c| c.borrow_mut().broker_connected = true);
+        if let Err(e) =
+            replay_backlog(&mut client, identity, &topics, state, backlog, &mut session).await
+        {
+            defmt::error!(
+                "failed to replay the backlog: {:?}",
+                defmt::Debug2Format(&e)
+            );
+            state.lock(|c| c.borrow_mut().broker_connected = false);
+            client.requeue_unacknowledged().await;
+            Timer::after_secs(2).await;
+            continue;
+        }
+
+        // the connection is kept open until publishing fails
+        let mut next_tick = Instant::now();
+        loop {
+            state.lock(|c| watchdog::check_in(&mut c.borrow_mut().watchdog, Task::Publish));
+            if state.lock(|c| mem::take(&mut c.borrow_mut().reconnect)) {
+                defmt::info!("MQTT broker changed, reconnecting");
+                break;
             }
 
-            let mut topic = heapless::String::<10>::new();
-            write!(topic, "afo-{}", sensor_id).unwrap();
-
-            if client
-                .send_message(
-                    topic.as_str(),
-                    json.as_bytes(),
-                    rust_mqtt::packet::v5::publish_packet::QualityOfService::QoS0,
-                    true,
-                )
-                .await
-                .is_err()
-            {
-                defmt::error!("failed to send MQTT message");
-                Timer::after_secs(2).await;
-                continue;
+            // the client receives only once a packet arrives, as the receive can't be cancelled
+            let event = select3(
+                frames.next_message_pure(),
+                socket.readable(),
+                Timer::at(next_tick),
+            )
+            .await;
+            let res = match event {
+                // diagnostics frames are published along with the next measurement
+                Either3::First(Frame::Measurement { measurement, .. }) => {
+                    match state.lock(|c| c.borrow().nodes.get(measurement.sensor_id)) {
+                        Some(node) => {
+                            publish_node(
+                                &mut client,
+                                identity,
+                                policies,
+                                &topics,
+                                &mut session,
+                                &node,
+                                false,
+                            )
+                            .await
+                        }
+                        None => Ok(()),
+                    }
+                }
+                Either3::First(Frame::Diagnostics { .. }) => Ok(()),
+                Either3::Second(Ok(())) => match client.receive().await {
+                    Ok(Some((topic, payload))) => {
+                        record_claim(identity, &topics, &mut session, topic, payload);
+                        Ok(())
+                    }
+                    Ok(None) => Ok(()),
+                    Err(e) => Err(e),
+                },
+                Either3::Second(Err(_)) => Err(ReasonCode::NetworkError),
+                Either3::Third(()) => {
+                    next_tick = Instant::now() + TICK_PERIOD;
+                    match publish_periodic(
+                        &mut client,
+                        sd,
+                        identity,
+                        policies,
+                        &topics,
+                        state,
+                        &mut session,
+                    )
+                    .await
+                    {
+                        // readings no longer tracked by the client are queued in the backlog
+                        Ok(()) => {
+                            replay_backlog(
+                                &mut client,
+                                identity,
+                                &topics,
+                                state,
+                                backlog,
+                                &mut session,
+                            )
+                            .await
+                        }
+                        Err(e) => Err(e),
+                    }
+                }
+            };
+            if let Err(e) = res {
+                defmt::error!("MQTT connection failed: {:?}", defmt::Debug2Format(&e));
+                break;
             }
         }
-
-        // do not remove as the mqtt message will not be sent.
-        // rust mqtt doesn't support flushing at the moment
+        state.lock(|c| c.borrow_mut().broker_connected = false);
+        client.requeue_unacknowledged().await;
         Timer::after_secs(2).await;
     }
 }
 
-/// Scans for AFO devices and saves their measurements to be sent over MQTT
+/// Settings of the publishing set at build time
+struct PublishSettings {
+    qos: QosConfig,
+}
+
+/// Scans for AFO devices, saves their measurements and pushes the accepted frames to the consumers.
+/// The scan is restarted whenever it ends, with a backoff if it ended with an error,
+/// using the scan parameters configured at that time.
 #[embassy_executor::task]
 async fn scan_task(sd: &'static Softdevice, state: &'static ThreadModeMutex<RefCell<AppState>>) {
-    let config = central::ScanConfig::default();
-    let res = central::scan(sd, &config, |params| unsafe {
-        let mut data = slice::from_raw_parts(params.data.p_data, params.data.len as usize);
-        let mut afo = false;
-        while data.len() != 0 {
-            let len = data[0] as usize;
-            if data.len() < len + 1 {
-                defmt::warn!("Advertisement data truncated?");
-                break;
+    let frames = frames::publisher();
+    loop {
+        let config = state.lock(|c| {
+            let mut c = c.borrow_mut();
+            c.scan.running = true;
+            watchdog::check_in(&mut c.watchdog, Task::Scan);
+            scan::config(&c.config.scan())
+        });
+        let res = central::scan(sd, &config, |params| unsafe {
+            state.lock(|c| watchdog::check_in(&mut c.borrow_mut().watchdog, Task::Scan));
+            let data = slice::from_raw_parts(params.data.p_data, params.data.len as usize);
+            if params.type_.scan_response() != 0 {
+                match decode_scan_response(data) {
+                    Ok(Some(diagnostics)) => {
+                        let address = params.peer_addr.addr;
+                        let accepted = state.lock(|c| {
+                            c.borrow_mut()
+                                .nodes
+                                .update_diagnostics(address, diagnostics)
+                        });
+                        if accepted {
+                            frames.publish_immediate(Frame::Diagnostics {
+                                address,
+                                diagnostics,
+                            });
+                        }
+                    }
+                    Ok(None) => {}
+                    Err(e) => defmt::debug!("Scan response malformed: {:?}", e),
+                }
+                return None;
             }
-            if len < 1 {
-                defmt::warn!("Advertisement data malformed?");
-                break;
+            match decode_advertisement(data) {
+                Ok(Some(adv)) => {
+                    let address = params.peer_addr.addr;
+                    let reception = Reception {
+                        rssi: params.rssi,
+                        phy: params.primary_phy,
+                    };
+                    let accepted = state.lock(|c| {
+                        let mut c = c.borrow_mut();
+                        c.scan.record_report();
+                        c.nodes.update(address, adv, reception)
+                    });
+                    match accepted {
+                        Ok(()) => frames.publish_immediate(Frame::Measurement {
+                            address,
+                            measurement: adv,
+                            reception,
+                        }),
+                        Err(e) => defmt::warn!("AFO node {:?} rejected: {:?}", address, e),
+                    }
+                }
+                Ok(None) => {}
+                Err(e) => {
+                    // malformed data of other devices is not interesting
+                    if let DecodeError::Payload = e {
+                        state.lock(|c| c.borrow_mut().scan.record_parse_error());
+                    }
+                    defmt::debug!("Advertisement malformed: {:?}", e);
+                }
+            }
+            None::<Infallible>
+        })
+        .await;
+
+        let end = match res {
+            Ok(never) => match never {},
+            Err(e) => ScanEnd::from(e),
+        };
+        let backoff = state.lock(|c| {
+            let mut c = c.borrow_mut();
+            c.scan.restarts = c.scan.restarts.wrapping_add(1);
+            c.scan.record_end(end)
+        });
+        if end.is_failure() {
+            defmt::error!(
+                "Scan failed: {:?}, restarting in {} ms",
+                end,
+                backoff.as_millis()
+            );
+            Timer::after(backoff).await;
+        } else {
+            defmt::debug!("Scan ended: {:?}, restarting", end);
+        }
+    }
+}
+
+/// Logs the frames received by the scan
+#[embassy_executor::task]
+async fn log_frames_task(mut frames: FrameSubscriber) {
+    loop {
+        match frames.next_message().await {
+            WaitResult::Message(frame) => defmt::trace!("AFO: {:?}", frame),
+            WaitResult::Lagged(missed) => defmt::debug!("Frame log missed {} frames", missed),
+        }
+    }
+}
+
+/// Adds the readings of the frames to the statistics windows
+#[embassy_executor::task]
+async fn stats_task(
+    state: &'static ThreadModeMutex<RefCell<AppState>>,
+    mut frames: FrameSubscriber,
+) {
+    loop {
+        match frames.next_message().await {
+            WaitResult::Message(Frame::Measurement { measurement, .. }) => {
+                let now = Instant::now().as_millis();
+                state.lock(|c| c.borrow_mut().stats.record(&measurement, now));
+            }
+            WaitResult::Message(Frame::Diagnostics { .. }) => {}
+            WaitResult::Lagged(missed) => defmt::warn!("Statistics missed {} frames", missed),
+        }
+    }
+}
+
+/// Pushes the new readings to the outputs enabled besides MQTT
+#[embassy_executor::task]
+async fn sinks_task(
+    stack: &'static Stack<Device<'static, MTU>>,
+    config: SinkConfig<'static>,
+    mut frames: FrameSubscriber,
+) {
+    let mut buffers = SocketBuffers::new();
+    let mut outputs = Outputs::new(stack, &mut buffers, config);
+
+    // the nodes repeat their readings in several advertisements
+    let mut pushed = LinearMap::<SensorId, Sequence, MAX_NODES>::new();
+    loop {
+        match frames.next_message().await {
+            WaitResult::Message(Frame::Measurement { measurement, .. }) => {
+                let id = measurement.sensor_id;
+                if pushed.get(&id) == Some(&measurement.sequence) || !stack.is_config_up() {
+                    continue;
+                }
+                let _ = pushed.insert(id, measurement.sequence);
+                let (node, name) = node_labels(id);
+                let sample = Sample {
+                    node: &node,
+                    name: &name,
+                    reading: Reading::from(&measurement),
+                };
+                outputs.push(&sample).await;
             }
-            let key = data[1];
-            let value = &data[2..len + 1];
-            data = &data[len + 1..];
-            // device has sent a name
-            if key == 9 && value.len() == 3 && value == b"AFO" {
-                afo = true;
+            WaitResult::Message(Frame::Diagnostics { .. }) => {}
+            WaitResult::Lagged(missed) => defmt::warn!("Outputs missed {} frames", missed),
+        }
+    }
+}
+
+/// Id and name of a node in the outputs
+fn node_labels(id: SensorId) -> (heapless::String<8>, heapless::String<16>) {
+    let mut node = heapless::String::new();
+    write!(node, "{}", id).unwrap();
+    let mut name = heapless::String::new();
+    write!(name, "AFO {}", id).unwrap();
+    (node, name)
+}
+
+/// Stores or erases the configuration in the flash as requested by the console
+#[embassy_executor::task]
+async fn settings_task(mut flash: Flash, state: &'static ThreadModeMutex<RefCell<AppState>>) -> ! {
+    loop {
+        match settings::REQUESTS.wait().await {
+            settings::Request::Store => {
+                let config = state.lock(|c| c.borrow().config.clone());
+                settings::store(&mut flash, &config).await;
             }
-            if afo && key == 0xff {
-                let adv = postcard::from_bytes::<AirQualityAdvertisement>(&value[2..]).unwrap();
-                state.lock(|c| {
-                    c.borrow_mut().measurements[adv.sensor_id as usize] = adv;
-                });
-                defmt::trace!("AFO: {:?}", adv);
+            settings::Request::Erase => settings::erase(&mut flash).await,
+        }
+    }
+}
+
+/// Samples the latest measurements of the nodes into the history
+#[embassy_executor::task]
+async fn history_task(state: &'static ThreadModeMutex<RefCell<AppState>>) {
+    loop {
+        Timer::after(HISTORY_PERIOD).await;
+        state.lock(|c| {
+            let mut c = c.borrow_mut();
+            let c = &mut *c;
+            c.history.record(c.nodes.nodes());
+        });
+    }
+}
+
+/// Queues the readings of the frames received while the broker is unreachable
+#[embassy_executor::task]
+async fn backlog_task(
+    state: &'static ThreadModeMutex<RefCell<AppState>>,
+    backlog: &'static SharedBacklog,
+    mut frames: FrameSubscriber,
+) {
+    loop {
+        match frames.next_message().await {
+            WaitResult::Message(Frame::Measurement { measurement, .. }) => {
+                if !state.lock(|c| c.borrow().broker_connected) {
+                    let now = Instant::now().as_millis();
+                    backlog.lock().await.push(measurement, now).await;
+                }
             }
+            WaitResult::Message(Frame::Diagnostics { .. }) => {}
+            WaitResult::Lagged(missed) => defmt::warn!("Backlog missed {} frames", missed),
         }
-        None
-    })
-    .await;
-    defmt::unwrap!(res);
-    defmt::info!("Scan returned");
+    }
 }
 
 /// Blink the LED for a very short time, to avoid the blinking being distracting at night
@@ -307,6 +870,18 @@ async fn softdevice_task(sd: &'static Softdevice, software_vbus: &'static Softwa
     .await
 }
 
+/// Feeds the watchdog as long as all the critical tasks keep checking in
+#[embassy_executor::task]
+async fn watchdog_task(
+    wdt: watchdog::Watchdog,
+    state: &'static ThreadModeMutex<RefCell<AppState>>,
+) -> ! {
+    watchdog::supervise::<Task>(wdt, || {
+        state.lock(|c| c.borrow().watchdog.overdue(Instant::now().as_millis()))
+    })
+    .await
+}
+
 #[embassy_executor::task]
 async fn usb_task(mut device: UsbDevice<'static, UsbDriver>) -> ! {
     device.run().await
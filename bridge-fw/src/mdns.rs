@@ -0,0 +1,38 @@
+//! mDNS responder announcing the bridge and its services on the USB network.
+
+use core::cell::RefCell;
+use core::fmt::Write;
+
+use embassy_net::Stack;
+use embassy_sync::blocking_mutex::ThreadModeMutex;
+use embassy_usb::class::cdc_ncm::embassy_net::Device;
+use shared::mdns::{self, Status};
+
+use crate::{AppState, MTU};
+
+/// Answers the mDNS queries of `<hostname>.local` and of the `_afo._tcp` and `_http._tcp` services
+/// of the bridge, the records are announced whenever the address of the bridge changes
+#[embassy_executor::task]
+pub async fn mdns_task(
+    stack: &'static Stack<Device<'static, MTU>>,
+    state: &'static ThreadModeMutex<RefCell<AppState>>,
+    hostname: &'static str,
+) -> ! {
+    mdns::respond(
+        stack,
+        hostname,
+        concat!("version=", env!("CARGO_PKG_VERSION")),
+        "path=/api/health",
+        || {
+            let mut nodes = Status::new();
+            write!(
+                nodes,
+                "nodes={}",
+                state.lock(|c| c.borrow().nodes.nodes().len())
+            )
+            .unwrap();
+            Some(nodes)
+        },
+    )
+    .await
+}
@@ -0,0 +1,252 @@
+//! Transport of the MQTT connection of the bridge.
+//!
+//! rust-mqtt reads a packet in several reads, so a receive can't be cancelled without losing
+//! the rest of the packet. The socket is shared by the client and [`Socket::readable`], which
+//! waits for the next packet without reading it, so that the client starts receiving
+//! only once a packet arrives and the receive is always completed.
+//!
+//! The acknowledgements of the QoS 1 messages are matched to the messages by their packet
+//! identifiers. The readings of the messages which are not acknowledged are queued in the
+//! backlog again, to be backfilled. rust-mqtt drops the packet identifier of the
+//! acknowledgements with a reason code, so the first bytes of each packet are kept by the
+//! socket to read it.
+
+use core::cell::RefCell;
+
+use embassy_net::tcp::{self, TcpSocket};
+use embassy_sync::blocking_mutex::raw::NoopRawMutex;
+use embassy_sync::blocking_mutex::ThreadModeMutex;
+use embassy_sync::mutex::Mutex;
+use embedded_io_async::{ErrorType, Read, Write};
+use heapless::Vec;
+use rand_core::RngCore;
+use rust_mqtt::client::raw_client::{Event, RawMqttClient};
+use rust_mqtt::packet::v5::publish_packet::QualityOfService;
+use rust_mqtt::packet::v5::reason_codes::ReasonCode;
+use shared::qos::{MessageClass, Qos, QosConfig, WillQos};
+
+use crate::backlog::{Queued, SharedBacklog};
+use crate::AppState;
+
+/// Number of QoS 1 messages awaiting their acknowledgement. Once more messages are sent,
+/// the oldest message is no longer tracked and its reading is queued in the backlog again.
+const MAX_IN_FLIGHT: usize = 16;
+
+/// Message awaiting its acknowledgement
+struct InFlight {
+    /// Packet identifier, `None` if the message failed to be sent
+    id: Option<u16>,
+    /// Reading carried by the message
+    reading: Option<Queued>,
+}
+
+/// Number of bytes kept of each received packet, enough for the fixed header, the packet
+/// identifier and the reason code of an acknowledgement
+const HEAD_LEN: usize = 8;
+
+pub struct Client<'a, 's, R: RngCore> {
+    raw: RawMqttClient<'a, WillQos<Transport<'a, 's>>, 5, R>,
+    socket: &'a Socket<'s>,
+    /// Messages in the order they were sent
+    in_flight: Vec<InFlight, MAX_IN_FLIGHT>,
+    qos: QosConfig,
+    backlog: &'static SharedBacklog,
+    state: &'static ThreadModeMutex<RefCell<AppState>>,
+}
+
+impl<'a, 's, R: RngCore> Client<'a, 's, R> {
+    pub fn new(
+        raw: RawMqttClient<'a, WillQos<Transport<'a, 's>>, 5, R>,
+        socket: &'a Socket<'s>,
+        qos: QosConfig,
+        backlog: &'static SharedBacklog,
+        state: &'static ThreadModeMutex<RefCell<AppState>>,
+    ) -> Self {
+        Self {
+            raw,
+            socket,
+            in_flight: Vec::new(),
+            qos,
+            backlog,
+            state,
+        }
+    }
+
+    pub async fn connect(&mut self) -> Result<(), ReasonCode> {
+        self.raw.connect_to_broker().await?;
+        match self.raw.poll::<0>().await? {
+            Event::Connack => Ok(()),
+            Event::Disconnect(reason) => Err(reason),
+            _ => Err(ReasonCode::ImplementationSpecificError),
+        }
+    }
+
+    pub async fn subscribe(&mut self, filter: &str) -> Result<(), ReasonCode> {
+        let mut filters = Vec::<&str, 1>::new();
+        filters.push(filter).unwrap();
+        let id = self.raw.subscribe_to_topics(&filters).await?;
+        match self.raw.poll::<1>().await? {
+            Event::Suback(ack) if ack == id => Ok(()),
+            Event::Suback(_) => Err(ReasonCode::PacketIdentifierNotFound),
+            Event::Disconnect(reason) => Err(reason),
+            _ => Err(ReasonCode::ImplementationSpecificError),
+        }
+    }
+
+    /// QoS of a message class
+    pub fn qos(&self, class: MessageClass) -> QualityOfService {
+        match self.qos.qos(class) {
+            Qos::AtMostOnce => QualityOfService::QoS0,
+            Qos::AtLeastOnce => QualityOfService::QoS1,
+        }
+    }
+
+    /// Sends a message, QoS 1 messages are tracked until the broker acknowledges them.
+    ///
+    /// The `reading` carried by the message is queued in the backlog again if the message
+    /// fails to be sent or isn't acknowledged, see [`Client::requeue_unacknowledged`].
+    pub async fn send(
+        &mut self,
+        qos: QualityOfService,
+        topic: &str,
+        payload: &[u8],
+        retain: bool,
+        reading: Option<Queued>,
+    ) -> Result<(), ReasonCode> {
+        let res = self.raw.send_message(topic, payload, qos, retain).await;
+        let id = match res {
+            Ok(id) if qos == QualityOfService::QoS1 => Some(id),
+            // delivered at most once
+            Ok(_) => return Ok(()),
+            Err(_) if reading.is_some() => None,
+            Err(e) => return Err(e),
+        };
+        if self.in_flight.is_full() {
+            let oldest = self.in_flight.remove(0);
+            if let Some(reading) = oldest.reading {
+                self.requeue(reading).await;
+            }
+        }
+        let _ = self.in_flight.push(InFlight { id, reading });
+        res.map(|_| ())
+    }
+
+    /// Receives the next packet, returns the topic and the payload if it is a message.
+    /// Acknowledgements are matched to the tracked messages, other packets are ignored.
+    pub async fn receive(&mut self) -> Result<Option<(&str, &[u8])>, ReasonCode> {
+        self.socket.head.borrow_mut().clear();
+        match self.raw.poll::<1>().await {
+            Ok(Event::Message(topic, payload)) => Ok(Some((topic, payload))),
+            Ok(Event::Puback(id)) => {
+                acknowledge(&mut self.in_flight, id);
+                Ok(None)
+            }
+            // rust-mqtt fails on this success code, the message was accepted
+            Err(ReasonCode::NoMatchingSubscribers) => {
+                match puback_id(&self.socket.head.borrow()) {
+                    Some(id) => acknowledge(&mut self.in_flight, id),
+                    None => defmt::warn!("Acknowledgement without packet identifier"),
+                }
+                Ok(None)
+            }
+            Ok(Event::Disconnect(reason)) => Err(reason),
+            Ok(_) => Ok(None),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Queues the readings of the messages not acknowledged in the backlog again,
+    /// once the connection failed
+    pub async fn requeue_unacknowledged(&mut self) {
+        while let Some(message) = self.in_flight.pop() {
+            if let Some(reading) = message.reading {
+                self.requeue(reading).await;
+            }
+        }
+    }
+
+    async fn requeue(&self, reading: Queued) {
+        self.backlog.lock().await.requeue(reading).await;
+        self.state.lock(|c| c.borrow_mut().mqtt.requeued += 1);
+    }
+}
+
+/// Removes an acknowledged message
+fn acknowledge(in_flight: &mut Vec<InFlight, MAX_IN_FLIGHT>, id: u16) {
+    let position = in_flight.iter().position(|message| message.id == Some(id));
+    match position {
+        Some(position) => {
+            in_flight.remove(position);
+        }
+        // the message is no longer tracked, its reading has been queued again
+        None => defmt::warn!("Acknowledgement of an unknown message {}", id),
+    }
+}
+
+/// Packet identifier of a PUBACK packet from its first bytes
+fn puback_id(head: &[u8]) -> Option<u16> {
+    let (&header, rest) = head.split_first()?;
+    if header >> 4 != 4 {
+        return None;
+    }
+    // the remaining length is encoded on up to 4 bytes, the last one without the top bit
+    let length_len = rest.iter().take(4).position(|byte| byte & 0x80 == 0)? + 1;
+    match rest.get(length_len..length_len + 2)? {
+        &[high, low] => Some(u16::from_be_bytes([high, low])),
+        _ => None,
+    }
+}
+
+pub struct Socket<'s> {
+    socket: Mutex<NoopRawMutex, TcpSocket<'s>>,
+    /// First bytes of the packet being received
+    head: RefCell<Vec<u8, HEAD_LEN>>,
+}
+
+impl<'s> Socket<'s> {
+    pub fn new(socket: TcpSocket<'s>) -> Self {
+        Self {
+            socket: Mutex::new(socket),
+            head: RefCell::new(Vec::new()),
+        }
+    }
+
+    /// Waits until there is data to receive, without reading it. Fails once the broker
+    /// closes the connection.
+    pub async fn readable(&self) -> Result<(), tcp::Error> {
+        self.socket.lock().await.read_with(|_| (0, ())).await
+    }
+
+    /// Connection of the client over the socket
+    pub fn transport(&self) -> Transport<'_, 's> {
+        Transport { socket: self }
+    }
+}
+
+pub struct Transport<'a, 's> {
+    socket: &'a Socket<'s>,
+}
+
+impl ErrorType for Transport<'_, '_> {
+    type Error = tcp::Error;
+}
+
+impl Read for Transport<'_, '_> {
+    async fn read(&mut self, buf: &mut [u8]) -> Result<usize, Self::Error> {
+        let len = self.socket.socket.lock().await.read(buf).await?;
+        let mut head = self.socket.head.borrow_mut();
+        let kept = len.min(head.capacity() - head.len());
+        let _ = head.extend_from_slice(&buf[..kept]);
+        Ok(len)
+    }
+}
+
+impl Write for Transport<'_, '_> {
+    async fn write(&mut self, buf: &[u8]) -> Result<usize, Self::Error> {
+        self.socket.socket.lock().await.write(buf).await
+    }
+
+    async fn flush(&mut self) -> Result<(), Self::Error> {
+        self.socket.socket.lock().await.flush().await
+    }
+}
@@ -0,0 +1,511 @@
+//! Publishing of the readings, the diagnostics and the claims of the nodes over the MQTT
+//! connection of the bridge, see [`crate::mqtt`] for its transport.
+
+use core::cell::RefCell;
+use core::fmt::{self, Write};
+
+use embassy_sync::blocking_mutex::ThreadModeMutex;
+use embassy_time::{with_timeout, Duration, Instant};
+use heapless::{LinearMap, Vec};
+use nrf_softdevice::Softdevice;
+use rust_mqtt::packet::v5::publish_packet::QualityOfService;
+use rust_mqtt::packet::v5::reason_codes::ReasonCode;
+use shared::coordination::{claim_topic_sensor_id, Claim, Ownership, CLAIM_RENEWAL_MS};
+use shared::crash::JSON_LEN as CRASH_JSON_LEN;
+use shared::policy::{PublishFilter, PublishPolicies, Reading};
+use shared::qos::MessageClass;
+use shared::topic::{Topic, TopicConfig, TopicValues};
+use shared::SensorId;
+
+use crate::backlog::{Queued, SharedBacklog};
+use crate::diagnostics::{self, Diagnostics};
+use crate::identity::Identity;
+use crate::registry::{Node, MAX_NODES};
+use crate::watchdog::{self, Task};
+use crate::{mqtt, AppState, SoftdeviceRng};
+
+pub type Client<'a, 'b> = mqtt::Client<'a, 'b, SoftdeviceRng>;
+
+/// What has been published over the current broker connection
+#[derive(Default)]
+pub struct Session {
+    /// Whether the discovery of the bridge entities has been published
+    announced: bool,
+    /// Nodes whose diagnostic entities have been announced
+    announced_nodes: Vec<SensorId, MAX_NODES>,
+    last_diagnostics: Option<Instant>,
+    /// What has been published of the nodes received by this bridge
+    published: LinearMap<SensorId, Published, MAX_NODES>,
+    /// Which bridge publishes the nodes, including the nodes this bridge doesn't receive
+    owners: LinearMap<SensorId, Ownership, MAX_DEPLOYMENT_NODES>,
+}
+
+/// Maximum number of nodes in a deployment with multiple bridges
+const MAX_DEPLOYMENT_NODES: usize = 4 * MAX_NODES;
+
+/// What has been published of a node
+#[derive(Default)]
+struct Published {
+    filter: PublishFilter,
+    /// When the claim of the node was published last
+    claimed_at: Option<Instant>,
+}
+
+impl Session {
+    /// Ownership of a node, `None` if there are too many nodes in the deployment
+    fn ownership(&mut self, id: SensorId) -> Option<&mut Ownership> {
+        if !self.owners.contains_key(&id) {
+            self.owners.insert(id, Ownership::default()).ok()?;
+        }
+        self.owners.get_mut(&id)
+    }
+
+    fn published(&mut self, id: SensorId) -> &mut Published {
+        if !self.published.contains_key(&id) {
+            // the registry can't hold more than MAX_NODES nodes
+            let _ = self.published.insert(id, Published::default());
+        }
+        self.published.get_mut(&id).unwrap()
+    }
+}
+
+/// Period of publishing the diagnostics
+const DIAGNOSTICS_PERIOD: Duration = Duration::from_secs(60);
+
+/// Period of the periodic publishing, which catches up with the readings of the frames
+/// missed by the publishing task
+pub const TICK_PERIOD: Duration = Duration::from_secs(1);
+
+/// Publishes the new reading of a node owned by this bridge if it changed beyond the deadband
+/// of the node's publish policy, or if `heartbeat` is set and the node has been quiet
+/// for the heartbeat interval of the policy.
+/// The claim of the node is published along with the reading, or renewed separately
+/// when the readings are held back by the deadband.
+pub async fn publish_node(
+    client: &mut Client<'_, '_>,
+    identity: &Identity,
+    policies: &PublishPolicies,
+    topics: &TopicConfig<'_>,
+    session: &mut Session,
+    node: &Node,
+    heartbeat: bool,
+) -> Result<(), ReasonCode> {
+    let s = node.measurement;
+    let policy = policies.get(s.sensor_id);
+    let now = Instant::now();
+    let heartbeat_due = heartbeat
+        && session
+            .published(s.sensor_id)
+            .filter
+            .heartbeat_due(policy, now.as_millis());
+    let Some(ownership) = session.ownership(s.sensor_id) else {
+        defmt::warn!("Too many nodes, node {} not published", s.sensor_id);
+        return Ok(());
+    };
+    let rssi = node.link.smoothed as i8;
+    let claim = match ownership.publish(identity.id, rssi, s.sequence, now.as_millis()) {
+        Some(claim) => claim,
+        None if heartbeat_due => match ownership.renew(identity.id, rssi, now.as_millis()) {
+            Some(claim) => claim,
+            None => return Ok(()),
+        },
+        // already published, or owned by another bridge
+        None => return Ok(()),
+    };
+
+    let published = session.published(s.sensor_id);
+    let reading_due = published
+        .filter
+        .publish(policy, Reading::from(&s), now.as_millis());
+    let renew_claim = reading_due
+        || published
+            .claimed_at
+            .map_or(true, |at| (now - at).as_millis() >= CLAIM_RENEWAL_MS);
+    if renew_claim {
+        published.claimed_at = Some(now);
+    }
+
+    if reading_due {
+        // the client backfills the reading if the broker doesn't acknowledge it
+        publish_reading(client, identity, topics, session, node).await?;
+    }
+
+    if renew_claim {
+        let mut topic = heapless::String::<64>::new();
+        topics
+            .write_topic(Topic::Claim, &s.sensor_id, &mut topic)
+            .unwrap();
+        let mut payload = [0u8; 8];
+        let payload = postcard::to_slice(&claim, &mut payload).unwrap();
+        // the claims are renewed continuously, so they are not acknowledged
+        client
+            .send(QualityOfService::QoS0, &topic, payload, true, None)
+            .await?;
+    }
+    Ok(())
+}
+
+/// Publishes the reading of a node, its link quality, diagnostics
+/// and the discovery of its entities, if not announced yet
+async fn publish_reading(
+    client: &mut Client<'_, '_>,
+    identity: &Identity,
+    topics: &TopicConfig<'_>,
+    session: &mut Session,
+    node: &Node,
+) -> Result<(), ReasonCode> {
+    let s = node.measurement;
+
+    let mut json = heapless::String::<64>::new();
+    write!(
+        &mut json,
+        r#"{{"co2": "{}", "temperature": "{:.1}", "humidity": "{}"}}"#,
+        s.co2_concentration,
+        s.temperature as f32 * 0.1,
+        s.humidity
+    )
+    .unwrap();
+
+    let values = TopicValues {
+        bridge: &identity.name,
+        node: &s.sensor_id,
+    };
+    let mut topic = heapless::String::<64>::new();
+    if topics.write_state(&values, &mut topic).is_ok() {
+        let reading = Queued {
+            measurement: s,
+            received_ms: Instant::now().as_millis(),
+        };
+        let qos = client.qos(MessageClass::Measurement);
+        client
+            .send(qos, &topic, json.as_bytes(), true, Some(reading))
+            .await?;
+    } else {
+        defmt::error!("Invalid state topic template, or the topic is too long");
+    }
+
+    if topics.metric.is_some() {
+        let temperature = s.temperature as f32 * 0.1;
+        publish_metric(
+            client,
+            topics,
+            &values,
+            "co2",
+            format_args!("{}", s.co2_concentration),
+        )
+        .await?;
+        publish_metric(
+            client,
+            topics,
+            &values,
+            "temperature",
+            format_args!("{:.1}", temperature),
+        )
+        .await?;
+        publish_metric(
+            client,
+            topics,
+            &values,
+            "humidity",
+            format_args!("{}", s.humidity),
+        )
+        .await?;
+    }
+
+    let mut diagnostics_topic = heapless::String::<64>::new();
+    topics
+        .write_topic(Topic::Diagnostics, &s.sensor_id, &mut diagnostics_topic)
+        .unwrap();
+    let mut link_topic = heapless::String::<64>::new();
+    topics
+        .write_topic(Topic::Link, &s.sensor_id, &mut link_topic)
+        .unwrap();
+
+    if !session.announced_nodes.contains(&s.sensor_id) {
+        let mut id = heapless::String::<16>::new();
+        write!(id, "afo_{}", s.sensor_id).unwrap();
+        let mut name = heapless::String::<16>::new();
+        write!(name, "AFO {}", s.sensor_id).unwrap();
+        let device = shared::discovery::Device {
+            id: &id,
+            name: &name,
+            model: "node-fw",
+            sw_version: None,
+        };
+        publish_discovery(
+            client,
+            &device,
+            &diagnostics_topic,
+            &diagnostics::NODE_SENSORS,
+        )
+        .await?;
+        publish_discovery(client, &device, &link_topic, &diagnostics::LINK_SENSORS).await?;
+        // the registry can't hold more than MAX_NODES nodes
+        let _ = session.announced_nodes.push(s.sensor_id);
+    }
+
+    let mut json = heapless::String::<192>::new();
+    node.write_link_json(&identity.name, &mut json).unwrap();
+    publish(
+        client,
+        MessageClass::Diagnostics,
+        &link_topic,
+        json.as_bytes(),
+    )
+    .await?;
+
+    if let Some(node_diagnostics) = node.diagnostics {
+        let mut json = heapless::String::<128>::new();
+        node_diagnostics.write_json(&mut json).unwrap();
+        publish(
+            client,
+            MessageClass::Diagnostics,
+            &diagnostics_topic,
+            json.as_bytes(),
+        )
+        .await?;
+    }
+    Ok(())
+}
+
+/// Publishes the readings missed in the frames, heartbeats of the quiet nodes,
+/// statistics of the closed windows, diagnostics and discovery of entities not announced yet
+pub async fn publish_periodic(
+    client: &mut Client<'_, '_>,
+    sd: &'static Softdevice,
+    identity: &Identity,
+    policies: &PublishPolicies,
+    topics: &TopicConfig<'_>,
+    state: &'static ThreadModeMutex<RefCell<AppState>>,
+    session: &mut Session,
+) -> Result<(), ReasonCode> {
+    let mut diagnostics_topic = heapless::String::<64>::new();
+    identity
+        .write_topic(topics, Topic::Diagnostics, &mut diagnostics_topic)
+        .unwrap();
+
+    if !session.announced {
+        publish_discovery(
+            client,
+            &identity.device(),
+            &diagnostics_topic,
+            &diagnostics::SENSORS,
+        )
+        .await?;
+        session.announced = true;
+    }
+
+    let nodes: Vec<_, MAX_NODES> =
+        state.lock(|c| Vec::from_slice(c.borrow().nodes.nodes()).unwrap());
+    for node in &nodes {
+        publish_node(client, identity, policies, topics, session, node, true).await?;
+    }
+
+    let now = Instant::now().as_millis();
+    while let Some((id, summary)) = state.lock(|c| c.borrow_mut().stats.take_closed(now)) {
+        // the statistics of every node are published by its owner only
+        let owned = session
+            .ownership(id)
+            .map_or(false, |ownership| ownership.owner() == Some(identity.id));
+        if owned {
+            let mut topic = heapless::String::<64>::new();
+            let window = summary.length;
+            topics
+                .write_topic(Topic::Stats { window }, &id, &mut topic)
+                .unwrap();
+            let mut json = heapless::String::<512>::new();
+            summary.write_json(&identity.name, &mut json).unwrap();
+            publish(client, MessageClass::Diagnostics, &topic, json.as_bytes()).await?;
+        }
+    }
+
+    if session
+        .last_diagnostics
+        .map_or(true, |last| last.elapsed() >= DIAGNOSTICS_PERIOD)
+    {
+        let diagnostics = state.lock(|c| {
+            let c = c.borrow();
+            Diagnostics {
+                id_source: identity.id_source,
+                reset: c.reset,
+                mqtt: c.mqtt,
+                scan: c.scan,
+                die_temperature: diagnostics::die_temperature(sd),
+                stack_free: diagnostics::stack_free(),
+            }
+        });
+        let mut json = heapless::String::<512>::new();
+        diagnostics.write_json(&mut json).unwrap();
+        publish(
+            client,
+            MessageClass::Diagnostics,
+            &diagnostics_topic,
+            json.as_bytes(),
+        )
+        .await?;
+        session.last_diagnostics = Some(Instant::now());
+    }
+
+    if let Some(crash) = state.lock(|c| c.borrow().crash) {
+        let mut json = heapless::String::<CRASH_JSON_LEN>::new();
+        crash.write_json(&mut json).unwrap();
+
+        let mut topic = heapless::String::<64>::new();
+        identity
+            .write_topic(topics, Topic::Crash, &mut topic)
+            .unwrap();
+        // retained, so that the last crash stays available
+        publish(client, MessageClass::Diagnostics, &topic, json.as_bytes()).await?;
+        state.lock(|c| c.borrow_mut().crash = None);
+    }
+
+    Ok(())
+}
+
+/// Publishes the Home Assistant discovery configs of the sensors of a device
+async fn publish_discovery(
+    client: &mut Client<'_, '_>,
+    device: &shared::discovery::Device<'_>,
+    state_topic: &str,
+    sensors: &[shared::discovery::Sensor<'_>],
+) -> Result<(), ReasonCode> {
+    for sensor in sensors {
+        let mut topic = heapless::String::<64>::new();
+        sensor.write_topic(device, &mut topic).unwrap();
+        let mut config = heapless::String::<448>::new();
+        sensor
+            .write_config(device, state_topic, &mut config)
+            .unwrap();
+        publish(client, MessageClass::Discovery, &topic, config.as_bytes()).await?;
+    }
+    Ok(())
+}
+
+/// Publishes a metric as a plain number to its own topic
+async fn publish_metric(
+    client: &mut Client<'_, '_>,
+    topics: &TopicConfig<'_>,
+    values: &TopicValues<'_>,
+    metric: &str,
+    value: fmt::Arguments<'_>,
+) -> Result<(), ReasonCode> {
+    let mut topic = heapless::String::<64>::new();
+    if topics.write_metric(values, metric, &mut topic).is_err() {
+        defmt::error!("Invalid metric topic template, or the topic is too long");
+        return Ok(());
+    }
+    let mut payload = heapless::String::<16>::new();
+    payload.write_fmt(value).unwrap();
+    publish(
+        client,
+        MessageClass::Measurement,
+        &topic,
+        payload.as_bytes(),
+    )
+    .await
+}
+
+/// Publishes the readings queued while the broker was unreachable, oldest first, to
+/// `<prefix>-<sensor id>-backfill` along with their age in seconds, so that the consumers can
+/// fill the gaps. The readings are not retained, not to replace the current state.
+pub async fn replay_backlog(
+    client: &mut Client<'_, '_>,
+    identity: &Identity,
+    topics: &TopicConfig<'_>,
+    state: &'static ThreadModeMutex<RefCell<AppState>>,
+    backlog: &SharedBacklog,
+    session: &mut Session,
+) -> Result<(), ReasonCode> {
+    loop {
+        state.lock(|c| watchdog::check_in(&mut c.borrow_mut().watchdog, Task::Publish));
+
+        let Some(queued) = backlog.lock().await.peek().await else {
+            return Ok(());
+        };
+        let s = queued.measurement;
+        // readings of the nodes taken over by another bridge meanwhile have been published by it
+        let owned = session.ownership(s.sensor_id).map_or(false, |ownership| {
+            ownership.owner().map_or(true, |owner| owner == identity.id)
+        });
+        if owned {
+            let age = Instant::now()
+                .as_millis()
+                .saturating_sub(queued.received_ms)
+                / 1000;
+            let mut json = heapless::String::<128>::new();
+            write!(
+                &mut json,
+                r#"{{"co2": "{}", "temperature": "{:.1}", "humidity": "{}", "sequence": {}, "age": {}}}"#,
+                s.co2_concentration,
+                s.temperature as f32 * 0.1,
+                s.humidity,
+                s.sequence,
+                age
+            )
+            .unwrap();
+            let mut topic = heapless::String::<64>::new();
+            topics
+                .write_topic(Topic::Backfill, &s.sensor_id, &mut topic)
+                .unwrap();
+            let qos = client.qos(MessageClass::Measurement);
+            client
+                .send(qos, &topic, json.as_bytes(), false, Some(queued))
+                .await?;
+        }
+        backlog.lock().await.pop().await;
+    }
+}
+
+/// Receives the claims of the nodes until `deadline`
+pub async fn receive_claims(
+    client: &mut Client<'_, '_>,
+    socket: &mqtt::Socket<'_>,
+    identity: &Identity,
+    topics: &TopicConfig<'_>,
+    session: &mut Session,
+    deadline: Instant,
+) -> Result<(), ReasonCode> {
+    loop {
+        let remaining = deadline.saturating_duration_since(Instant::now());
+        match with_timeout(remaining, socket.readable()).await {
+            Ok(Ok(())) => {}
+            Ok(Err(_)) => return Err(ReasonCode::NetworkError),
+            Err(_) => return Ok(()),
+        }
+        if let Some((topic, payload)) = client.receive().await? {
+            record_claim(identity, topics, session, topic, payload);
+        }
+    }
+}
+
+/// Records a claim received from the broker, other messages are ignored
+pub fn record_claim(
+    identity: &Identity,
+    topics: &TopicConfig<'_>,
+    session: &mut Session,
+    topic: &str,
+    payload: &[u8],
+) {
+    let Some(id) = claim_topic_sensor_id(topics, topic) else {
+        return;
+    };
+    match postcard::from_bytes::<Claim>(payload) {
+        Ok(claim) => {
+            if let Some(ownership) = session.ownership(id) {
+                ownership.record_claim(identity.id, claim, Instant::now().as_millis());
+            }
+        }
+        Err(_) => defmt::warn!("Malformed claim of node {}", id),
+    }
+}
+
+/// Publishes a retained message with the QoS of its class
+pub async fn publish(
+    client: &mut Client<'_, '_>,
+    class: MessageClass,
+    topic: &str,
+    payload: &[u8],
+) -> Result<(), ReasonCode> {
+    let qos = client.qos(class);
+    client.send(qos, topic, payload, true, None).await
+}
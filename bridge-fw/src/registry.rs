@@ -0,0 +1,215 @@
+//! Registry of the nodes heard by the bridge, keyed by their sensor id.
+
+use core::fmt::{self, Write};
+
+use embassy_time::Instant;
+use heapless::Vec;
+use nrf_softdevice::raw;
+use shared::{AirQualityAdvertisement, NodeDiagnostics, SensorId};
+
+/// Maximum number of nodes tracked by the bridge
+pub const MAX_NODES: usize = 16;
+
+/// BLE address of the device a node advertises from
+pub type PeerAddress = [u8; 6];
+
+/// Radio properties of a received advertisement
+#[derive(Clone, Copy, Debug, defmt::Format)]
+pub struct Reception {
+    /// Signal strength in dBm
+    pub rssi: i8,
+    /// PHY the advertisement was received on, one of `BLE_GAP_PHY_*`
+    pub phy: u8,
+}
+
+/// Signal strength statistics of the advertisements received from a node
+#[derive(Clone, Copy, Debug, defmt::Format)]
+pub struct LinkQuality {
+    pub last: i8,
+    pub min: i8,
+    pub max: i8,
+    /// Exponential moving average of the RSSI
+    pub smoothed: f32,
+    pub phy: u8,
+}
+
+impl LinkQuality {
+    /// Weight of a new sample in the moving average
+    const SMOOTHING: f32 = 0.125;
+
+    fn new(reception: Reception) -> Self {
+        Self {
+            last: reception.rssi,
+            min: reception.rssi,
+            max: reception.rssi,
+            smoothed: reception.rssi as f32,
+            phy: reception.phy,
+        }
+    }
+
+    fn record(&mut self, reception: Reception) {
+        self.last = reception.rssi;
+        self.min = self.min.min(reception.rssi);
+        self.max = self.max.max(reception.rssi);
+        self.smoothed += (reception.rssi as f32 - self.smoothed) * Self::SMOOTHING;
+        self.phy = reception.phy;
+    }
+
+    pub fn phy_str(&self) -> &'static str {
+        match self.phy as u32 {
+            raw::BLE_GAP_PHY_1MBPS => "1M",
+            raw::BLE_GAP_PHY_2MBPS => "2M",
+            raw::BLE_GAP_PHY_CODED => "coded",
+            _ => "unknown",
+        }
+    }
+}
+
+/// Formats a peer address the usual way, most significant byte first
+pub struct DisplayAddress<'a>(pub &'a PeerAddress);
+
+impl fmt::Display for DisplayAddress<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (i, byte) in self.0.iter().rev().enumerate() {
+            if i > 0 {
+                f.write_char(':')?;
+            }
+            write!(f, "{:02X}", byte)?;
+        }
+        Ok(())
+    }
+}
+
+#[derive(Clone, Copy)]
+pub struct Node {
+    pub address: PeerAddress,
+    pub measurement: AirQualityAdvertisement,
+    pub link: LinkQuality,
+    /// Diagnostics from the scan response, available with active scanning only
+    pub diagnostics: Option<NodeDiagnostics>,
+    /// When the last measurement of the node was received
+    pub last_seen: Instant,
+}
+
+impl Node {
+    pub fn id(&self) -> SensorId {
+        self.measurement.sensor_id
+    }
+
+    /// Writes the link quality and the address of the node as a JSON object,
+    /// along with the name of the receiving bridge
+    pub fn write_link_json(&self, bridge: &str, w: &mut impl Write) -> fmt::Result {
+        write!(
+            w,
+            r#"{{"rssi": {}, "rssi_min": {}, "rssi_max": {}, "rssi_smoothed": {:.1}, "address": "{}", "phy": "{}", "bridge": "{}"}}"#,
+            self.link.last,
+            self.link.min,
+            self.link.max,
+            self.link.smoothed,
+            DisplayAddress(&self.address),
+            self.link.phy_str(),
+            bridge
+        )
+    }
+
+    /// Writes the measurement, the link quality and the diagnostics of the node as a JSON object,
+    /// `last_seen` is the number of seconds since the last measurement
+    pub fn write_json(&self, bridge: &str, w: &mut impl Write) -> fmt::Result {
+        let m = &self.measurement;
+        write!(
+            w,
+            r#"{{"id": {}, "sequence": {}, "co2": {}, "temperature": {:.1}, "humidity": {}, "last_seen": {}, "link": "#,
+            m.sensor_id,
+            m.sequence,
+            m.co2_concentration,
+            m.temperature as f32 * 0.1,
+            m.humidity,
+            self.last_seen.elapsed().as_secs(),
+        )?;
+        self.write_link_json(bridge, w)?;
+        w.write_str(r#", "diagnostics": "#)?;
+        match &self.diagnostics {
+            Some(diagnostics) => diagnostics.write_json(w)?,
+            None => w.write_str("null")?,
+        }
+        w.write_char('}')
+    }
+}
+
+#[derive(Clone, Copy, Debug, defmt::Format, PartialEq, Eq)]
+pub enum UpdateError {
+    /// The registry is full and the node is not known yet
+    Full,
+    /// Another device already advertises with the same sensor id
+    Conflict { id: SensorId },
+}
+
+#[derive(Default)]
+pub struct NodeRegistry {
+    nodes: Vec<Node, MAX_NODES>,
+}
+
+impl NodeRegistry {
+    pub const fn new() -> Self {
+        Self { nodes: Vec::new() }
+    }
+
+    /// Stores the measurement of a node, registering the node if it hasn't been heard yet.
+    /// Measurements with an id already owned by a different device are rejected
+    /// instead of overwriting the other node's data.
+    pub fn update(
+        &mut self,
+        address: PeerAddress,
+        measurement: AirQualityAdvertisement,
+        reception: Reception,
+    ) -> Result<(), UpdateError> {
+        let id = measurement.sensor_id;
+        match self.nodes.iter_mut().find(|node| node.id() == id) {
+            Some(node) if node.address != address => Err(UpdateError::Conflict { id }),
+            Some(node) => {
+                node.measurement = measurement;
+                node.link.record(reception);
+                node.last_seen = Instant::now();
+                Ok(())
+            }
+            None => self
+                .nodes
+                .push(Node {
+                    address,
+                    measurement,
+                    link: LinkQuality::new(reception),
+                    diagnostics: None,
+                    last_seen: Instant::now(),
+                })
+                .map_err(|_| UpdateError::Full),
+        }
+    }
+
+    /// Stores the diagnostics of an already registered node, diagnostics of unknown nodes are ignored.
+    /// Returns whether the diagnostics were stored.
+    pub fn update_diagnostics(
+        &mut self,
+        address: PeerAddress,
+        diagnostics: NodeDiagnostics,
+    ) -> bool {
+        match self
+            .nodes
+            .iter_mut()
+            .find(|node| node.id() == diagnostics.sensor_id && node.address == address)
+        {
+            Some(node) => {
+                node.diagnostics = Some(diagnostics);
+                true
+            }
+            None => false,
+        }
+    }
+
+    pub fn get(&self, id: SensorId) -> Option<Node> {
+        self.nodes.iter().find(|node| node.id() == id).copied()
+    }
+
+    pub fn nodes(&self) -> &[Node] {
+        &self.nodes
+    }
+}
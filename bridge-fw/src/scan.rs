@@ -0,0 +1,117 @@
+//! Configuration and health tracking of the BLE scanning.
+
+use embassy_time::{Duration, Instant};
+use nrf_softdevice::ble::central::{ScanConfig, ScanError};
+use nrf_softdevice::ble::PhySet;
+use nrf_softdevice::RawError;
+use shared::scan::{ScanParameters, ScanPhy};
+
+/// Softdevice configuration of the scan parameters
+pub fn config(parameters: &ScanParameters) -> ScanConfig<'static> {
+    ScanConfig {
+        interval: parameters.interval_units(),
+        window: parameters.window_units(),
+        active: parameters.active,
+        phys: match parameters.phy {
+            ScanPhy::OneMbit => PhySet::M1,
+            ScanPhy::Coded => PhySet::Coded,
+            ScanPhy::Both => PhySet::M1Coded,
+        },
+        timeout: parameters.timeout_units(),
+        ..Default::default()
+    }
+}
+
+/// Backoff after the first failed scan, doubled with every consecutive failure
+const MIN_BACKOFF: Duration = Duration::from_millis(100);
+const MAX_BACKOFF: Duration = Duration::from_secs(10);
+
+/// Reason the scan has ended
+#[derive(Clone, Copy, PartialEq, Eq, defmt::Format)]
+pub enum ScanEnd {
+    /// The configured timeout has elapsed, this is the regular way the scan ends
+    Timeout,
+    /// The softdevice is busy, e.g. another scan is in progress
+    Busy,
+    /// The softdevice lacks resources to run the scan
+    Resources,
+    /// The scan parameters were rejected, this needs a configuration change to recover
+    InvalidParameters,
+    Other(RawError),
+}
+
+impl From<ScanError> for ScanEnd {
+    fn from(e: ScanError) -> Self {
+        match e {
+            ScanError::Timeout => ScanEnd::Timeout,
+            ScanError::Raw(RawError::InvalidState | RawError::Busy) => ScanEnd::Busy,
+            ScanError::Raw(RawError::NoMem | RawError::Resources) => ScanEnd::Resources,
+            ScanError::Raw(RawError::InvalidParam | RawError::NotSupported) => {
+                ScanEnd::InvalidParameters
+            }
+            ScanError::Raw(e) => ScanEnd::Other(e),
+        }
+    }
+}
+
+impl ScanEnd {
+    pub fn is_failure(&self) -> bool {
+        *self != ScanEnd::Timeout
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ScanEnd::Timeout => "timeout",
+            ScanEnd::Busy => "busy",
+            ScanEnd::Resources => "resources",
+            ScanEnd::InvalidParameters => "invalid_parameters",
+            ScanEnd::Other(_) => "other",
+        }
+    }
+}
+
+/// Statistics of the scanning exposed in diagnostics
+#[derive(Clone, Copy, Default)]
+pub struct ScanHealth {
+    pub running: bool,
+    /// Number of times the scan was restarted
+    pub restarts: u32,
+    /// Number of scans that ended with an error
+    pub failures: u32,
+    /// Consecutive failures, used for computing the backoff
+    pub consecutive_failures: u32,
+    pub last_end: Option<ScanEnd>,
+    /// Number of reports received from AFO nodes
+    pub reports: u32,
+    /// Number of reports that couldn't be decoded
+    pub parse_errors: u32,
+    pub last_report: Option<Instant>,
+}
+
+impl ScanHealth {
+    /// Records the end of a scan and returns how long to wait before restarting it
+    pub fn record_end(&mut self, end: ScanEnd) -> Duration {
+        self.running = false;
+        self.last_end = Some(end);
+        if !end.is_failure() {
+            self.consecutive_failures = 0;
+            return Duration::from_ticks(0);
+        }
+
+        self.failures = self.failures.wrapping_add(1);
+        let shift = self.consecutive_failures.min(16);
+        self.consecutive_failures = self.consecutive_failures.saturating_add(1);
+        Duration::from_ticks(MIN_BACKOFF.as_ticks() << shift).min(MAX_BACKOFF)
+    }
+
+    pub fn record_report(&mut self) {
+        self.reports = self.reports.wrapping_add(1);
+        self.last_report = Some(Instant::now());
+        // the scan is evidently working
+        self.consecutive_failures = 0;
+    }
+
+    pub fn record_parse_error(&mut self) {
+        self.parse_errors = self.parse_errors.wrapping_add(1);
+    }
+}
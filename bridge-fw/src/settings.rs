@@ -0,0 +1,136 @@
+//! Persistence of the [`BridgeConfig`] in the internal flash.
+//!
+//! The record is kept in a sequential-storage map, which appends every change after the previous
+//! record and erases the pages in turns, spreading the wear over the region.
+
+use core::ops::Range;
+
+use embassy_sync::blocking_mutex::raw::ThreadModeRawMutex;
+use embassy_sync::signal::Signal;
+use embedded_storage_async::nor_flash::NorFlash;
+use heapless::Vec;
+use nrf_softdevice::Flash;
+use sequential_storage::cache::NoCache;
+use sequential_storage::map::{self, StorageItem};
+use sequential_storage::Error;
+use shared::config::{BridgeConfig, ConfigError, MAX_RECORD_LEN};
+
+/// The last two pages of the internal flash, excluded from the firmware in `memory.x`
+const RANGE: Range<u32> = 0xFE000..0x100000;
+/// Key of the record, the map holds the configuration only
+const KEY: u8 = 0;
+/// Item of the map, the key followed by the record, rounded up to the flash words
+const ITEM_LEN: usize = (1 + MAX_RECORD_LEN + 3) / 4 * 4;
+
+pub enum Request {
+    /// Stores the configuration of the state
+    Store,
+    /// Removes the stored configuration, the defaults are used after a reset
+    Erase,
+}
+
+/// Requests to the task owning the flash, only the latest request is kept
+pub static REQUESTS: Signal<ThreadModeRawMutex, Request> = Signal::new();
+
+/// Encoded configuration stored under [`KEY`], decoded outside of the map,
+/// so that the map can always move the records between its pages
+struct Record(Vec<u8, MAX_RECORD_LEN>);
+
+impl StorageItem for Record {
+    type Key = u8;
+    type Error = ConfigError;
+
+    fn serialize_into(&self, buffer: &mut [u8]) -> Result<usize, Self::Error> {
+        let len = 1 + self.0.len();
+        let item = buffer.get_mut(..len).ok_or(ConfigError::TooLong)?;
+        item[0] = KEY;
+        item[1..].copy_from_slice(&self.0);
+        Ok(len)
+    }
+
+    fn deserialize_from(buffer: &[u8]) -> Result<Self, Self::Error> {
+        let (_, record) = buffer.split_first().ok_or(ConfigError::Malformed)?;
+        Vec::from_slice(record)
+            .map(Record)
+            .map_err(|_| ConfigError::TooLong)
+    }
+
+    fn deserialize_key_only(buffer: &[u8]) -> Result<Self::Key, Self::Error> {
+        buffer.first().copied().ok_or(ConfigError::Malformed)
+    }
+
+    fn key(&self) -> Self::Key {
+        KEY
+    }
+}
+
+/// Loads the stored configuration, `None` if there is none or if it can't be used.
+///
+/// The region is erased only when its content is unusable, so that it can be written again.
+/// After a failed read of the flash, the stored configuration is kept for the next boot.
+pub async fn load(flash: &mut Flash) -> Option<BridgeConfig> {
+    let mut buffer = [0; ITEM_LEN];
+    let record =
+        match map::fetch_item::<Record, _>(flash, RANGE, NoCache::new(), &mut buffer, KEY).await {
+            Ok(Some(record)) => record,
+            Ok(None) => return None,
+            Err(e @ (Error::Corrupted { .. } | Error::Item(_))) => {
+                defmt::error!(
+                    "Corrupted configuration: {:?}, erasing it and using the defaults",
+                    e
+                );
+                erase(flash).await;
+                return None;
+            }
+            Err(e) => {
+                defmt::error!(
+                    "Failed to load the configuration: {:?}, using the defaults",
+                    e
+                );
+                return None;
+            }
+        };
+    match BridgeConfig::decode(&record.0) {
+        Ok(config) => Some(config),
+        // kept for the newer firmware which stored it
+        Err(e @ ConfigError::UnsupportedVersion(_)) => {
+            defmt::error!(
+                "Configuration stored by a newer firmware: {:?}, using the defaults",
+                e
+            );
+            None
+        }
+        Err(e) => {
+            defmt::error!(
+                "Invalid stored configuration: {:?}, erasing it and using the defaults",
+                e
+            );
+            erase(flash).await;
+            None
+        }
+    }
+}
+
+/// Stores the configuration, replacing the previous one
+pub async fn store(flash: &mut Flash, config: &BridgeConfig) {
+    let mut record = [0; MAX_RECORD_LEN];
+    let record = match config.encode(&mut record) {
+        // the record fits, as the buffers have the same length
+        Ok(record) => Record(Vec::from_slice(record).unwrap()),
+        Err(e) => {
+            defmt::error!("Failed to encode the configuration: {:?}", e);
+            return;
+        }
+    };
+    let mut buffer = [0; ITEM_LEN];
+    match map::store_item(flash, RANGE, NoCache::new(), &mut buffer, &record).await {
+        Ok(()) => defmt::info!("Configuration stored"),
+        Err(e) => defmt::error!("Failed to store the configuration: {:?}", e),
+    }
+}
+
+pub async fn erase(flash: &mut Flash) {
+    if let Err(e) = NorFlash::erase(flash, RANGE.start, RANGE.end).await {
+        defmt::error!("Failed to erase the configuration: {:?}", e);
+    }
+}
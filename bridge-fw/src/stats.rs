@@ -0,0 +1,70 @@
+//! Statistics of the readings of the nodes over the configured windows.
+
+use heapless::Vec;
+use shared::policy::Reading;
+use shared::stats::{Window, WindowSummary, MAX_WINDOWS};
+use shared::{AirQualityAdvertisement, SensorId, Sequence};
+
+use crate::registry::MAX_NODES;
+
+struct NodeStats {
+    id: SensorId,
+    /// Sequence of the last recorded reading, as every reading is advertised repeatedly
+    sequence: Sequence,
+    windows: Vec<Window, MAX_WINDOWS>,
+}
+
+pub struct StatsRegistry {
+    /// Window lengths in seconds
+    lengths: Vec<u32, MAX_WINDOWS>,
+    nodes: Vec<NodeStats, MAX_NODES>,
+}
+
+impl StatsRegistry {
+    pub const fn new(lengths: Vec<u32, MAX_WINDOWS>) -> Self {
+        Self {
+            lengths,
+            nodes: Vec::new(),
+        }
+    }
+
+    /// Adds a reading to the windows of its node, readings already recorded are ignored
+    pub fn record(&mut self, measurement: &AirQualityAdvertisement, now_ms: u64) {
+        let reading = Reading::from(measurement);
+        let id = measurement.sensor_id;
+        match self.nodes.iter_mut().find(|node| node.id == id) {
+            Some(node) if node.sequence == measurement.sequence => {}
+            Some(node) => {
+                node.sequence = measurement.sequence;
+                for window in &mut node.windows {
+                    window.add(&reading, now_ms);
+                }
+            }
+            None => {
+                let mut windows = Vec::new();
+                for length in &self.lengths {
+                    let mut window = Window::new(*length, now_ms);
+                    window.add(&reading, now_ms);
+                    // there are at most MAX_WINDOWS lengths
+                    let _ = windows.push(window);
+                }
+                // the registry of the nodes can't hold more nodes either
+                let _ = self.nodes.push(NodeStats {
+                    id,
+                    sequence: measurement.sequence,
+                    windows,
+                });
+            }
+        }
+    }
+
+    /// Takes the statistics of a closed window of any node
+    pub fn take_closed(&mut self, now_ms: u64) -> Option<(SensorId, WindowSummary)> {
+        self.nodes.iter_mut().find_map(|node| {
+            node.windows
+                .iter_mut()
+                .find_map(|window| window.take_closed(now_ms))
+                .map(|summary| (node.id, summary))
+        })
+    }
+}
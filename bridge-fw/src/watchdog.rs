@@ -0,0 +1,59 @@
+//! Hardware watchdog fed only while all critical tasks keep checking in.
+
+use embassy_nrf::peripherals::WDT;
+use shared::watchdog::{self, nrf, TaskMonitor};
+
+pub use shared::watchdog::{check_in, supervise};
+
+/// Tasks that have to check in for the watchdog to be fed
+#[derive(Clone, Copy, defmt::Format)]
+pub enum Task {
+    Scan,
+    Publish,
+}
+
+impl watchdog::Task for Task {
+    const ALL: &'static [Task] = &[Task::Scan, Task::Publish];
+
+    fn index(self) -> usize {
+        self as usize
+    }
+
+    fn as_str(&self) -> &'static str {
+        match self {
+            Task::Scan => "scan",
+            Task::Publish => "publish",
+        }
+    }
+
+    /// The scan checks in with every report and whenever it is restarted,
+    /// the publishing with every connection attempt.
+    fn deadline_ms(self) -> u64 {
+        match self {
+            Task::Scan => 90_000,
+            Task::Publish => 60_000,
+        }
+    }
+}
+
+/// Hardware watchdog timeout in 32.768 kHz ticks
+const TIMEOUT_TICKS: u32 = 32768 * 5;
+
+pub type Monitor = TaskMonitor<Task>;
+
+pub type ResetInfo = watchdog::ResetInfo<Task>;
+
+pub type Watchdog = nrf::Watchdog;
+
+/// Starts the hardware watchdog, it can't be stopped until the next reset
+pub fn start(_wdt: WDT) -> Watchdog {
+    nrf::start(TIMEOUT_TICKS, |value| unsafe {
+        nrf_softdevice::raw::sd_power_gpregret_clr(1, 0xff);
+        nrf_softdevice::raw::sd_power_gpregret_set(1, value);
+    })
+}
+
+/// Reads and clears the reset reason, has to be called before the softdevice is enabled.
+pub fn take_reset_info() -> ResetInfo {
+    nrf::take_reset_info()
+}
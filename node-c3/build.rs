@@ -0,0 +1,30 @@
+//! Checks the MQTT broker and its pinned key set at build time, so that the firmware never fails
+//! to parse them.
+
+fn main() {
+    println!("cargo:rerun-if-env-changed=SERVER_IP");
+    if let Ok(host) = std::env::var("SERVER_IP") {
+        if let Err(e) = shared::network::Host::parse(&host) {
+            panic!(
+                "SERVER_IP `{}` is neither an address nor a hostname: {:?}",
+                host, e
+            );
+        }
+    }
+
+    println!("cargo:rerun-if-env-changed=MQTT_SERVER_CERT");
+    if std::env::var_os("CARGO_FEATURE_TLS").is_some() {
+        if let Ok(path) = std::env::var("MQTT_SERVER_CERT") {
+            println!("cargo:rerun-if-changed={}", path);
+            let der = std::fs::read(&path)
+                .unwrap_or_else(|e| panic!("MQTT_SERVER_CERT `{}`: {}", path, e));
+            if shared::x509::p256_public_key(&der).is_none() {
+                panic!(
+                    "MQTT_SERVER_CERT `{}` is not a DER encoded certificate \
+                     or subject public key info with a P-256 key",
+                    path
+                );
+            }
+        }
+    }
+}
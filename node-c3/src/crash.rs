@@ -0,0 +1,23 @@
+//! Persistence of crash reports across resets in RTC memory that isn't initialized on startup.
+
+use hal::macros::ram;
+use shared::crash::CrashRecord;
+
+#[ram(rtc_fast, uninitialized)]
+static mut CRASH_RECORD: CrashRecord = CrashRecord::empty();
+
+pub fn record_panic(info: &core::panic::PanicInfo) {
+    let line = info.location().map_or(0, |location| location.line());
+    unsafe {
+        core::ptr::write_volatile(
+            core::ptr::addr_of_mut!(CRASH_RECORD),
+            CrashRecord::panic(line, info),
+        )
+    }
+}
+
+/// Returns the crash recorded before the last reset, if there was any
+pub fn take() -> Option<CrashRecord> {
+    // the memory holds garbage after a power on reset, which is rejected by the record validation
+    unsafe { CrashRecord::take(&mut *core::ptr::addr_of_mut!(CRASH_RECORD)) }
+}
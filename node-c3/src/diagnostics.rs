@@ -0,0 +1,95 @@
+//! Health telemetry of the node and the Home Assistant discovery of the diagnostic entities.
+
+use core::fmt::{self, Write};
+use core::ptr::{addr_of, addr_of_mut};
+
+use embassy_time::Instant;
+use shared::diagnostics::{self, MqttStats};
+use shared::discovery::{diagnostic, Device, Sensor};
+use shared::watchdog::Task as _;
+
+use crate::watchdog::ResetInfo;
+
+pub const FIRMWARE_VERSION: &str = env!("CARGO_PKG_VERSION");
+
+pub const DEVICE: Device = Device {
+    id: "afo_c3",
+    name: "AFO C3",
+    model: "node-c3",
+    sw_version: Some(FIRMWARE_VERSION),
+};
+
+/// Entities of the values in [`Diagnostics`].
+/// The die temperature is left out, as the temperature sensor isn't supported by the HAL.
+pub const SENSORS: [Sensor; 7] = [
+    diagnostic("uptime", "Uptime", Some("s"), Some("duration")),
+    diagnostic("reset_reason", "Reset reason", None, None),
+    diagnostic("firmware", "Firmware version", None, None),
+    diagnostic("mqtt_reconnects", "MQTT reconnects", None, None),
+    diagnostic("mqtt_last_error", "MQTT last error", None, None),
+    diagnostic("sensor_errors", "Sensor read errors", None, None),
+    diagnostic("stack_free", "Free stack", Some("B"), Some("data_size")),
+];
+
+pub struct Diagnostics {
+    pub reset: ResetInfo,
+    pub mqtt: MqttStats,
+    pub sensor_errors: u32,
+    pub stack_free: usize,
+}
+
+impl Diagnostics {
+    pub fn write_json(&self, w: &mut impl Write) -> fmt::Result {
+        write!(
+            w,
+            r#"{{"uptime": {}, "firmware": "{}", "reset_reason": "{}", "starved_task": "{}", "#,
+            Instant::now().as_secs(),
+            FIRMWARE_VERSION,
+            self.reset.reason.as_str(),
+            self.reset.starved_task.map_or("none", |task| task.as_str()),
+        )?;
+        write!(
+            w,
+            r#""mqtt_reconnects": {}, "mqtt_connect_failures": {}, "mqtt_auth_failures": {}, "mqtt_last_error": "{}", "sensor_errors": {}, "#,
+            self.mqtt.reconnects(),
+            self.mqtt.connect_failures,
+            self.mqtt.auth_failures,
+            self.mqtt
+                .last_failure
+                .map_or("none", |failure| failure.as_str()),
+            self.sensor_errors
+        )?;
+        write!(
+            w,
+            r#""die_temperature": null, "stack_free": {}}}"#,
+            self.stack_free
+        )
+    }
+}
+
+extern "C" {
+    /// Bottom of the stack, the stack grows down towards it
+    static mut _stack_end: u32;
+    static mut _stack_start: u32;
+}
+
+fn stack_pointer() -> usize {
+    let sp: usize;
+    unsafe { core::arch::asm!("mv {}, sp", out(reg) sp) };
+    sp
+}
+
+/// Fills the unused part of the stack with a pattern, to later measure its high-water mark.
+/// Has to be called early after boot, while the stack is shallow.
+pub fn paint_stack() {
+    unsafe {
+        // leave a margin below the stack pointer for the frame of this function
+        let top = (stack_pointer() as *mut u32).sub(64);
+        diagnostics::paint_stack(addr_of_mut!(_stack_end), top);
+    }
+}
+
+/// Returns the number of bytes of the stack that have never been used since boot
+pub fn stack_free() -> usize {
+    unsafe { diagnostics::stack_free(addr_of!(_stack_end), addr_of!(_stack_start)) }
+}
@@ -0,0 +1,78 @@
+//! HTTP server of the node, serving the Prometheus metrics.
+
+use core::cell::RefCell;
+use core::fmt::{self, Write};
+
+use embassy_net::tcp;
+use embassy_sync::blocking_mutex::NoopMutex;
+use embassy_time::Instant;
+use shared::http::{self, Body, Content, Handler, Route, Status};
+use shared::prometheus;
+
+use crate::{State, NAME};
+
+/// The metrics of the node, the only resource served
+struct Metrics {
+    state: &'static NoopMutex<RefCell<State>>,
+    hostname: &'static str,
+}
+
+impl Handler for Metrics {
+    type Resource = State;
+
+    fn route(&self, path: &str) -> Result<(State, Content), Status> {
+        match Route::parse(path) {
+            Some(Route::Metrics) => {
+                Ok((self.state.lock(|c| c.borrow().clone()), Content::Prometheus))
+            }
+            _ => Err(Status::NotFound),
+        }
+    }
+
+    async fn write_body(&self, state: State, body: &mut Body<'_, '_>) -> Result<(), tcp::Error> {
+        let labels = [("node", NAME), ("name", self.hostname)];
+        write_measurement(&state, &labels, body).unwrap();
+        body.flush().await?;
+        let health = [
+            (prometheus::UPTIME, Instant::now().as_secs()),
+            (prometheus::MQTT_CONNECTIONS, state.mqtt.connections.into()),
+            (
+                prometheus::MQTT_CONNECT_FAILURES,
+                state.mqtt.connect_failures.into(),
+            ),
+            (
+                prometheus::MQTT_AUTH_FAILURES,
+                state.mqtt.auth_failures.into(),
+            ),
+            (prometheus::SENSOR_ERRORS, state.sensor_errors.into()),
+        ];
+        for (metric, value) in health {
+            metric.write(&labels, value, body).unwrap();
+            body.flush().await?;
+        }
+        Ok(())
+    }
+}
+
+/// Serves the metrics of the node over HTTP, one connection at a time
+#[embassy_executor::task]
+pub async fn http_task(
+    stack: &'static embassy_net::Stack<
+        esp_wifi::wifi::WifiDevice<'static, esp_wifi::wifi::WifiStaDevice>,
+    >,
+    state: &'static NoopMutex<RefCell<State>>,
+    hostname: &'static str,
+) {
+    http::serve(stack, &Metrics { state, hostname }).await
+}
+
+/// Writes the metrics of the last measurement, none until the first one is read out
+fn write_measurement(state: &State, labels: &[(&str, &str)], w: &mut impl Write) -> fmt::Result {
+    if let Some(last_reading) = state.last_reading {
+        prometheus::CO2.write(labels, state.co2_concentration, w)?;
+        prometheus::TEMPERATURE.write(labels, format_args!("{:.2}", state.temperature), w)?;
+        prometheus::HUMIDITY.write(labels, format_args!("{:.2}", state.humidity), w)?;
+        prometheus::LAST_SEEN.write(labels, last_reading.elapsed().as_secs(), w)?;
+    }
+    Ok(())
+}
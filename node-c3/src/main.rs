@@ -2,47 +2,134 @@
 #![no_main]
 #![feature(type_alias_impl_trait)]
 
+mod crash;
+mod diagnostics;
+mod http;
+#[cfg(feature = "tls")]
+mod tls;
+mod watchdog;
+
 use core::cell::RefCell;
+use core::fmt::{self, Write};
 
+use diagnostics::Diagnostics;
 use embassy_executor::Spawner;
 use embassy_net::tcp::TcpSocket;
-use embassy_net::{Config, Ipv4Address, StackResources};
+use embassy_net::{Config, StackResources};
 use embassy_sync::blocking_mutex::NoopMutex;
-use embassy_time::{Duration, Timer};
+use embassy_time::{Duration, Instant, Timer};
 use esp_backtrace as _;
 use esp_println as _;
-use esp_println::println;
 use esp_wifi::wifi::{ClientConfiguration, Configuration};
 use hal::embassy;
 use hal::i2c::I2C;
 use hal::Rng;
-use hal::{clock::ClockControl, peripherals::Peripherals, prelude::*, IO};
+use hal::{clock::ClockControl, peripherals::Peripherals, prelude::*, Rtc, IO};
 use rust_mqtt::client::client::MqttClient;
+use rust_mqtt::packet::v5::publish_packet::QualityOfService;
+use rust_mqtt::packet::v5::reason_codes::ReasonCode;
 use rust_mqtt::utils::rng_generator::CountingRng;
 use sensirion_async::scd4x::{Celsius, Meter, Scd4x};
+use shared::crash::{CrashRecord, JSON_LEN as CRASH_JSON_LEN};
+use shared::diagnostics::{ConnectFailure, MqttStats};
+use shared::mdns;
+use shared::network::Host;
+use shared::policy::{PublishFilter, PublishPolicy, Reading};
+use shared::qos::{MessageClass, Qos, QosConfig, WillQos};
+use shared::resolve;
+use shared::sinks::{Outputs, Sample, SinkConfig, SocketBuffers};
+use shared::topic::{Topic, TopicConfig, TopicValues, MAX_PREFIX_LEN};
 use static_cell::make_static;
+use watchdog::{ResetInfo, Task};
 
 const SSID: &str = env!("SSID");
 const PASSWORD: &str = env!("PASSWORD");
+/// Address or hostname of the broker
 const SERVER_IP: &str = env!("SERVER_IP");
+#[cfg(feature = "tls")]
+const PORT: u16 = tls::PORT;
+#[cfg(not(feature = "tls"))]
+const PORT: u16 = 1883;
+
+/// Credentials of the MQTT broker, the broker is accessed anonymously if not set
+const MQTT_USERNAME: Option<&str> = option_env!("MQTT_USERNAME");
+const MQTT_PASSWORD: Option<&str> = option_env!("MQTT_PASSWORD");
+
+/// Name of the node in the topics
+const NAME: &str = "c3";
+
+/// mDNS host name, without `.local`, unless set by `AFO_HOSTNAME`
+const DEFAULT_HOSTNAME: &str = "afo-c3";
+
+/// Topics of the node
+const TOPICS: TopicConfig = TopicConfig::from_env();
+const _: () = assert!(
+    TOPICS.prefix.len() <= MAX_PREFIX_LEN,
+    "AFO_TOPIC_PREFIX is too long"
+);
+
+/// Writes a topic of the node itself, e.g. `<prefix>-c3-diagnostics`
+fn own_topic(topic: Topic) -> heapless::String<64> {
+    let mut w = heapless::String::new();
+    // fits, as the length of the prefix is checked at build time
+    TOPICS.write_topic(topic, &NAME, &mut w).unwrap();
+    w
+}
 
 #[panic_handler]
 fn panic(info: &core::panic::PanicInfo) -> ! {
     defmt::error!("panic: {:?}", defmt::Debug2Format(info));
+    crash::record_panic(info);
     hal::reset::software_reset();
     loop {}
 }
 
-#[derive(Default, Debug, Clone)]
+#[derive(Debug, Clone)]
 struct State {
     co2_concentration: u16,
     temperature: f32,
     humidity: f32,
+    /// When the last measurement was read out, `None` until the first one
+    last_reading: Option<Instant>,
+    watchdog: watchdog::Monitor,
+    reset: ResetInfo,
+    mqtt: MqttStats,
+    /// Failed readouts of the CO2 sensor
+    sensor_errors: u32,
+    /// Crash before the last reset, cleared once published
+    crash: Option<CrashRecord>,
+}
+
+impl State {
+    /// The last measurement in the units of the advertisements
+    fn reading(&self) -> Reading {
+        Reading {
+            co2: self.co2_concentration,
+            temperature: (self.temperature / 0.1) as i16,
+            humidity: self.humidity as u8,
+        }
+    }
 }
 
 #[main]
 async fn main(spawner: Spawner) {
+    diagnostics::paint_stack();
     defmt::info!("Hello world!");
+    let policy = PublishPolicy::from_env(None).unwrap_or_else(|e| {
+        defmt::error!("Invalid publish policy: {:?}, publishing every change", e);
+        PublishPolicy::DEFAULT
+    });
+    let qos = QosConfig::from_env().unwrap_or_else(|e| {
+        defmt::error!(
+            "Invalid MQTT QoS configuration: {:?}, using the defaults",
+            e
+        );
+        QosConfig::DEFAULT
+    });
+    let sink_config = SinkConfig::from_env().unwrap_or_else(|e| {
+        defmt::error!("Invalid output configuration: {:?}, outputs disabled", e);
+        SinkConfig::DEFAULT
+    });
     let peripherals = Peripherals::take();
     let system = peripherals.SYSTEM.split();
     let clocks = ClockControl::max(system.clock_control).freeze();
@@ -53,10 +140,32 @@ async fn main(spawner: Spawner) {
     );
     let io = IO::new(peripherals.GPIO, peripherals.IO_MUX);
 
+    let reset = watchdog::take_reset_info();
+    defmt::info!("Reset reason: {:?}", reset);
+    let crash = crash::take();
+    if let Some(crash) = crash {
+        defmt::warn!("Crashed before reset: {}", crash.message());
+    }
+
     let state = make_static!(embassy_sync::blocking_mutex::NoopMutex::new(RefCell::new(
-        State::default()
+        State {
+            co2_concentration: 0,
+            temperature: 0.0,
+            humidity: 0.0,
+            last_reading: None,
+            watchdog: watchdog::Monitor::new(),
+            reset,
+            mqtt: MqttStats::default(),
+            sensor_errors: 0,
+            crash,
+        }
     )));
 
+    let rtc = Rtc::new(peripherals.LPWR);
+    spawner
+        .spawn(watchdog_task(watchdog::start(rtc.rwdt), state))
+        .unwrap();
+
     let i2c0 = I2C::new(
         peripherals.I2C0,
         io.pins.gpio8,
@@ -85,15 +194,30 @@ async fn main(spawner: Spawner) {
     let stack = &*make_static!(embassy_net::Stack::new(
         wifi_interface,
         Config::dhcpv4(Default::default()),
-        make_static!(StackResources::<3>::new()),
+        // the MQTT connection, DHCP, the DNS and the mDNS queries, the mDNS responder,
+        // the HTTP server, the outputs and their mDNS queries
+        make_static!(StackResources::<9>::new()),
         stack_seed
     ));
 
     spawner.spawn(connection(controller)).ok();
     spawner.spawn(net_task(stack)).ok();
 
-    wait_for_connection(stack).await;
-    spawner.spawn(comm(stack, state)).ok();
+    wait_for_connection(stack, state).await;
+    spawner.spawn(comm(stack, state, policy, qos, rng)).ok();
+    let hostname = mdns::hostname_from_env()
+        .unwrap_or_else(|e| {
+            defmt::error!("Invalid hostname: {:?}, using {}", e, DEFAULT_HOSTNAME);
+            None
+        })
+        .unwrap_or(DEFAULT_HOSTNAME);
+    spawner.spawn(mdns_task(stack, hostname)).ok();
+    spawner.spawn(http::http_task(stack, state, hostname)).ok();
+    if sink_config.is_enabled() {
+        spawner
+            .spawn(sinks_task(stack, state, sink_config, hostname))
+            .ok();
+    }
 }
 
 #[embassy_executor::task]
@@ -102,79 +226,345 @@ async fn comm(
         esp_wifi::wifi::WifiDevice<'static, esp_wifi::wifi::WifiStaDevice>,
     >,
     state: &'static NoopMutex<RefCell<State>>,
+    policy: PublishPolicy,
+    qos: QosConfig,
+    rng: Rng,
 ) {
-    use core::fmt::Write;
+    // the randomness of the TLS handshakes
+    #[cfg(not(feature = "tls"))]
+    let _ = rng;
     let rx_buffer = make_static!([0; 512]);
     let tx_buffer = make_static!([0; 512]);
+    #[cfg(feature = "tls")]
+    let tls_read_buffer = make_static!([0; tls::READ_BUFFER_SIZE]);
+    #[cfg(feature = "tls")]
+    let tls_write_buffer = make_static!([0; tls::WRITE_BUFFER_SIZE]);
 
-    let mut octets = [0u8; 4];
-    for (idx, oct) in SERVER_IP
-        .splitn(4, '.')
-        .map(|s| s.parse::<u8>().unwrap())
-        .enumerate()
-    {
-        octets[idx] = oct;
-    }
+    // checked by the build script
+    let host = defmt::unwrap!(Host::parse(SERVER_IP));
 
     loop {
+        state.lock(|c| watchdog::check_in(&mut c.borrow_mut().watchdog, Task::Publish));
+
+        // resolved with every connection, as the address of the broker may change
+        let Some(address) = resolve::resolve(stack, host).await else {
+            let failure = ConnectFailure::Resolution;
+            let delay = state.lock(|c| c.borrow_mut().mqtt.record_failure(failure));
+            Timer::after_secs(delay).await;
+            continue;
+        };
+
         let mut socket = TcpSocket::new(stack, rx_buffer, tx_buffer);
-        if socket.connect((Ipv4Address(octets), 1883)).await.is_err() {
+        socket.set_timeout(Some(Duration::from_secs(10)));
+        if socket.connect((address, PORT)).await.is_err() {
             defmt::error!("failed to connect to MQTT broker");
-            Timer::after_secs(2).await;
+            let failure = ConnectFailure::Network;
+            let delay = state.lock(|c| c.borrow_mut().mqtt.record_failure(failure));
+            Timer::after_secs(delay).await;
             continue;
         }
 
+        #[cfg(feature = "tls")]
+        let socket = match tls::open(socket, rng, tls_read_buffer, tls_write_buffer).await {
+            Ok(connection) => connection,
+            Err(e) => {
+                defmt::error!("TLS handshake with MQTT broker failed: {:?}", e);
+                let failure = ConnectFailure::Tls;
+                let delay = state.lock(|c| c.borrow_mut().mqtt.record_failure(failure));
+                Timer::after_secs(delay).await;
+                continue;
+            }
+        };
+
         let mut config = rust_mqtt::client::client_config::ClientConfig::new(
             rust_mqtt::client::client_config::MqttVersion::MQTTv5,
             CountingRng(10),
         );
-        config.add_max_subscribe_qos(rust_mqtt::packet::v5::publish_packet::QualityOfService::QoS0);
+        config.add_max_subscribe_qos(QualityOfService::QoS0);
         config.add_client_id("afo-c3");
-        config.max_packet_size = 100;
-        let mut recv_buffer = [0; 80];
-        let mut write_buffer = [0; 80];
-
-        let mut client =
-            MqttClient::<_, 5, _>::new(socket, &mut write_buffer, 80, &mut recv_buffer, 80, config);
+        // retained `online`, replaced by the `offline` will once the node disconnects
+        let availability_topic = own_topic(Topic::Availability);
+        config.add_will(&availability_topic, b"offline", true);
+        if let Some(username) = MQTT_USERNAME {
+            config.add_username(username);
+        }
+        if let Some(password) = MQTT_PASSWORD {
+            config.add_password(password);
+        }
+        config.max_packet_size = 512;
+        let mut recv_buffer = [0; 512];
+        // the crash reports are the longest messages
+        let mut write_buffer = [0; 768];
+
+        let mut client = MqttClient::<_, 5, _>::new(
+            WillQos::new(socket, qos.qos(MessageClass::Availability)),
+            &mut write_buffer,
+            768,
+            &mut recv_buffer,
+            512,
+            config,
+        );
 
-        if client.connect_to_broker().await.is_err() {
-            defmt::error!("failed to connect to MQTT broker");
+        if let Err(code) = client.connect_to_broker().await {
+            let code = u8::from(code);
+            let failure = ConnectFailure::from_reason_code(code);
+            match failure {
+                ConnectFailure::Network => defmt::error!("failed to connect to MQTT broker"),
+                _ => defmt::error!(
+                    "MQTT broker refused the connection: {}, reason code {:#04x}",
+                    failure,
+                    code
+                ),
+            }
+            let delay = state.lock(|c| c.borrow_mut().mqtt.record_failure(failure));
+            Timer::after_secs(delay).await;
+            continue;
+        }
+        state.lock(|c| c.borrow_mut().mqtt.connections += 1);
+
+        if let Err(e) = publish(
+            &mut client,
+            &qos,
+            MessageClass::Availability,
+            &availability_topic,
+            b"online",
+        )
+        .await
+        {
+            defmt::error!(
+                "failed to publish availability: {:?}",
+                defmt::Debug2Format(&e)
+            );
             Timer::after_secs(2).await;
             continue;
         }
 
-        let topic = "afo-c3";
-        let mut json = heapless::String::<64>::new();
+        // the filter starts over, so the last measurement is published again after a reconnect,
+        // in case it was not acknowledged before the connection failed
+        let mut session = Session::default();
+        // the connection is kept open until publishing fails
+        loop {
+            state.lock(|c| watchdog::check_in(&mut c.borrow_mut().watchdog, Task::Publish));
 
-        {
-            let s = state.lock(|c| c.borrow().clone());
-            write!(
-                &mut json,
-                r#"{{"co2": "{}", "temperature": "{:.1}", "humidity": "{}"}}"#,
-                s.co2_concentration, s.temperature, s.humidity
-            )
-            .unwrap();
+            if let Err(e) = publish_state(&mut client, &policy, &qos, state, &mut session).await {
+                defmt::error!("failed to send MQTT message: {:?}", defmt::Debug2Format(&e));
+                break;
+            }
+
+            // do not remove as the mqtt message will not be sent.
+            // rust mqtt doesn't support flushing at the moment
+            Timer::after_secs(2).await;
         }
+        Timer::after_secs(2).await;
+    }
+}
+
+#[cfg(feature = "tls")]
+type Transport<'a> = tls::Connection<'a>;
+#[cfg(not(feature = "tls"))]
+type Transport<'a> = TcpSocket<'a>;
+
+type Client<'a, 'b> = MqttClient<'a, WillQos<Transport<'b>>, 5, CountingRng>;
+
+/// What has been published over the current broker connection
+#[derive(Default)]
+struct Session {
+    /// Whether the discovery of the diagnostic entities has been published
+    announced: bool,
+    last_diagnostics: Option<Instant>,
+    filter: PublishFilter,
+}
+
+/// Period of publishing the diagnostics
+const DIAGNOSTICS_PERIOD: Duration = Duration::from_secs(60);
 
-        if client
-            .send_message(
-                topic,
-                json.as_bytes(),
-                rust_mqtt::packet::v5::publish_packet::QualityOfService::QoS0,
-                true,
+/// Publishes the measurement if it changed beyond the deadband of the publish policy
+/// or the heartbeat is due, diagnostics and the discovery of the diagnostic entities
+async fn publish_state(
+    client: &mut Client<'_, '_>,
+    policy: &PublishPolicy,
+    qos: &QosConfig,
+    state: &'static NoopMutex<RefCell<State>>,
+    session: &mut Session,
+) -> Result<(), ReasonCode> {
+    let diagnostics_topic = own_topic(Topic::Diagnostics);
+    if !session.announced {
+        for sensor in &diagnostics::SENSORS {
+            let mut topic = heapless::String::<64>::new();
+            sensor
+                .write_topic(&diagnostics::DEVICE, &mut topic)
+                .unwrap();
+            let mut config = heapless::String::<448>::new();
+            sensor
+                .write_config(&diagnostics::DEVICE, &diagnostics_topic, &mut config)
+                .unwrap();
+            publish(
+                client,
+                qos,
+                MessageClass::Discovery,
+                &topic,
+                config.as_bytes(),
             )
-            .await
-            .is_err()
-        {
-            println!("failed to send MQTT message");
-            Timer::after_secs(2).await;
-            continue;
+            .await?;
         }
+        session.announced = true;
+    }
 
-        // do not remove as the mqtt message will not be sent.
-        // rust mqtt doesn't support flushing at the moment
-        Timer::after_secs(2).await;
+    let s = state.lock(|c| c.borrow().clone());
+    let reading = s.reading();
+    if session
+        .filter
+        .publish(policy, reading, Instant::now().as_millis())
+    {
+        publish_measurement(client, qos, &s).await?;
     }
+
+    if session
+        .last_diagnostics
+        .map_or(true, |last| last.elapsed() >= DIAGNOSTICS_PERIOD)
+    {
+        let diagnostics = state.lock(|c| {
+            let c = c.borrow();
+            Diagnostics {
+                reset: c.reset,
+                mqtt: c.mqtt,
+                sensor_errors: c.sensor_errors,
+                stack_free: diagnostics::stack_free(),
+            }
+        });
+        let mut json = heapless::String::<384>::new();
+        diagnostics.write_json(&mut json).unwrap();
+        publish(
+            client,
+            qos,
+            MessageClass::Diagnostics,
+            &diagnostics_topic,
+            json.as_bytes(),
+        )
+        .await?;
+        session.last_diagnostics = Some(Instant::now());
+    }
+
+    if let Some(crash) = state.lock(|c| c.borrow().crash) {
+        let mut json = heapless::String::<CRASH_JSON_LEN>::new();
+        crash.write_json(&mut json).unwrap();
+
+        // retained, so that the last crash stays available
+        publish(
+            client,
+            qos,
+            MessageClass::Diagnostics,
+            &own_topic(Topic::Crash),
+            json.as_bytes(),
+        )
+        .await?;
+        state.lock(|c| c.borrow_mut().crash = None);
+    }
+
+    Ok(())
+}
+
+/// Publishes the measurement as a JSON object and, if enabled, each metric to its own topic
+async fn publish_measurement(
+    client: &mut Client<'_, '_>,
+    qos: &QosConfig,
+    s: &State,
+) -> Result<(), ReasonCode> {
+    let mut json = heapless::String::<64>::new();
+    write!(
+        &mut json,
+        r#"{{"co2": "{}", "temperature": "{:.1}", "humidity": "{}"}}"#,
+        s.co2_concentration, s.temperature, s.humidity
+    )
+    .unwrap();
+
+    // the node publishes directly, acting as its own bridge
+    let values = TopicValues {
+        bridge: NAME,
+        node: &NAME,
+    };
+    let mut topic = heapless::String::<64>::new();
+    if TOPICS.write_state(&values, &mut topic).is_ok() {
+        publish(
+            client,
+            qos,
+            MessageClass::Measurement,
+            &topic,
+            json.as_bytes(),
+        )
+        .await?;
+    } else {
+        defmt::error!("Invalid state topic template, or the topic is too long");
+    }
+
+    if TOPICS.metric.is_some() {
+        publish_metric(
+            client,
+            qos,
+            &values,
+            "co2",
+            format_args!("{}", s.co2_concentration),
+        )
+        .await?;
+        publish_metric(
+            client,
+            qos,
+            &values,
+            "temperature",
+            format_args!("{:.1}", s.temperature),
+        )
+        .await?;
+        publish_metric(
+            client,
+            qos,
+            &values,
+            "humidity",
+            format_args!("{}", s.humidity),
+        )
+        .await?;
+    }
+    Ok(())
+}
+
+/// Publishes a metric as a plain number to its own topic
+async fn publish_metric(
+    client: &mut Client<'_, '_>,
+    qos: &QosConfig,
+    values: &TopicValues<'_>,
+    metric: &str,
+    value: fmt::Arguments<'_>,
+) -> Result<(), ReasonCode> {
+    let mut topic = heapless::String::<64>::new();
+    if TOPICS.write_metric(values, metric, &mut topic).is_err() {
+        defmt::error!("Invalid metric topic template, or the topic is too long");
+        return Ok(());
+    }
+    let mut payload = heapless::String::<16>::new();
+    payload.write_fmt(value).unwrap();
+    publish(
+        client,
+        qos,
+        MessageClass::Measurement,
+        &topic,
+        payload.as_bytes(),
+    )
+    .await
+}
+
+/// Publishes a retained message with the QoS of its class. QoS 1 messages wait for
+/// the acknowledgement of the broker, publishing fails if the next packet isn't the acknowledgement
+async fn publish(
+    client: &mut Client<'_, '_>,
+    qos: &QosConfig,
+    class: MessageClass,
+    topic: &str,
+    payload: &[u8],
+) -> Result<(), ReasonCode> {
+    let qos = match qos.qos(class) {
+        Qos::AtMostOnce => QualityOfService::QoS0,
+        Qos::AtLeastOnce => QualityOfService::QoS1,
+    };
+    client.send_message(topic, payload, qos, true).await
 }
 
 #[embassy_executor::task]
@@ -216,6 +606,58 @@ async fn net_task(
     stack.run().await
 }
 
+/// Answers the mDNS queries of `<hostname>.local` and of the `_afo._tcp` and `_http._tcp` services
+/// of the node, the records are announced whenever the address of the node changes
+#[embassy_executor::task]
+async fn mdns_task(
+    stack: &'static embassy_net::Stack<
+        esp_wifi::wifi::WifiDevice<'static, esp_wifi::wifi::WifiStaDevice>,
+    >,
+    hostname: &'static str,
+) {
+    mdns::respond(
+        stack,
+        hostname,
+        concat!("version=", env!("CARGO_PKG_VERSION")),
+        "path=/metrics",
+        || None,
+    )
+    .await
+}
+
+/// Period of checking for a new measurement to push to the outputs
+const SINKS_POLL_PERIOD: Duration = Duration::from_secs(1);
+
+/// Pushes the new measurements to the outputs enabled besides MQTT
+#[embassy_executor::task]
+async fn sinks_task(
+    stack: &'static embassy_net::Stack<
+        esp_wifi::wifi::WifiDevice<'static, esp_wifi::wifi::WifiStaDevice>,
+    >,
+    state: &'static NoopMutex<RefCell<State>>,
+    config: SinkConfig<'static>,
+    hostname: &'static str,
+) {
+    let mut buffers = SocketBuffers::new();
+    let mut outputs = Outputs::new(stack, &mut buffers, config);
+
+    let mut pushed = None;
+    loop {
+        Timer::after(SINKS_POLL_PERIOD).await;
+        let s = state.lock(|c| c.borrow().clone());
+        if s.last_reading.is_none() || s.last_reading == pushed || !stack.is_config_up() {
+            continue;
+        }
+        pushed = s.last_reading;
+        let sample = Sample {
+            node: NAME,
+            name: hostname,
+            reading: s.reading(),
+        };
+        outputs.push(&sample).await;
+    }
+}
+
 #[embassy_executor::task]
 async fn scd4x_task(
     mut sensor: Scd4x<I2C<'static, hal::peripherals::I2C0>>,
@@ -247,6 +689,8 @@ async fn scd4x_task(
     Timer::after(Duration::from_millis(500)).await;
 
     loop {
+        state.lock(|c| watchdog::check_in(&mut c.borrow_mut().watchdog, Task::Sensor));
+
         if sensor.data_ready().await.unwrap() {
             let measurement = sensor.read().await;
             match measurement {
@@ -256,6 +700,7 @@ async fn scd4x_task(
                         state.co2_concentration = measurement.co2;
                         state.humidity = measurement.humidity;
                         state.temperature = measurement.temperature;
+                        state.last_reading = Some(Instant::now());
                     });
                     defmt::info!(
                         "CO2: {}, Temperature: {}, Humidity: {}",
@@ -266,6 +711,7 @@ async fn scd4x_task(
                 }
                 Err(err) => {
                     defmt::error!("Error accessing Scd4x: {:?}", err);
+                    state.lock(|c| c.borrow_mut().sensor_errors += 1);
                 }
             }
         }
@@ -274,12 +720,25 @@ async fn scd4x_task(
     }
 }
 
+/// Feeds the watchdog as long as all the critical tasks keep checking in
+#[embassy_executor::task]
+async fn watchdog_task(wdt: watchdog::Watchdog, state: &'static NoopMutex<RefCell<State>>) {
+    watchdog::supervise::<Task>(wdt, || {
+        state.lock(|c| c.borrow().watchdog.overdue(Instant::now().as_millis()))
+    })
+    .await
+}
+
+/// Waits for the WiFi connection, checking in for the publishing task,
+/// as restarting the device doesn't help when the network is unavailable.
 async fn wait_for_connection(
     stack: &'static embassy_net::Stack<
         esp_wifi::wifi::WifiDevice<'static, esp_wifi::wifi::WifiStaDevice>,
     >,
+    state: &'static NoopMutex<RefCell<State>>,
 ) {
     loop {
+        state.lock(|c| watchdog::check_in(&mut c.borrow_mut().watchdog, Task::Publish));
         if stack.is_link_up() {
             break;
         }
@@ -288,6 +747,7 @@ async fn wait_for_connection(
 
     defmt::info!("Waiting to get IP address...");
     loop {
+        state.lock(|c| watchdog::check_in(&mut c.borrow_mut().watchdog, Task::Publish));
         if let Some(config) = stack.config_v4() {
             defmt::warn!("Got IP: {}", config.address);
             break;
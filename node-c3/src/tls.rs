@@ -0,0 +1,135 @@
+//! MQTT over TLS 1.3, with the public key of the broker pinned at build time.
+//!
+//! The broker has to use a P-256 ECDSA certificate, whose key is compared with the pinned one
+//! and used to verify the handshake. The pinned key is read from a DER encoded certificate
+//! or subject public key info, so the broker certificate can be renewed with the same key
+//! without reflashing the node.
+//!
+//! Only the key is checked: chains, validity periods and names of the certificates are not
+//! validated, so pinning a CA is not supported.
+
+use embassy_net::tcp::TcpSocket;
+use embedded_tls::{
+    Aes128GcmSha256, Certificate, CertificateEntryRef, CertificateRef, CertificateVerifyRef,
+    SignatureScheme, TlsCipherSuite, TlsConfig, TlsConnection, TlsContext, TlsError, TlsVerifier,
+};
+use hal::Rng;
+use p256::ecdsa::signature::Verifier;
+use p256::ecdsa::{Signature, VerifyingKey};
+use sha2::Digest;
+use shared::x509::p256_public_key;
+
+/// Port of MQTT over TLS
+pub const PORT: u16 = 8883;
+
+/// DER encoded certificate or subject public key info of the broker, checked by the build script
+const SERVER_KEY: &[u8] = include_bytes!(env!("MQTT_SERVER_CERT"));
+/// Name of the broker sent in the handshake
+const SERVER_NAME: &str = env!("MQTT_SERVER_NAME");
+
+/// DER encoded certificate and private key of the node, for brokers requiring client certificates
+#[cfg(feature = "tls-client-cert")]
+const CLIENT_CERT: &[u8] = include_bytes!(env!("MQTT_CLIENT_CERT"));
+#[cfg(feature = "tls-client-cert")]
+const CLIENT_KEY: &[u8] = include_bytes!(env!("MQTT_CLIENT_KEY"));
+
+/// Size of the record buffers, the read buffer has to fit the largest record the broker sends
+pub const READ_BUFFER_SIZE: usize = 16640;
+pub const WRITE_BUFFER_SIZE: usize = 4096;
+
+pub type Connection<'a> = TlsConnection<'a, TcpSocket<'a>, Aes128GcmSha256>;
+
+/// Performs the TLS handshake over a connected socket
+pub async fn open<'a>(
+    socket: TcpSocket<'a>,
+    rng: Rng,
+    read_buffer: &'a mut [u8],
+    write_buffer: &'a mut [u8],
+) -> Result<Connection<'a>, TlsError> {
+    let config = TlsConfig::new().with_server_name(SERVER_NAME);
+    #[cfg(feature = "tls-client-cert")]
+    let config = config
+        .with_cert(Certificate::X509(CLIENT_CERT))
+        .with_priv_key(CLIENT_KEY);
+
+    let mut connection = TlsConnection::new(socket, read_buffer, write_buffer);
+    connection
+        .open::<_, PinnedVerifier>(TlsContext::new(&config, &mut HardwareRng(rng)))
+        .await?;
+    Ok(connection)
+}
+
+/// Hardware random number generator, which is a cryptographically secure source
+/// while the radio is enabled
+struct HardwareRng(Rng);
+
+impl rand_core::RngCore for HardwareRng {
+    fn next_u32(&mut self) -> u32 {
+        self.0.random()
+    }
+
+    fn next_u64(&mut self) -> u64 {
+        rand_core::impls::next_u64_via_u32(self)
+    }
+
+    fn fill_bytes(&mut self, dest: &mut [u8]) {
+        rand_core::impls::fill_bytes_via_next(self, dest)
+    }
+
+    fn try_fill_bytes(&mut self, dest: &mut [u8]) -> Result<(), rand_core::Error> {
+        self.fill_bytes(dest);
+        Ok(())
+    }
+}
+
+impl rand_core::CryptoRng for HardwareRng {}
+
+/// Accepts only certificates with the pinned key and checks the broker owns the key
+struct PinnedVerifier {
+    /// Hash of the handshake up to the certificate, signed by the broker
+    transcript: Option<[u8; 32]>,
+}
+
+impl<'a> TlsVerifier<'a, Aes128GcmSha256> for PinnedVerifier {
+    fn new(_host: Option<&'a str>) -> Self {
+        Self { transcript: None }
+    }
+
+    fn verify_certificate(
+        &mut self,
+        transcript: &<Aes128GcmSha256 as TlsCipherSuite>::Hash,
+        _ca: &Option<Certificate>,
+        cert: CertificateRef,
+    ) -> Result<(), TlsError> {
+        let key = match cert.entries.first() {
+            Some(CertificateEntryRef::X509(der)) => p256_public_key(der),
+            _ => None,
+        };
+        match (key, p256_public_key(SERVER_KEY)) {
+            (Some(key), Some(pinned)) if key == pinned => {
+                self.transcript = Some(transcript.clone().finalize().into());
+                Ok(())
+            }
+            _ => Err(TlsError::InvalidCertificate),
+        }
+    }
+
+    fn verify_signature(&mut self, verify: CertificateVerifyRef) -> Result<(), TlsError> {
+        let transcript = self.transcript.ok_or(TlsError::InvalidCertificate)?;
+        if verify.signature_scheme != SignatureScheme::EcdsaSecp256r1Sha256 {
+            return Err(TlsError::InvalidSignatureScheme);
+        }
+        let key = p256_public_key(SERVER_KEY)
+            .and_then(|key| VerifyingKey::from_sec1_bytes(key).ok())
+            .ok_or(TlsError::InvalidCertificate)?;
+        let signature =
+            Signature::from_der(verify.signature).map_err(|_| TlsError::InvalidSignature)?;
+
+        // content covered by the signature, RFC 8446 section 4.4.3
+        let mut message = [0x20u8; 64 + 34 + 32];
+        message[64..98].copy_from_slice(b"TLS 1.3, server CertificateVerify\0");
+        message[98..].copy_from_slice(&transcript);
+        key.verify(&message, &signature)
+            .map_err(|_| TlsError::InvalidSignature)
+    }
+}
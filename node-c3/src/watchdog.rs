@@ -0,0 +1,100 @@
+//! RTC watchdog fed only while all critical tasks keep checking in.
+
+use hal::macros::ram;
+use hal::prelude::*;
+use hal::reset::SocResetReason;
+use hal::rtc_cntl::Rwdt;
+use shared::reset::ResetReason;
+use shared::watchdog::{self, TaskMonitor};
+
+pub use shared::watchdog::{check_in, supervise};
+
+/// Tasks that have to check in for the watchdog to be fed
+#[derive(Clone, Copy, Debug, defmt::Format)]
+pub enum Task {
+    Sensor,
+    Publish,
+}
+
+impl watchdog::Task for Task {
+    const ALL: &'static [Task] = &[Task::Sensor, Task::Publish];
+
+    fn index(self) -> usize {
+        self as usize
+    }
+
+    fn as_str(&self) -> &'static str {
+        match self {
+            Task::Sensor => "sensor",
+            Task::Publish => "publish",
+        }
+    }
+
+    /// The sensor is read every 6 seconds, the publishing checks in with every connection attempt.
+    fn deadline_ms(self) -> u64 {
+        match self {
+            Task::Sensor => 30_000,
+            Task::Publish => 60_000,
+        }
+    }
+}
+
+pub type Monitor = TaskMonitor<Task>;
+
+pub type ResetInfo = watchdog::ResetInfo<Task>;
+
+/// Marks the value of [`STARVED_TASK`] as written by this firmware
+const STARVED_TASK_MAGIC: u32 = 0xaf0d_0000;
+
+/// Task which missed its deadline, retained in RTC memory across the watchdog reset
+#[ram(rtc_fast, uninitialized)]
+static mut STARVED_TASK: u32 = 0;
+
+/// The RTC watchdog, the starved task is stored in [`STARVED_TASK`]
+pub struct Watchdog(Rwdt);
+
+impl watchdog::Watchdog for Watchdog {
+    fn feed(&mut self) {
+        self.0.feed();
+    }
+
+    fn record_starved(&mut self, value: u32) {
+        unsafe {
+            STARVED_TASK = STARVED_TASK_MAGIC | value;
+        }
+    }
+}
+
+/// Starts the watchdog, it has to be fed by [`supervise`]
+pub fn start(mut rwdt: Rwdt) -> Watchdog {
+    rwdt.start(5u64.secs());
+    Watchdog(rwdt)
+}
+
+/// Reads the reset reason and the task recorded before a watchdog reset
+pub fn take_reset_info() -> ResetInfo {
+    let reason = match hal::reset::get_reset_reason() {
+        Some(SocResetReason::ChipPowerOn) => ResetReason::PowerOn,
+        Some(SocResetReason::CoreSw | SocResetReason::Cpu0Sw) => ResetReason::Software,
+        Some(SocResetReason::CoreDeepSleep) => ResetReason::WakeUp,
+        Some(SocResetReason::SysBrownOut) => ResetReason::BrownOut,
+        Some(
+            SocResetReason::CoreMwdt0
+            | SocResetReason::CoreMwdt1
+            | SocResetReason::CoreRtcWdt
+            | SocResetReason::Cpu0Mwdt0
+            | SocResetReason::Cpu0Mwdt1
+            | SocResetReason::Cpu0RtcWdt
+            | SocResetReason::SysRtcWdt
+            | SocResetReason::SysSuperWdt,
+        ) => ResetReason::Watchdog,
+        _ => ResetReason::Other,
+    };
+
+    let starved = unsafe { core::mem::replace(&mut STARVED_TASK, 0) };
+    let starved = match starved & 0xffff_0000 {
+        STARVED_TASK_MAGIC => starved & 0xffff,
+        _ => 0,
+    };
+    ResetInfo::new(reason, starved)
+}
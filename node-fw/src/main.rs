@@ -2,36 +2,48 @@
 #![no_main]
 #![feature(type_alias_impl_trait)]
 
+mod watchdog;
+
 use core::cell::RefCell;
 use defmt_rtt as _;
 use static_cell::make_static;
 
 use embassy_executor::Spawner;
-use embassy_nrf::gpio::{AnyPin, Input, Output, Pin, Pull};
+use embassy_nrf::gpio::{AnyPin, Output, Pin};
+#[cfg(feature = "legacy-id-jumper")]
+use embassy_nrf::gpio::{Input, Pull};
 use embassy_nrf::twim::{self, Twim};
 use embassy_nrf::interrupt::{self, InterruptExt};
 use embassy_nrf::{bind_interrupts, peripherals};
 use embassy_sync::blocking_mutex::ThreadModeMutex;
-use embassy_time::{with_timeout, Duration, Timer};
+use embassy_time::{with_timeout, Duration, Instant, Timer};
 
 use nrf_softdevice::ble::peripheral;
-use nrf_softdevice::Softdevice;
+use nrf_softdevice::{raw, Softdevice};
 
 use sensirion_async::scd4x::{Celsius, Meter, Scd4x};
-use shared::{fill_adv_data, AirQuality, AirQualityAdvertisement, Co2, Humidity, Temperature};
+use shared::crash::{storage, CrashSummary};
+use shared::reset::ResetReason;
+use shared::{
+    fill_adv_data, AirQuality, AirQualityAdvertisement, Co2, Humidity, NodeDiagnostics, SensorId,
+    Sequence, Temperature,
+};
+use watchdog::Task;
 
 #[cfg(feature = "dev")]
 use panic_probe as _;
 
 #[cfg(not(feature = "dev"))]
 #[panic_handler]
-fn panic(_info: &core::panic::PanicInfo) -> ! {
+fn panic(info: &core::panic::PanicInfo) -> ! {
     defmt::error!("panic!");
+    storage::record_panic(info);
     cortex_m::peripheral::SCB::sys_reset();
 }
 
 #[cortex_m_rt::exception]
-unsafe fn HardFault(_frame: &cortex_m_rt::ExceptionFrame) -> ! {
+unsafe fn HardFault(frame: &cortex_m_rt::ExceptionFrame) -> ! {
+    storage::record_hard_fault(frame);
     cortex_m::peripheral::SCB::sys_reset()
 }
 
@@ -39,9 +51,12 @@ bind_interrupts!(struct Irqs {
     SPIM0_SPIS0_TWIM0_TWIS0_SPI0_TWI0 => twim::InterruptHandler<peripherals::TWISPI0>;
 });
 
-#[derive(Clone, Copy, Default)]
+#[derive(Clone, Copy)]
 struct State {
     measurement: AirQuality,
+    /// Sequence number of the measurement, incremented with every reading
+    sequence: Sequence,
+    watchdog: watchdog::Monitor,
 }
 
 #[embassy_executor::main]
@@ -56,6 +71,17 @@ async fn main(spawner: Spawner) {
 
     unsafe { reinitialize_reset() };
 
+    let reset = watchdog::take_reset_info();
+    defmt::info!("Reset reason: {:?}", reset);
+    let crash = storage::take();
+    if let Some(crash) = crash {
+        defmt::warn!(
+            "Crashed before reset: {:?}, pc: {:x}",
+            crash.kind(),
+            crash.pc()
+        );
+    }
+
     // set priority to avoid collisions with softdevice
     interrupt::SPIM0_SPIS0_TWIM0_TWIS0_SPI0_TWI0.set_priority(interrupt::Priority::P2);
 
@@ -69,15 +95,38 @@ async fn main(spawner: Spawner) {
     );
     spawner.spawn(blinky(led)).unwrap();
 
-    let id_pin = Input::new(p.P0_28, Pull::Up);
-    // First read was 0, lets wait a bit and read again
-    Timer::after(Duration::from_millis(100)).await;
+    #[cfg(feature = "legacy-id-jumper")]
+    let device_id = {
+        let id_pin = Input::new(p.P0_28, Pull::Up);
+        // First read was 0, lets wait a bit and read again
+        Timer::after(Duration::from_millis(100)).await;
+        defmt::info!("Device id set by jumper");
+        if id_pin.is_low() {
+            0
+        } else {
+            1
+        }
+    };
+
+    #[cfg(not(feature = "legacy-id-jumper"))]
+    let device_id = {
+        let (id, source) = shared::identity::id();
+        defmt::info!("Device id source: {}", source);
+        id
+    };
+
+    let state = make_static!(ThreadModeMutex::new(RefCell::new(State {
+        measurement: AirQuality::default(),
+        sequence: 0,
+        watchdog: watchdog::Monitor::new(),
+    })));
 
-    let state = make_static!(ThreadModeMutex::new(RefCell::new(State::default())));
+    let wdt = watchdog::start(p.WDT);
+    spawner.spawn(watchdog_task(wdt, state)).unwrap();
 
-    let device_id = if id_pin.is_low() { 0 } else { 1 };
+    let crash = crash.and_then(|crash| crash.summary());
     spawner
-        .spawn(advertising_task(device_id, state, sd))
+        .spawn(advertising_task(device_id, reset.reason, crash, state, sd))
         .unwrap();
 
     let twi = Twim::new(p.TWISPI0, Irqs, p.P0_12, p.P0_13, Default::default());
@@ -98,26 +147,40 @@ async fn blinky(mut led: Output<'static, AnyPin>) {
     }
 }
 
-/// Updates the advertisement every second
+/// Updates the advertisement every second, the diagnostics are sent in the scan response
 #[embassy_executor::task]
 async fn advertising_task(
-    device_id: u8,
+    device_id: SensorId,
+    reset_reason: ResetReason,
+    crash: Option<CrashSummary>,
     state: &'static ThreadModeMutex<RefCell<State>>,
     softdevice: &'static Softdevice,
 ) {
     loop {
+        state.lock(|c| watchdog::check_in(&mut c.borrow_mut().watchdog, Task::Advertise));
+
+        let diagnostics = NodeDiagnostics {
+            sensor_id: device_id,
+            uptime: Instant::now().as_secs() as u32,
+            reset_reason,
+            die_temperature: die_temperature(softdevice),
+            crash,
+        };
+        let mut scan_data = [0u8; 31];
+        let scan_len = build_scan_data(&diagnostics, &mut scan_data);
+
         let config = peripheral::Config::default();
 
         let mut adv_data = [0u8; 31];
-        let adv_len = build_adv_data(
-            device_id,
-            &state.lock(|c| c.borrow().measurement),
-            &mut adv_data,
-        );
+        let (measurement, sequence) = state.lock(|c| {
+            let c = c.borrow();
+            (c.measurement, c.sequence)
+        });
+        let adv_len = build_adv_data(device_id, sequence, &measurement, &mut adv_data);
 
         let adv = peripheral::NonconnectableAdvertisement::ScannableUndirected {
             adv_data: &adv_data[..adv_len],
-            scan_data: &[],
+            scan_data: &scan_data[..scan_len],
         };
 
         match with_timeout(
@@ -158,6 +221,8 @@ async fn scd4x_task(mut sensor: Scd4x<Twim<'static, peripherals::TWISPI0>>, stat
     Timer::after(Duration::from_millis(500)).await;
 
     loop {
+        state.lock(|c| watchdog::check_in(&mut c.borrow_mut().watchdog, Task::Sensor));
+
         if defmt::unwrap!(sensor.data_ready().await) {
             let measurement = sensor.read().await;
             match measurement {
@@ -167,6 +232,7 @@ async fn scd4x_task(mut sensor: Scd4x<Twim<'static, peripherals::TWISPI0>>, stat
                         state.measurement.co2 = Co2(measurement.co2 as f32);
                         state.measurement.humidity = Humidity(measurement.humidity);
                         state.measurement.temperature = Temperature(measurement.temperature);
+                        state.sequence = state.sequence.wrapping_add(1);
                     });
                     defmt::info!(
                         "CO2: {}, Temperature: {}, Humidity: {}",
@@ -185,6 +251,18 @@ async fn scd4x_task(mut sensor: Scd4x<Twim<'static, peripherals::TWISPI0>>, stat
     }
 }
 
+/// Feeds the watchdog as long as all the critical tasks keep checking in
+#[embassy_executor::task]
+async fn watchdog_task(
+    wdt: watchdog::Watchdog,
+    state: &'static ThreadModeMutex<RefCell<State>>,
+) -> ! {
+    watchdog::supervise::<Task>(wdt, || {
+        state.lock(|c| c.borrow().watchdog.overdue(Instant::now().as_millis()))
+    })
+    .await
+}
+
 #[embassy_executor::task]
 async fn softdevice_task(sd: &'static Softdevice) {
     sd.run().await
@@ -192,7 +270,6 @@ async fn softdevice_task(sd: &'static Softdevice) {
 
 /// Basic configuration for the softdevice
 fn softdevice_config() -> nrf_softdevice::Config {
-    use nrf_softdevice::raw;
     nrf_softdevice::Config {
         clock: Some(raw::nrf_clock_lf_cfg_t {
             source: raw::NRF_CLOCK_LF_SRC_RC as u8,
@@ -231,7 +308,12 @@ fn softdevice_config() -> nrf_softdevice::Config {
 /// Encode measurement and device id into advertisement data
 /// The data is encoded into the Manufacturer Specific Data in the advertisement
 /// This method also encodes other BLE specific data in the advertisement - such as the device name
-fn build_adv_data(device_id: u8, air_quality: &AirQuality, adv_data: &mut [u8; 31]) -> usize {
+fn build_adv_data(
+    device_id: SensorId,
+    sequence: Sequence,
+    air_quality: &AirQuality,
+    adv_data: &mut [u8; 31],
+) -> usize {
     let mut adv_offset = 0;
 
     adv_offset += fill_adv_data(
@@ -244,7 +326,7 @@ fn build_adv_data(device_id: u8, air_quality: &AirQuality, adv_data: &mut [u8; 3
     let mut buffer = [0u8; 31];
     buffer[0] = 0xff;
     buffer[1] = 0xff;
-    let data = AirQualityAdvertisement::from((device_id, *air_quality));
+    let data = AirQualityAdvertisement::from((device_id, sequence, *air_quality));
 
     let serialized_len = postcard::to_slice(&data, &mut buffer[2..]).unwrap().len();
 
@@ -256,6 +338,36 @@ fn build_adv_data(device_id: u8, air_quality: &AirQuality, adv_data: &mut [u8; 3
     adv_offset
 }
 
+/// Reads the temperature of the chip in °C
+fn die_temperature(_sd: &Softdevice) -> i8 {
+    let mut temperature: i32 = 0;
+    if unsafe { raw::sd_temp_get(&mut temperature) } != raw::NRF_SUCCESS {
+        return i8::MIN;
+    }
+    // the temperature is in 0.25 °C steps
+    (temperature / 4) as i8
+}
+
+/// Encode diagnostics into scan response data
+/// The name is included, so that the scan response can be recognized on its own
+fn build_scan_data(diagnostics: &NodeDiagnostics, scan_data: &mut [u8; 31]) -> usize {
+    let mut offset = fill_adv_data(&mut scan_data[..], 0x09, b"AFO");
+
+    let mut buffer = [0u8; 29];
+    buffer[0] = 0xff;
+    buffer[1] = 0xff;
+    let serialized_len = postcard::to_slice(diagnostics, &mut buffer[2..])
+        .unwrap()
+        .len();
+
+    offset += fill_adv_data(
+        &mut scan_data[offset..],
+        0xff,
+        &buffer[..2 + serialized_len],
+    );
+    offset
+}
+
 /// Reinitializes reset pin in the hardware.
 ///
 /// ```
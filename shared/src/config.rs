@@ -0,0 +1,563 @@
+//! Configuration of the bridge persisted in its internal flash.
+//!
+//! The defaults are set by the environment variables at build time, the stored configuration
+//! overrides them, so a single firmware can serve every deployment. A record is a version byte
+//! followed by the postcard encoding of the configuration of that version. Corrupted records
+//! and records of newer firmwares are replaced by the defaults.
+
+use core::fmt::Write;
+
+use heapless::String;
+use serde::{Deserialize, Serialize};
+
+use crate::mdns::{self, MdnsError};
+use crate::network::{is_valid_label, AddressConfig, Broker, NetworkError};
+use crate::policy::{PolicyError, PublishPolicies};
+use crate::scan::{ScanError, ScanParameters};
+use crate::topic::{TopicConfig, MAX_PREFIX_LEN};
+
+/// Version of the layout of [`BridgeConfig`], increased with every change of the layout
+pub const CONFIG_VERSION: u8 = 1;
+/// Longest encoded record, including the version
+pub const MAX_RECORD_LEN: usize = 1024;
+/// Longest client id every MQTT broker has to accept
+pub const MAX_CLIENT_ID_LEN: usize = 23;
+
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum ConfigError {
+    Network(NetworkError),
+    Scan(ScanError),
+    Policy(PolicyError),
+    InvalidHostname,
+    InvalidClientId,
+    InvalidTopic,
+    TooLong,
+    /// The setting can't be unset, it can only be changed
+    Required,
+    Malformed,
+    /// Stored by a newer firmware
+    UnsupportedVersion(u8),
+}
+
+impl From<NetworkError> for ConfigError {
+    fn from(e: NetworkError) -> Self {
+        ConfigError::Network(e)
+    }
+}
+
+impl From<ScanError> for ConfigError {
+    fn from(e: ScanError) -> Self {
+        ConfigError::Scan(e)
+    }
+}
+
+impl From<PolicyError> for ConfigError {
+    fn from(e: PolicyError) -> Self {
+        ConfigError::Policy(e)
+    }
+}
+
+impl From<MdnsError> for ConfigError {
+    fn from(_: MdnsError) -> Self {
+        ConfigError::InvalidHostname
+    }
+}
+
+impl ConfigError {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ConfigError::Network(NetworkError::InvalidAddress) => "invalid address",
+            ConfigError::Network(NetworkError::InvalidHostname) => "invalid hostname",
+            ConfigError::Network(NetworkError::InvalidPrefix) => "invalid prefix length",
+            ConfigError::Network(NetworkError::InvalidPort) => "invalid port",
+            ConfigError::Scan(ScanError::UnknownParameter) => "unknown scan parameter",
+            ConfigError::Scan(ScanError::InvalidValue) => "invalid scan parameter value",
+            ConfigError::Scan(ScanError::WindowTooLong) => {
+                "the scan window can't be longer than the interval"
+            }
+            ConfigError::Policy(PolicyError::UnknownKey) => "unknown publish policy metric",
+            ConfigError::Policy(PolicyError::InvalidValue) => "invalid publish policy value",
+            ConfigError::Policy(PolicyError::InvalidNode) => "invalid publish policy node",
+            ConfigError::Policy(PolicyError::TooManyNodes) => {
+                "too many nodes in the publish policy"
+            }
+            ConfigError::InvalidHostname => "invalid hostname",
+            ConfigError::InvalidClientId => {
+                "invalid client id, up to 23 letters, digits and hyphens"
+            }
+            ConfigError::InvalidTopic => {
+                "invalid topic, see the placeholders, the wildcards + and # are not allowed"
+            }
+            ConfigError::TooLong => "value too long",
+            ConfigError::Required => "the setting can't be unset",
+            ConfigError::Malformed => "malformed configuration",
+            ConfigError::UnsupportedVersion(_) => "unsupported configuration version",
+        }
+    }
+}
+
+/// Setting of the configuration, as named by the console
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum ConfigKey {
+    Network,
+    Broker,
+    Hostname,
+    ClientId,
+    MqttUsername,
+    MqttPassword,
+    TopicPrefix,
+    StateTopic,
+    MetricTopic,
+    Scan,
+    PublishPolicy,
+}
+
+impl ConfigKey {
+    pub const ALL: [ConfigKey; 11] = [
+        ConfigKey::Network,
+        ConfigKey::Broker,
+        ConfigKey::Hostname,
+        ConfigKey::ClientId,
+        ConfigKey::MqttUsername,
+        ConfigKey::MqttPassword,
+        ConfigKey::TopicPrefix,
+        ConfigKey::StateTopic,
+        ConfigKey::MetricTopic,
+        ConfigKey::Scan,
+        ConfigKey::PublishPolicy,
+    ];
+
+    pub fn parse(key: &str) -> Option<Self> {
+        Self::ALL.into_iter().find(|k| k.as_str() == key)
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ConfigKey::Network => "network",
+            ConfigKey::Broker => "broker",
+            ConfigKey::Hostname => "hostname",
+            ConfigKey::ClientId => "client-id",
+            ConfigKey::MqttUsername => "mqtt-username",
+            ConfigKey::MqttPassword => "mqtt-password",
+            ConfigKey::TopicPrefix => "topic-prefix",
+            ConfigKey::StateTopic => "state-topic",
+            ConfigKey::MetricTopic => "metric-topic",
+            ConfigKey::Scan => "scan",
+            ConfigKey::PublishPolicy => "publish-policy",
+        }
+    }
+
+    /// Whether the setting is applied after a reset only
+    pub fn needs_reboot(&self) -> bool {
+        matches!(self, ConfigKey::Network | ConfigKey::Hostname)
+    }
+
+    /// Whether the setting is applied with the next connection to the broker, the scan
+    /// parameters are applied once the scan restarts
+    pub fn needs_reconnect(&self) -> bool {
+        !self.needs_reboot() && *self != ConfigKey::Scan
+    }
+}
+
+#[derive(Clone, Debug, Eq, PartialEq, Serialize, Deserialize)]
+pub struct BridgeConfig {
+    /// Address configuration in the format of `AFO_NETWORK`
+    pub network: String<40>,
+    /// `host:port` of the MQTT broker
+    pub broker: String<64>,
+    /// Hostname advertised using mDNS without `.local`, the bridge name if not set
+    pub hostname: Option<String<63>>,
+    /// Client id of the MQTT connection, the bridge name if not set
+    pub client_id: Option<String<MAX_CLIENT_ID_LEN>>,
+    pub mqtt_username: Option<String<32>>,
+    pub mqtt_password: Option<String<64>>,
+    /// Templates of the topics of the measurements, see [`TopicConfig`]
+    pub topic_prefix: String<MAX_PREFIX_LEN>,
+    pub state_topic: String<64>,
+    pub metric_topic: Option<String<64>>,
+    /// Scan parameters in the format of `AFO_SCAN`
+    pub scan: String<64>,
+    /// Publish policy in the format of `AFO_PUBLISH_POLICY`, every change is published if not set
+    pub publish_policy: Option<String<192>>,
+}
+
+impl Default for BridgeConfig {
+    fn default() -> Self {
+        let mut config = Self {
+            network: String::new(),
+            broker: String::new(),
+            hostname: None,
+            client_id: None,
+            mqtt_username: None,
+            mqtt_password: None,
+            topic_prefix: String::new(),
+            state_topic: String::new(),
+            metric_topic: None,
+            scan: String::new(),
+            publish_policy: None,
+        };
+        // the defaults fit
+        config.network.push_str("10.42.0.61/24,10.42.0.1").unwrap();
+        write!(config.broker, "{}", Broker::DEFAULT).unwrap();
+        config.topic_prefix.push_str("afo").unwrap();
+        config.state_topic.push_str("{prefix}-{node}").unwrap();
+        write!(config.scan, "{}", ScanParameters::DEFAULT).unwrap();
+        config
+    }
+}
+
+impl BridgeConfig {
+    /// Configuration set by the environment variables at build time
+    pub fn from_env() -> Result<Self, ConfigError> {
+        let mut config = Self::default();
+        if let Some(network) = option_env!("AFO_NETWORK") {
+            config.set(ConfigKey::Network, Some(network))?;
+        }
+        if let Some(broker) = option_env!("AFO_BROKER") {
+            config.set(ConfigKey::Broker, Some(broker))?;
+        }
+        config.hostname = mdns::hostname_from_env()?
+            .map(|hostname| String::try_from(hostname).map_err(|_| ConfigError::TooLong))
+            .transpose()?;
+        config.set(ConfigKey::MqttUsername, option_env!("MQTT_USERNAME"))?;
+        config.set(ConfigKey::MqttPassword, option_env!("MQTT_PASSWORD"))?;
+        config.set_topics(&TopicConfig::from_env())?;
+        if let Some(scan) = option_env!("AFO_SCAN") {
+            config.set(ConfigKey::Scan, Some(scan))?;
+        }
+        config.set(ConfigKey::PublishPolicy, option_env!("AFO_PUBLISH_POLICY"))?;
+        Ok(config)
+    }
+
+    fn set_topics(&mut self, topics: &TopicConfig) -> Result<(), ConfigError> {
+        self.set(ConfigKey::TopicPrefix, Some(topics.prefix))?;
+        self.set(ConfigKey::StateTopic, Some(topics.state))?;
+        self.set(ConfigKey::MetricTopic, topics.metric)
+    }
+
+    /// Address configuration, validated when set
+    pub fn address(&self) -> AddressConfig {
+        AddressConfig::parse(&self.network).unwrap_or(AddressConfig::DEFAULT)
+    }
+
+    /// MQTT broker, validated when set
+    pub fn broker(&self) -> Broker<'_> {
+        Broker::parse(&self.broker).unwrap_or(Broker::DEFAULT)
+    }
+
+    /// Scan parameters, validated when set
+    pub fn scan(&self) -> ScanParameters {
+        ScanParameters::parse(&self.scan).unwrap_or(ScanParameters::DEFAULT)
+    }
+
+    /// Publish policies of the nodes, validated when set
+    pub fn policies(&self) -> PublishPolicies {
+        self.publish_policy
+            .as_deref()
+            .and_then(|policy| PublishPolicies::parse(policy).ok())
+            .unwrap_or(PublishPolicies::DEFAULT)
+    }
+
+    /// Topics, validated when set
+    pub fn topics(&self) -> TopicConfig<'_> {
+        TopicConfig {
+            prefix: &self.topic_prefix,
+            state: &self.state_topic,
+            metric: self.metric_topic.as_deref(),
+        }
+    }
+
+    /// Changes a setting, `None` unsets it
+    pub fn set(&mut self, key: ConfigKey, value: Option<&str>) -> Result<(), ConfigError> {
+        let value = value.map(str::trim);
+        match key {
+            ConfigKey::Network => {
+                let network = value.ok_or(ConfigError::Required)?;
+                AddressConfig::parse(network)?;
+                self.network = to_string(network)?;
+            }
+            ConfigKey::Broker => {
+                let broker = Broker::parse(value.ok_or(ConfigError::Required)?)?;
+                let mut formatted = String::new();
+                write!(formatted, "{}", broker).map_err(|_| ConfigError::TooLong)?;
+                self.broker = formatted;
+            }
+            ConfigKey::Hostname => {
+                if let Some(hostname) = value {
+                    if !is_valid_label(hostname) {
+                        return Err(ConfigError::InvalidHostname);
+                    }
+                }
+                self.hostname = value.map(to_string).transpose()?;
+            }
+            ConfigKey::ClientId => {
+                if let Some(client_id) = value {
+                    if client_id.is_empty()
+                        || client_id.len() > MAX_CLIENT_ID_LEN
+                        || !client_id
+                            .bytes()
+                            .all(|b| b.is_ascii_alphanumeric() || b == b'-')
+                    {
+                        return Err(ConfigError::InvalidClientId);
+                    }
+                }
+                self.client_id = value.map(to_string).transpose()?;
+            }
+            ConfigKey::MqttUsername => self.mqtt_username = value.map(to_string).transpose()?,
+            ConfigKey::MqttPassword => self.mqtt_password = value.map(to_string).transpose()?,
+            ConfigKey::TopicPrefix => {
+                let prefix = value.ok_or(ConfigError::Required)?;
+                validate_topics(TopicConfig {
+                    prefix,
+                    ..self.topics()
+                })?;
+                self.topic_prefix = to_string(prefix)?;
+            }
+            ConfigKey::StateTopic => {
+                let state = value.ok_or(ConfigError::Required)?;
+                validate_topics(TopicConfig {
+                    state,
+                    ..self.topics()
+                })?;
+                self.state_topic = to_string(state)?;
+            }
+            ConfigKey::MetricTopic => {
+                validate_topics(TopicConfig {
+                    metric: value,
+                    ..self.topics()
+                })?;
+                self.metric_topic = value.map(to_string).transpose()?;
+            }
+            ConfigKey::Scan => {
+                let scan = ScanParameters::parse(value.ok_or(ConfigError::Required)?)?;
+                let mut formatted = String::new();
+                write!(formatted, "{}", scan).map_err(|_| ConfigError::TooLong)?;
+                self.scan = formatted;
+            }
+            ConfigKey::PublishPolicy => {
+                if let Some(policy) = value {
+                    PublishPolicies::parse(policy)?;
+                }
+                self.publish_policy = value.map(to_string).transpose()?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Value of a setting, the password is not shown
+    pub fn get(&self, key: ConfigKey) -> Option<&str> {
+        match key {
+            ConfigKey::Network => Some(&self.network),
+            ConfigKey::Broker => Some(&self.broker),
+            ConfigKey::Hostname => self.hostname.as_deref(),
+            ConfigKey::ClientId => self.client_id.as_deref(),
+            ConfigKey::MqttUsername => self.mqtt_username.as_deref(),
+            ConfigKey::MqttPassword => self.mqtt_password.as_ref().map(|_| "********"),
+            ConfigKey::TopicPrefix => Some(&self.topic_prefix),
+            ConfigKey::StateTopic => Some(&self.state_topic),
+            ConfigKey::MetricTopic => self.metric_topic.as_deref(),
+            ConfigKey::Scan => Some(&self.scan),
+            ConfigKey::PublishPolicy => self.publish_policy.as_deref(),
+        }
+    }
+
+    /// Encodes the configuration as a record of the current version
+    pub fn encode<'a>(&self, buffer: &'a mut [u8]) -> Result<&'a [u8], ConfigError> {
+        let (version, payload) = buffer.split_first_mut().ok_or(ConfigError::TooLong)?;
+        *version = CONFIG_VERSION;
+        let len = postcard::to_slice(self, payload)
+            .map_err(|_| ConfigError::TooLong)?
+            .len();
+        Ok(&buffer[..1 + len])
+    }
+
+    /// Decodes a record of the current version
+    pub fn decode(record: &[u8]) -> Result<Self, ConfigError> {
+        let (&version, payload) = record.split_first().ok_or(ConfigError::Malformed)?;
+        let config: Self = match version {
+            CONFIG_VERSION => postcard::from_bytes(payload).map_err(|_| ConfigError::Malformed)?,
+            version => return Err(ConfigError::UnsupportedVersion(version)),
+        };
+        config.validate()?;
+        Ok(config)
+    }
+
+    /// Checks the settings of a decoded record, the flash may hold any bytes
+    fn validate(&self) -> Result<(), ConfigError> {
+        let mut copy = self.clone();
+        for key in ConfigKey::ALL {
+            let value = match key {
+                // the password is hidden by `get`
+                ConfigKey::MqttPassword => self.mqtt_password.as_deref(),
+                key => self.get(key),
+            };
+            copy.set(key, value)?;
+        }
+        Ok(())
+    }
+}
+
+fn validate_topics(topics: TopicConfig) -> Result<(), ConfigError> {
+    topics.validate().map_err(|_| ConfigError::InvalidTopic)
+}
+
+fn to_string<const N: usize>(value: &str) -> Result<String<N>, ConfigError> {
+    String::try_from(value).map_err(|_| ConfigError::TooLong)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::network::Host;
+
+    #[test]
+    fn sets_settings() {
+        let mut config = BridgeConfig::default();
+        assert_eq!(config.address(), AddressConfig::DEFAULT);
+        assert_eq!(config.broker(), Broker::DEFAULT);
+
+        config.set(ConfigKey::Broker, Some(" mqtt.lan ")).unwrap();
+        assert_eq!(config.get(ConfigKey::Broker), Some("mqtt.lan:1883"));
+        assert_eq!(config.broker().host, Host::Name("mqtt.lan"));
+        config.set(ConfigKey::Network, Some("dhcp")).unwrap();
+        assert_eq!(config.address(), AddressConfig::Dhcp);
+        config.set(ConfigKey::MqttPassword, Some("secret")).unwrap();
+        assert_eq!(config.get(ConfigKey::MqttPassword), Some("********"));
+        config
+            .set(ConfigKey::ClientId, Some("afo-kitchen"))
+            .unwrap();
+        config.set(ConfigKey::ClientId, None).unwrap();
+        assert_eq!(config.get(ConfigKey::ClientId), None);
+
+        assert_eq!(
+            config.set(ConfigKey::Broker, None),
+            Err(ConfigError::Required)
+        );
+        assert_eq!(
+            config.set(ConfigKey::Network, Some("10.42.0.61")),
+            Err(ConfigError::Network(NetworkError::InvalidPrefix))
+        );
+        assert_eq!(
+            config.set(ConfigKey::Hostname, Some("afo.bridge")),
+            Err(ConfigError::InvalidHostname)
+        );
+        assert_eq!(
+            config.set(ConfigKey::ClientId, Some("a-client-id-longer-than-23")),
+            Err(ConfigError::InvalidClientId)
+        );
+        // failed changes keep the previous value
+        assert_eq!(config.address(), AddressConfig::Dhcp);
+
+        config
+            .set(ConfigKey::StateTopic, Some("{prefix}/{bridge}/{node}"))
+            .unwrap();
+        config
+            .set(ConfigKey::TopicPrefix, Some("home/afo"))
+            .unwrap();
+        assert_eq!(config.topics().prefix, "home/afo");
+        assert_eq!(config.topics().metric, None);
+        assert_eq!(
+            config.set(ConfigKey::MetricTopic, Some("{prefix}/#")),
+            Err(ConfigError::InvalidTopic)
+        );
+        assert_eq!(
+            config.set(ConfigKey::StateTopic, Some("{prefix}/{sensor}")),
+            Err(ConfigError::InvalidTopic)
+        );
+        assert_eq!(
+            config.set(ConfigKey::TopicPrefix, None),
+            Err(ConfigError::Required)
+        );
+        assert_eq!(config.topics().state, "{prefix}/{bridge}/{node}");
+
+        assert_eq!(config.scan(), ScanParameters::DEFAULT);
+        config
+            .set(ConfigKey::Scan, Some("mode=passive,timeout=30"))
+            .unwrap();
+        assert_eq!(
+            config.get(ConfigKey::Scan),
+            Some("interval=100,window=100,mode=passive,phy=1m,timeout=30")
+        );
+        assert!(!config.scan().active);
+        assert_eq!(
+            config.set(ConfigKey::Scan, Some("interval=50")),
+            Err(ConfigError::Scan(ScanError::WindowTooLong))
+        );
+        assert_eq!(config.scan().timeout_s, 30);
+
+        assert_eq!(config.policies(), PublishPolicies::DEFAULT);
+        config
+            .set(ConfigKey::PublishPolicy, Some("co2=10; 42: co2=50"))
+            .unwrap();
+        assert_eq!(config.policies().get(42).co2, 50);
+        assert_eq!(config.policies().get(7).co2, 10);
+        assert_eq!(
+            config.set(ConfigKey::PublishPolicy, Some("pressure=1")),
+            Err(ConfigError::Policy(PolicyError::UnknownKey))
+        );
+        config.set(ConfigKey::PublishPolicy, None).unwrap();
+        assert_eq!(config.policies(), PublishPolicies::DEFAULT);
+
+        assert_eq!(ConfigKey::parse("client-id"), Some(ConfigKey::ClientId));
+        assert_eq!(ConfigKey::parse("port"), None);
+    }
+
+    #[test]
+    fn encodes_records() {
+        let mut config = BridgeConfig::default();
+        config
+            .set(ConfigKey::Hostname, Some("afo-kitchen"))
+            .unwrap();
+        config.set(ConfigKey::MqttUsername, Some("afo")).unwrap();
+        config.set(ConfigKey::MqttPassword, Some("secret")).unwrap();
+        config
+            .set(ConfigKey::MetricTopic, Some("{prefix}/{node}/{metric}"))
+            .unwrap();
+
+        let mut buffer = [0; MAX_RECORD_LEN];
+        let record = config.encode(&mut buffer).unwrap();
+        assert_eq!(record[0], CONFIG_VERSION);
+        assert_eq!(BridgeConfig::decode(record), Ok(config));
+
+        assert_eq!(BridgeConfig::decode(&[]), Err(ConfigError::Malformed));
+        assert_eq!(
+            BridgeConfig::decode(&[CONFIG_VERSION, 0xff]),
+            Err(ConfigError::Malformed)
+        );
+        assert_eq!(
+            BridgeConfig::decode(&[CONFIG_VERSION + 1, 0]),
+            Err(ConfigError::UnsupportedVersion(CONFIG_VERSION + 1))
+        );
+
+        // a record which decodes, but holds an invalid setting
+        let mut invalid = BridgeConfig::default();
+        invalid.network.clear();
+        invalid.network.push_str("dhcp6").unwrap();
+        let record = invalid.encode(&mut buffer).unwrap();
+        assert_eq!(
+            BridgeConfig::decode(record),
+            Err(ConfigError::Network(NetworkError::InvalidPrefix))
+        );
+
+        // the longest configuration fits
+        fn filled<const N: usize>() -> String<N> {
+            String::try_from("x".repeat(N).as_str()).unwrap()
+        }
+        let longest = BridgeConfig {
+            network: filled(),
+            broker: filled(),
+            hostname: Some(filled()),
+            client_id: Some(filled()),
+            mqtt_username: Some(filled()),
+            mqtt_password: Some(filled()),
+            topic_prefix: filled(),
+            state_topic: filled(),
+            metric_topic: Some(filled()),
+            scan: filled(),
+            publish_policy: Some(filled()),
+        };
+        assert!(longest.encode(&mut buffer).is_ok());
+    }
+}
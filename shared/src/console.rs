@@ -0,0 +1,170 @@
+//! Line commands of the serial console of the bridge.
+//!
+//! The console streams the readings and accepts commands terminated by a newline,
+//! the responses are prefixed by `# ` so that they can be told apart from the readings.
+
+use crate::config::ConfigKey;
+
+/// Longest command line accepted, longer lines are discarded
+pub const MAX_LINE_LEN: usize = 96;
+
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum CommandError {
+    Unknown,
+    MissingArgument,
+    UnknownSetting,
+    InvalidFormat,
+}
+
+impl CommandError {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            CommandError::Unknown => "unknown command, see help",
+            CommandError::MissingArgument => "missing argument, see help",
+            CommandError::UnknownSetting => "unknown setting, see help",
+            CommandError::InvalidFormat => "invalid format, expected json, csv or off",
+        }
+    }
+}
+
+/// Format of the streamed readings
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub enum StreamFormat {
+    /// Newline-delimited JSON
+    #[default]
+    Json,
+    Csv,
+    /// Only the responses to the commands are written
+    Off,
+}
+
+impl StreamFormat {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            StreamFormat::Json => "json",
+            StreamFormat::Csv => "csv",
+            StreamFormat::Off => "off",
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Command<'a> {
+    Help,
+    /// Lists the nodes heard by the bridge
+    Nodes,
+    /// Shows the configuration of the bridge
+    Config,
+    /// Changes a setting of the stored configuration
+    Set(ConfigKey, &'a str),
+    /// Unsets an optional setting of the stored configuration
+    Unset(ConfigKey),
+    /// Restores the configuration set at build time
+    Defaults,
+    /// Sets the format of the streamed readings
+    SetFormat(StreamFormat),
+    Reboot,
+}
+
+pub const HELP: &str = "\
+commands:
+  nodes                  list the nodes heard by the bridge
+  config                 show the configuration
+  broker <host[:port]>   set the MQTT broker
+  set <setting> <value>  change a setting: network, broker, hostname, client-id,
+                         mqtt-username, mqtt-password, topic-prefix, state-topic,
+                         metric-topic, scan or publish-policy
+  unset <setting>        unset the hostname, client-id, MQTT credentials,
+                         metric-topic or publish-policy
+  defaults               restore the configuration set at build time
+  format <json|csv|off>  set the format of the streamed readings
+  reboot                 restart the bridge
+";
+
+impl<'a> Command<'a> {
+    /// Parses a command line, `None` for an empty line
+    pub fn parse(line: &'a str) -> Option<Result<Self, CommandError>> {
+        let mut words = line.split_whitespace();
+        let command = words.next()?;
+        let arguments = (words.next(), words.next(), words.next());
+        Some(match (command, arguments) {
+            ("help" | "?", _) => Ok(Command::Help),
+            ("nodes", (None, ..)) => Ok(Command::Nodes),
+            ("config", (None, ..)) => Ok(Command::Config),
+            ("defaults", (None, ..)) => Ok(Command::Defaults),
+            ("reboot", (None, ..)) => Ok(Command::Reboot),
+            ("broker" | "format" | "set" | "unset", (None, ..)) | ("set", (_, None, _)) => {
+                Err(CommandError::MissingArgument)
+            }
+            ("broker", (Some(broker), None, _)) => Ok(Command::Set(ConfigKey::Broker, broker)),
+            ("set", (Some(key), Some(value), None)) => ConfigKey::parse(key)
+                .map(|key| Command::Set(key, value))
+                .ok_or(CommandError::UnknownSetting),
+            ("unset", (Some(key), None, _)) => ConfigKey::parse(key)
+                .map(Command::Unset)
+                .ok_or(CommandError::UnknownSetting),
+            ("format", (Some(format), None, _)) => match format {
+                "json" => Ok(Command::SetFormat(StreamFormat::Json)),
+                "csv" => Ok(Command::SetFormat(StreamFormat::Csv)),
+                "off" => Ok(Command::SetFormat(StreamFormat::Off)),
+                _ => Err(CommandError::InvalidFormat),
+            },
+            _ => Err(CommandError::Unknown),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_commands() {
+        assert_eq!(Command::parse("  \r"), None);
+        assert_eq!(Command::parse("nodes\r"), Some(Ok(Command::Nodes)));
+        assert_eq!(Command::parse("help nodes"), Some(Ok(Command::Help)));
+        assert_eq!(
+            Command::parse("broker homeassistant.local"),
+            Some(Ok(Command::Set(ConfigKey::Broker, "homeassistant.local")))
+        );
+        assert_eq!(
+            Command::parse("set client-id afo-kitchen"),
+            Some(Ok(Command::Set(ConfigKey::ClientId, "afo-kitchen")))
+        );
+        assert_eq!(
+            Command::parse("unset mqtt-password"),
+            Some(Ok(Command::Unset(ConfigKey::MqttPassword)))
+        );
+        assert_eq!(
+            Command::parse("format csv"),
+            Some(Ok(Command::SetFormat(StreamFormat::Csv)))
+        );
+        assert_eq!(
+            Command::parse("broker"),
+            Some(Err(CommandError::MissingArgument))
+        );
+        assert_eq!(
+            Command::parse("set hostname"),
+            Some(Err(CommandError::MissingArgument))
+        );
+        assert_eq!(
+            Command::parse("set port 1884"),
+            Some(Err(CommandError::UnknownSetting))
+        );
+        assert_eq!(
+            Command::parse("set mqtt-password two words"),
+            Some(Err(CommandError::Unknown))
+        );
+        assert_eq!(
+            Command::parse("format xml"),
+            Some(Err(CommandError::InvalidFormat))
+        );
+        assert_eq!(
+            Command::parse("reboot now"),
+            Some(Err(CommandError::Unknown))
+        );
+        assert_eq!(Command::parse("shutdown"), Some(Err(CommandError::Unknown)));
+    }
+}
@@ -0,0 +1,238 @@
+//! Coordination of bridges sharing a broker, so that every reading of a node is published once,
+//! by the bridge receiving the node best.
+//!
+//! The bridge publishing a node claims it by publishing a retained [`Claim`] to
+//! `<prefix>-owner/<sensor id>` with every reading, all the bridges subscribe to the claims.
+//! Another bridge takes over when the claim expires, or when it receives the node
+//! with a signal stronger by [`HANDOVER_MARGIN`].
+
+use heapless::String;
+
+use crate::topic::{Topic, TopicConfig};
+use crate::{SensorId, Sequence};
+
+/// Identifier of a bridge, unique within a deployment
+pub type BridgeId = u16;
+
+/// Time after which a claim not renewed by its bridge can be taken over
+pub const CLAIM_EXPIRY_MS: u64 = 30_000;
+
+/// Period of renewing the claims of the owned nodes, when their readings are not published
+pub const CLAIM_RENEWAL_MS: u64 = CLAIM_EXPIRY_MS / 3;
+
+/// How much stronger in dB the signal has to be to take over a node from another bridge,
+/// to avoid bridges with a similar signal taking turns
+pub const HANDOVER_MARGIN: i8 = 6;
+
+/// Claim of a bridge to publish a node, encoded with postcard
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+#[derive(Clone, Copy, Debug, Eq, PartialEq, serde::Serialize, serde::Deserialize)]
+pub struct Claim {
+    pub bridge: BridgeId,
+    /// Smoothed RSSI of the node at the claiming bridge
+    pub rssi: i8,
+    /// Sequence number of the last reading published by the claiming bridge
+    pub sequence: Sequence,
+}
+
+/// Parses the sensor id from a claim topic
+pub fn claim_topic_sensor_id(topics: &TopicConfig, topic: &str) -> Option<SensorId> {
+    let mut prefix = String::<64>::new();
+    topics.write_topic(Topic::Claim, &"", &mut prefix).ok()?;
+    topic.strip_prefix(prefix.as_str())?.parse().ok()
+}
+
+/// Ownership of a node as seen by one bridge
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Ownership {
+    /// Latest claim of the node and the time it was received, in ms
+    claim: Option<(Claim, u64)>,
+    /// Sequence number of the last reading published by any bridge
+    published: Option<Sequence>,
+}
+
+impl Ownership {
+    /// Records a claim received from the broker, including the claims of this bridge
+    pub fn record_claim(&mut self, own: BridgeId, claim: Claim, now_ms: u64) {
+        self.claim = Some((claim, now_ms));
+        // the own claims are older than what has been published since
+        if claim.bridge != own {
+            self.published = Some(claim.sequence);
+        }
+    }
+
+    pub fn owner(&self) -> Option<BridgeId> {
+        self.claim.map(|(claim, _)| claim.bridge)
+    }
+
+    /// Decides whether this bridge publishes the reading with `sequence`, received with `rssi`.
+    /// Returns the claim to publish along with the reading.
+    pub fn publish(
+        &mut self,
+        own: BridgeId,
+        rssi: i8,
+        sequence: Sequence,
+        now_ms: u64,
+    ) -> Option<Claim> {
+        if self.published == Some(sequence) {
+            return None;
+        }
+        let owned = match self.claim {
+            None => true,
+            Some((claim, _)) if claim.bridge == own => true,
+            Some((claim, received)) => {
+                now_ms.saturating_sub(received) >= CLAIM_EXPIRY_MS
+                    || rssi > claim.rssi.saturating_add(HANDOVER_MARGIN)
+            }
+        };
+        if !owned {
+            return None;
+        }
+
+        let claim = Claim {
+            bridge: own,
+            rssi,
+            sequence,
+        };
+        self.claim = Some((claim, now_ms));
+        self.published = Some(sequence);
+        Some(claim)
+    }
+
+    /// Renews the claim of a node owned by this bridge, when publishing an unchanged reading again.
+    /// Returns the claim to publish along with the reading.
+    pub fn renew(&mut self, own: BridgeId, rssi: i8, now_ms: u64) -> Option<Claim> {
+        let (claim, _) = self.claim?;
+        if claim.bridge != own {
+            return None;
+        }
+        let claim = Claim { rssi, ..claim };
+        self.claim = Some((claim, now_ms));
+        Some(claim)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const OWN: BridgeId = 1;
+    const OTHER: BridgeId = 2;
+
+    #[test]
+    fn publishes_unclaimed_node_once() {
+        let mut ownership = Ownership::default();
+        let claim = ownership.publish(OWN, -70, 5, 0);
+        assert_eq!(
+            claim,
+            Some(Claim {
+                bridge: OWN,
+                rssi: -70,
+                sequence: 5
+            })
+        );
+        assert_eq!(ownership.publish(OWN, -70, 5, 2_000), None);
+
+        // the echo of the own claim doesn't cause the reading to be published again
+        ownership.record_claim(OWN, claim.unwrap(), 100);
+        assert_eq!(ownership.publish(OWN, -70, 5, 2_000), None);
+        assert!(ownership.publish(OWN, -70, 6, 4_000).is_some());
+    }
+
+    #[test]
+    fn defers_to_other_bridge() {
+        let mut ownership = Ownership::default();
+        let claim = Claim {
+            bridge: OTHER,
+            rssi: -80,
+            sequence: 5,
+        };
+        ownership.record_claim(OWN, claim, 0);
+
+        assert_eq!(ownership.owner(), Some(OTHER));
+        assert_eq!(ownership.publish(OWN, -75, 6, 1_000), None);
+        assert_eq!(
+            ownership.publish(OWN, -80 + HANDOVER_MARGIN, 6, 1_000),
+            None
+        );
+
+        // the reading already published by the other bridge isn't published again on takeover
+        assert_eq!(ownership.publish(OWN, -60, 5, 1_000), None);
+        assert!(ownership.publish(OWN, -60, 6, 1_000).is_some());
+        assert_eq!(ownership.owner(), Some(OWN));
+    }
+
+    #[test]
+    fn takes_over_expired_claim() {
+        let mut ownership = Ownership::default();
+        let claim = Claim {
+            bridge: OTHER,
+            rssi: -50,
+            sequence: 5,
+        };
+        ownership.record_claim(OWN, claim, 1_000);
+
+        assert_eq!(
+            ownership.publish(OWN, -90, 6, 1_000 + CLAIM_EXPIRY_MS - 1),
+            None
+        );
+        assert!(ownership
+            .publish(OWN, -90, 6, 1_000 + CLAIM_EXPIRY_MS)
+            .is_some());
+    }
+
+    #[test]
+    fn renews_own_claim_only() {
+        let mut ownership = Ownership::default();
+        assert_eq!(ownership.renew(OWN, -70, 0), None);
+
+        ownership.publish(OWN, -70, 5, 0).unwrap();
+        assert_eq!(
+            ownership.renew(OWN, -72, 10_000),
+            Some(Claim {
+                bridge: OWN,
+                rssi: -72,
+                sequence: 5
+            })
+        );
+
+        let claim = Claim {
+            bridge: OTHER,
+            rssi: -50,
+            sequence: 6,
+        };
+        ownership.record_claim(OWN, claim, 20_000);
+        assert_eq!(ownership.renew(OWN, -72, 20_000), None);
+    }
+
+    #[test]
+    fn claim_topic() {
+        let mut topics = TopicConfig {
+            prefix: "afo",
+            state: "{prefix}-{node}",
+            metric: None,
+        };
+        assert_eq!(claim_topic_sensor_id(&topics, "afo-owner/42"), Some(42));
+        assert_eq!(claim_topic_sensor_id(&topics, "afo-owner/x"), None);
+        assert_eq!(claim_topic_sensor_id(&topics, "afo-42"), None);
+
+        topics.prefix = "home/afo";
+        assert_eq!(
+            claim_topic_sensor_id(&topics, "home/afo-owner/42"),
+            Some(42)
+        );
+        assert_eq!(claim_topic_sensor_id(&topics, "afo-owner/42"), None);
+    }
+
+    #[test]
+    fn claim_encoding() {
+        let claim = Claim {
+            bridge: BridgeId::MAX,
+            rssi: -128,
+            sequence: 255,
+        };
+        let mut buffer = [0u8; 16];
+        let encoded = postcard::to_slice(&claim, &mut buffer).unwrap();
+        assert_eq!(postcard::from_bytes::<Claim>(encoded).unwrap(), claim);
+    }
+}
@@ -0,0 +1,346 @@
+//! Crash reports persisted across resets in memory that isn't initialized on startup.
+
+use core::fmt::{self, Write};
+
+use crate::json::Escaped;
+
+#[cfg(feature = "cortex-m")]
+pub mod storage;
+
+/// Marks a record written by the panic or fault handler
+const MAGIC: u32 = 0xdead_af01;
+
+/// Maximum length of the stored panic message, longer messages are truncated
+pub const MESSAGE_LEN: usize = 96;
+
+/// Longest output of [`CrashRecord::write_json`], the escaped control characters
+/// of a message take six times their length
+pub const JSON_LEN: usize = 64 + 6 * MESSAGE_LEN;
+
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+#[derive(Clone, Copy, Debug, Eq, PartialEq, serde::Serialize, serde::Deserialize)]
+pub enum CrashKind {
+    Panic,
+    HardFault,
+}
+
+impl CrashKind {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            CrashKind::Panic => "panic",
+            CrashKind::HardFault => "hard_fault",
+        }
+    }
+}
+
+/// Crash record stored in `.uninit` memory.
+/// Only plain integers are stored, so that any content left in the memory is a valid value,
+/// the record is checked with a magic number and a checksum before use.
+#[repr(C)]
+#[derive(Clone, Copy, Debug)]
+pub struct CrashRecord {
+    magic: u32,
+    checksum: u32,
+    kind: u32,
+    line: u32,
+    /// Exception frame: r0, r1, r2, r3, r12, lr, pc, xpsr
+    registers: [u32; 8],
+    cfsr: u32,
+    hfsr: u32,
+    message_len: u32,
+    message: [u8; MESSAGE_LEN],
+}
+
+impl CrashRecord {
+    pub const fn empty() -> Self {
+        Self {
+            magic: 0,
+            checksum: 0,
+            kind: 0,
+            line: 0,
+            registers: [0; 8],
+            cfsr: 0,
+            hfsr: 0,
+            message_len: 0,
+            message: [0; MESSAGE_LEN],
+        }
+    }
+
+    /// Records a panic, `message` is usually the `PanicInfo` containing the location and the message
+    pub fn panic(line: u32, message: &dyn fmt::Display) -> Self {
+        let mut record = Self::empty();
+        record.kind = CrashKind::Panic as u32 + 1;
+        record.line = line;
+        let mut writer = Truncating::new(&mut record.message);
+        let _ = write!(writer, "{}", message);
+        record.message_len = writer.len as u32;
+        record.seal()
+    }
+
+    /// Records a HardFault with its exception frame and the fault status registers
+    pub fn hard_fault(registers: [u32; 8], cfsr: u32, hfsr: u32) -> Self {
+        let mut record = Self::empty();
+        record.kind = CrashKind::HardFault as u32 + 1;
+        record.registers = registers;
+        record.cfsr = cfsr;
+        record.hfsr = hfsr;
+        record.seal()
+    }
+
+    fn seal(mut self) -> Self {
+        self.magic = MAGIC;
+        self.checksum = self.compute_checksum();
+        self
+    }
+
+    /// FNV-1a of all the fields but the checksum
+    fn compute_checksum(&self) -> u32 {
+        let words = [
+            self.magic,
+            self.kind,
+            self.line,
+            self.cfsr,
+            self.hfsr,
+            self.message_len,
+        ];
+        words
+            .iter()
+            .chain(self.registers.iter())
+            .flat_map(|word| word.to_le_bytes())
+            .chain(self.message.iter().copied())
+            .fold(0x811c_9dc5, |hash, byte| {
+                (hash ^ byte as u32).wrapping_mul(0x0100_0193)
+            })
+    }
+
+    pub fn is_valid(&self) -> bool {
+        self.magic == MAGIC
+            && self.checksum == self.compute_checksum()
+            && self.kind().is_some()
+            && self.message_len as usize <= MESSAGE_LEN
+    }
+
+    /// Returns the record if it is valid and clears the slot, so that it is reported only once
+    pub fn take(slot: &mut CrashRecord) -> Option<CrashRecord> {
+        let record = core::mem::replace(slot, CrashRecord::empty());
+        record.is_valid().then_some(record)
+    }
+
+    pub fn kind(&self) -> Option<CrashKind> {
+        match self.kind {
+            1 => Some(CrashKind::Panic),
+            2 => Some(CrashKind::HardFault),
+            _ => None,
+        }
+    }
+
+    pub fn pc(&self) -> u32 {
+        self.registers[6]
+    }
+
+    pub fn message(&self) -> &str {
+        let message = &self.message[..(self.message_len as usize).min(MESSAGE_LEN)];
+        match core::str::from_utf8(message) {
+            Ok(message) => message,
+            Err(e) => core::str::from_utf8(&message[..e.valid_up_to()]).unwrap_or(""),
+        }
+    }
+
+    /// Compact summary of the crash, fitting into an advertisement
+    pub fn summary(&self) -> Option<CrashSummary> {
+        let kind = self.kind()?;
+        let location = match kind {
+            CrashKind::Panic => self.line,
+            CrashKind::HardFault => self.pc(),
+        };
+        Some(CrashSummary { kind, location })
+    }
+
+    /// Writes the record as a JSON object
+    pub fn write_json(&self, w: &mut impl Write) -> fmt::Result {
+        let kind = self.kind().map_or("unknown", |kind| kind.as_str());
+        match self.kind() {
+            Some(CrashKind::HardFault) => {
+                let [r0, r1, r2, r3, r12, lr, pc, xpsr] = self.registers;
+                write!(
+                    w,
+                    r#"{{"kind": "{}", "pc": "{:#010x}", "lr": "{:#010x}", "xpsr": "{:#010x}", "#,
+                    kind, pc, lr, xpsr
+                )?;
+                write!(
+                    w,
+                    r#""r0": "{:#010x}", "r1": "{:#010x}", "r2": "{:#010x}", "r3": "{:#010x}", "r12": "{:#010x}", "#,
+                    r0, r1, r2, r3, r12
+                )?;
+                write!(
+                    w,
+                    r#""cfsr": "{:#010x}", "hfsr": "{:#010x}"}}"#,
+                    self.cfsr, self.hfsr
+                )
+            }
+            _ => write!(
+                w,
+                r#"{{"kind": "{}", "line": {}, "message": {}}}"#,
+                kind,
+                self.line,
+                Escaped(self.message())
+            ),
+        }
+    }
+}
+
+/// Crash information advertised by the nodes.
+/// `location` is the line of a panic or the program counter of a HardFault.
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+#[derive(Clone, Copy, Debug, Eq, PartialEq, serde::Serialize, serde::Deserialize)]
+pub struct CrashSummary {
+    pub kind: CrashKind,
+    pub location: u32,
+}
+
+/// Writer storing the output up to the first character which doesn't fit,
+/// without splitting characters
+struct Truncating<'a> {
+    buffer: &'a mut [u8],
+    len: usize,
+    /// A character didn't fit, the rest of the output is dropped
+    full: bool,
+}
+
+impl<'a> Truncating<'a> {
+    fn new(buffer: &'a mut [u8]) -> Self {
+        Self {
+            buffer,
+            len: 0,
+            full: false,
+        }
+    }
+}
+
+impl Write for Truncating<'_> {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        for c in s.chars() {
+            if self.full {
+                break;
+            }
+            let mut encoded = [0u8; 4];
+            let encoded = c.encode_utf8(&mut encoded).as_bytes();
+            if self.len + encoded.len() > self.buffer.len() {
+                self.full = true;
+                break;
+            }
+            self.buffer[self.len..][..encoded.len()].copy_from_slice(encoded);
+            self.len += encoded.len();
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn panic() {
+        let mut slot = CrashRecord::panic(
+            42,
+            &format_args!("panicked at src/main.rs:42:5:\n\"{}\"", "x".repeat(200)),
+        );
+        let record = CrashRecord::take(&mut slot).unwrap();
+
+        assert_eq!(record.kind(), Some(CrashKind::Panic));
+        assert_eq!(record.message().len(), MESSAGE_LEN);
+        assert!(record
+            .message()
+            .starts_with("panicked at src/main.rs:42:5:\n\"xxx"));
+        assert_eq!(
+            record.summary(),
+            Some(CrashSummary {
+                kind: CrashKind::Panic,
+                location: 42
+            })
+        );
+
+        let mut json = String::new();
+        record.write_json(&mut json).unwrap();
+        assert!(json.starts_with(
+            r#"{"kind": "panic", "line": 42, "message": "panicked at src/main.rs:42:5:\n\"xxx"#
+        ));
+    }
+
+    #[test]
+    fn json_fits() {
+        let message = "\u{1}".repeat(MESSAGE_LEN);
+        let mut json = heapless::String::<JSON_LEN>::new();
+        let record = CrashRecord::panic(u32::MAX, &message);
+        record.write_json(&mut json).unwrap();
+        assert!(json.ends_with(r#"\u0001\u0001"}"#));
+
+        let mut json = heapless::String::<JSON_LEN>::new();
+        let record = CrashRecord::hard_fault([u32::MAX; 8], u32::MAX, u32::MAX);
+        record.write_json(&mut json).unwrap();
+    }
+
+    #[test]
+    fn hard_fault() {
+        let mut slot = CrashRecord::hard_fault(
+            [0, 1, 2, 3, 12, 0x1001, 0x2002, 0x6100_0000],
+            0x400,
+            0x4000_0000,
+        );
+        let record = CrashRecord::take(&mut slot).unwrap();
+
+        assert_eq!(record.kind(), Some(CrashKind::HardFault));
+        assert_eq!(
+            record.summary(),
+            Some(CrashSummary {
+                kind: CrashKind::HardFault,
+                location: 0x2002
+            })
+        );
+        assert!(CrashRecord::take(&mut slot).is_none());
+
+        let mut json = String::new();
+        record.write_json(&mut json).unwrap();
+        assert!(
+            json.starts_with(r#"{"kind": "hard_fault", "pc": "0x00002002", "lr": "0x00001001""#)
+        );
+        assert!(json.ends_with(r#""cfsr": "0x00000400", "hfsr": "0x40000000"}"#));
+    }
+
+    #[test]
+    fn corrupted_record() {
+        let mut record = CrashRecord::hard_fault([0; 8], 0, 0);
+        record.registers[6] = 1;
+        assert!(!record.is_valid());
+
+        let mut record = CrashRecord::hard_fault([0; 8], 0, 0);
+        record.kind = 7;
+        record = record.seal();
+        assert!(!record.is_valid());
+
+        assert!(!CrashRecord::empty().is_valid());
+    }
+
+    #[test]
+    fn truncating_writer() {
+        let mut buffer = [0u8; 4];
+        let mut writer = Truncating::new(&mut buffer);
+        write!(writer, "abčd").unwrap();
+        let len = writer.len;
+        assert_eq!(&buffer[..len], "abč".as_bytes());
+
+        let mut writer = Truncating::new(&mut buffer);
+        write!(writer, "abcč").unwrap();
+        let len = writer.len;
+        assert_eq!(&buffer[..len], b"abc");
+
+        // nothing is written after a character which didn't fit, even if the rest would fit
+        let mut writer = Truncating::new(&mut buffer);
+        write!(writer, "abc").unwrap();
+        write!(writer, "ř").unwrap();
+        write!(writer, "e").unwrap();
+        let len = writer.len;
+        assert_eq!(&buffer[..len], b"abc");
+    }
+}
@@ -0,0 +1,50 @@
+//! Persistence of crash reports across resets in RAM that isn't initialized on startup.
+//!
+//! The firmwares record their panics and HardFaults from their own handlers.
+
+use core::mem::MaybeUninit;
+use cortex_m::peripheral::SCB;
+use cortex_m_rt::ExceptionFrame;
+
+use super::CrashRecord;
+
+#[link_section = ".uninit.CRASH_RECORD"]
+static mut CRASH_RECORD: MaybeUninit<CrashRecord> = MaybeUninit::uninit();
+
+fn store(record: CrashRecord) {
+    unsafe { core::ptr::write_volatile(CRASH_RECORD.as_mut_ptr(), record) }
+}
+
+pub fn record_panic(info: &core::panic::PanicInfo) {
+    let line = info.location().map_or(0, |location| location.line());
+    store(CrashRecord::panic(line, info));
+}
+
+/// Records a HardFault, to be called from the HardFault handler before the reset
+pub fn record_hard_fault(frame: &ExceptionFrame) {
+    let scb = unsafe { &*SCB::PTR };
+    let registers = [
+        frame.r0(),
+        frame.r1(),
+        frame.r2(),
+        frame.r3(),
+        frame.r12(),
+        frame.lr(),
+        frame.pc(),
+        frame.xpsr(),
+    ];
+    store(CrashRecord::hard_fault(
+        registers,
+        scb.cfsr.read(),
+        scb.hfsr.read(),
+    ));
+}
+
+/// Returns the crash recorded before the last reset, if there was any
+pub fn take() -> Option<CrashRecord> {
+    // the memory holds garbage after a power on reset, which is rejected by the record validation
+    let mut record = unsafe { core::ptr::read_volatile(CRASH_RECORD.as_ptr()) };
+    let crash = CrashRecord::take(&mut record);
+    store(record);
+    crash
+}
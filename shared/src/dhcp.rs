@@ -0,0 +1,292 @@
+//! Minimal DHCPv4 server for the point-to-point USB link of the bridge.
+//!
+//! There is a single client on the link, the host, so the server hands out a single address
+//! to any client. No router is advertised, so that the host keeps its default route.
+
+use crate::network::Ipv4;
+
+pub const SERVER_PORT: u16 = 67;
+pub const CLIENT_PORT: u16 = 68;
+
+/// Size of the fixed part of the message up to and including the magic cookie
+const HEADER_LEN: usize = 240;
+const MAGIC_COOKIE: [u8; 4] = [99, 130, 83, 99];
+/// Replies are padded to the minimal BOOTP message size, as some clients drop shorter ones
+pub const REPLY_LEN: usize = 300;
+
+const OP_REQUEST: u8 = 1;
+const OP_REPLY: u8 = 2;
+
+const OPTION_PAD: u8 = 0;
+const OPTION_SUBNET_MASK: u8 = 1;
+const OPTION_REQUESTED_ADDRESS: u8 = 50;
+const OPTION_LEASE_TIME: u8 = 51;
+const OPTION_MESSAGE_TYPE: u8 = 53;
+const OPTION_SERVER_ID: u8 = 54;
+const OPTION_END: u8 = 255;
+
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum DhcpError {
+    Truncated,
+    /// Not a BOOTP request, or not a DHCP message
+    NotRequest,
+    MissingMessageType,
+}
+
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum MessageType {
+    Discover = 1,
+    Offer = 2,
+    Request = 3,
+    Decline = 4,
+    Ack = 5,
+    Nak = 6,
+    Release = 7,
+    Inform = 8,
+}
+
+impl MessageType {
+    fn from_u8(value: u8) -> Option<Self> {
+        Some(match value {
+            1 => Self::Discover,
+            2 => Self::Offer,
+            3 => Self::Request,
+            4 => Self::Decline,
+            5 => Self::Ack,
+            6 => Self::Nak,
+            7 => Self::Release,
+            8 => Self::Inform,
+            _ => return None,
+        })
+    }
+}
+
+/// Message of a DHCP client
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct ClientMessage {
+    pub message_type: MessageType,
+    pub xid: u32,
+    pub flags: u16,
+    /// Address of a client renewing its lease
+    pub ciaddr: Ipv4,
+    /// Hardware address, padded to 16 bytes
+    pub chaddr: [u8; 16],
+    pub requested_address: Option<Ipv4>,
+    pub server_id: Option<Ipv4>,
+}
+
+impl ClientMessage {
+    pub fn parse(packet: &[u8]) -> Result<Self, DhcpError> {
+        if packet.len() < HEADER_LEN {
+            return Err(DhcpError::Truncated);
+        }
+        if packet[0] != OP_REQUEST || packet[236..240] != MAGIC_COOKIE {
+            return Err(DhcpError::NotRequest);
+        }
+        let mut message_type = None;
+        let mut requested_address = None;
+        let mut server_id = None;
+        let mut options = &packet[HEADER_LEN..];
+        while let Some((&code, rest)) = options.split_first() {
+            match code {
+                OPTION_PAD => {
+                    options = rest;
+                    continue;
+                }
+                OPTION_END => break,
+                _ => {}
+            }
+            let (&len, rest) = rest.split_first().ok_or(DhcpError::Truncated)?;
+            let value = rest.get(..len as usize).ok_or(DhcpError::Truncated)?;
+            match (code, value) {
+                (OPTION_MESSAGE_TYPE, [value]) => message_type = MessageType::from_u8(*value),
+                (OPTION_REQUESTED_ADDRESS, &[a, b, c, d]) => requested_address = Some([a, b, c, d]),
+                (OPTION_SERVER_ID, &[a, b, c, d]) => server_id = Some([a, b, c, d]),
+                _ => {}
+            }
+            options = &rest[len as usize..];
+        }
+
+        let mut chaddr = [0; 16];
+        chaddr.copy_from_slice(&packet[28..44]);
+        Ok(Self {
+            message_type: message_type.ok_or(DhcpError::MissingMessageType)?,
+            xid: u32::from_be_bytes([packet[4], packet[5], packet[6], packet[7]]),
+            flags: u16::from_be_bytes([packet[10], packet[11]]),
+            ciaddr: [packet[12], packet[13], packet[14], packet[15]],
+            chaddr,
+            requested_address,
+            server_id,
+        })
+    }
+}
+
+/// The single lease handed out by the server
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct Lease {
+    /// Address of the bridge, sent as the server identifier
+    pub server: Ipv4,
+    /// Address handed to the host
+    pub client: Ipv4,
+    pub prefix_len: u8,
+    /// In seconds
+    pub lease_time: u32,
+}
+
+impl Lease {
+    /// Type of the reply to a client message, `None` if the message is not answered
+    pub fn reply_type(&self, message: &ClientMessage) -> Option<MessageType> {
+        match message.message_type {
+            MessageType::Discover => Some(MessageType::Offer),
+            MessageType::Request => {
+                // the client has chosen an offer of another server
+                if matches!(message.server_id, Some(server) if server != self.server) {
+                    return None;
+                }
+                let requested = match message.requested_address {
+                    Some(address) => address,
+                    None => message.ciaddr,
+                };
+                if requested == self.client {
+                    Some(MessageType::Ack)
+                } else {
+                    Some(MessageType::Nak)
+                }
+            }
+            _ => None,
+        }
+    }
+
+    /// Writes the reply to a client message, returns its length
+    pub fn write_reply(
+        &self,
+        message: &ClientMessage,
+        reply_type: MessageType,
+        buffer: &mut [u8; REPLY_LEN],
+    ) -> usize {
+        buffer.fill(0);
+        buffer[0] = OP_REPLY;
+        // Ethernet, 6 byte hardware address
+        buffer[1] = 1;
+        buffer[2] = 6;
+        buffer[4..8].copy_from_slice(&message.xid.to_be_bytes());
+        buffer[10..12].copy_from_slice(&message.flags.to_be_bytes());
+        if reply_type != MessageType::Nak {
+            buffer[16..20].copy_from_slice(&self.client);
+        }
+        buffer[28..44].copy_from_slice(&message.chaddr);
+        buffer[236..240].copy_from_slice(&MAGIC_COOKIE);
+
+        let mut options = Options {
+            buffer,
+            len: HEADER_LEN,
+        };
+        options.push(OPTION_MESSAGE_TYPE, &[reply_type as u8]);
+        options.push(OPTION_SERVER_ID, &self.server);
+        if reply_type != MessageType::Nak {
+            options.push(OPTION_LEASE_TIME, &self.lease_time.to_be_bytes());
+            let mask = u32::MAX
+                .checked_shl(32 - self.prefix_len as u32)
+                .unwrap_or(0);
+            options.push(OPTION_SUBNET_MASK, &mask.to_be_bytes());
+        }
+        options.buffer[options.len] = OPTION_END;
+        REPLY_LEN
+    }
+}
+
+struct Options<'a> {
+    buffer: &'a mut [u8; REPLY_LEN],
+    len: usize,
+}
+
+impl Options<'_> {
+    fn push(&mut self, code: u8, value: &[u8]) {
+        self.buffer[self.len] = code;
+        self.buffer[self.len + 1] = value.len() as u8;
+        self.buffer[self.len + 2..][..value.len()].copy_from_slice(value);
+        self.len += 2 + value.len();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const LEASE: Lease = Lease {
+        server: [10, 42, 0, 61],
+        client: [10, 42, 0, 1],
+        prefix_len: 24,
+        lease_time: 3600,
+    };
+
+    fn client_message(options: &[u8]) -> [u8; 300] {
+        let mut packet = [0; 300];
+        packet[0] = OP_REQUEST;
+        packet[1] = 1;
+        packet[2] = 6;
+        packet[4..8].copy_from_slice(&0x1234_5678u32.to_be_bytes());
+        packet[10] = 0x80;
+        packet[28..34].copy_from_slice(&[0x88; 6]);
+        packet[236..240].copy_from_slice(&MAGIC_COOKIE);
+        packet[240..][..options.len()].copy_from_slice(options);
+        packet
+    }
+
+    #[test]
+    fn offers_and_acknowledges_lease() {
+        let discover = client_message(&[53, 1, 1, 0, 55, 2, 1, 3, 255]);
+        let discover = ClientMessage::parse(&discover).unwrap();
+        assert_eq!(discover.message_type, MessageType::Discover);
+        assert_eq!(discover.xid, 0x1234_5678);
+        assert_eq!(LEASE.reply_type(&discover), Some(MessageType::Offer));
+
+        let mut reply = [0; REPLY_LEN];
+        assert_eq!(
+            LEASE.write_reply(&discover, MessageType::Offer, &mut reply),
+            REPLY_LEN
+        );
+        assert_eq!(reply[0], OP_REPLY);
+        assert_eq!(reply[4..8], 0x1234_5678u32.to_be_bytes());
+        assert_eq!(reply[10], 0x80);
+        assert_eq!(reply[16..20], [10, 42, 0, 1]);
+        assert_eq!(reply[28..34], [0x88; 6]);
+        assert_eq!(
+            reply[240..262],
+            [53, 1, 2, 54, 4, 10, 42, 0, 61, 51, 4, 0, 0, 14, 16, 1, 4, 255, 255, 255, 0, 255]
+        );
+
+        let request = client_message(&[53, 1, 3, 50, 4, 10, 42, 0, 1, 54, 4, 10, 42, 0, 61, 255]);
+        let request = ClientMessage::parse(&request).unwrap();
+        assert_eq!(request.requested_address, Some([10, 42, 0, 1]));
+        assert_eq!(LEASE.reply_type(&request), Some(MessageType::Ack));
+    }
+
+    #[test]
+    fn rejects_other_addresses() {
+        let request = client_message(&[53, 1, 3, 50, 4, 192, 168, 1, 5, 255]);
+        let request = ClientMessage::parse(&request).unwrap();
+        assert_eq!(LEASE.reply_type(&request), Some(MessageType::Nak));
+
+        // offer of another server selected
+        let request = client_message(&[53, 1, 3, 54, 4, 10, 42, 0, 2, 255]);
+        let request = ClientMessage::parse(&request).unwrap();
+        assert_eq!(LEASE.reply_type(&request), None);
+
+        let release = client_message(&[53, 1, 7, 255]);
+        let release = ClientMessage::parse(&release).unwrap();
+        assert_eq!(LEASE.reply_type(&release), None);
+
+        assert_eq!(
+            ClientMessage::parse(&client_message(&[255])),
+            Err(DhcpError::MissingMessageType)
+        );
+        assert_eq!(
+            ClientMessage::parse(&client_message(&[53, 4, 1])[..243]),
+            Err(DhcpError::Truncated)
+        );
+        assert_eq!(ClientMessage::parse(&[0; 64]), Err(DhcpError::Truncated));
+    }
+}
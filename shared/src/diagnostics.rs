@@ -0,0 +1,162 @@
+//! Health telemetry common to the firmwares connecting to the broker.
+
+/// Reason of a failed connection to the broker
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum ConnectFailure {
+    /// The broker is unreachable or the connection broke
+    Network,
+    /// The hostname of the broker could not be resolved
+    Resolution,
+    /// The TLS handshake failed, e.g. the broker certificate doesn't match the pinned one
+    Tls,
+    BadCredentials,
+    NotAuthorized,
+    /// The broker is unavailable, busy or the client is banned
+    Unavailable,
+    /// Any other reason code in the CONNACK
+    Rejected,
+}
+
+impl ConnectFailure {
+    /// Classifies the MQTT v5 reason code of a failed connection,
+    /// `0xff` is the code rust-mqtt reports network errors with
+    pub fn from_reason_code(code: u8) -> Self {
+        match code {
+            0xff => ConnectFailure::Network,
+            0x86 => ConnectFailure::BadCredentials,
+            0x87 => ConnectFailure::NotAuthorized,
+            0x88..=0x8a => ConnectFailure::Unavailable,
+            _ => ConnectFailure::Rejected,
+        }
+    }
+
+    /// Authorization failures don't go away by retrying soon
+    pub fn is_auth(&self) -> bool {
+        matches!(
+            self,
+            ConnectFailure::BadCredentials | ConnectFailure::NotAuthorized
+        )
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ConnectFailure::Network => "network",
+            ConnectFailure::Resolution => "resolution",
+            ConnectFailure::Tls => "tls",
+            ConnectFailure::BadCredentials => "bad_credentials",
+            ConnectFailure::NotAuthorized => "not_authorized",
+            ConnectFailure::Unavailable => "unavailable",
+            ConnectFailure::Rejected => "rejected",
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default)]
+pub struct MqttStats {
+    /// Successful connections to the broker
+    pub connections: u32,
+    pub connect_failures: u32,
+    /// Connections refused due to the credentials, included in `connect_failures`
+    pub auth_failures: u32,
+    pub last_failure: Option<ConnectFailure>,
+    /// Readings which failed to be published and were queued for the backfill
+    pub requeued: u32,
+}
+
+impl MqttStats {
+    pub fn reconnects(&self) -> u32 {
+        self.connections.saturating_sub(1)
+    }
+
+    /// Records a failed connection, returns the delay in seconds before connecting again
+    pub fn record_failure(&mut self, failure: ConnectFailure) -> u64 {
+        self.connect_failures += 1;
+        self.last_failure = Some(failure);
+        // kept shorter than the watchdog deadline of the publishing task
+        if failure.is_auth() {
+            self.auth_failures += 1;
+            30
+        } else {
+            2
+        }
+    }
+}
+
+/// Pattern filling the unused stack
+const STACK_PAINT: u32 = 0x5afe_57ac;
+
+/// Fills the words from `bottom` up to `top` with a pattern, to later measure
+/// the high-water mark of the stack with [`stack_free`].
+///
+/// # Safety
+/// The range has to be unused memory of the stack, below its current pointer.
+pub unsafe fn paint_stack(bottom: *mut u32, top: *mut u32) {
+    let mut p = bottom;
+    while p < top {
+        p.write_volatile(STACK_PAINT);
+        p = p.add(1);
+    }
+}
+
+/// Returns the number of bytes from `bottom` up to `top` still holding the pattern
+/// of [`paint_stack`], i.e. the part of the stack that has never been used.
+///
+/// # Safety
+/// The range has to be valid for reads, e.g. the whole stack.
+pub unsafe fn stack_free(bottom: *const u32, top: *const u32) -> usize {
+    let mut p = bottom;
+    let mut free = 0;
+    while p < top && p.read_volatile() == STACK_PAINT {
+        free += core::mem::size_of::<u32>();
+        p = p.add(1);
+    }
+    free
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn classifies_reason_codes() {
+        assert_eq!(
+            ConnectFailure::from_reason_code(0xff),
+            ConnectFailure::Network
+        );
+        assert_eq!(
+            ConnectFailure::from_reason_code(0x86),
+            ConnectFailure::BadCredentials
+        );
+        assert_eq!(
+            ConnectFailure::from_reason_code(0x8a),
+            ConnectFailure::Unavailable
+        );
+        assert_eq!(
+            ConnectFailure::from_reason_code(0x80),
+            ConnectFailure::Rejected
+        );
+    }
+
+    #[test]
+    fn backs_off_after_auth_failures() {
+        let mut stats = MqttStats::default();
+        assert_eq!(stats.record_failure(ConnectFailure::Network), 2);
+        assert_eq!(stats.record_failure(ConnectFailure::NotAuthorized), 30);
+        assert_eq!(stats.connect_failures, 2);
+        assert_eq!(stats.auth_failures, 1);
+        assert_eq!(stats.last_failure, Some(ConnectFailure::NotAuthorized));
+    }
+
+    #[test]
+    fn measures_unused_stack() {
+        let mut stack = [0u32; 16];
+        let range = stack.as_mut_ptr_range();
+        unsafe {
+            paint_stack(range.start, range.start.add(12));
+            // the deepest use of the stack so far
+            range.start.add(10).write(0);
+            assert_eq!(stack_free(range.start, range.end), 40);
+        }
+    }
+}
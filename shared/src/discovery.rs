@@ -0,0 +1,160 @@
+//! Home Assistant MQTT discovery of the published values.
+
+use core::fmt::{self, Write};
+
+use crate::json::Escaped;
+
+/// Topic prefix Home Assistant listens to for discovery messages
+pub const DISCOVERY_PREFIX: &str = "homeassistant";
+
+/// Device the entities are grouped under in Home Assistant
+pub struct Device<'a> {
+    /// Unique id of the device, used as a prefix of the entity ids
+    pub id: &'a str,
+    pub name: &'a str,
+    pub model: &'a str,
+    pub sw_version: Option<&'a str>,
+}
+
+/// A sensor reading a single value from a JSON state topic
+pub struct Sensor<'a> {
+    /// Key of the value in the JSON state, unique within the device
+    pub key: &'a str,
+    pub name: &'a str,
+    pub unit: Option<&'a str>,
+    pub device_class: Option<&'a str>,
+    /// Diagnostic entities are shown separately from the measurements
+    pub diagnostic: bool,
+}
+
+/// A sensor of a diagnostic value, shown separately from the measurements
+pub const fn diagnostic<'a>(
+    key: &'a str,
+    name: &'a str,
+    unit: Option<&'a str>,
+    device_class: Option<&'a str>,
+) -> Sensor<'a> {
+    Sensor {
+        key,
+        name,
+        unit,
+        device_class,
+        diagnostic: true,
+    }
+}
+
+impl Sensor<'_> {
+    /// Writes the topic the discovery config of the sensor is published to
+    pub fn write_topic(&self, device: &Device, w: &mut impl Write) -> fmt::Result {
+        write!(
+            w,
+            "{}/sensor/{}/{}/config",
+            DISCOVERY_PREFIX, device.id, self.key
+        )
+    }
+
+    /// Writes the discovery config of the sensor
+    pub fn write_config(
+        &self,
+        device: &Device,
+        state_topic: &str,
+        w: &mut impl Write,
+    ) -> fmt::Result {
+        write!(
+            w,
+            r#"{{"name": {}, "unique_id": "{}_{}", "state_topic": {}, "value_template": "{{{{ value_json.{} }}}}""#,
+            Escaped(self.name),
+            device.id,
+            self.key,
+            Escaped(state_topic),
+            self.key
+        )?;
+        if let Some(unit) = self.unit {
+            write!(w, r#", "unit_of_measurement": {}"#, Escaped(unit))?;
+        }
+        if let Some(device_class) = self.device_class {
+            write!(w, r#", "device_class": "{}""#, device_class)?;
+        }
+        if self.diagnostic {
+            w.write_str(r#", "entity_category": "diagnostic""#)?;
+        }
+        write!(
+            w,
+            r#", "device": {{"identifiers": ["{}"], "name": {}, "model": {}"#,
+            device.id,
+            Escaped(device.name),
+            Escaped(device.model)
+        )?;
+        if let Some(sw_version) = device.sw_version {
+            write!(w, r#", "sw_version": {}"#, Escaped(sw_version))?;
+        }
+        w.write_str("}}")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const DEVICE: Device = Device {
+        id: "afo_bridge",
+        name: "AFO Bridge",
+        model: "bridge-fw",
+        sw_version: Some("0.1.0"),
+    };
+
+    #[test]
+    fn sensor_config() {
+        let sensor = Sensor {
+            key: "uptime",
+            name: "Uptime",
+            unit: Some("s"),
+            device_class: Some("duration"),
+            diagnostic: true,
+        };
+
+        let mut topic = String::new();
+        sensor.write_topic(&DEVICE, &mut topic).unwrap();
+        assert_eq!(topic, "homeassistant/sensor/afo_bridge/uptime/config");
+
+        let mut config = String::new();
+        sensor
+            .write_config(&DEVICE, "afo-bridge-diagnostics", &mut config)
+            .unwrap();
+        assert_eq!(
+            config,
+            concat!(
+                r#"{"name": "Uptime", "unique_id": "afo_bridge_uptime", "state_topic": "afo-bridge-diagnostics", "#,
+                r#""value_template": "{{ value_json.uptime }}", "unit_of_measurement": "s", "device_class": "duration", "#,
+                r#""entity_category": "diagnostic", "#,
+                r#""device": {"identifiers": ["afo_bridge"], "name": "AFO Bridge", "model": "bridge-fw", "sw_version": "0.1.0"}}"#
+            )
+        );
+    }
+
+    #[test]
+    fn minimal_sensor_config() {
+        let sensor = Sensor {
+            key: "reset_reason",
+            name: "Reset reason",
+            unit: None,
+            device_class: None,
+            diagnostic: false,
+        };
+        let device = Device {
+            sw_version: None,
+            ..DEVICE
+        };
+
+        let mut config = String::new();
+        sensor.write_config(&device, "afo-1", &mut config).unwrap();
+        assert_eq!(
+            config,
+            concat!(
+                r#"{"name": "Reset reason", "unique_id": "afo_bridge_reset_reason", "state_topic": "afo-1", "#,
+                r#""value_template": "{{ value_json.reset_reason }}", "#,
+                r#""device": {"identifiers": ["afo_bridge"], "name": "AFO Bridge", "model": "bridge-fw"}}"#
+            )
+        );
+    }
+}
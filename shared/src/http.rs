@@ -0,0 +1,234 @@
+//! Minimal HTTP/1.1 server side: parsing of the request line and routing of the bridge resources.
+//!
+//! The responses are delimited by closing the connection, so that the bodies can be written
+//! as they are generated, without computing their length first.
+
+use core::fmt::{self, Write};
+
+use crate::SensorId;
+
+#[cfg(feature = "embassy-net")]
+mod server;
+#[cfg(feature = "embassy-net")]
+pub use server::{serve, Body, Handler};
+
+pub const HTTP_PORT: u16 = 80;
+
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum HttpError {
+    /// The end of the request head hasn't been received yet
+    Incomplete,
+    Malformed,
+}
+
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Method {
+    Get,
+    /// Like `GET`, without the body
+    Head,
+    Other,
+}
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct Request<'a> {
+    pub method: Method,
+    /// Path without the query string
+    pub path: &'a str,
+}
+
+impl<'a> Request<'a> {
+    /// Parses the request line, once the whole request head has been received.
+    /// The headers are ignored.
+    pub fn parse(buffer: &'a [u8]) -> Result<Self, HttpError> {
+        if !buffer.windows(4).any(|w| w == b"\r\n\r\n") {
+            return Err(HttpError::Incomplete);
+        }
+        let line = buffer
+            .split(|b| *b == b'\r')
+            .next()
+            .and_then(|line| core::str::from_utf8(line).ok())
+            .ok_or(HttpError::Malformed)?;
+        let mut parts = line.split(' ');
+        let (Some(method), Some(target), Some(version), None) =
+            (parts.next(), parts.next(), parts.next(), parts.next())
+        else {
+            return Err(HttpError::Malformed);
+        };
+        if !version.starts_with("HTTP/1.") || !target.starts_with('/') {
+            return Err(HttpError::Malformed);
+        }
+        let method = match method {
+            "GET" => Method::Get,
+            "HEAD" => Method::Head,
+            _ => Method::Other,
+        };
+        let path = target.split('?').next().unwrap_or(target);
+        Ok(Self { method, path })
+    }
+}
+
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Status {
+    Ok,
+    BadRequest,
+    NotFound,
+    MethodNotAllowed,
+}
+
+impl Status {
+    pub fn code(&self) -> u16 {
+        match self {
+            Status::Ok => 200,
+            Status::BadRequest => 400,
+            Status::NotFound => 404,
+            Status::MethodNotAllowed => 405,
+        }
+    }
+
+    pub fn reason(&self) -> &'static str {
+        match self {
+            Status::Ok => "OK",
+            Status::BadRequest => "Bad Request",
+            Status::NotFound => "Not Found",
+            Status::MethodNotAllowed => "Method Not Allowed",
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Content {
+    Json,
+    /// HTML page compressed with gzip
+    GzipHtml,
+    /// Prometheus text exposition format
+    Prometheus,
+}
+
+impl Content {
+    fn headers(&self) -> &'static str {
+        match self {
+            Content::Json => "Content-Type: application/json\r\n",
+            Content::GzipHtml => {
+                "Content-Type: text/html; charset=utf-8\r\nContent-Encoding: gzip\r\n"
+            }
+            Content::Prometheus => "Content-Type: text/plain; version=0.0.4; charset=utf-8\r\n",
+        }
+    }
+}
+
+/// Writes the head of a response, the connection is closed after the body
+pub fn write_head(status: Status, content: Content, w: &mut impl Write) -> fmt::Result {
+    write!(
+        w,
+        "HTTP/1.1 {} {}\r\n{}Connection: close\r\n\r\n",
+        status.code(),
+        status.reason(),
+        content.headers()
+    )
+}
+
+/// Resources served by the bridge
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Route {
+    /// `/`, the dashboard page
+    Dashboard,
+    /// `/api/nodes`, all the nodes heard by the bridge
+    Nodes,
+    /// `/api/nodes/{id}`
+    Node(SensorId),
+    /// `/api/health`, state of the bridge
+    Health,
+    /// `/api/history`, recent measurements of all the nodes
+    History,
+    /// `/metrics`, for Prometheus
+    Metrics,
+}
+
+impl Route {
+    pub fn parse(path: &str) -> Option<Self> {
+        if path == "/" || path == "/index.html" {
+            return Some(Route::Dashboard);
+        }
+        if path == "/metrics" {
+            return Some(Route::Metrics);
+        }
+        let path = path.trim_end_matches('/');
+        match path.strip_prefix("/api/")? {
+            "nodes" => Some(Route::Nodes),
+            "health" => Some(Route::Health),
+            "history" => Some(Route::History),
+            resource => resource
+                .strip_prefix("nodes/")
+                .and_then(|id| id.parse().ok())
+                .map(Route::Node),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_requests() {
+        let request = b"GET /api/nodes?pretty HTTP/1.1\r\nHost: 10.42.0.61\r\n\r\n";
+        assert_eq!(
+            Request::parse(request),
+            Ok(Request {
+                method: Method::Get,
+                path: "/api/nodes",
+            })
+        );
+        assert_eq!(
+            Request::parse(b"HEAD / HTTP/1.0\r\n\r\n").unwrap().method,
+            Method::Head
+        );
+        assert_eq!(
+            Request::parse(b"POST /api/nodes HTTP/1.1\r\n\r\n")
+                .unwrap()
+                .method,
+            Method::Other
+        );
+        assert_eq!(
+            Request::parse(b"GET /api/nodes HTTP/1.1\r\nHost:"),
+            Err(HttpError::Incomplete)
+        );
+        assert_eq!(
+            Request::parse(b"GET /api/nodes\r\n\r\n"),
+            Err(HttpError::Malformed)
+        );
+        assert_eq!(
+            Request::parse(b"GET api HTTP/1.1\r\n\r\n"),
+            Err(HttpError::Malformed)
+        );
+    }
+
+    #[test]
+    fn routes_api() {
+        assert_eq!(Route::parse("/api/nodes"), Some(Route::Nodes));
+        assert_eq!(Route::parse("/api/nodes/"), Some(Route::Nodes));
+        assert_eq!(Route::parse("/api/nodes/42"), Some(Route::Node(42)));
+        assert_eq!(Route::parse("/api/health"), Some(Route::Health));
+        assert_eq!(Route::parse("/api/nodes/c3"), None);
+        assert_eq!(Route::parse("/api/nodes/42/link"), None);
+        assert_eq!(Route::parse("/api/history"), Some(Route::History));
+        assert_eq!(Route::parse("/"), Some(Route::Dashboard));
+        assert_eq!(Route::parse("/index.html"), Some(Route::Dashboard));
+        assert_eq!(Route::parse("/metrics"), Some(Route::Metrics));
+        assert_eq!(Route::parse("/api"), None);
+
+        let mut head = String::new();
+        write_head(Status::NotFound, Content::Json, &mut head).unwrap();
+        assert_eq!(
+            head,
+            "HTTP/1.1 404 Not Found\r\nContent-Type: application/json\r\nConnection: close\r\n\r\n"
+        );
+
+        head.clear();
+        write_head(Status::Ok, Content::GzipHtml, &mut head).unwrap();
+        assert!(head.contains("\r\nContent-Encoding: gzip\r\n"));
+    }
+}
@@ -0,0 +1,154 @@
+//! Server accepting one connection at a time on an `embassy-net` stack, the resources
+//! are resolved and written by the [`Handler`] of the firmware.
+
+use core::fmt::{self, Write};
+
+use embassy_net::driver::Driver;
+use embassy_net::tcp::{self, TcpSocket};
+use embassy_net::Stack;
+use embassy_time::Duration;
+use embedded_io_async::Write as _;
+
+use super::{write_head, Content, HttpError, Method, Request, Status, HTTP_PORT};
+
+/// Connections of clients which don't send the request or don't read the response are closed
+/// after the timeout, so that they don't block the other clients
+const HTTP_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Part of a response written to the socket at once,
+/// fits the JSON of a single node or of its history
+type Chunk = heapless::String<1024>;
+
+/// The chunk is written to the socket by [`Body::flush`] once it's filled over the limit,
+/// so that it always fits another line of the metrics
+const FLUSH_LEN: usize = 768;
+
+/// Resolves the resources of the requests and writes their bodies
+// the handlers run on the single-threaded executors, the futures don't have to be `Send`
+#[allow(async_fn_in_trait)]
+pub trait Handler {
+    /// Resource resolved from the state, before the response is written
+    type Resource;
+
+    /// Resolves the resource of a `GET` or `HEAD` request, or the status of the error response
+    fn route(&self, path: &str) -> Result<(Self::Resource, Content), Status>;
+
+    /// Writes the body of the response, not called for `HEAD` requests
+    async fn write_body(
+        &self,
+        resource: Self::Resource,
+        body: &mut Body<'_, '_>,
+    ) -> Result<(), tcp::Error>;
+}
+
+/// Body of a response, formatted into a chunk which is written to the socket
+/// by [`Body::send`] and [`Body::flush`], and once the body is complete
+pub struct Body<'s, 'a> {
+    socket: &'s mut TcpSocket<'a>,
+    chunk: Chunk,
+}
+
+impl Body<'_, '_> {
+    /// Writes the chunk to the socket
+    pub async fn send(&mut self) -> Result<(), tcp::Error> {
+        self.socket.write_all(self.chunk.as_bytes()).await?;
+        self.chunk.clear();
+        Ok(())
+    }
+
+    /// Writes the chunk to the socket once it's filled over the limit
+    pub async fn flush(&mut self) -> Result<(), tcp::Error> {
+        if self.chunk.len() > FLUSH_LEN {
+            self.send().await?;
+        }
+        Ok(())
+    }
+
+    /// Writes the chunk and the bytes to the socket
+    pub async fn write_all(&mut self, bytes: &[u8]) -> Result<(), tcp::Error> {
+        self.send().await?;
+        self.socket.write_all(bytes).await
+    }
+}
+
+impl Write for Body<'_, '_> {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        self.chunk.push_str(s).map_err(|_| fmt::Error)
+    }
+}
+
+/// Serves the requests with the handler, one connection at a time
+pub async fn serve<D: Driver>(stack: &Stack<D>, handler: &impl Handler) -> ! {
+    let mut rx_buffer = [0; 1024];
+    let mut tx_buffer = [0; 2048];
+    let mut request = [0; 1024];
+    loop {
+        let mut socket = TcpSocket::new(stack, &mut rx_buffer, &mut tx_buffer);
+        socket.set_timeout(Some(HTTP_TIMEOUT));
+        if let Err(e) = socket.accept(HTTP_PORT).await {
+            defmt::warn!("Failed to accept a HTTP connection: {:?}", e);
+            continue;
+        }
+        if let Err(e) = respond(&mut socket, handler, &mut request).await {
+            defmt::debug!("HTTP connection failed: {:?}", e);
+        }
+        socket.close();
+        if socket.flush().await.is_err() {
+            socket.abort();
+        }
+    }
+}
+
+/// Reads a request from the socket and writes the response
+async fn respond(
+    socket: &mut TcpSocket<'_>,
+    handler: &impl Handler,
+    buffer: &mut [u8],
+) -> Result<(), tcp::Error> {
+    let mut len = 0;
+    let request = loop {
+        if len == buffer.len() {
+            break Err(HttpError::Malformed);
+        }
+        match socket.read(&mut buffer[len..]).await? {
+            // closed before the whole request was sent
+            0 => return Ok(()),
+            read => len += read,
+        }
+        match Request::parse(&buffer[..len]) {
+            Err(HttpError::Incomplete) => {}
+            result => break result,
+        }
+    };
+
+    let resource = match request {
+        Ok(request) if request.method == Method::Other => Err(Status::MethodNotAllowed),
+        Ok(request) => handler.route(request.path),
+        Err(_) => Err(Status::BadRequest),
+    };
+    let (status, content) = match &resource {
+        Ok((_, content)) => (Status::Ok, *content),
+        Err(status) => (*status, Content::Json),
+    };
+    defmt::debug!("HTTP {:?}", status);
+
+    let mut body = Body {
+        socket,
+        chunk: Chunk::new(),
+    };
+    write_head(status, content, &mut body).unwrap();
+    if matches!(
+        request,
+        Ok(Request {
+            method: Method::Head,
+            ..
+        })
+    ) {
+        return body.send().await;
+    }
+    match resource {
+        Ok((resource, _)) => handler.write_body(resource, &mut body).await?,
+        Err(status) => write!(body, r#"{{"error": "{}"}}"#, status.reason()).unwrap(),
+    }
+    body.send().await
+}
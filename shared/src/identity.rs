@@ -0,0 +1,75 @@
+//! Resolution of the ids of the nRF52 devices, the sensor ids of the nodes and the bridge ids.
+//!
+//! The id is primarily read from the UICR CUSTOMER[0] register, which can be provisioned after
+//! flashing the softdevice, e.g. `nrfjprog --memwr 0x10001080 --val 42`.
+//! Unprovisioned devices fall back to an id derived from the factory programmed FICR device id.
+
+/// Value of an erased (unprovisioned) UICR register
+const ERASED: u32 = 0xffff_ffff;
+
+/// Address of the UICR CUSTOMER[0] register
+#[cfg(feature = "nrf52")]
+const UICR_CUSTOMER0: *const u32 = 0x1000_1080 as *const u32;
+
+/// Address of the FICR DEVICEID[0] register, followed by DEVICEID[1]
+#[cfg(feature = "nrf52")]
+const FICR_DEVICEID: *const u32 = 0x1000_0060 as *const u32;
+
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum IdSource {
+    Provisioned,
+    DeviceId,
+}
+
+impl IdSource {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            IdSource::Provisioned => "uicr",
+            IdSource::DeviceId => "ficr",
+        }
+    }
+}
+
+/// Decodes the content of UICR CUSTOMER[0], only the lower 16 bits are used
+pub fn provisioned_id(raw: u32) -> Option<u16> {
+    if raw == ERASED || raw > u16::MAX as u32 {
+        None
+    } else {
+        Some(raw as u16)
+    }
+}
+
+/// Returns the provisioned id, or one derived from the device id if none was provisioned
+#[cfg(feature = "nrf52")]
+pub fn id() -> (u16, IdSource) {
+    let raw = unsafe { UICR_CUSTOMER0.read_volatile() };
+    match provisioned_id(raw) {
+        Some(id) => (id, IdSource::Provisioned),
+        None => (
+            crate::sensor_id_from_device_id(device_id()),
+            IdSource::DeviceId,
+        ),
+    }
+}
+
+/// Reads the 64-bit factory programmed device id
+#[cfg(feature = "nrf52")]
+fn device_id() -> u64 {
+    let low = unsafe { FICR_DEVICEID.read_volatile() } as u64;
+    let high = unsafe { FICR_DEVICEID.add(1).read_volatile() } as u64;
+    (high << 32) | low
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn provisioned() {
+        assert_eq!(provisioned_id(42), Some(42));
+        assert_eq!(provisioned_id(0xffff), Some(0xffff));
+        assert_eq!(provisioned_id(ERASED), None);
+        assert_eq!(provisioned_id(0x1_0000), None);
+    }
+}
@@ -0,0 +1,38 @@
+//! Helpers for writing JSON payloads with `core::fmt`.
+
+use core::fmt;
+
+/// Formats a string as a JSON string literal, including the quotes
+pub struct Escaped<'a>(pub &'a str);
+
+impl fmt::Display for Escaped<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("\"")?;
+        for c in self.0.chars() {
+            match c {
+                '"' => f.write_str("\\\"")?,
+                '\\' => f.write_str("\\\\")?,
+                '\n' => f.write_str("\\n")?,
+                '\r' => f.write_str("\\r")?,
+                '\t' => f.write_str("\\t")?,
+                c if (c as u32) < 0x20 => write!(f, "\\u{:04x}", c as u32)?,
+                c => write!(f, "{}", c)?,
+            }
+        }
+        f.write_str("\"")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn escaped() {
+        assert_eq!(Escaped("abc").to_string(), r#""abc""#);
+        assert_eq!(
+            Escaped("a \"b\"\\\n\u{1}").to_string(),
+            r#""a \"b\"\\\n\u0001""#
+        );
+    }
+}
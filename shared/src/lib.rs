@@ -1,5 +1,34 @@
 #![cfg_attr(not(test), no_std)]
 
+pub mod config;
+pub mod console;
+pub mod coordination;
+pub mod crash;
+pub mod diagnostics;
+pub mod dhcp;
+pub mod discovery;
+pub mod http;
+pub mod identity;
+pub mod json;
+pub mod mdns;
+pub mod network;
+pub mod policy;
+pub mod prometheus;
+pub mod qos;
+pub mod reset;
+#[cfg(feature = "embassy-net")]
+pub mod resolve;
+pub mod scan;
+pub mod sinks;
+pub mod stats;
+pub mod topic;
+pub mod watchdog;
+pub mod x509;
+
+use core::fmt::{self, Write};
+use crash::CrashSummary;
+use reset::ResetReason;
+
 #[cfg_attr(feature = "defmt", derive(defmt::Format))]
 #[derive(Clone, Copy, Debug, Default)]
 pub struct Co2(pub f32);
@@ -20,28 +49,117 @@ pub struct AirQuality {
     pub humidity: Humidity,
 }
 
+/// Identifier of a node, unique within a deployment
+pub type SensorId = u16;
+
+/// Sequence number of a reading of a node, wrapping around
+pub type Sequence = u8;
+
 #[cfg_attr(feature = "defmt", derive(defmt::Format))]
-#[derive(
-    Debug, serde::Serialize, serde::Deserialize, Eq, PartialEq, Default, Clone, Copy,
-)]
+#[derive(Debug, serde::Serialize, serde::Deserialize, Eq, PartialEq, Default, Clone, Copy)]
 pub struct AirQualityAdvertisement {
-    pub sensor_id: u8,
+    pub sensor_id: SensorId,
     pub co2_concentration: u16,
     pub temperature: i16, // scaled by 0.1
     pub humidity: u8,
+    /// Incremented with every new reading, allows bridges to tell readings apart.
+    /// Appended last, so that the advertisements of the nodes without it still decode.
+    pub sequence: Sequence,
 }
 
-impl From<(u8, AirQuality)> for AirQualityAdvertisement {
-    fn from((id, raw): (u8, AirQuality)) -> Self {
+/// Advertisement of the nodes built before the sequence was added
+#[derive(serde::Deserialize)]
+struct LegacyAdvertisement {
+    sensor_id: SensorId,
+    co2_concentration: u16,
+    temperature: i16,
+    humidity: u8,
+}
+
+impl From<LegacyAdvertisement> for AirQualityAdvertisement {
+    /// The sequence is derived from the values, as the legacy nodes advertise
+    /// every reading repeatedly too. A reading with the values of the previous one is
+    /// taken for the same reading, which changes none of the published values.
+    fn from(legacy: LegacyAdvertisement) -> Self {
+        let [co2_low, co2_high] = legacy.co2_concentration.to_le_bytes();
+        let [temperature_low, temperature_high] = legacy.temperature.to_le_bytes();
+        let sequence = [
+            co2_low,
+            co2_high,
+            temperature_low,
+            temperature_high,
+            legacy.humidity,
+        ]
+        .iter()
+        .fold(0, |hash: Sequence, byte| hash.rotate_left(3) ^ byte);
+        AirQualityAdvertisement {
+            sensor_id: legacy.sensor_id,
+            co2_concentration: legacy.co2_concentration,
+            temperature: legacy.temperature,
+            humidity: legacy.humidity,
+            sequence,
+        }
+    }
+}
+
+impl From<(SensorId, Sequence, AirQuality)> for AirQualityAdvertisement {
+    fn from((id, sequence, raw): (SensorId, Sequence, AirQuality)) -> Self {
         AirQualityAdvertisement {
             co2_concentration: raw.co2.0 as u16,
             temperature: (raw.temperature.0 / 0.1) as i16,
             humidity: raw.humidity.0 as u8,
             sensor_id: id,
+            sequence,
         }
     }
 }
 
+/// Diagnostics of a node, sent in the scan response
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+#[derive(Debug, serde::Serialize, serde::Deserialize, Eq, PartialEq, Clone, Copy)]
+pub struct NodeDiagnostics {
+    pub sensor_id: SensorId,
+    /// Uptime in seconds
+    pub uptime: u32,
+    pub reset_reason: ResetReason,
+    /// Die temperature in °C
+    pub die_temperature: i8,
+    /// Crash that caused the last reset
+    pub crash: Option<CrashSummary>,
+}
+
+impl NodeDiagnostics {
+    /// Writes the diagnostics as a JSON object
+    pub fn write_json(&self, w: &mut impl Write) -> fmt::Result {
+        write!(
+            w,
+            r#"{{"uptime": {}, "reset_reason": "{}", "die_temperature": {}"#,
+            self.uptime,
+            self.reset_reason.as_str(),
+            self.die_temperature
+        )?;
+        match self.crash {
+            Some(crash) => write!(
+                w,
+                r#", "crash": "{}", "crash_location": {}}}"#,
+                crash.kind.as_str(),
+                crash.location
+            ),
+            None => w.write_str(r#", "crash": "none", "crash_location": null}"#),
+        }
+    }
+}
+
+/// Derives a sensor id from the factory programmed 64-bit device id (FICR DEVICEID on nRF52).
+/// Used when no id was provisioned. Values 0 and 1 are skipped, as those are used by nodes
+/// configured with the legacy GPIO jumper: the folded value is shifted by 2 and wrapped around
+/// over them, so no derived id is more likely than the others.
+pub fn sensor_id_from_device_id(device_id: u64) -> SensorId {
+    const RESERVED: u16 = 2;
+    let folded = (device_id ^ (device_id >> 16) ^ (device_id >> 32) ^ (device_id >> 48)) as u16;
+    folded % (u16::MAX - RESERVED + 1) + RESERVED
+}
+
 /// Fills adv data with data type, length and data, return size of the filled data
 pub fn fill_adv_data(adv_data: &mut [u8], data_type: u8, data: &[u8]) -> usize {
     let offset = 1 + data.len();
@@ -51,6 +169,113 @@ pub fn fill_adv_data(adv_data: &mut [u8], data_type: u8, data: &[u8]) -> usize {
     offset + 1
 }
 
+/// AD type of the complete local name
+pub const AD_TYPE_COMPLETE_NAME: u8 = 0x09;
+/// AD type of manufacturer specific data
+pub const AD_TYPE_MANUFACTURER_DATA: u8 = 0xff;
+/// Local name the nodes advertise with
+pub const NODE_NAME: &[u8] = b"AFO";
+
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum AdvDataError {
+    /// An AD structure claims to be longer than the remaining data
+    Truncated,
+    /// An AD structure has zero length
+    Malformed,
+}
+
+/// Iterates over `(data type, data)` AD structures in advertisement data, the inverse of [`fill_adv_data`]
+pub struct AdvDataIter<'a> {
+    data: &'a [u8],
+}
+
+impl<'a> AdvDataIter<'a> {
+    pub fn new(data: &'a [u8]) -> Self {
+        Self { data }
+    }
+}
+
+impl<'a> Iterator for AdvDataIter<'a> {
+    type Item = Result<(u8, &'a [u8]), AdvDataError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.data.is_empty() {
+            return None;
+        }
+        let len = self.data[0] as usize;
+        if self.data.len() < len + 1 {
+            self.data = &[];
+            return Some(Err(AdvDataError::Truncated));
+        }
+        if len < 1 {
+            self.data = &[];
+            return Some(Err(AdvDataError::Malformed));
+        }
+        let key = self.data[1];
+        let value = &self.data[2..len + 1];
+        self.data = &self.data[len + 1..];
+        Some(Ok((key, value)))
+    }
+}
+
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum DecodeError {
+    AdvData(AdvDataError),
+    /// The manufacturer specific data of an AFO node could not be deserialized
+    Payload,
+}
+
+impl From<AdvDataError> for DecodeError {
+    fn from(e: AdvDataError) -> Self {
+        DecodeError::AdvData(e)
+    }
+}
+
+/// Decodes the measurement from advertisement data, including the advertisements
+/// of the nodes without the sequence.
+/// Returns `Ok(None)` for advertisements of devices other than AFO nodes.
+pub fn decode_advertisement(
+    adv_data: &[u8],
+) -> Result<Option<AirQualityAdvertisement>, DecodeError> {
+    let Some(payload) = manufacturer_payload(adv_data)? else {
+        return Ok(None);
+    };
+    postcard::from_bytes::<AirQualityAdvertisement>(payload)
+        .or_else(|_| postcard::from_bytes::<LegacyAdvertisement>(payload).map(Into::into))
+        .map(Some)
+        .map_err(|_| DecodeError::Payload)
+}
+
+/// Decodes the diagnostics from scan response data.
+/// Returns `Ok(None)` for scan responses of devices other than AFO nodes.
+pub fn decode_scan_response(scan_data: &[u8]) -> Result<Option<NodeDiagnostics>, DecodeError> {
+    let Some(payload) = manufacturer_payload(scan_data)? else {
+        return Ok(None);
+    };
+    postcard::from_bytes(payload)
+        .map(Some)
+        .map_err(|_| DecodeError::Payload)
+}
+
+/// Returns the manufacturer specific data of an AFO node without the company id
+fn manufacturer_payload(adv_data: &[u8]) -> Result<Option<&[u8]>, DecodeError> {
+    let mut afo = false;
+    for structure in AdvDataIter::new(adv_data) {
+        let (key, value) = structure?;
+        // device has sent a name
+        if key == AD_TYPE_COMPLETE_NAME && value == NODE_NAME {
+            afo = true;
+        }
+        if afo && key == AD_TYPE_MANUFACTURER_DATA {
+            // skip the company id
+            return value.get(2..).map(Some).ok_or(DecodeError::Payload);
+        }
+    }
+    Ok(None)
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -63,6 +288,7 @@ mod tests {
             temperature: 225,
             humidity: 40,
             sensor_id: 0,
+            sequence: 0,
         };
         let mut buffer = [0u8; 100];
         let output = to_slice(&data, &mut buffer).unwrap();
@@ -70,6 +296,42 @@ mod tests {
         println!("size: {}", output.len());
     }
 
+    #[test]
+    fn adv_fits_wide_sensor_id() {
+        let data = AirQualityAdvertisement {
+            co2_concentration: u16::MAX,
+            temperature: i16::MIN,
+            humidity: 100,
+            sensor_id: SensorId::MAX,
+            sequence: Sequence::MAX,
+        };
+        let mut buffer = [0u8; 100];
+        let output = to_slice(&data, &mut buffer).unwrap();
+
+        // 31 bytes of adv data - flags (3) - name (5) - manufacturer data header (4)
+        assert!(output.len() <= 19);
+        assert_eq!(
+            postcard::from_bytes::<AirQualityAdvertisement>(output).unwrap(),
+            data
+        );
+    }
+
+    #[test]
+    fn sensor_id_from_device_id_test() {
+        assert_eq!(sensor_id_from_device_id(0x1234_0000_0000_0000), 0x1236);
+        // the reserved ids are remapped, not clamped onto the first free id
+        assert_eq!(sensor_id_from_device_id(0), 2);
+        assert_eq!(sensor_id_from_device_id(0x0001_0000_0000_0001), 2);
+        assert_eq!(sensor_id_from_device_id(1), 3);
+        assert_eq!(sensor_id_from_device_id(2), 4);
+        assert_eq!(sensor_id_from_device_id(0xfffd), u16::MAX);
+        assert_eq!(sensor_id_from_device_id(0xfffe), 2);
+        assert_ne!(
+            sensor_id_from_device_id(0xdead_beef_0000_0001),
+            sensor_id_from_device_id(0xdead_beef_0000_0002)
+        );
+    }
+
     #[test]
     fn fill_adv_data_test() {
         let mut data = [0u8; 31];
@@ -88,4 +350,136 @@ mod tests {
 
         assert_eq!(&data[..offset], &correct_data[..])
     }
+
+    fn node_adv_data(adv: &AirQualityAdvertisement) -> ([u8; 31], usize) {
+        let mut data = [0u8; 31];
+        let mut payload = [0xffu8; 29];
+        let payload_len = 2 + to_slice(adv, &mut payload[2..]).unwrap().len();
+
+        let mut offset = fill_adv_data(&mut data, 0x01, &[0x06]);
+        offset += fill_adv_data(&mut data[offset..], AD_TYPE_COMPLETE_NAME, NODE_NAME);
+        offset += fill_adv_data(
+            &mut data[offset..],
+            AD_TYPE_MANUFACTURER_DATA,
+            &payload[..payload_len],
+        );
+        (data, offset)
+    }
+
+    #[test]
+    fn decode_advertisement_test() {
+        let adv = AirQualityAdvertisement {
+            co2_concentration: 812,
+            temperature: -15,
+            humidity: 55,
+            sensor_id: 1234,
+            sequence: 7,
+        };
+        let (data, len) = node_adv_data(&adv);
+
+        assert_eq!(decode_advertisement(&data[..len]), Ok(Some(adv)));
+    }
+
+    #[test]
+    fn decode_legacy_advertisement() {
+        // sensor id 1234, CO2 812 ppm, -1.5 °C, 55 % from a node without the sequence
+        let payload = [0xff, 0xff, 0xd2, 0x04, 0x2c, 0x03, 0xf1, 0xff, 55];
+        let mut data = [0u8; 31];
+        let mut offset = fill_adv_data(&mut data, AD_TYPE_COMPLETE_NAME, NODE_NAME);
+        offset += fill_adv_data(&mut data[offset..], AD_TYPE_MANUFACTURER_DATA, &payload);
+
+        let adv = decode_advertisement(&data[..offset]).unwrap().unwrap();
+        assert_eq!(adv.sensor_id, 1234);
+        assert_eq!(adv.co2_concentration, 812);
+        assert_eq!(adv.temperature, -15);
+        assert_eq!(adv.humidity, 55);
+
+        // the same reading keeps its sequence, a new one changes it
+        assert_eq!(decode_advertisement(&data[..offset]).unwrap().unwrap(), adv);
+        data[offset - 1] = 56;
+        let next = decode_advertisement(&data[..offset]).unwrap().unwrap();
+        assert_ne!(next.sequence, adv.sequence);
+    }
+
+    #[test]
+    fn decode_advertisement_ignores_other_devices() {
+        let mut data = [0u8; 31];
+        let mut offset = fill_adv_data(&mut data, 0x01, &[0x06]);
+        offset += fill_adv_data(
+            &mut data[offset..],
+            AD_TYPE_MANUFACTURER_DATA,
+            &[0x4c, 0x00],
+        );
+
+        assert_eq!(decode_advertisement(&data[..offset]), Ok(None));
+    }
+
+    #[test]
+    fn decode_scan_response_test() {
+        let diagnostics = NodeDiagnostics {
+            sensor_id: SensorId::MAX,
+            uptime: u32::MAX,
+            reset_reason: ResetReason::Watchdog,
+            die_temperature: -40,
+            crash: Some(CrashSummary {
+                kind: crash::CrashKind::HardFault,
+                location: u32::MAX,
+            }),
+        };
+        let mut payload = [0xffu8; 29];
+        let payload_len = 2 + to_slice(&diagnostics, &mut payload[2..]).unwrap().len();
+
+        let mut data = [0u8; 31];
+        let mut offset = fill_adv_data(&mut data, AD_TYPE_COMPLETE_NAME, NODE_NAME);
+        offset += fill_adv_data(
+            &mut data[offset..],
+            AD_TYPE_MANUFACTURER_DATA,
+            &payload[..payload_len],
+        );
+
+        assert_eq!(decode_scan_response(&data[..offset]), Ok(Some(diagnostics)));
+    }
+
+    #[test]
+    fn node_diagnostics_json() {
+        let mut diagnostics = NodeDiagnostics {
+            sensor_id: 3,
+            uptime: 3600,
+            reset_reason: ResetReason::PowerOn,
+            die_temperature: 24,
+            crash: None,
+        };
+        let mut json = String::new();
+        diagnostics.write_json(&mut json).unwrap();
+        assert_eq!(
+            json,
+            r#"{"uptime": 3600, "reset_reason": "power_on", "die_temperature": 24, "crash": "none", "crash_location": null}"#
+        );
+
+        diagnostics.crash = Some(CrashSummary {
+            kind: crash::CrashKind::Panic,
+            location: 120,
+        });
+        json.clear();
+        diagnostics.write_json(&mut json).unwrap();
+        assert!(json.ends_with(r#""crash": "panic", "crash_location": 120}"#));
+    }
+
+    #[test]
+    fn decode_advertisement_errors() {
+        let (data, len) = node_adv_data(&AirQualityAdvertisement::default());
+
+        assert_eq!(
+            decode_advertisement(&data[..len - 1]),
+            Err(DecodeError::AdvData(AdvDataError::Truncated))
+        );
+        assert_eq!(
+            decode_advertisement(&[0x00, 0x01]),
+            Err(DecodeError::AdvData(AdvDataError::Malformed))
+        );
+        assert_eq!(
+            decode_advertisement(&[0x04, 0x09, b'A', b'F', b'O', 0x02, 0xff, 0xff]),
+            Err(DecodeError::Payload)
+        );
+    }
 }
@@ -0,0 +1,590 @@
+//! Multicast DNS queries of the address of a `.local` host, and a responder advertising
+//! the device and its DNS-SD services.
+//!
+//! The queries are sent from a port other than 5353, so the responders answer directly
+//! to the querier, without joining the multicast group (RFC 6762, section 6.7).
+//! The responder answers such queries directly too, other queries are answered to the group.
+//! All the records are uncompressed and sent as answers, known answers are not suppressed.
+
+use crate::network::{is_valid_label, Ipv4};
+
+#[cfg(feature = "embassy-net")]
+mod transport;
+#[cfg(feature = "embassy-net")]
+pub use transport::{respond, Status};
+
+pub const MDNS_ADDRESS: Ipv4 = [224, 0, 0, 251];
+pub const MDNS_PORT: u16 = 5353;
+
+const HEADER_LEN: usize = 12;
+const TYPE_A: u16 = 1;
+const TYPE_PTR: u16 = 12;
+const TYPE_TXT: u16 = 16;
+const TYPE_SRV: u16 = 33;
+const TYPE_ANY: u16 = 255;
+const CLASS_IN: u16 = 1;
+const CLASS_ANY: u16 = 255;
+/// Requests a unicast response in the class of a question, flushes the cache in a record
+const CLASS_UNICAST: u16 = 0x8000;
+/// Compressed names are followed at most this many times, to stop on pointer loops
+const MAX_POINTERS: usize = 8;
+
+const LOCAL: &str = "local";
+/// Service enumerating the advertised service types
+const SERVICE_TYPES: &str = "_services._dns-sd._udp";
+/// TTLs of the records with the host name, of the other records (RFC 6762, section 10)
+/// and of the records in the responses to legacy unicast queries
+const HOST_TTL: u32 = 120;
+const OTHER_TTL: u32 = 4500;
+const LEGACY_TTL: u32 = 10;
+
+/// Maximum number of advertised services, the others are ignored
+pub const MAX_SERVICES: usize = 4;
+
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum MdnsError {
+    InvalidName,
+    BufferTooSmall,
+    Malformed,
+}
+
+/// Host name set by the `AFO_HOSTNAME` environment variable at build time, without `.local`
+pub fn hostname_from_env() -> Result<Option<&'static str>, MdnsError> {
+    match option_env!("AFO_HOSTNAME") {
+        Some(hostname) if is_valid_label(hostname) => Ok(Some(hostname)),
+        Some(_) => Err(MdnsError::InvalidName),
+        None => Ok(None),
+    }
+}
+
+/// Writes a query of the IPv4 address of `name`, returns its length
+pub fn write_query(name: &str, buffer: &mut [u8]) -> Result<usize, MdnsError> {
+    let mut writer = Writer { buffer, len: 0 };
+    // id, flags, one question, no records
+    writer.write(&[0, 0, 0, 0, 0, 1, 0, 0, 0, 0, 0, 0])?;
+    writer.write_name(&[name])?;
+    writer.write(&TYPE_A.to_be_bytes())?;
+    writer.write(&(CLASS_IN | CLASS_UNICAST).to_be_bytes())?;
+    Ok(writer.len)
+}
+
+/// Finds the IPv4 address of `name` in the records of a response
+pub fn find_address(packet: &[u8], name: &str) -> Option<Ipv4> {
+    let count = |offset: usize| -> Option<usize> {
+        Some(u16::from_be_bytes([*packet.get(offset)?, *packet.get(offset + 1)?]) as usize)
+    };
+    let questions = count(4)?;
+    let records = count(6)? + count(8)? + count(10)?;
+
+    let mut offset = HEADER_LEN;
+    for _ in 0..questions {
+        offset = skip_name(packet, offset)? + 4;
+    }
+    for _ in 0..records {
+        let matches = name_matches(packet, offset, &[name])?;
+        offset = skip_name(packet, offset)?;
+        let record_type = count(offset)? as u16;
+        let class = count(offset + 2)? as u16 & !CLASS_UNICAST;
+        let len = count(offset + 8)?;
+        let data = packet.get(offset + 10..offset + 10 + len)?;
+        if matches && record_type == TYPE_A && class == CLASS_IN {
+            if let &[a, b, c, d] = data {
+                return Some([a, b, c, d]);
+            }
+        }
+        offset += 10 + len;
+    }
+    None
+}
+
+/// Returns the offset following the name at `offset`
+fn skip_name(packet: &[u8], mut offset: usize) -> Option<usize> {
+    loop {
+        let len = *packet.get(offset)? as usize;
+        if len & 0xc0 == 0xc0 {
+            return Some(offset + 2);
+        }
+        offset += 1 + len;
+        if len == 0 {
+            return Some(offset);
+        }
+    }
+}
+
+/// Compares the possibly compressed name at `offset` with the name made of `parts`,
+/// ignoring the case
+fn name_matches(packet: &[u8], mut offset: usize, parts: &[&str]) -> Option<bool> {
+    let mut labels = parts
+        .iter()
+        .flat_map(|part| part.trim_end_matches('.').split('.'));
+    let mut pointers = 0;
+    loop {
+        let len = *packet.get(offset)? as usize;
+        if len & 0xc0 == 0xc0 {
+            pointers += 1;
+            if pointers > MAX_POINTERS {
+                return None;
+            }
+            offset = ((len & 0x3f) << 8) | *packet.get(offset + 1)? as usize;
+            continue;
+        }
+        if len == 0 {
+            return Some(labels.next().is_none());
+        }
+        let label = packet.get(offset + 1..offset + 1 + len)?;
+        match labels.next() {
+            Some(expected) if expected.as_bytes().eq_ignore_ascii_case(label) => {}
+            _ => return Some(false),
+        }
+        offset += 1 + len;
+    }
+}
+
+/// DNS-SD service of the device, e.g. the `_http._tcp` service of `afo-bridge-3`
+#[derive(Clone, Copy, Debug)]
+pub struct Service<'a> {
+    /// Name of the instance, a single label
+    pub instance: &'a str,
+    /// Service type and protocol, e.g. `_http._tcp`
+    pub service_type: &'a str,
+    pub port: u16,
+    /// Entries of the TXT record, `key=value`
+    pub txt: &'a [&'a str],
+}
+
+/// Record advertised by the responder
+#[derive(Clone, Copy, Debug)]
+enum Record {
+    Address,
+    /// Service type in the enumeration of the service types
+    ServiceType(usize),
+    /// Pointer from the service type to the instance
+    Instance(usize),
+    Server(usize),
+    Text(usize),
+}
+
+impl Record {
+    fn bit(&self) -> u32 {
+        match self {
+            Record::Address => 1,
+            Record::ServiceType(i) => 1 << (1 + 4 * i),
+            Record::Instance(i) => 1 << (2 + 4 * i),
+            Record::Server(i) => 1 << (3 + 4 * i),
+            Record::Text(i) => 1 << (4 + 4 * i),
+        }
+    }
+}
+
+/// Answers the queries of the address of `<hostname>.local` and of the services
+pub struct Responder<'a> {
+    /// Host name without `.local`
+    pub hostname: &'a str,
+    pub address: Ipv4,
+    pub services: &'a [Service<'a>],
+}
+
+impl Responder<'_> {
+    /// Writes the response to a query, returns its length, zero if there is nothing to answer.
+    /// Legacy queries, sent from a port other than 5353, are answered directly to the querier.
+    pub fn write_response(
+        &self,
+        query: &[u8],
+        legacy: bool,
+        buffer: &mut [u8],
+    ) -> Result<usize, MdnsError> {
+        let field = |offset: usize| -> Result<u16, MdnsError> {
+            match query.get(offset..offset + 2) {
+                Some(&[a, b]) => Ok(u16::from_be_bytes([a, b])),
+                _ => Err(MdnsError::Malformed),
+            }
+        };
+        // responses of other responders
+        if field(2)? & 0x8000 != 0 {
+            return Ok(0);
+        }
+        let question_count = field(4)?;
+
+        let mut records = 0;
+        let mut offset = HEADER_LEN;
+        for _ in 0..question_count {
+            let name = offset;
+            offset = skip_name(query, offset).ok_or(MdnsError::Malformed)?;
+            let record_type = field(offset)?;
+            let class = field(offset + 2)? & !CLASS_UNICAST;
+            offset += 4;
+            if class == CLASS_IN || class == CLASS_ANY {
+                records |= self.answers(query, name, record_type)?;
+            }
+        }
+        if records == 0 {
+            return Ok(0);
+        }
+
+        let mut writer = Writer { buffer, len: 0 };
+        // legacy responses repeat the id and the questions, the questions are kept at the same
+        // offset, so that their compressed names stay valid
+        let (id, question_count, questions) = match legacy {
+            true => (
+                [query[0], query[1]],
+                question_count,
+                &query[HEADER_LEN..offset],
+            ),
+            false => ([0, 0], 0, &[][..]),
+        };
+        self.write_records(&mut writer, id, question_count, questions, records, legacy)?;
+        Ok(writer.len)
+    }
+
+    /// Writes an unsolicited response with all the records, announcing the device
+    pub fn write_announcement(&self, buffer: &mut [u8]) -> Result<usize, MdnsError> {
+        let mut records = Record::Address.bit();
+        for i in 0..self.services.len().min(MAX_SERVICES) {
+            records |= Record::ServiceType(i).bit()
+                | Record::Instance(i).bit()
+                | Record::Server(i).bit()
+                | Record::Text(i).bit();
+        }
+        let mut writer = Writer { buffer, len: 0 };
+        self.write_records(&mut writer, [0, 0], 0, &[], records, false)?;
+        Ok(writer.len)
+    }
+
+    /// Records answering a question of the name at `name`
+    fn answers(&self, query: &[u8], name: usize, record_type: u16) -> Result<u32, MdnsError> {
+        let is = |parts: &[&str]| name_matches(query, name, parts).ok_or(MdnsError::Malformed);
+        let of_type = |expected: u16| record_type == expected || record_type == TYPE_ANY;
+
+        let mut records = 0;
+        if of_type(TYPE_A) && is(&[self.hostname, LOCAL])? {
+            records |= Record::Address.bit();
+        }
+        for (i, service) in self.services.iter().take(MAX_SERVICES).enumerate() {
+            if of_type(TYPE_PTR) && is(&[SERVICE_TYPES, LOCAL])? {
+                records |= Record::ServiceType(i).bit();
+            }
+            // the records needed to connect to the instance are sent along with the pointer
+            if of_type(TYPE_PTR) && is(&[service.service_type, LOCAL])? {
+                records |= Record::Instance(i).bit()
+                    | Record::Server(i).bit()
+                    | Record::Text(i).bit()
+                    | Record::Address.bit();
+            }
+            if is(&[service.instance, service.service_type, LOCAL])? {
+                if of_type(TYPE_SRV) {
+                    records |= Record::Server(i).bit() | Record::Address.bit();
+                }
+                if of_type(TYPE_TXT) {
+                    records |= Record::Text(i).bit();
+                }
+            }
+        }
+        Ok(records)
+    }
+
+    fn write_records(
+        &self,
+        writer: &mut Writer,
+        id: [u8; 2],
+        question_count: u16,
+        questions: &[u8],
+        records: u32,
+        legacy: bool,
+    ) -> Result<(), MdnsError> {
+        writer.write(&id)?;
+        // response, authoritative
+        writer.write(&[0x84, 0])?;
+        writer.write(&question_count.to_be_bytes())?;
+        writer.write(&(records.count_ones() as u16).to_be_bytes())?;
+        writer.write(&[0, 0, 0, 0])?;
+        writer.write(questions)?;
+
+        let mut all = [Record::Address; 1 + 4 * MAX_SERVICES];
+        for i in 0..MAX_SERVICES {
+            all[1 + 4 * i] = Record::ServiceType(i);
+            all[2 + 4 * i] = Record::Instance(i);
+            all[3 + 4 * i] = Record::Server(i);
+            all[4 + 4 * i] = Record::Text(i);
+        }
+        for record in all.iter().filter(|record| records & record.bit() != 0) {
+            self.write_record(writer, *record, legacy)?;
+        }
+        Ok(())
+    }
+
+    fn write_record(
+        &self,
+        writer: &mut Writer,
+        record: Record,
+        legacy: bool,
+    ) -> Result<(), MdnsError> {
+        // the records unique to the device flush the caches, except in the legacy responses
+        let unique = if legacy {
+            CLASS_IN
+        } else {
+            CLASS_IN | CLASS_UNICAST
+        };
+        let (host_ttl, other_ttl) = match legacy {
+            true => (LEGACY_TTL, LEGACY_TTL),
+            false => (HOST_TTL, OTHER_TTL),
+        };
+        match record {
+            Record::Address => {
+                writer.write_name(&[self.hostname, LOCAL])?;
+                writer.write_header(TYPE_A, unique, host_ttl)?;
+                writer.write_data(|w| w.write(&self.address))
+            }
+            Record::ServiceType(i) => {
+                let service = &self.services[i];
+                writer.write_name(&[SERVICE_TYPES, LOCAL])?;
+                writer.write_header(TYPE_PTR, CLASS_IN, other_ttl)?;
+                writer.write_data(|w| w.write_name(&[service.service_type, LOCAL]))
+            }
+            Record::Instance(i) => {
+                let service = &self.services[i];
+                writer.write_name(&[service.service_type, LOCAL])?;
+                writer.write_header(TYPE_PTR, CLASS_IN, other_ttl)?;
+                writer
+                    .write_data(|w| w.write_name(&[service.instance, service.service_type, LOCAL]))
+            }
+            Record::Server(i) => {
+                let service = &self.services[i];
+                writer.write_name(&[service.instance, service.service_type, LOCAL])?;
+                writer.write_header(TYPE_SRV, unique, host_ttl)?;
+                writer.write_data(|w| {
+                    // priority and weight
+                    w.write(&[0, 0, 0, 0])?;
+                    w.write(&service.port.to_be_bytes())?;
+                    w.write_name(&[self.hostname, LOCAL])
+                })
+            }
+            Record::Text(i) => {
+                let service = &self.services[i];
+                writer.write_name(&[service.instance, service.service_type, LOCAL])?;
+                writer.write_header(TYPE_TXT, unique, other_ttl)?;
+                writer.write_data(|w| {
+                    // an empty TXT record holds a single empty string
+                    if service.txt.is_empty() {
+                        return w.write(&[0]);
+                    }
+                    for entry in service.txt {
+                        let len = u8::try_from(entry.len()).map_err(|_| MdnsError::InvalidName)?;
+                        w.write(&[len])?;
+                        w.write(entry.as_bytes())?;
+                    }
+                    Ok(())
+                })
+            }
+        }
+    }
+}
+
+struct Writer<'a> {
+    buffer: &'a mut [u8],
+    len: usize,
+}
+
+impl Writer<'_> {
+    fn write(&mut self, data: &[u8]) -> Result<(), MdnsError> {
+        self.buffer
+            .get_mut(self.len..self.len + data.len())
+            .ok_or(MdnsError::BufferTooSmall)?
+            .copy_from_slice(data);
+        self.len += data.len();
+        Ok(())
+    }
+
+    fn write_name(&mut self, parts: &[&str]) -> Result<(), MdnsError> {
+        for label in parts
+            .iter()
+            .flat_map(|part| part.trim_end_matches('.').split('.'))
+        {
+            if !(1..=63).contains(&label.len()) {
+                return Err(MdnsError::InvalidName);
+            }
+            self.write(&[label.len() as u8])?;
+            self.write(label.as_bytes())?;
+        }
+        self.write(&[0])
+    }
+
+    fn write_header(&mut self, record_type: u16, class: u16, ttl: u32) -> Result<(), MdnsError> {
+        self.write(&record_type.to_be_bytes())?;
+        self.write(&class.to_be_bytes())?;
+        self.write(&ttl.to_be_bytes())
+    }
+
+    /// Writes the data of a record preceded by its length
+    fn write_data(
+        &mut self,
+        data: impl FnOnce(&mut Self) -> Result<(), MdnsError>,
+    ) -> Result<(), MdnsError> {
+        let start = self.len;
+        self.write(&[0, 0])?;
+        data(self)?;
+        let len = (self.len - start - 2) as u16;
+        self.buffer[start..start + 2].copy_from_slice(&len.to_be_bytes());
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn writes_query() {
+        let mut buffer = [0; 64];
+        let len = write_query("broker.local", &mut buffer).unwrap();
+        assert_eq!(
+            buffer[..len],
+            [
+                0, 0, 0, 0, 0, 1, 0, 0, 0, 0, 0, 0, 6, b'b', b'r', b'o', b'k', b'e', b'r', 5, b'l',
+                b'o', b'c', b'a', b'l', 0, 0, 1, 0x80, 1
+            ]
+        );
+        assert_eq!(
+            write_query("broker..local", &mut buffer),
+            Err(MdnsError::InvalidName)
+        );
+        assert_eq!(
+            write_query("broker.local", &mut buffer[..20]),
+            Err(MdnsError::BufferTooSmall)
+        );
+    }
+
+    #[test]
+    fn finds_address_in_response() {
+        #[rustfmt::skip]
+        let mut response = [
+            // response, authoritative, no questions, 2 answers
+            0, 0, 0x84, 0, 0, 0, 0, 2, 0, 0, 0, 0,
+            // AAAA record of broker.local
+            6, b'b', b'r', b'o', b'k', b'e', b'r', 5, b'l', b'o', b'c', b'a', b'l', 0,
+            0, 28, 0x80, 1, 0, 0, 0, 120, 0, 4, 1, 2, 3, 4,
+            // A record of broker.local as a compressed name, with the cache flush bit
+            0xc0, 12, 0, 1, 0x80, 1, 0, 0, 0, 120, 0, 4, 10, 42, 0, 1,
+        ];
+        assert_eq!(
+            find_address(&response, "Broker.local"),
+            Some([10, 42, 0, 1])
+        );
+        assert_eq!(find_address(&response, "other.local"), None);
+
+        // truncated
+        assert_eq!(find_address(&response[..50], "broker.local"), None);
+        // pointer loop
+        response[41] = 40;
+        assert_eq!(find_address(&response, "broker.local"), None);
+    }
+
+    const SERVICES: [Service; 1] = [Service {
+        instance: "afo-bridge-3",
+        service_type: "_afo._tcp",
+        port: 80,
+        txt: &["version=0.1.0", "nodes=2"],
+    }];
+
+    const RESPONDER: Responder = Responder {
+        hostname: "afo-bridge-3",
+        address: [10, 42, 0, 61],
+        services: &SERVICES,
+    };
+
+    /// Query of a name with the id 0x1234
+    fn write_test_query(name: &str, record_type: u16) -> ([u8; 64], usize) {
+        let mut query = [0; 64];
+        let len = write_query(name, &mut query).unwrap();
+        query[..2].copy_from_slice(&[0x12, 0x34]);
+        query[len - 4..len - 2].copy_from_slice(&record_type.to_be_bytes());
+        (query, len)
+    }
+
+    fn answer_count(response: &[u8]) -> u16 {
+        u16::from_be_bytes([response[6], response[7]])
+    }
+
+    #[test]
+    fn answers_address_queries() {
+        let mut response = [0; 512];
+        let (query, len) = write_test_query("AFO-bridge-3.local", TYPE_A);
+
+        let response_len = RESPONDER
+            .write_response(&query[..len], false, &mut response)
+            .unwrap();
+        let response = &response[..response_len];
+        assert_eq!(response[..8], [0, 0, 0x84, 0, 0, 0, 0, 1]);
+        assert_eq!(
+            find_address(response, "afo-bridge-3.local"),
+            Some([10, 42, 0, 61])
+        );
+        // TTL of the host records with the cache flush bit
+        assert_eq!(
+            response[response_len - 12..response_len - 6],
+            [0x80, 1, 0, 0, 0, 120]
+        );
+
+        // legacy queries get the id and the question back
+        let mut response = [0; 512];
+        let response_len = RESPONDER
+            .write_response(&query[..len], true, &mut response)
+            .unwrap();
+        let response = &response[..response_len];
+        assert_eq!(response[..8], [0x12, 0x34, 0x84, 0, 0, 1, 0, 1]);
+        assert_eq!(response[12..len], query[12..len]);
+        assert_eq!(
+            response[response_len - 12..response_len - 6],
+            [0, 1, 0, 0, 0, 10]
+        );
+
+        let (query, len) = write_test_query("other.local", TYPE_A);
+        assert_eq!(
+            RESPONDER.write_response(&query[..len], false, &mut [0; 512]),
+            Ok(0)
+        );
+        assert_eq!(
+            RESPONDER.write_response(&query[..10], false, &mut [0; 512]),
+            Err(MdnsError::Malformed)
+        );
+    }
+
+    #[test]
+    fn answers_service_queries() {
+        let mut response = [0; 512];
+
+        let (query, len) = write_test_query("_services._dns-sd._udp.local", TYPE_PTR);
+        let response_len = RESPONDER
+            .write_response(&query[..len], false, &mut response)
+            .unwrap();
+        assert_eq!(answer_count(&response[..response_len]), 1);
+
+        // pointer, server, text and address
+        let (query, len) = write_test_query("_afo._tcp.local", TYPE_PTR);
+        let response_len = RESPONDER
+            .write_response(&query[..len], false, &mut response)
+            .unwrap();
+        let response = &response[..response_len];
+        assert_eq!(answer_count(response), 4);
+        assert_eq!(
+            find_address(response, "afo-bridge-3.local"),
+            Some([10, 42, 0, 61])
+        );
+        let txt = b"\x0dversion=0.1.0\x07nodes=2";
+        assert_eq!(response[response_len - txt.len()..], txt[..]);
+
+        let mut response = [0; 512];
+        let (query, len) = write_test_query("afo-bridge-3._afo._tcp.local", TYPE_SRV);
+        let response_len = RESPONDER
+            .write_response(&query[..len], false, &mut response)
+            .unwrap();
+        assert_eq!(answer_count(&response[..response_len]), 2);
+
+        let response_len = RESPONDER.write_announcement(&mut response).unwrap();
+        assert_eq!(answer_count(&response[..response_len]), 5);
+        assert_eq!(
+            RESPONDER.write_announcement(&mut [0; 64]),
+            Err(MdnsError::BufferTooSmall)
+        );
+    }
+}
@@ -0,0 +1,111 @@
+//! Running the [`Responder`] over an `embassy-net` stack.
+
+use super::{Responder, Service, MDNS_ADDRESS, MDNS_PORT};
+use crate::http::HTTP_PORT;
+use embassy_net::driver::Driver;
+use embassy_net::udp::{PacketMetadata, UdpSocket};
+use embassy_net::{Ipv4Address, Stack};
+use embassy_time::{with_timeout, Duration, Timer};
+
+/// Period of checking for a new address to announce while there are no mDNS queries
+const ANNOUNCE_CHECK_PERIOD: Duration = Duration::from_secs(5);
+
+/// Entry of the TXT record of the `_afo._tcp` service which changes while the device runs,
+/// e.g. `nodes=3`
+pub type Status = heapless::String<16>;
+
+/// Answers the mDNS queries of `<hostname>.local` and of the `_afo._tcp` and `_http._tcp`
+/// services of the device, the records are announced whenever the address of the device changes.
+///
+/// `version` is the TXT entry of the `_afo._tcp` service, followed by the one returned
+/// by `status` for every query, `path` is the TXT entry of the `_http._tcp` service.
+pub async fn respond<D: Driver>(
+    stack: &Stack<D>,
+    hostname: &str,
+    version: &str,
+    path: &str,
+    mut status: impl FnMut() -> Option<Status>,
+) -> ! {
+    let mut rx_meta = [PacketMetadata::EMPTY; 4];
+    let mut tx_meta = [PacketMetadata::EMPTY; 4];
+    let mut rx_buffer = [0; 1024];
+    let mut tx_buffer = [0; 1024];
+    let mut socket = UdpSocket::new(
+        stack,
+        &mut rx_meta,
+        &mut rx_buffer,
+        &mut tx_meta,
+        &mut tx_buffer,
+    );
+    defmt::unwrap!(socket.bind(MDNS_PORT));
+    if let Err(e) = stack.join_multicast_group(Ipv4Address(MDNS_ADDRESS)).await {
+        defmt::error!("Failed to join the mDNS group: {:?}", e);
+    }
+    let group = (Ipv4Address(MDNS_ADDRESS), MDNS_PORT);
+
+    let mut query = [0; 512];
+    let mut response = [0; 512];
+    let mut announced = None;
+    loop {
+        let Some(config) = stack.config_v4() else {
+            Timer::after(ANNOUNCE_CHECK_PERIOD).await;
+            continue;
+        };
+        let status = status();
+        let mut txt = heapless::Vec::<&str, 2>::new();
+        let _ = txt.push(version);
+        if let Some(status) = &status {
+            let _ = txt.push(status);
+        }
+        // `_afo._tcp` marks the device as a bridge or a node, its port is the one of the API
+        let services = [
+            Service {
+                instance: hostname,
+                service_type: "_afo._tcp",
+                port: HTTP_PORT,
+                txt: &txt,
+            },
+            Service {
+                instance: hostname,
+                service_type: "_http._tcp",
+                port: HTTP_PORT,
+                txt: &[path],
+            },
+        ];
+        let responder = Responder {
+            hostname,
+            address: config.address.address().0,
+            services: &services,
+        };
+
+        if announced != Some(responder.address) {
+            match responder.write_announcement(&mut response) {
+                Ok(len) => {
+                    if let Err(e) = socket.send_to(&response[..len], group).await {
+                        defmt::warn!("Failed to send the mDNS announcement: {:?}", e);
+                    }
+                }
+                Err(e) => defmt::error!("Failed to write the mDNS announcement: {:?}", e),
+            }
+            announced = Some(responder.address);
+        }
+
+        let Ok(Ok((len, source))) =
+            with_timeout(ANNOUNCE_CHECK_PERIOD, socket.recv_from(&mut query)).await
+        else {
+            continue;
+        };
+        // queries from other ports than 5353 are answered directly
+        let legacy = source.port != MDNS_PORT;
+        match responder.write_response(&query[..len], legacy, &mut response) {
+            Ok(0) => {}
+            Ok(len) => {
+                let destination = if legacy { source } else { group.into() };
+                if let Err(e) = socket.send_to(&response[..len], destination).await {
+                    defmt::warn!("Failed to send the mDNS response: {:?}", e);
+                }
+            }
+            Err(e) => defmt::debug!("Invalid mDNS query: {:?}", e),
+        }
+    }
+}
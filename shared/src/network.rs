@@ -0,0 +1,265 @@
+//! Network configuration of the bridge.
+//!
+//! Parsed from the settings of the [`crate::config::BridgeConfig`]:
+//! - `network`: `dhcp` to obtain the address from the host, or a static address
+//!   with its prefix length and an optional gateway, `10.42.0.61/24,10.42.0.1` by default.
+//! - `broker`: address or hostname of the MQTT broker with an optional port,
+//!   `10.42.0.1:1883` by default. Hostnames ending with `.local` are resolved using mDNS,
+//!   other hostnames using the DNS servers leased by DHCP.
+
+use core::fmt;
+
+/// Default port of unencrypted MQTT
+pub const MQTT_PORT: u16 = 1883;
+
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum NetworkError {
+    InvalidAddress,
+    InvalidHostname,
+    InvalidPrefix,
+    InvalidPort,
+}
+
+/// IPv4 address as its octets
+pub type Ipv4 = [u8; 4];
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum AddressConfig {
+    /// The address, gateway and DNS servers are leased from a DHCP server
+    Dhcp,
+    Static {
+        address: Ipv4,
+        prefix_len: u8,
+        gateway: Option<Ipv4>,
+    },
+}
+
+impl AddressConfig {
+    pub const DEFAULT: Self = Self::Static {
+        address: [10, 42, 0, 61],
+        prefix_len: 24,
+        gateway: Some([10, 42, 0, 1]),
+    };
+
+    pub fn parse(config: &str) -> Result<Self, NetworkError> {
+        let config = config.trim();
+        if config == "dhcp" {
+            return Ok(Self::Dhcp);
+        }
+        let (cidr, gateway) = match config.split_once(',') {
+            Some((cidr, gateway)) => (cidr, Some(parse_ipv4(gateway)?)),
+            None => (config, None),
+        };
+        let (address, prefix_len) = cidr.split_once('/').ok_or(NetworkError::InvalidPrefix)?;
+        let prefix_len = prefix_len
+            .trim()
+            .parse::<u8>()
+            .ok()
+            .filter(|len| *len <= 32)
+            .ok_or(NetworkError::InvalidPrefix)?;
+        Ok(Self::Static {
+            address: parse_ipv4(address)?,
+            prefix_len,
+            gateway,
+        })
+    }
+}
+
+/// Address or hostname of a host
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Host<'a> {
+    Address(Ipv4),
+    Name(&'a str),
+}
+
+impl fmt::Display for Host<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Host::Address([a, b, c, d]) => write!(f, "{}.{}.{}.{}", a, b, c, d),
+            Host::Name(name) => f.write_str(name),
+        }
+    }
+}
+
+impl<'a> Host<'a> {
+    /// Parses an IPv4 address or a hostname, names ending with a number are parsed as addresses
+    pub fn parse(host: &'a str) -> Result<Self, NetworkError> {
+        let host = host.trim().trim_end_matches('.');
+        let last_label = host.rsplit('.').next().unwrap_or(host);
+        if last_label.bytes().all(|b| b.is_ascii_digit()) {
+            return parse_ipv4(host).map(Host::Address);
+        }
+        if host.len() > 253 || !host.split('.').all(is_valid_label) {
+            return Err(NetworkError::InvalidHostname);
+        }
+        Ok(Host::Name(host))
+    }
+
+    /// Whether the host is resolved using multicast DNS
+    pub fn is_mdns(&self) -> bool {
+        match self {
+            Host::Address(_) => false,
+            Host::Name(name) => {
+                let suffix = name.len().saturating_sub(".local".len());
+                matches!(name.get(suffix..), Some(s) if s.eq_ignore_ascii_case(".local"))
+            }
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct Broker<'a> {
+    pub host: Host<'a>,
+    pub port: u16,
+}
+
+impl Broker<'static> {
+    pub const DEFAULT: Self = Self {
+        host: Host::Address([10, 42, 0, 1]),
+        port: MQTT_PORT,
+    };
+}
+
+impl<'a> Broker<'a> {
+    pub fn parse(broker: &'a str) -> Result<Self, NetworkError> {
+        let Endpoint { host, port } = Endpoint::parse(broker, MQTT_PORT)?;
+        Ok(Self { host, port })
+    }
+}
+
+impl fmt::Display for Broker<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}:{}", self.host, self.port)
+    }
+}
+
+/// Host and port of a service
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct Endpoint<'a> {
+    pub host: Host<'a>,
+    pub port: u16,
+}
+
+impl<'a> Endpoint<'a> {
+    /// Parses `host[:port]`, using the default port if it's not set
+    pub fn parse(endpoint: &'a str, default_port: u16) -> Result<Self, NetworkError> {
+        let (host, port) = match endpoint.trim().split_once(':') {
+            Some((host, port)) => (host, port.parse().map_err(|_| NetworkError::InvalidPort)?),
+            None => (endpoint, default_port),
+        };
+        Ok(Self {
+            host: Host::parse(host)?,
+            port,
+        })
+    }
+}
+
+/// Whether the label is a valid part of a hostname, letters, digits and inner hyphens
+pub fn is_valid_label(label: &str) -> bool {
+    (1..=63).contains(&label.len())
+        && !label.starts_with('-')
+        && !label.ends_with('-')
+        && label
+            .bytes()
+            .all(|b| b.is_ascii_alphanumeric() || b == b'-')
+}
+
+/// Parses a dotted IPv4 address
+pub fn parse_ipv4(address: &str) -> Result<Ipv4, NetworkError> {
+    let mut octets = [0u8; 4];
+    let mut parts = address.trim().split('.');
+    for octet in &mut octets {
+        *octet = parts
+            .next()
+            .and_then(|part| part.parse().ok())
+            .ok_or(NetworkError::InvalidAddress)?;
+    }
+    match parts.next() {
+        Some(_) => Err(NetworkError::InvalidAddress),
+        None => Ok(octets),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_address_config() {
+        assert_eq!(AddressConfig::parse("dhcp"), Ok(AddressConfig::Dhcp));
+        assert_eq!(
+            AddressConfig::parse("10.42.0.61/24,10.42.0.1"),
+            Ok(AddressConfig::DEFAULT)
+        );
+        assert_eq!(
+            AddressConfig::parse("192.168.7.2/30"),
+            Ok(AddressConfig::Static {
+                address: [192, 168, 7, 2],
+                prefix_len: 30,
+                gateway: None,
+            })
+        );
+        assert_eq!(
+            AddressConfig::parse("10.42.0.61"),
+            Err(NetworkError::InvalidPrefix)
+        );
+        assert_eq!(
+            AddressConfig::parse("10.42.0.61/33"),
+            Err(NetworkError::InvalidPrefix)
+        );
+        assert_eq!(
+            AddressConfig::parse("10.42.0/24"),
+            Err(NetworkError::InvalidAddress)
+        );
+    }
+
+    #[test]
+    fn parses_broker() {
+        assert_eq!(Broker::parse("10.42.0.1"), Ok(Broker::DEFAULT));
+        assert_eq!(Broker::DEFAULT.to_string(), "10.42.0.1:1883");
+        assert_eq!(
+            Broker::parse("192.168.1.10:8883"),
+            Ok(Broker {
+                host: Host::Address([192, 168, 1, 10]),
+                port: 8883,
+            })
+        );
+        assert_eq!(
+            Broker::parse("homeassistant.local"),
+            Ok(Broker {
+                host: Host::Name("homeassistant.local"),
+                port: MQTT_PORT,
+            })
+        );
+        assert_eq!(
+            Broker::parse("10.42.0.1:mqtt"),
+            Err(NetworkError::InvalidPort)
+        );
+        assert_eq!(
+            Broker::parse("10.42.0.1.5"),
+            Err(NetworkError::InvalidAddress)
+        );
+    }
+
+    #[test]
+    fn parses_hosts() {
+        assert_eq!(Host::parse("broker"), Ok(Host::Name("broker")));
+        assert_eq!(
+            Host::parse("mqtt.example.com."),
+            Ok(Host::Name("mqtt.example.com"))
+        );
+        assert_eq!(Host::parse("10.42.0"), Err(NetworkError::InvalidAddress));
+        assert_eq!(Host::parse("my_broker"), Err(NetworkError::InvalidHostname));
+        assert_eq!(
+            Host::parse("-broker.lan"),
+            Err(NetworkError::InvalidHostname)
+        );
+        assert_eq!(Host::parse(""), Err(NetworkError::InvalidAddress));
+
+        assert!(Host::Name("homeassistant.LOCAL").is_mdns());
+        assert!(!Host::Name("local").is_mdns());
+        assert!(!Host::Name("broker.lan").is_mdns());
+        assert!(!Host::Address([10, 42, 0, 1]).is_mdns());
+    }
+}
@@ -0,0 +1,324 @@
+//! Publishing policy of the measurements.
+//!
+//! A reading is published only when a metric changed by at least its deadband since the last
+//! published reading, or as a heartbeat once nothing has been published for the heartbeat
+//! interval. The policy is set when building the firmware with the `AFO_PUBLISH_POLICY`
+//! environment variable, e.g. `co2=10,temperature=0.1,heartbeat=300;42:co2=50`, the bridge
+//! stores it in its configuration. Entries are separated by semicolons, entries prefixed by
+//! `<sensor id>:` override the default entry for a single node.
+
+use heapless::Vec;
+
+use crate::{AirQualityAdvertisement, SensorId};
+
+/// Maximum number of nodes overriding the default entry
+pub const MAX_OVERRIDES: usize = 16;
+
+/// Reading in the units of the advertisement
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct Reading {
+    pub co2: u16,
+    /// Scaled by 0.1
+    pub temperature: i16,
+    pub humidity: u8,
+}
+
+impl From<&AirQualityAdvertisement> for Reading {
+    fn from(adv: &AirQualityAdvertisement) -> Self {
+        Self {
+            co2: adv.co2_concentration,
+            temperature: adv.temperature,
+            humidity: adv.humidity,
+        }
+    }
+}
+
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum PolicyError {
+    UnknownKey,
+    InvalidValue,
+    InvalidNode,
+    TooManyNodes,
+}
+
+/// Minimal changes of the metrics worth publishing, a deadband of zero publishes any change
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct PublishPolicy {
+    /// In ppm
+    pub co2: u16,
+    /// In 0.1 °C
+    pub temperature: u16,
+    /// In %
+    pub humidity: u8,
+    /// Maximum interval without publishing in seconds
+    pub heartbeat: u32,
+}
+
+impl PublishPolicy {
+    /// Publishes every change and unchanged readings once a minute
+    pub const DEFAULT: Self = Self {
+        co2: 0,
+        temperature: 0,
+        humidity: 0,
+        heartbeat: 60,
+    };
+
+    /// Policy of a node set by the environment variable at build time,
+    /// `None` selects the default entry only
+    pub fn from_env(node: Option<SensorId>) -> Result<Self, PolicyError> {
+        match option_env!("AFO_PUBLISH_POLICY") {
+            Some(config) => Self::parse(config, node),
+            None => Ok(Self::DEFAULT),
+        }
+    }
+
+    /// Parses the policy of a node from the configuration, `None` selects the default entry only
+    pub fn parse(config: &str, node: Option<SensorId>) -> Result<Self, PolicyError> {
+        let mut policy = Self::DEFAULT;
+        let mut overrides = None;
+        for entry in config.split(';').map(str::trim).filter(|e| !e.is_empty()) {
+            match entry.split_once(':') {
+                Some((id, settings)) => {
+                    let id = id
+                        .trim()
+                        .parse::<SensorId>()
+                        .map_err(|_| PolicyError::InvalidNode)?;
+                    if Some(id) == node {
+                        overrides = Some(settings);
+                    }
+                }
+                None => policy.apply(entry)?,
+            }
+        }
+        if let Some(settings) = overrides {
+            policy.apply(settings)?;
+        }
+        Ok(policy)
+    }
+
+    fn apply(&mut self, settings: &str) -> Result<(), PolicyError> {
+        for setting in settings.split(',').map(str::trim).filter(|s| !s.is_empty()) {
+            let (key, value) = setting.split_once('=').ok_or(PolicyError::InvalidValue)?;
+            let value = value.trim();
+            match key.trim() {
+                "co2" => self.co2 = parse_value(value)?,
+                "temperature" => {
+                    let celsius = value
+                        .parse::<f32>()
+                        .map_err(|_| PolicyError::InvalidValue)?;
+                    if !(0.0..=100.0).contains(&celsius) {
+                        return Err(PolicyError::InvalidValue);
+                    }
+                    self.temperature = (celsius * 10.0 + 0.5) as u16;
+                }
+                "humidity" => self.humidity = parse_value(value)?,
+                "heartbeat" => self.heartbeat = parse_value(value)?,
+                _ => return Err(PolicyError::UnknownKey),
+            }
+        }
+        Ok(())
+    }
+
+    /// Whether any metric changed by at least its deadband
+    fn exceeded(&self, last: &Reading, reading: &Reading) -> bool {
+        fn exceeds(change: u32, deadband: u32) -> bool {
+            change > 0 && change >= deadband
+        }
+        exceeds(last.co2.abs_diff(reading.co2) as u32, self.co2 as u32)
+            || exceeds(
+                last.temperature.abs_diff(reading.temperature) as u32,
+                self.temperature as u32,
+            )
+            || exceeds(
+                last.humidity.abs_diff(reading.humidity) as u32,
+                self.humidity as u32,
+            )
+    }
+}
+
+/// Policies of all nodes, parsed from the configuration once
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct PublishPolicies {
+    default: PublishPolicy,
+    overrides: Vec<(SensorId, PublishPolicy), MAX_OVERRIDES>,
+}
+
+impl PublishPolicies {
+    /// Every node publishes with [`PublishPolicy::DEFAULT`]
+    pub const DEFAULT: Self = Self {
+        default: PublishPolicy::DEFAULT,
+        overrides: Vec::new(),
+    };
+
+    /// Parses the configuration, the overrides apply to the default entry
+    /// wherever they are listed, the last entry of a node is used
+    pub fn parse(config: &str) -> Result<Self, PolicyError> {
+        let mut default = PublishPolicy::DEFAULT;
+        let entries = || config.split(';').map(str::trim).filter(|e| !e.is_empty());
+        for entry in entries().filter(|e| !e.contains(':')) {
+            default.apply(entry)?;
+        }
+        let mut policies = Self {
+            default,
+            overrides: Vec::new(),
+        };
+        for (id, settings) in entries().filter_map(|e| e.split_once(':')) {
+            let id = id
+                .trim()
+                .parse::<SensorId>()
+                .map_err(|_| PolicyError::InvalidNode)?;
+            let mut policy = default;
+            policy.apply(settings)?;
+            match policies.overrides.iter_mut().find(|(node, _)| *node == id) {
+                Some((_, overridden)) => *overridden = policy,
+                None => policies
+                    .overrides
+                    .push((id, policy))
+                    .map_err(|_| PolicyError::TooManyNodes)?,
+            }
+        }
+        Ok(policies)
+    }
+
+    /// Policy of a node
+    pub fn get(&self, node: SensorId) -> &PublishPolicy {
+        self.overrides
+            .iter()
+            .find(|(id, _)| *id == node)
+            .map(|(_, policy)| policy)
+            .unwrap_or(&self.default)
+    }
+}
+
+fn parse_value<T: core::str::FromStr>(value: &str) -> Result<T, PolicyError> {
+    value.parse().map_err(|_| PolicyError::InvalidValue)
+}
+
+/// Decides which readings of a node are published
+#[derive(Clone, Copy, Debug, Default)]
+pub struct PublishFilter {
+    /// Last published reading and when it was published
+    last: Option<(Reading, u64)>,
+}
+
+impl PublishFilter {
+    /// Whether nothing has been published for the heartbeat interval
+    pub fn heartbeat_due(&self, policy: &PublishPolicy, now_ms: u64) -> bool {
+        match self.last {
+            None => true,
+            Some((_, at)) => now_ms.saturating_sub(at) >= policy.heartbeat as u64 * 1000,
+        }
+    }
+
+    /// Whether the reading should be published, records it as published if so
+    pub fn publish(&mut self, policy: &PublishPolicy, reading: Reading, now_ms: u64) -> bool {
+        let publish = self.heartbeat_due(policy, now_ms)
+            || matches!(self.last, Some((last, _)) if policy.exceeded(&last, &reading));
+        if publish {
+            self.last = Some((reading, now_ms));
+        }
+        publish
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn reading(co2: u16, temperature: i16, humidity: u8) -> Reading {
+        Reading {
+            co2,
+            temperature,
+            humidity,
+        }
+    }
+
+    #[test]
+    fn parses_node_overrides() {
+        let config = "co2=10, temperature=0.1, heartbeat=300; 42: co2=50, humidity=2";
+        assert_eq!(
+            PublishPolicy::parse(config, Some(42)),
+            Ok(PublishPolicy {
+                co2: 50,
+                temperature: 1,
+                humidity: 2,
+                heartbeat: 300,
+            })
+        );
+        assert_eq!(
+            PublishPolicy::parse(config, Some(7)),
+            Ok(PublishPolicy {
+                co2: 10,
+                temperature: 1,
+                humidity: 0,
+                heartbeat: 300,
+            })
+        );
+        assert_eq!(PublishPolicy::parse("", None), Ok(PublishPolicy::DEFAULT));
+
+        assert_eq!(
+            PublishPolicy::parse("pressure=1", None),
+            Err(PolicyError::UnknownKey)
+        );
+        assert_eq!(
+            PublishPolicy::parse("co2=-1", None),
+            Err(PolicyError::InvalidValue)
+        );
+        assert_eq!(
+            PublishPolicy::parse("c3:co2=1", None),
+            Err(PolicyError::InvalidNode)
+        );
+    }
+
+    #[test]
+    fn parses_policies_of_all_nodes() {
+        let config = "co2=10; 42: co2=50; 7: humidity=2; 42: heartbeat=30";
+        let policies = PublishPolicies::parse(config).unwrap();
+        assert_eq!(
+            policies.get(42),
+            &PublishPolicy::parse(config, Some(42)).unwrap()
+        );
+        assert_eq!(policies.get(7).humidity, 2);
+        assert_eq!(
+            policies.get(8),
+            &PublishPolicy::parse(config, None).unwrap()
+        );
+        assert_eq!(PublishPolicies::parse(""), Ok(PublishPolicies::DEFAULT));
+
+        let mut config = heapless::String::<512>::new();
+        for id in 0..=MAX_OVERRIDES as SensorId {
+            core::fmt::Write::write_fmt(&mut config, format_args!("{}:co2=1;", id)).unwrap();
+        }
+        assert_eq!(
+            PublishPolicies::parse(&config),
+            Err(PolicyError::TooManyNodes)
+        );
+    }
+
+    #[test]
+    fn publishes_changes_beyond_deadband() {
+        let policy = PublishPolicy::parse("co2=10,temperature=0.5", None).unwrap();
+        let mut filter = PublishFilter::default();
+
+        assert!(filter.publish(&policy, reading(800, 215, 40), 0));
+        assert!(!filter.publish(&policy, reading(800, 215, 40), 6_000));
+        assert!(!filter.publish(&policy, reading(809, 219, 40), 12_000));
+        assert!(filter.publish(&policy, reading(810, 219, 40), 18_000));
+        assert!(filter.publish(&policy, reading(810, 214, 40), 24_000));
+        // any change of the humidity, as it has no deadband
+        assert!(filter.publish(&policy, reading(810, 214, 41), 30_000));
+    }
+
+    #[test]
+    fn publishes_heartbeat() {
+        let policy = PublishPolicy::DEFAULT;
+        let mut filter = PublishFilter::default();
+        assert!(filter.heartbeat_due(&policy, 0));
+
+        assert!(filter.publish(&policy, reading(800, 215, 40), 0));
+        assert!(!filter.heartbeat_due(&policy, 59_999));
+        assert!(!filter.publish(&policy, reading(800, 215, 40), 59_999));
+        assert!(filter.publish(&policy, reading(800, 215, 40), 60_000));
+    }
+}
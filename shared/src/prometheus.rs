@@ -0,0 +1,172 @@
+//! Prometheus text exposition format of the metrics served on `/metrics`.
+//!
+//! All the samples are labelled with the `node` id and its `name`, the metrics of the bridge
+//! itself use `bridge` as the id.
+
+use core::fmt::{self, Display, Write};
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum MetricType {
+    Gauge,
+    Counter,
+}
+
+impl MetricType {
+    fn as_str(&self) -> &'static str {
+        match self {
+            MetricType::Gauge => "gauge",
+            MetricType::Counter => "counter",
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct Metric<'a> {
+    pub name: &'a str,
+    pub help: &'a str,
+    pub metric_type: MetricType,
+}
+
+const fn gauge<'a>(name: &'a str, help: &'a str) -> Metric<'a> {
+    Metric {
+        name,
+        help,
+        metric_type: MetricType::Gauge,
+    }
+}
+
+const fn counter<'a>(name: &'a str, help: &'a str) -> Metric<'a> {
+    Metric {
+        name,
+        help,
+        metric_type: MetricType::Counter,
+    }
+}
+
+pub const CO2: Metric = gauge("afo_co2_ppm", "CO2 concentration");
+pub const TEMPERATURE: Metric = gauge("afo_temperature_celsius", "Temperature");
+pub const HUMIDITY: Metric = gauge("afo_humidity_percent", "Relative humidity");
+pub const RSSI: Metric = gauge("afo_rssi_dbm", "Signal strength of the last advertisement");
+pub const LAST_SEEN: Metric = gauge(
+    "afo_last_seen_seconds",
+    "Seconds since the last measurement",
+);
+pub const UPTIME: Metric = gauge("afo_uptime_seconds", "Seconds since the last reset");
+pub const MQTT_CONNECTIONS: Metric = counter(
+    "afo_mqtt_connections_total",
+    "Successful connections to the MQTT broker",
+);
+pub const MQTT_CONNECT_FAILURES: Metric = counter(
+    "afo_mqtt_connect_failures_total",
+    "Failed connections to the MQTT broker",
+);
+pub const MQTT_AUTH_FAILURES: Metric = counter(
+    "afo_mqtt_auth_failures_total",
+    "Connections refused by the MQTT broker due to the credentials",
+);
+pub const MQTT_REQUEUED: Metric = counter(
+    "afo_mqtt_requeued_total",
+    "Readings which failed to be published and were queued for the backfill",
+);
+pub const BROKER_CONNECTED: Metric = gauge(
+    "afo_broker_connected",
+    "Whether the MQTT broker is connected",
+);
+pub const NODES: Metric = gauge("afo_nodes", "Nodes heard by the bridge");
+pub const SCAN_REPORTS: Metric = counter(
+    "afo_scan_reports_total",
+    "Advertisements of the nodes received",
+);
+pub const SCAN_RESTARTS: Metric = counter("afo_scan_restarts_total", "Restarts of the BLE scan");
+pub const SCAN_FAILURES: Metric = counter("afo_scan_failures_total", "Failures of the BLE scan");
+pub const PARSE_ERRORS: Metric = counter(
+    "afo_parse_errors_total",
+    "Malformed advertisements of the nodes",
+);
+pub const SENSOR_ERRORS: Metric = counter(
+    "afo_sensor_errors_total",
+    "Failed readouts of the CO2 sensor",
+);
+
+impl Metric<'_> {
+    /// Writes the `HELP` and `TYPE` lines, which precede the samples of the metric
+    pub fn write_header(&self, w: &mut impl Write) -> fmt::Result {
+        writeln!(w, "# HELP {} {}", self.name, self.help)?;
+        writeln!(w, "# TYPE {} {}", self.name, self.metric_type.as_str())
+    }
+
+    /// Writes the header followed by the only sample of the metric
+    pub fn write(
+        &self,
+        labels: &[(&str, &str)],
+        value: impl Display,
+        w: &mut impl Write,
+    ) -> fmt::Result {
+        self.write_header(w)?;
+        self.write_sample(labels, value, w)
+    }
+
+    pub fn write_sample(
+        &self,
+        labels: &[(&str, &str)],
+        value: impl Display,
+        w: &mut impl Write,
+    ) -> fmt::Result {
+        w.write_str(self.name)?;
+        for (i, (label, label_value)) in labels.iter().enumerate() {
+            let separator = if i == 0 { '{' } else { ',' };
+            write!(w, r#"{}{}="{}""#, separator, label, LabelValue(label_value))?;
+        }
+        if !labels.is_empty() {
+            w.write_char('}')?;
+        }
+        writeln!(w, " {}", value)
+    }
+}
+
+/// Escapes a label value
+struct LabelValue<'a>(&'a str);
+
+impl Display for LabelValue<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for c in self.0.chars() {
+            match c {
+                '\\' => f.write_str(r"\\")?,
+                '"' => f.write_str(r#"\""#)?,
+                '\n' => f.write_str(r"\n")?,
+                c => f.write_char(c)?,
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn writes_metrics() {
+        let mut out = String::new();
+        TEMPERATURE.write_header(&mut out).unwrap();
+        TEMPERATURE
+            .write_sample(
+                &[("node", "42"), ("name", "AFO 42")],
+                224.0 / 10.0,
+                &mut out,
+            )
+            .unwrap();
+        MQTT_CONNECTIONS.write_sample(&[], 3, &mut out).unwrap();
+        NODES
+            .write_sample(&[("name", "living \"room\"\\2")], 2, &mut out)
+            .unwrap();
+        assert_eq!(
+            out,
+            "# HELP afo_temperature_celsius Temperature\n\
+             # TYPE afo_temperature_celsius gauge\n\
+             afo_temperature_celsius{node=\"42\",name=\"AFO 42\"} 22.4\n\
+             afo_mqtt_connections_total 3\n\
+             afo_nodes{name=\"living \\\"room\\\"\\\\2\"} 2\n"
+        );
+    }
+}
@@ -0,0 +1,204 @@
+//! Quality of service of the MQTT messages, selected per message class.
+//!
+//! The QoS of the classes is set when building the firmware with the `AFO_MQTT_QOS`
+//! environment variable, e.g. `measurement=1,diagnostics=0`. Classes not listed keep
+//! their default QoS. QoS 2 is not supported by the MQTT client.
+//!
+//! The MQTT client always sets QoS 0 for the will, [`WillQos`] sets the QoS of the
+//! availability class in the CONNECT packet instead.
+
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum MessageClass {
+    /// Readings of the nodes, including the per-metric and backfilled readings
+    Measurement,
+    /// Online/offline state of the publishing device
+    Availability,
+    /// Diagnostics, link quality, statistics and crash reports
+    Diagnostics,
+    /// Home Assistant discovery configs
+    Discovery,
+}
+
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Qos {
+    AtMostOnce,
+    /// The broker acknowledges the message, the firmwares publish unacknowledged readings again
+    AtLeastOnce,
+}
+
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum QosError {
+    UnknownClass,
+    InvalidLevel,
+}
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct QosConfig {
+    pub measurement: Qos,
+    pub availability: Qos,
+    pub diagnostics: Qos,
+    pub discovery: Qos,
+}
+
+impl QosConfig {
+    /// Measurements and availability are acknowledged, the rest is republished periodically anyway
+    pub const DEFAULT: Self = Self {
+        measurement: Qos::AtLeastOnce,
+        availability: Qos::AtLeastOnce,
+        diagnostics: Qos::AtMostOnce,
+        discovery: Qos::AtMostOnce,
+    };
+
+    /// Configuration set by the environment variable at build time
+    pub fn from_env() -> Result<Self, QosError> {
+        match option_env!("AFO_MQTT_QOS") {
+            Some(config) => Self::parse(config),
+            None => Ok(Self::DEFAULT),
+        }
+    }
+
+    pub fn parse(config: &str) -> Result<Self, QosError> {
+        let mut qos = Self::DEFAULT;
+        for setting in config.split(',').map(str::trim).filter(|s| !s.is_empty()) {
+            let (class, level) = setting.split_once('=').ok_or(QosError::InvalidLevel)?;
+            let level = match level.trim() {
+                "0" => Qos::AtMostOnce,
+                "1" => Qos::AtLeastOnce,
+                _ => return Err(QosError::InvalidLevel),
+            };
+            match class.trim() {
+                "measurement" => qos.measurement = level,
+                "availability" => qos.availability = level,
+                "diagnostics" => qos.diagnostics = level,
+                "discovery" => qos.discovery = level,
+                _ => return Err(QosError::UnknownClass),
+            }
+        }
+        Ok(qos)
+    }
+
+    pub fn qos(&self, class: MessageClass) -> Qos {
+        match class {
+            MessageClass::Measurement => self.measurement,
+            MessageClass::Availability => self.availability,
+            MessageClass::Diagnostics => self.diagnostics,
+            MessageClass::Discovery => self.discovery,
+        }
+    }
+}
+
+/// Offset of the connect flags in a CONNECT packet with a will, `None` for other packets
+pub fn will_flags_offset(packet: &[u8]) -> Option<usize> {
+    if *packet.first()? != 0x10 {
+        return None;
+    }
+    // the remaining length is encoded on up to 4 bytes, the last one without the top bit
+    let length_len = packet[1..]
+        .iter()
+        .take(4)
+        .position(|byte| byte & 0x80 == 0)?
+        + 1;
+    // protocol name and version
+    let offset = 1 + length_len + 2 + 4 + 1;
+    let flags = *packet.get(offset)?;
+    (flags & WILL_FLAG != 0).then_some(offset)
+}
+
+const WILL_FLAG: u8 = 0x04;
+
+/// Connection which sets the QoS of the will in the first packet written, the CONNECT packet
+#[cfg(feature = "embassy-net")]
+pub struct WillQos<T> {
+    io: T,
+    /// QoS still to set
+    qos: Option<Qos>,
+}
+
+#[cfg(feature = "embassy-net")]
+impl<T> WillQos<T> {
+    pub fn new(io: T, qos: Qos) -> Self {
+        Self { io, qos: Some(qos) }
+    }
+}
+
+#[cfg(feature = "embassy-net")]
+impl<T: embedded_io_async::ErrorType> embedded_io_async::ErrorType for WillQos<T> {
+    type Error = T::Error;
+}
+
+#[cfg(feature = "embassy-net")]
+impl<T: embedded_io_async::Read> embedded_io_async::Read for WillQos<T> {
+    async fn read(&mut self, buf: &mut [u8]) -> Result<usize, Self::Error> {
+        self.io.read(buf).await
+    }
+}
+
+#[cfg(feature = "embassy-net")]
+impl<T: embedded_io_async::Write> embedded_io_async::Write for WillQos<T> {
+    /// The MQTT client writes each packet at once, the CONNECT packet is written entirely
+    async fn write(&mut self, buf: &[u8]) -> Result<usize, Self::Error> {
+        let Some(qos) = self.qos.take() else {
+            return self.io.write(buf).await;
+        };
+        let Some(offset) = will_flags_offset(buf) else {
+            return self.io.write(buf).await;
+        };
+        // bits 3 and 4 of the connect flags
+        let level = match qos {
+            Qos::AtMostOnce => 0,
+            Qos::AtLeastOnce => 1 << 3,
+        };
+        self.io.write_all(&buf[..offset]).await?;
+        self.io.write_all(&[buf[offset] | level]).await?;
+        self.io.write_all(&buf[offset + 1..]).await?;
+        Ok(buf.len())
+    }
+
+    async fn flush(&mut self) -> Result<(), Self::Error> {
+        self.io.flush().await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse() {
+        assert_eq!(QosConfig::parse(""), Ok(QosConfig::DEFAULT));
+
+        let config = QosConfig::parse("measurement=0, diagnostics=1").unwrap();
+        assert_eq!(config.qos(MessageClass::Measurement), Qos::AtMostOnce);
+        assert_eq!(config.qos(MessageClass::Availability), Qos::AtLeastOnce);
+        assert_eq!(config.qos(MessageClass::Diagnostics), Qos::AtLeastOnce);
+        assert_eq!(config.qos(MessageClass::Discovery), Qos::AtMostOnce);
+
+        assert_eq!(
+            QosConfig::parse("measurement=2"),
+            Err(QosError::InvalidLevel)
+        );
+        assert_eq!(QosConfig::parse("claims=1"), Err(QosError::UnknownClass));
+    }
+
+    #[test]
+    fn will_flags() {
+        // CONNECT, remaining length, "MQTT", version 5, clean start and retained will
+        let connect = [0x10, 0x20, 0, 4, b'M', b'Q', b'T', b'T', 5, 0x26, 0, 180];
+        assert_eq!(will_flags_offset(&connect), Some(9));
+
+        let mut long = [0u8; 13];
+        long[..2].copy_from_slice(&[0x10, 0x80]);
+        long[2..].copy_from_slice(&connect[1..]);
+        assert_eq!(will_flags_offset(&long), Some(10));
+
+        let mut without_will = connect;
+        without_will[9] = 0x02;
+        assert_eq!(will_flags_offset(&without_will), None);
+        // PUBLISH
+        assert_eq!(will_flags_offset(&[0x30, 0x02, 0, 1]), None);
+        assert_eq!(will_flags_offset(&connect[..5]), None);
+    }
+}
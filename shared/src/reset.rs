@@ -0,0 +1,85 @@
+//! Reasons for the last reset of the device.
+
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+#[derive(Clone, Copy, Debug, Eq, PartialEq, serde::Serialize, serde::Deserialize)]
+pub enum ResetReason {
+    PowerOn,
+    /// Reset pin or a debugger
+    Pin,
+    Watchdog,
+    /// Software requested reset, e.g. after a panic
+    Software,
+    /// CPU lockup, e.g. a fault within the HardFault handler
+    Lockup,
+    /// Wake up from deep sleep (System OFF on nRF)
+    WakeUp,
+    BrownOut,
+    Other,
+}
+
+impl ResetReason {
+    /// Decodes the content of the nRF52 RESETREAS register.
+    /// The register accumulates reasons until cleared, the most specific one is reported.
+    pub fn from_nrf_resetreas(resetreas: u32) -> Self {
+        const RESETPIN: u32 = 1 << 0;
+        const DOG: u32 = 1 << 1;
+        const SREQ: u32 = 1 << 2;
+        const LOCKUP: u32 = 1 << 3;
+        // OFF, LPCOMP, DIF, NFC and VBUS wake ups
+        const WAKE_UP: u32 = 0b11111 << 16;
+
+        if resetreas & DOG != 0 {
+            ResetReason::Watchdog
+        } else if resetreas & LOCKUP != 0 {
+            ResetReason::Lockup
+        } else if resetreas & SREQ != 0 {
+            ResetReason::Software
+        } else if resetreas & RESETPIN != 0 {
+            ResetReason::Pin
+        } else if resetreas & WAKE_UP != 0 {
+            ResetReason::WakeUp
+        } else if resetreas == 0 {
+            // no reason is recorded after a power on reset
+            ResetReason::PowerOn
+        } else {
+            ResetReason::Other
+        }
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ResetReason::PowerOn => "power_on",
+            ResetReason::Pin => "pin",
+            ResetReason::Watchdog => "watchdog",
+            ResetReason::Software => "software",
+            ResetReason::Lockup => "lockup",
+            ResetReason::WakeUp => "wake_up",
+            ResetReason::BrownOut => "brown_out",
+            ResetReason::Other => "other",
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn from_nrf_resetreas() {
+        assert_eq!(ResetReason::from_nrf_resetreas(0), ResetReason::PowerOn);
+        assert_eq!(ResetReason::from_nrf_resetreas(0b0001), ResetReason::Pin);
+        assert_eq!(
+            ResetReason::from_nrf_resetreas(0b0011),
+            ResetReason::Watchdog
+        );
+        assert_eq!(
+            ResetReason::from_nrf_resetreas(0b0100),
+            ResetReason::Software
+        );
+        assert_eq!(ResetReason::from_nrf_resetreas(0b1100), ResetReason::Lockup);
+        assert_eq!(
+            ResetReason::from_nrf_resetreas(1 << 16),
+            ResetReason::WakeUp
+        );
+    }
+}
@@ -0,0 +1,82 @@
+//! Resolution of the hostnames of the broker and of the outputs, see [`crate::network::Host`].
+
+use crate::mdns;
+use crate::network::Host;
+use embassy_net::dns::DnsQueryType;
+use embassy_net::driver::Driver;
+use embassy_net::udp::{PacketMetadata, UdpSocket};
+use embassy_net::{IpAddress, Ipv4Address, Stack};
+use embassy_time::{with_timeout, Duration};
+
+const MDNS_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// Resolves the address of a host, `.local` names using mDNS, other names using unicast DNS
+pub async fn resolve<D: Driver>(stack: &Stack<D>, host: Host<'_>) -> Option<Ipv4Address> {
+    match host {
+        Host::Address(address) => Some(Ipv4Address(address)),
+        Host::Name(name) if host.is_mdns() => query_mdns(stack, name).await,
+        Host::Name(name) => match stack.dns_query(name, DnsQueryType::A).await {
+            Ok(addresses) => addresses.first().map(|address| match address {
+                IpAddress::Ipv4(address) => *address,
+            }),
+            Err(e) => {
+                defmt::warn!("Failed to resolve {}: {:?}", name, e);
+                None
+            }
+        },
+    }
+}
+
+async fn query_mdns<D: Driver>(stack: &Stack<D>, name: &str) -> Option<Ipv4Address> {
+    let mut rx_meta = [PacketMetadata::EMPTY; 4];
+    let mut tx_meta = [PacketMetadata::EMPTY; 1];
+    let mut rx_buffer = [0; 1024];
+    let mut tx_buffer = [0; 128];
+    let mut socket = UdpSocket::new(
+        stack,
+        &mut rx_meta,
+        &mut rx_buffer,
+        &mut tx_meta,
+        &mut tx_buffer,
+    );
+    // any port other than 5353 gets the responses directly, an ephemeral one is used
+    // as the hosts are resolved by several tasks
+    if socket.bind(0).is_err() {
+        defmt::warn!("Failed to bind the mDNS query socket");
+        return None;
+    }
+
+    let mut query = [0; 128];
+    let len = match mdns::write_query(name, &mut query) {
+        Ok(len) => len,
+        Err(e) => {
+            defmt::warn!("Invalid mDNS name {}: {:?}", name, e);
+            return None;
+        }
+    };
+    let destination = (Ipv4Address(mdns::MDNS_ADDRESS), mdns::MDNS_PORT);
+    if let Err(e) = socket.send_to(&query[..len], destination).await {
+        defmt::warn!("Failed to send the mDNS query: {:?}", e);
+        return None;
+    }
+
+    let mut packet = [0; 512];
+    let response = with_timeout(MDNS_TIMEOUT, async {
+        loop {
+            // responses of other hosts and truncated responses are skipped
+            if let Ok((len, _)) = socket.recv_from(&mut packet).await {
+                if let Some(address) = mdns::find_address(&packet[..len], name) {
+                    return Ipv4Address(address);
+                }
+            }
+        }
+    })
+    .await;
+    match response {
+        Ok(address) => Some(address),
+        Err(_) => {
+            defmt::warn!("No mDNS response for {}", name);
+            None
+        }
+    }
+}
@@ -0,0 +1,204 @@
+//! Parameters of the BLE scan of the bridge.
+//!
+//! The parameters are set when building the firmware with the `AFO_SCAN` environment variable,
+//! e.g. `interval=100,window=50,mode=passive`, and can be changed in the stored configuration,
+//! see [`crate::config::BridgeConfig`].
+//! Parameters not listed keep their default. The interval and the window are in ms, the timeout
+//! in s.
+
+use core::fmt;
+
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum ScanError {
+    UnknownParameter,
+    InvalidValue,
+    /// The window is longer than the interval
+    WindowTooLong,
+}
+
+/// PHYs scanned on
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum ScanPhy {
+    OneMbit,
+    Coded,
+    /// 1 Mbit and coded, each scanned in turns
+    Both,
+}
+
+impl ScanPhy {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ScanPhy::OneMbit => "1m",
+            ScanPhy::Coded => "coded",
+            ScanPhy::Both => "1m+coded",
+        }
+    }
+}
+
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct ScanParameters {
+    /// Time between the starts of two scan windows in ms
+    pub interval_ms: u16,
+    /// Time scanned in each interval in ms
+    pub window_ms: u16,
+    /// Request scan responses from the advertisers
+    pub active: bool,
+    pub phy: ScanPhy,
+    /// Time after which the scan ends and is restarted in s.
+    /// Restarting periodically recovers from a scan that stopped delivering reports.
+    pub timeout_s: u16,
+}
+
+/// Range of the interval and the window supported by the controller, 2.5 ms to 10.24 s
+const TIMES_MS: core::ops::RangeInclusive<u16> = 3..=10240;
+/// The scan checks in with the watchdog of the bridge when it is restarted, also while no
+/// reports are received
+const MAX_TIMEOUT_S: u16 = 60;
+
+impl ScanParameters {
+    /// Continuous active scanning, to receive the diagnostics the nodes send in scan responses
+    pub const DEFAULT: Self = Self {
+        interval_ms: 100,
+        window_ms: 100,
+        active: true,
+        phy: ScanPhy::OneMbit,
+        timeout_s: 60,
+    };
+
+    pub fn parse(parameters: &str) -> Result<Self, ScanError> {
+        let mut scan = Self::DEFAULT;
+        for setting in parameters
+            .split(',')
+            .map(str::trim)
+            .filter(|s| !s.is_empty())
+        {
+            let (name, value) = setting.split_once('=').ok_or(ScanError::InvalidValue)?;
+            let value = value.trim();
+            match name.trim() {
+                "interval" => scan.interval_ms = parse_time(value)?,
+                "window" => scan.window_ms = parse_time(value)?,
+                "mode" => {
+                    scan.active = match value {
+                        "active" => true,
+                        "passive" => false,
+                        _ => return Err(ScanError::InvalidValue),
+                    }
+                }
+                "phy" => {
+                    scan.phy = match value {
+                        "1m" => ScanPhy::OneMbit,
+                        "coded" => ScanPhy::Coded,
+                        "1m+coded" => ScanPhy::Both,
+                        _ => return Err(ScanError::InvalidValue),
+                    }
+                }
+                "timeout" => {
+                    scan.timeout_s = value.parse().map_err(|_| ScanError::InvalidValue)?;
+                    if !(1..=MAX_TIMEOUT_S).contains(&scan.timeout_s) {
+                        return Err(ScanError::InvalidValue);
+                    }
+                }
+                _ => return Err(ScanError::UnknownParameter),
+            }
+        }
+        if scan.window_ms > scan.interval_ms {
+            return Err(ScanError::WindowTooLong);
+        }
+        Ok(scan)
+    }
+
+    /// Interval in units of 0.625 ms
+    pub fn interval_units(&self) -> u32 {
+        u32::from(self.interval_ms) * 8 / 5
+    }
+
+    /// Window in units of 0.625 ms
+    pub fn window_units(&self) -> u32 {
+        u32::from(self.window_ms) * 8 / 5
+    }
+
+    /// Timeout in units of 10 ms
+    pub fn timeout_units(&self) -> u16 {
+        self.timeout_s * 100
+    }
+}
+
+fn parse_time(value: &str) -> Result<u16, ScanError> {
+    value
+        .parse()
+        .ok()
+        .filter(|ms| TIMES_MS.contains(ms))
+        .ok_or(ScanError::InvalidValue)
+}
+
+impl fmt::Display for ScanParameters {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "interval={},window={},mode={},phy={},timeout={}",
+            self.interval_ms,
+            self.window_ms,
+            if self.active { "active" } else { "passive" },
+            self.phy.as_str(),
+            self.timeout_s
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse() {
+        assert_eq!(ScanParameters::parse(""), Ok(ScanParameters::DEFAULT));
+
+        let scan = ScanParameters::parse("window=30, mode=passive, phy=1m+coded").unwrap();
+        assert_eq!(scan.interval_units(), 160);
+        assert_eq!(scan.window_units(), 48);
+        assert!(!scan.active);
+        assert_eq!(scan.phy, ScanPhy::Both);
+        assert_eq!(scan.timeout_units(), 6000);
+        assert_eq!(
+            ScanParameters::parse("timeout=5").unwrap().timeout_units(),
+            500
+        );
+        assert_eq!(ScanParameters::parse(&scan.to_string()), Ok(scan));
+
+        assert_eq!(
+            ScanParameters::parse("interval=50,window=60"),
+            Err(ScanError::WindowTooLong)
+        );
+        assert_eq!(
+            ScanParameters::parse("window=200"),
+            Err(ScanError::WindowTooLong)
+        );
+        assert_eq!(
+            ScanParameters::parse("interval=2"),
+            Err(ScanError::InvalidValue)
+        );
+        assert_eq!(
+            ScanParameters::parse("interval=10241"),
+            Err(ScanError::InvalidValue)
+        );
+        assert_eq!(
+            ScanParameters::parse("timeout=0"),
+            Err(ScanError::InvalidValue)
+        );
+        assert_eq!(
+            ScanParameters::parse("timeout=61"),
+            Err(ScanError::InvalidValue)
+        );
+        assert_eq!(
+            ScanParameters::parse("mode=fast"),
+            Err(ScanError::InvalidValue)
+        );
+        assert_eq!(
+            ScanParameters::parse("duty=50"),
+            Err(ScanError::UnknownParameter)
+        );
+    }
+}
@@ -0,0 +1,370 @@
+//! Outputs pushing the readings to other collectors than the MQTT broker.
+//!
+//! Each output is enabled independently when building the firmware with an environment variable:
+//! - `AFO_INFLUX_UDP`: `host[:port]` of an InfluxDB or Telegraf UDP listener of the line protocol,
+//!   port 8089 by default.
+//! - `AFO_INFLUX_HTTP`: `host[:port]/database` of the InfluxDB 1.x HTTP API, port 8086 by default.
+//! - `AFO_GRAPHITE`: `host[:port]` of a Carbon plaintext TCP listener, port 2003 by default.
+//! - `AFO_JSON_UDP`: port the readings are broadcast to as newline-delimited JSON.
+
+use core::fmt::{self, Write};
+
+use crate::json::Escaped;
+use crate::network::{Endpoint, NetworkError};
+use crate::policy::Reading;
+
+#[cfg(feature = "embassy-net")]
+mod transport;
+#[cfg(feature = "embassy-net")]
+pub use transport::{Outputs, SocketBuffers};
+
+pub const INFLUX_UDP_PORT: u16 = 8089;
+pub const INFLUX_HTTP_PORT: u16 = 8086;
+pub const GRAPHITE_PORT: u16 = 2003;
+
+/// Name of the InfluxDB measurement and of the root of the Graphite paths
+const MEASUREMENT: &str = "afo";
+
+/// Header line of the CSV written by [`Sample::write_csv`]
+pub const CSV_HEADER: &str = "node,name,co2,temperature,humidity\n";
+
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum SinkError {
+    Network(NetworkError),
+    InvalidDatabase,
+}
+
+impl From<NetworkError> for SinkError {
+    fn from(e: NetworkError) -> Self {
+        SinkError::Network(e)
+    }
+}
+
+/// Database of the InfluxDB HTTP API the readings are written to
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct InfluxHttp<'a> {
+    pub endpoint: Endpoint<'a>,
+    pub database: &'a str,
+}
+
+impl<'a> InfluxHttp<'a> {
+    pub fn parse(config: &'a str) -> Result<Self, SinkError> {
+        let (endpoint, database) = config
+            .trim()
+            .split_once('/')
+            .ok_or(SinkError::InvalidDatabase)?;
+        // the name is used in the query string unescaped
+        if database.is_empty()
+            || !database
+                .bytes()
+                .all(|b| b.is_ascii_alphanumeric() || b == b'_' || b == b'-')
+        {
+            return Err(SinkError::InvalidDatabase);
+        }
+        Ok(Self {
+            endpoint: Endpoint::parse(endpoint, INFLUX_HTTP_PORT)?,
+            database,
+        })
+    }
+
+    /// Writes the head of the request writing a body of the line protocol,
+    /// the connection is closed after the response
+    pub fn write_request_head(&self, body_len: usize, w: &mut impl Write) -> fmt::Result {
+        write!(
+            w,
+            "POST /write?db={} HTTP/1.1\r\nHost: {}:{}\r\nContent-Type: text/plain\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+            self.database, self.endpoint.host, self.endpoint.port, body_len
+        )
+    }
+}
+
+/// Whether the response of the HTTP API reports the readings as written
+pub fn is_success_response(response: &[u8]) -> bool {
+    // e.g. `HTTP/1.1 204 No Content`
+    matches!(response.get(8..11), Some([b' ', b'2', _])) && response.starts_with(b"HTTP/1.")
+}
+
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub struct SinkConfig<'a> {
+    pub influx_udp: Option<Endpoint<'a>>,
+    pub influx_http: Option<InfluxHttp<'a>>,
+    pub graphite: Option<Endpoint<'a>>,
+    /// Port of the JSON broadcast
+    pub json_udp: Option<u16>,
+}
+
+impl SinkConfig<'static> {
+    /// All the outputs disabled
+    pub const DEFAULT: Self = Self {
+        influx_udp: None,
+        influx_http: None,
+        graphite: None,
+        json_udp: None,
+    };
+
+    /// Outputs enabled by the environment variables at build time
+    pub fn from_env() -> Result<Self, SinkError> {
+        Self::parse(
+            option_env!("AFO_INFLUX_UDP"),
+            option_env!("AFO_INFLUX_HTTP"),
+            option_env!("AFO_GRAPHITE"),
+            option_env!("AFO_JSON_UDP"),
+        )
+    }
+}
+
+impl<'a> SinkConfig<'a> {
+    pub fn parse(
+        influx_udp: Option<&'a str>,
+        influx_http: Option<&'a str>,
+        graphite: Option<&'a str>,
+        json_udp: Option<&str>,
+    ) -> Result<Self, SinkError> {
+        Ok(Self {
+            influx_udp: influx_udp
+                .map(|endpoint| Endpoint::parse(endpoint, INFLUX_UDP_PORT))
+                .transpose()?,
+            influx_http: influx_http.map(InfluxHttp::parse).transpose()?,
+            graphite: graphite
+                .map(|endpoint| Endpoint::parse(endpoint, GRAPHITE_PORT))
+                .transpose()?,
+            json_udp: json_udp
+                .map(|port| port.trim().parse().map_err(|_| NetworkError::InvalidPort))
+                .transpose()?,
+        })
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        *self != Self::default()
+    }
+}
+
+/// Reading of a node formatted by the outputs
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct Sample<'a> {
+    /// Id of the node, the sensor id or the name of a node publishing directly
+    pub node: &'a str,
+    pub name: &'a str,
+    pub reading: Reading,
+}
+
+impl Sample<'_> {
+    fn temperature(&self) -> f32 {
+        f32::from(self.reading.temperature) / 10.0
+    }
+
+    /// Writes a line of the InfluxDB line protocol, the time is set by the server
+    pub fn write_influx(&self, w: &mut impl Write) -> fmt::Result {
+        writeln!(
+            w,
+            "{},node={},name={} co2={}i,temperature={},humidity={}i",
+            MEASUREMENT,
+            TagValue(self.node),
+            TagValue(self.name),
+            self.reading.co2,
+            self.temperature(),
+            self.reading.humidity
+        )
+    }
+
+    /// Writes a line of the Graphite plaintext protocol for each quantity,
+    /// the timestamp `-1` makes Carbon use the time of reception
+    pub fn write_graphite(&self, w: &mut impl Write) -> fmt::Result {
+        let path = PathSegment(self.node);
+        writeln!(w, "{}.{}.co2 {} -1", MEASUREMENT, path, self.reading.co2)?;
+        writeln!(
+            w,
+            "{}.{}.temperature {} -1",
+            MEASUREMENT,
+            path,
+            self.temperature()
+        )?;
+        writeln!(
+            w,
+            "{}.{}.humidity {} -1",
+            MEASUREMENT, path, self.reading.humidity
+        )
+    }
+
+    /// Writes a line of JSON
+    pub fn write_json(&self, w: &mut impl Write) -> fmt::Result {
+        writeln!(
+            w,
+            r#"{{"node": {}, "name": {}, "co2": {}, "temperature": {}, "humidity": {}}}"#,
+            Escaped(self.node),
+            Escaped(self.name),
+            self.reading.co2,
+            self.temperature(),
+            self.reading.humidity
+        )
+    }
+
+    /// Writes a line of CSV with the columns of [`CSV_HEADER`]
+    pub fn write_csv(&self, w: &mut impl Write) -> fmt::Result {
+        writeln!(
+            w,
+            "{},{},{},{},{}",
+            CsvField(self.node),
+            CsvField(self.name),
+            self.reading.co2,
+            self.temperature(),
+            self.reading.humidity
+        )
+    }
+}
+
+/// Quotes a CSV field containing a separator or a quote
+struct CsvField<'a>(&'a str);
+
+impl fmt::Display for CsvField<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if !self.0.contains([',', '"', '\n', '\r']) {
+            return f.write_str(self.0);
+        }
+        f.write_char('"')?;
+        for c in self.0.chars() {
+            if c == '"' {
+                f.write_char('"')?;
+            }
+            f.write_char(c)?;
+        }
+        f.write_char('"')
+    }
+}
+
+/// Escapes a tag value of the line protocol
+struct TagValue<'a>(&'a str);
+
+impl fmt::Display for TagValue<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for c in self.0.chars() {
+            if matches!(c, ',' | '=' | ' ') {
+                f.write_char('\\')?;
+            }
+            f.write_char(c)?;
+        }
+        Ok(())
+    }
+}
+
+/// Replaces the characters separating the Graphite path
+struct PathSegment<'a>(&'a str);
+
+impl fmt::Display for PathSegment<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for c in self.0.chars() {
+            f.write_char(if matches!(c, '.' | ' ') { '_' } else { c })?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::network::Host;
+
+    const SAMPLE: Sample = Sample {
+        node: "42",
+        name: "AFO 42",
+        reading: Reading {
+            co2: 812,
+            temperature: 224,
+            humidity: 41,
+        },
+    };
+
+    #[test]
+    fn parses_config() {
+        assert!(!SinkConfig::DEFAULT.is_enabled());
+
+        let config = SinkConfig::parse(
+            Some("10.42.0.1"),
+            Some("influx.local/afo"),
+            Some("carbon.lan:2004"),
+            Some("4242"),
+        )
+        .unwrap();
+        assert!(config.is_enabled());
+        assert_eq!(
+            config.influx_udp,
+            Some(Endpoint {
+                host: Host::Address([10, 42, 0, 1]),
+                port: INFLUX_UDP_PORT,
+            })
+        );
+        assert_eq!(
+            config.influx_http,
+            Some(InfluxHttp {
+                endpoint: Endpoint {
+                    host: Host::Name("influx.local"),
+                    port: INFLUX_HTTP_PORT,
+                },
+                database: "afo",
+            })
+        );
+        assert_eq!(config.graphite.unwrap().port, 2004);
+        assert_eq!(config.json_udp, Some(4242));
+
+        assert_eq!(
+            SinkConfig::parse(None, Some("influx.local:8086"), None, None),
+            Err(SinkError::InvalidDatabase)
+        );
+        assert_eq!(
+            SinkConfig::parse(None, Some("influx.local/a&b"), None, None),
+            Err(SinkError::InvalidDatabase)
+        );
+        assert_eq!(
+            SinkConfig::parse(None, None, None, Some("json")),
+            Err(SinkError::Network(NetworkError::InvalidPort))
+        );
+    }
+
+    #[test]
+    fn writes_samples() {
+        let mut out = String::new();
+        SAMPLE.write_influx(&mut out).unwrap();
+        assert_eq!(
+            out,
+            "afo,node=42,name=AFO\\ 42 co2=812i,temperature=22.4,humidity=41i\n"
+        );
+
+        out.clear();
+        SAMPLE.write_graphite(&mut out).unwrap();
+        assert_eq!(
+            out,
+            "afo.42.co2 812 -1\nafo.42.temperature 22.4 -1\nafo.42.humidity 41 -1\n"
+        );
+
+        out.clear();
+        SAMPLE.write_json(&mut out).unwrap();
+        assert_eq!(
+            out,
+            "{\"node\": \"42\", \"name\": \"AFO 42\", \"co2\": 812, \"temperature\": 22.4, \"humidity\": 41}\n"
+        );
+
+        out.clear();
+        SAMPLE.write_csv(&mut out).unwrap();
+        Sample {
+            name: "kitchen, \"north\"",
+            ..SAMPLE
+        }
+        .write_csv(&mut out)
+        .unwrap();
+        assert_eq!(
+            out,
+            "42,AFO 42,812,22.4,41\n42,\"kitchen, \"\"north\"\"\",812,22.4,41\n"
+        );
+
+        out.clear();
+        let influx = InfluxHttp::parse("10.42.0.1/afo").unwrap();
+        influx.write_request_head(64, &mut out).unwrap();
+        assert!(out.starts_with("POST /write?db=afo HTTP/1.1\r\nHost: 10.42.0.1:8086\r\n"));
+        assert!(out.ends_with("Content-Length: 64\r\nConnection: close\r\n\r\n"));
+
+        assert!(is_success_response(b"HTTP/1.1 204 No Content\r\n"));
+        assert!(!is_success_response(b"HTTP/1.1 404 Not Found\r\n"));
+        assert!(!is_success_response(b"HTTP/1.1"));
+    }
+}
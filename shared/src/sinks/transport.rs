@@ -0,0 +1,202 @@
+//! Pushing the readings to the outputs enabled in [`SinkConfig`] over an `embassy-net` stack.
+//!
+//! The readings are not queued, a reading which fails to be sent to an output is dropped.
+
+use super::{is_success_response, InfluxHttp, Sample, SinkConfig};
+use crate::network::Endpoint;
+use crate::resolve::resolve;
+use embassy_net::driver::Driver;
+use embassy_net::tcp::TcpSocket;
+use embassy_net::udp::{PacketMetadata, UdpSocket};
+use embassy_net::{Ipv4Address, Stack};
+use embassy_time::{with_timeout, Duration};
+use embedded_io_async::Write as _;
+
+/// Time allowed for connecting to an output and for its response
+const SINK_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Lines of a single sample in any of the formats
+type Lines = heapless::String<256>;
+
+/// Buffers of the socket the datagrams of the [`Outputs`] are sent from
+pub struct SocketBuffers {
+    rx_meta: [PacketMetadata; 1],
+    tx_meta: [PacketMetadata; 4],
+    rx: [u8; 64],
+    tx: [u8; 1024],
+}
+
+impl SocketBuffers {
+    pub const fn new() -> Self {
+        Self {
+            rx_meta: [PacketMetadata::EMPTY; 1],
+            tx_meta: [PacketMetadata::EMPTY; 4],
+            rx: [0; 64],
+            tx: [0; 1024],
+        }
+    }
+}
+
+impl Default for SocketBuffers {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// The outputs enabled besides MQTT
+pub struct Outputs<'a, D: Driver> {
+    stack: &'a Stack<D>,
+    socket: UdpSocket<'a>,
+    config: SinkConfig<'a>,
+}
+
+impl<'a, D: Driver> Outputs<'a, D> {
+    pub fn new(
+        stack: &'a Stack<D>,
+        buffers: &'a mut SocketBuffers,
+        config: SinkConfig<'a>,
+    ) -> Self {
+        let SocketBuffers {
+            rx_meta,
+            tx_meta,
+            rx,
+            tx,
+        } = buffers;
+        let mut socket = UdpSocket::new(stack, rx_meta, rx, tx_meta, tx);
+        defmt::unwrap!(socket.bind(0));
+        Self {
+            stack,
+            socket,
+            config,
+        }
+    }
+
+    /// Sends the sample to all the enabled outputs
+    pub async fn push(&mut self, sample: &Sample<'_>) {
+        push(self.stack, &mut self.socket, &self.config, sample).await;
+    }
+}
+
+/// Sends the sample to all the enabled outputs, the datagrams are sent using the bound socket
+async fn push<D: Driver>(
+    stack: &Stack<D>,
+    socket: &mut UdpSocket<'_>,
+    config: &SinkConfig<'_>,
+    sample: &Sample<'_>,
+) {
+    let mut lines = Lines::new();
+    if let Some(endpoint) = config.influx_udp {
+        sample.write_influx(&mut lines).unwrap();
+        if let Some(address) = resolve(stack, endpoint.host).await {
+            if let Err(e) = socket
+                .send_to(lines.as_bytes(), (address, endpoint.port))
+                .await
+            {
+                defmt::warn!("Failed to send to InfluxDB: {:?}", e);
+            }
+        }
+        lines.clear();
+    }
+    if let Some(port) = config.json_udp {
+        sample.write_json(&mut lines).unwrap();
+        if let Err(e) = socket
+            .send_to(lines.as_bytes(), (Ipv4Address::BROADCAST, port))
+            .await
+        {
+            defmt::warn!("Failed to broadcast the JSON: {:?}", e);
+        }
+        lines.clear();
+    }
+    if let Some(influx) = config.influx_http {
+        sample.write_influx(&mut lines).unwrap();
+        post_influx(stack, &influx, &lines).await;
+        lines.clear();
+    }
+    if let Some(endpoint) = config.graphite {
+        sample.write_graphite(&mut lines).unwrap();
+        send_graphite(stack, endpoint, &lines).await;
+    }
+}
+
+/// Writes the lines using the InfluxDB HTTP API
+async fn post_influx<D: Driver>(stack: &Stack<D>, influx: &InfluxHttp<'_>, lines: &str) {
+    let mut rx_buffer = [0; 256];
+    let mut tx_buffer = [0; 512];
+    let Some(mut socket) = connect(stack, influx.endpoint, &mut rx_buffer, &mut tx_buffer).await
+    else {
+        return;
+    };
+    let mut head = heapless::String::<192>::new();
+    influx.write_request_head(lines.len(), &mut head).unwrap();
+    let mut response = [0; 32];
+    let result = async {
+        socket.write_all(head.as_bytes()).await?;
+        socket.write_all(lines.as_bytes()).await?;
+        // the status line is enough, the rest of the response is discarded
+        let mut len = 0;
+        while len < response.len() {
+            match socket.read(&mut response[len..]).await? {
+                0 => break,
+                read => len += read,
+            }
+        }
+        Ok::<_, embassy_net::tcp::Error>(len)
+    }
+    .await;
+    match result {
+        Ok(len) if is_success_response(&response[..len]) => {}
+        Ok(_) => defmt::warn!("InfluxDB rejected the readings"),
+        Err(e) => defmt::warn!("Failed to send to InfluxDB: {:?}", e),
+    }
+    socket.close();
+    if socket.flush().await.is_err() {
+        socket.abort();
+    }
+}
+
+/// Sends the lines to Carbon using the plaintext protocol
+async fn send_graphite<D: Driver>(stack: &Stack<D>, endpoint: Endpoint<'_>, lines: &str) {
+    let mut rx_buffer = [0; 64];
+    let mut tx_buffer = [0; 256];
+    let Some(mut socket) = connect(stack, endpoint, &mut rx_buffer, &mut tx_buffer).await else {
+        return;
+    };
+    if let Err(e) = socket.write_all(lines.as_bytes()).await {
+        defmt::warn!("Failed to send to Graphite: {:?}", e);
+    }
+    socket.close();
+    if socket.flush().await.is_err() {
+        socket.abort();
+    }
+}
+
+async fn connect<'a, D: Driver>(
+    stack: &'a Stack<D>,
+    endpoint: Endpoint<'_>,
+    rx_buffer: &'a mut [u8],
+    tx_buffer: &'a mut [u8],
+) -> Option<TcpSocket<'a>> {
+    let address = resolve(stack, endpoint.host).await?;
+    let mut socket = TcpSocket::new(stack, rx_buffer, tx_buffer);
+    socket.set_timeout(Some(SINK_TIMEOUT));
+    match with_timeout(SINK_TIMEOUT, socket.connect((address, endpoint.port))).await {
+        Ok(Ok(())) => Some(socket),
+        Ok(Err(e)) => {
+            defmt::warn!(
+                "Failed to connect to {}:{}: {:?}",
+                defmt::Display2Format(&endpoint.host),
+                endpoint.port,
+                e
+            );
+            None
+        }
+        Err(_) => {
+            defmt::warn!(
+                "Timed out connecting to {}:{}",
+                defmt::Display2Format(&endpoint.host),
+                endpoint.port
+            );
+            None
+        }
+    }
+}
@@ -0,0 +1,254 @@
+//! Statistics of the readings of a node over consecutive windows of fixed length.
+//!
+//! The windows are aligned to multiples of their length since boot, so that the windows
+//! of all the nodes close at the same time. The window lengths are set when building
+//! the firmware with the `AFO_STATS_WINDOWS` environment variable, a comma separated list
+//! of lengths in seconds, `300,3600` by default.
+
+use core::fmt::{self, Write};
+
+use heapless::Vec;
+
+use crate::policy::Reading;
+
+/// Maximum number of windows per node
+pub const MAX_WINDOWS: usize = 4;
+
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum WindowsError {
+    InvalidLength,
+    TooMany,
+}
+
+/// Window lengths in seconds set by the environment variable at build time
+pub fn windows_from_env() -> Result<Vec<u32, MAX_WINDOWS>, WindowsError> {
+    parse_windows(option_env!("AFO_STATS_WINDOWS").unwrap_or("300,3600"))
+}
+
+/// Parses a comma separated list of window lengths in seconds
+pub fn parse_windows(config: &str) -> Result<Vec<u32, MAX_WINDOWS>, WindowsError> {
+    let mut windows = Vec::new();
+    for length in config.split(',').map(str::trim).filter(|l| !l.is_empty()) {
+        let length = length
+            .parse::<u32>()
+            .ok()
+            .filter(|length| *length > 0)
+            .ok_or(WindowsError::InvalidLength)?;
+        windows.push(length).map_err(|_| WindowsError::TooMany)?;
+    }
+    Ok(windows)
+}
+
+/// Running statistics of a metric, using Welford's algorithm to keep the variance accurate
+#[derive(Clone, Copy, Debug, Default)]
+struct Accumulator {
+    count: u32,
+    min: f32,
+    max: f32,
+    mean: f32,
+    /// Sum of the squared differences from the mean
+    m2: f32,
+}
+
+impl Accumulator {
+    fn add(&mut self, value: f32) {
+        if self.count == 0 {
+            self.min = value;
+            self.max = value;
+        } else {
+            self.min = self.min.min(value);
+            self.max = self.max.max(value);
+        }
+        self.count += 1;
+        let delta = value - self.mean;
+        self.mean += delta / self.count as f32;
+        self.m2 += delta * (value - self.mean);
+    }
+
+    fn summary(&self) -> Summary {
+        Summary {
+            min: self.min,
+            max: self.max,
+            mean: self.mean,
+            std_dev: libm::sqrtf(self.m2 / self.count.max(1) as f32),
+        }
+    }
+}
+
+/// Statistics of a metric over a window
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Summary {
+    pub min: f32,
+    pub max: f32,
+    pub mean: f32,
+    /// Population standard deviation
+    pub std_dev: f32,
+}
+
+/// Statistics of the readings of a closed window
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct WindowSummary {
+    /// Length of the window in seconds
+    pub length: u32,
+    /// End of the window in ms since boot
+    pub end_ms: u64,
+    pub samples: u32,
+    pub co2: Summary,
+    /// In °C
+    pub temperature: Summary,
+    pub humidity: Summary,
+}
+
+impl WindowSummary {
+    /// Writes the statistics as a flat JSON object, along with the name of the publishing bridge
+    pub fn write_json(&self, bridge: &str, w: &mut impl Write) -> fmt::Result {
+        write!(
+            w,
+            r#"{{"window": {}, "samples": {}, "#,
+            self.length, self.samples
+        )?;
+        for (name, summary, precision) in [
+            ("co2", &self.co2, 0),
+            ("temperature", &self.temperature, 1),
+            ("humidity", &self.humidity, 0),
+        ] {
+            write!(
+                w,
+                r#""{name}_min": {:.precision$}, "{name}_max": {:.precision$}, "{name}_mean": {:.precision$}, "{name}_std_dev": {:.2}, "#,
+                summary.min, summary.max, summary.mean, summary.std_dev,
+            )?;
+        }
+        write!(w, r#""bridge": "{}"}}"#, bridge)
+    }
+}
+
+/// Statistics of the readings of a node over consecutive windows of one length
+#[derive(Clone, Copy, Debug)]
+pub struct Window {
+    length: u32,
+    start_ms: u64,
+    co2: Accumulator,
+    temperature: Accumulator,
+    humidity: Accumulator,
+    /// Last closed window not taken yet
+    closed: Option<WindowSummary>,
+}
+
+impl Window {
+    /// Creates a window of `length` seconds
+    pub fn new(length: u32, now_ms: u64) -> Self {
+        Self {
+            length,
+            start_ms: Self::align(length, now_ms),
+            co2: Accumulator::default(),
+            temperature: Accumulator::default(),
+            humidity: Accumulator::default(),
+            closed: None,
+        }
+    }
+
+    fn align(length: u32, now_ms: u64) -> u64 {
+        now_ms - now_ms % (length as u64 * 1000)
+    }
+
+    /// Closes the window once its end has passed, windows without samples are dropped
+    fn roll(&mut self, now_ms: u64) {
+        let end_ms = self.start_ms + self.length as u64 * 1000;
+        if now_ms < end_ms {
+            return;
+        }
+        if self.co2.count > 0 {
+            self.closed = Some(WindowSummary {
+                length: self.length,
+                end_ms,
+                samples: self.co2.count,
+                co2: self.co2.summary(),
+                temperature: self.temperature.summary(),
+                humidity: self.humidity.summary(),
+            });
+        }
+        *self = Self {
+            closed: self.closed,
+            ..Self::new(self.length, now_ms)
+        };
+    }
+
+    pub fn add(&mut self, reading: &Reading, now_ms: u64) {
+        self.roll(now_ms);
+        self.co2.add(reading.co2 as f32);
+        self.temperature.add(reading.temperature as f32 * 0.1);
+        self.humidity.add(reading.humidity as f32);
+    }
+
+    /// Takes the statistics of the last closed window, if not taken yet
+    pub fn take_closed(&mut self, now_ms: u64) -> Option<WindowSummary> {
+        self.roll(now_ms);
+        self.closed.take()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn reading(co2: u16, temperature: i16, humidity: u8) -> Reading {
+        Reading {
+            co2,
+            temperature,
+            humidity,
+        }
+    }
+
+    #[test]
+    fn windows_config() {
+        assert_eq!(parse_windows("300, 3600").unwrap(), [300, 3600]);
+        assert_eq!(parse_windows("").unwrap(), []);
+        assert_eq!(parse_windows("0"), Err(WindowsError::InvalidLength));
+        assert_eq!(parse_windows("5m"), Err(WindowsError::InvalidLength));
+        assert_eq!(parse_windows("1,2,3,4,5"), Err(WindowsError::TooMany));
+    }
+
+    #[test]
+    fn closes_aligned_windows() {
+        let mut window = Window::new(300, 100_000);
+        window.add(&reading(800, 210, 40), 100_000);
+        window.add(&reading(900, 220, 40), 200_000);
+        window.add(&reading(1000, 230, 40), 299_999);
+        assert_eq!(window.take_closed(299_999), None);
+
+        let summary = window.take_closed(300_000).unwrap();
+        assert_eq!(summary.length, 300);
+        assert_eq!(summary.end_ms, 300_000);
+        assert_eq!(summary.samples, 3);
+        assert_eq!(summary.co2.min, 800.0);
+        assert_eq!(summary.co2.max, 1000.0);
+        assert!((summary.co2.mean - 900.0).abs() < 0.01);
+        assert!((summary.co2.std_dev - 81.65).abs() < 0.01);
+        assert!((summary.temperature.mean - 22.0).abs() < 0.01);
+        assert_eq!(summary.humidity.std_dev, 0.0);
+        assert_eq!(window.take_closed(300_000), None);
+
+        // windows without samples are not published
+        window.add(&reading(800, 210, 40), 1_000_000);
+        assert_eq!(window.take_closed(1_199_999), None);
+        assert_eq!(window.take_closed(1_200_000).unwrap().samples, 1);
+    }
+
+    #[test]
+    fn json() {
+        let mut window = Window::new(300, 0);
+        window.add(&reading(800, 215, 40), 0);
+        window.add(&reading(820, 225, 42), 6_000);
+        let mut json = String::new();
+        window
+            .take_closed(300_000)
+            .unwrap()
+            .write_json("afo-bridge-2", &mut json)
+            .unwrap();
+        assert_eq!(
+            json,
+            r#"{"window": 300, "samples": 2, "co2_min": 800, "co2_max": 820, "co2_mean": 810, "co2_std_dev": 10.00, "temperature_min": 21.5, "temperature_max": 22.5, "temperature_mean": 22.0, "temperature_std_dev": 0.50, "humidity_min": 40, "humidity_max": 42, "humidity_mean": 41, "humidity_std_dev": 1.00, "bridge": "afo-bridge-2"}"#
+        );
+    }
+}
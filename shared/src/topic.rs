@@ -0,0 +1,311 @@
+//! Configurable MQTT topics.
+//!
+//! Topics are built from templates with the placeholders `{prefix}`, `{bridge}`, `{node}`
+//! and, in the metric template, `{metric}`. The templates are set when building the firmware
+//! with the `AFO_TOPIC_PREFIX`, `AFO_STATE_TOPIC` and `AFO_METRIC_TOPIC` environment variables,
+//! the bridge keeps them in its stored configuration.
+//!
+//! The other topics, see [`Topic`], have fixed templates starting with the prefix, so that
+//! a deployment sharing a broker with another one only needs to change the prefix.
+
+use core::fmt::{self, Write};
+
+/// Longest prefix, so that every topic fits in 64 bytes
+pub const MAX_PREFIX_LEN: usize = 32;
+
+/// Topic configuration of the measurements
+#[derive(Clone, Copy, Debug)]
+pub struct TopicConfig<'a> {
+    pub prefix: &'a str,
+    /// Template of the topic the measurements are published to as a JSON object
+    pub state: &'a str,
+    /// Template of the topics each metric is published to as a plain number, if enabled
+    pub metric: Option<&'a str>,
+}
+
+impl TopicConfig<'static> {
+    /// Configuration set by the environment variables at build time.
+    /// The defaults keep the original `afo-<sensor id>` topics with no per-metric topics.
+    pub const fn from_env() -> Self {
+        Self {
+            prefix: match option_env!("AFO_TOPIC_PREFIX") {
+                Some(prefix) => prefix,
+                None => "afo",
+            },
+            state: match option_env!("AFO_STATE_TOPIC") {
+                Some(state) => state,
+                None => "{prefix}-{node}",
+            },
+            metric: option_env!("AFO_METRIC_TOPIC"),
+        }
+    }
+}
+
+/// Topics besides the measurements
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Topic {
+    /// Health of a node, the bridge or the C3 node, `{prefix}-{node}-diagnostics`
+    Diagnostics,
+    /// Link quality of a node, `{prefix}-{node}-link`
+    Link,
+    /// Statistics of a node over a window in seconds, `{prefix}-{node}-stats-{window}`
+    Stats { window: u32 },
+    /// Readings queued while the broker was unreachable, `{prefix}-{node}-backfill`
+    Backfill,
+    /// Last crash of the bridge or the C3 node, `{prefix}-{node}-crash`
+    Crash,
+    /// Availability of the bridge or the C3 node, `{prefix}-{node}-availability`
+    Availability,
+    /// Claim of a node by a bridge, `{prefix}-owner/{node}`
+    Claim,
+}
+
+impl Topic {
+    fn template(&self) -> &'static str {
+        match self {
+            Topic::Diagnostics => "{prefix}-{node}-diagnostics",
+            Topic::Link => "{prefix}-{node}-link",
+            Topic::Stats { .. } => "{prefix}-{node}-stats-{window}",
+            Topic::Backfill => "{prefix}-{node}-backfill",
+            Topic::Crash => "{prefix}-{node}-crash",
+            Topic::Availability => "{prefix}-{node}-availability",
+            Topic::Claim => "{prefix}-owner/{node}",
+        }
+    }
+}
+
+/// Values of the placeholders
+#[derive(Clone, Copy)]
+pub struct TopicValues<'a> {
+    /// Name of the publishing bridge, nodes publishing directly use their own name
+    pub bridge: &'a str,
+    pub node: &'a dyn fmt::Display,
+}
+
+impl TopicConfig<'_> {
+    /// Writes the state topic
+    pub fn write_state(&self, values: &TopicValues, w: &mut impl Write) -> fmt::Result {
+        self.expand(self.state, values, None, w)
+    }
+
+    /// Writes one of the other topics, `node` is the sensor id of a node,
+    /// or the name of the bridge or the C3 node for their own topics
+    pub fn write_topic(
+        &self,
+        topic: Topic,
+        node: &dyn fmt::Display,
+        w: &mut impl Write,
+    ) -> fmt::Result {
+        let values = TopicValues { bridge: "", node };
+        let extra = match &topic {
+            Topic::Stats { window } => Some(("window", window as &dyn fmt::Display)),
+            _ => None,
+        };
+        self.expand(topic.template(), &values, extra, w)
+    }
+
+    /// Writes the topic of a metric, fails if the per-metric topics are disabled
+    pub fn write_metric(
+        &self,
+        values: &TopicValues,
+        metric: &str,
+        w: &mut impl Write,
+    ) -> fmt::Result {
+        let template = self.metric.ok_or(fmt::Error)?;
+        self.expand(template, values, Some(("metric", &metric)), w)
+    }
+
+    /// Checks the templates, the topics can't be empty or contain the wildcards of the filters
+    pub fn validate(&self) -> fmt::Result {
+        if self.state.is_empty() || self.metric == Some("") {
+            return Err(fmt::Error);
+        }
+        let values = TopicValues {
+            bridge: "bridge",
+            node: &0,
+        };
+        Checker.write_str(self.prefix)?;
+        self.write_state(&values, &mut Checker)?;
+        if self.metric.is_some() {
+            self.write_metric(&values, "co2", &mut Checker)?;
+        }
+        Ok(())
+    }
+
+    /// Expands the template, `extra` is the name and the value of the placeholder
+    /// specific to the template
+    fn expand(
+        &self,
+        template: &str,
+        values: &TopicValues,
+        extra: Option<(&str, &dyn fmt::Display)>,
+        w: &mut impl Write,
+    ) -> fmt::Result {
+        let mut rest = template;
+        while let Some(start) = rest.find('{') {
+            w.write_str(&rest[..start])?;
+            let end = rest[start..].find('}').ok_or(fmt::Error)? + start;
+            match &rest[start + 1..end] {
+                "prefix" => w.write_str(self.prefix)?,
+                "bridge" => w.write_str(values.bridge)?,
+                "node" => write!(w, "{}", values.node)?,
+                name => match extra {
+                    Some((extra, value)) if extra == name => write!(w, "{}", value)?,
+                    _ => return Err(fmt::Error),
+                },
+            }
+            rest = &rest[end + 1..];
+        }
+        w.write_str(rest)
+    }
+}
+
+/// Fails on the characters topics can't contain
+struct Checker;
+
+impl Write for Checker {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        if s.contains(['+', '#', '\0']) {
+            Err(fmt::Error)
+        } else {
+            Ok(())
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn default_topics() {
+        let config = TopicConfig {
+            prefix: "afo",
+            state: "{prefix}-{node}",
+            metric: None,
+        };
+        let values = TopicValues {
+            bridge: "afo-bridge-2",
+            node: &42,
+        };
+
+        let mut topic = String::new();
+        config.write_state(&values, &mut topic).unwrap();
+        assert_eq!(topic, "afo-42");
+
+        assert!(config
+            .write_metric(&values, "co2", &mut String::new())
+            .is_err());
+    }
+
+    #[test]
+    fn hierarchical_topics() {
+        let config = TopicConfig {
+            prefix: "home/afo",
+            state: "{prefix}/{bridge}/{node}/state",
+            metric: Some("{prefix}/{bridge}/{node}/{metric}"),
+        };
+        let values = TopicValues {
+            bridge: "afo-bridge-2",
+            node: &42,
+        };
+
+        let mut topic = String::new();
+        config.write_state(&values, &mut topic).unwrap();
+        assert_eq!(topic, "home/afo/afo-bridge-2/42/state");
+
+        let mut topic = String::new();
+        config.write_metric(&values, "co2", &mut topic).unwrap();
+        assert_eq!(topic, "home/afo/afo-bridge-2/42/co2");
+    }
+
+    #[test]
+    fn other_topics() {
+        let config = TopicConfig {
+            prefix: "afo",
+            state: "{prefix}-{node}",
+            metric: None,
+        };
+        let topic = |t, node: &dyn fmt::Display| {
+            let mut topic = String::new();
+            config.write_topic(t, node, &mut topic).unwrap();
+            topic
+        };
+        assert_eq!(topic(Topic::Diagnostics, &42), "afo-42-diagnostics");
+        assert_eq!(topic(Topic::Link, &42), "afo-42-link");
+        assert_eq!(topic(Topic::Stats { window: 300 }, &42), "afo-42-stats-300");
+        assert_eq!(topic(Topic::Backfill, &42), "afo-42-backfill");
+        assert_eq!(topic(Topic::Claim, &42), "afo-owner/42");
+        assert_eq!(topic(Topic::Claim, &"+"), "afo-owner/+");
+        assert_eq!(
+            topic(Topic::Diagnostics, &"bridge-2"),
+            "afo-bridge-2-diagnostics"
+        );
+        assert_eq!(topic(Topic::Crash, &"bridge-2"), "afo-bridge-2-crash");
+        assert_eq!(
+            topic(Topic::Availability, &"bridge-2"),
+            "afo-bridge-2-availability"
+        );
+        assert_eq!(topic(Topic::Crash, &"c3"), "afo-c3-crash");
+        assert_eq!(topic(Topic::Availability, &"c3"), "afo-c3-availability");
+    }
+
+    #[test]
+    fn other_topics_follow_the_prefix() {
+        let config = TopicConfig {
+            prefix: "home/afo",
+            state: "{prefix}/{node}",
+            metric: None,
+        };
+        let mut topic = String::new();
+        config
+            .write_topic(Topic::Diagnostics, &42, &mut topic)
+            .unwrap();
+        assert_eq!(topic, "home/afo-42-diagnostics");
+
+        let mut topic = String::new();
+        config.write_topic(Topic::Claim, &"+", &mut topic).unwrap();
+        assert_eq!(topic, "home/afo-owner/+");
+
+        let mut topic = String::new();
+        config.write_topic(Topic::Crash, &"c3", &mut topic).unwrap();
+        assert_eq!(topic, "home/afo-c3-crash");
+    }
+
+    #[test]
+    fn invalid_templates() {
+        let values = TopicValues {
+            bridge: "c3",
+            node: &"c3",
+        };
+        for state in [
+            "{prefix}/{unknown}",
+            "{prefix}/{node",
+            "{metric}",
+            "{window}",
+        ] {
+            let config = TopicConfig {
+                prefix: "afo",
+                state,
+                metric: None,
+            };
+            assert!(config.write_state(&values, &mut String::new()).is_err());
+            assert!(config.validate().is_err());
+        }
+
+        let mut config = TopicConfig {
+            prefix: "home/afo",
+            state: "{prefix}/{node}",
+            metric: Some("{prefix}/{node}/{metric}"),
+        };
+        assert!(config.validate().is_ok());
+        config.prefix = "home/+";
+        assert!(config.validate().is_err());
+        config.prefix = "afo";
+        config.metric = Some("{prefix}/#");
+        assert!(config.validate().is_err());
+        config.metric = None;
+        config.state = "";
+        assert!(config.validate().is_err());
+    }
+}
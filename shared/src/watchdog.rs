@@ -0,0 +1,277 @@
+//! Supervision of tasks feeding the hardware watchdog.
+//!
+//! Every critical task periodically checks in, the watchdog is fed only while
+//! all of the tasks have checked in within their deadlines. The tasks are an enum of
+//! each firmware implementing [`Task`], the hardware watchdog implements [`Watchdog`].
+//! The watchdog of the nRF52 firmwares is in [`nrf`].
+
+use core::marker::PhantomData;
+
+use crate::reset::ResetReason;
+
+/// Maximum number of tasks of a firmware
+pub const MAX_TASKS: usize = 4;
+
+/// Task that has to check in for the watchdog to be fed
+pub trait Task: Copy + 'static {
+    /// All the tasks, up to [`MAX_TASKS`]
+    const ALL: &'static [Self];
+
+    /// Position of the task in [`Task::ALL`]
+    fn index(self) -> usize;
+
+    fn as_str(&self) -> &'static str;
+
+    /// Longest time between two check-ins in milliseconds
+    fn deadline_ms(self) -> u64;
+
+    fn from_index(index: usize) -> Option<Self> {
+        Self::ALL.get(index).copied()
+    }
+}
+
+/// Hardware watchdog fed by [`supervise`]
+pub trait Watchdog {
+    fn feed(&mut self);
+
+    /// Stores the value identifying the starved task, in memory retained across
+    /// the watchdog reset, to be decoded by [`ResetInfo::new`]
+    fn record_starved(&mut self, value: u32);
+}
+
+/// Tracks the last check-in of the tasks, timestamps are in milliseconds since boot
+#[derive(Clone, Copy, Debug)]
+pub struct TaskMonitor<T> {
+    last_check_in: [u64; MAX_TASKS],
+    tasks: PhantomData<T>,
+}
+
+impl<T: Task> TaskMonitor<T> {
+    /// Tasks are considered to have checked in at boot
+    pub const fn new() -> Self {
+        assert!(T::ALL.len() <= MAX_TASKS);
+        Self {
+            last_check_in: [0; MAX_TASKS],
+            tasks: PhantomData,
+        }
+    }
+
+    pub fn check_in(&mut self, task: T, now: u64) {
+        self.last_check_in[task.index()] = now;
+    }
+
+    /// Returns the first task that has missed its deadline
+    pub fn overdue(&self, now: u64) -> Option<T> {
+        T::ALL
+            .iter()
+            .copied()
+            .find(|&task| now.saturating_sub(self.last_check_in[task.index()]) > task.deadline_ms())
+    }
+}
+
+impl<T: Task> Default for TaskMonitor<T> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Records the task with a check-in now
+#[cfg(feature = "embassy-time")]
+pub fn check_in<T: Task>(monitor: &mut TaskMonitor<T>, task: T) {
+    monitor.check_in(task, embassy_time::Instant::now().as_millis());
+}
+
+/// Feeds the watchdog while `overdue` returns `None`.
+/// Once a task misses its deadline, it is recorded to be reported after the reset.
+#[cfg(feature = "embassy-time")]
+pub async fn supervise<T: Task>(
+    mut watchdog: impl Watchdog,
+    mut overdue: impl FnMut() -> Option<T>,
+) -> ! {
+    use embassy_time::Timer;
+
+    loop {
+        if let Some(task) = overdue() {
+            defmt::error!("Task {} missed its watchdog deadline", task.as_str());
+            watchdog.record_starved(task.index() as u32 + 1);
+            // let the watchdog reset the device
+            loop {
+                Timer::after_secs(1).await;
+            }
+        }
+        watchdog.feed();
+        Timer::after_secs(1).await;
+    }
+}
+
+/// Cause of the last reset, including the task that made the watchdog expire
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+#[derive(Clone, Copy, Debug)]
+pub struct ResetInfo<T> {
+    pub reason: ResetReason,
+    pub starved_task: Option<T>,
+}
+
+impl<T: Task> ResetInfo<T> {
+    /// Decodes the value stored by [`Watchdog::record_starved`] before the reset
+    pub fn new(reason: ResetReason, starved: u32) -> Self {
+        let starved_task = match reason {
+            ResetReason::Watchdog => (starved as usize).checked_sub(1).and_then(T::from_index),
+            _ => None,
+        };
+        Self {
+            reason,
+            starved_task,
+        }
+    }
+}
+
+/// Watchdog of the nRF52, accessed by its registers as the WDT peripheral is shared with the
+/// HAL of the firmwares
+#[cfg(feature = "nrf52")]
+pub mod nrf {
+    use super::{ResetInfo, Task};
+    use crate::reset::ResetReason;
+
+    const WDT: usize = 0x4001_0000;
+    const TASKS_START: *mut u32 = WDT as *mut u32;
+    const RUNSTATUS: *const u32 = (WDT + 0x400) as *const u32;
+    const CRV: *mut u32 = (WDT + 0x504) as *mut u32;
+    const RREN: *mut u32 = (WDT + 0x508) as *mut u32;
+    const CONFIG: *mut u32 = (WDT + 0x50c) as *mut u32;
+    /// Reload request register RR[0], the only one enabled
+    const RR0: *mut u32 = (WDT + 0x600) as *mut u32;
+    const RELOAD: u32 = 0x6e52_4635;
+    /// Keep running while the CPU sleeps, pause while halted by the debugger
+    const RUN_DURING_SLEEP: u32 = 1 << 0;
+
+    const RESETREAS: *mut u32 = 0x4000_0400 as *mut u32;
+    const GPREGRET2: *mut u32 = 0x4000_0520 as *mut u32;
+
+    /// The hardware watchdog, the starved task is stored in GPREGRET2, which is retained
+    /// across a watchdog reset
+    pub struct Watchdog {
+        /// Writes GPREGRET2, through the softdevice while it owns the POWER peripheral
+        record: fn(u32),
+    }
+
+    impl super::Watchdog for Watchdog {
+        fn feed(&mut self) {
+            unsafe { RR0.write_volatile(RELOAD) };
+        }
+
+        fn record_starved(&mut self, value: u32) {
+            (self.record)(value);
+        }
+    }
+
+    /// Starts the hardware watchdog with a timeout in 32.768 kHz ticks,
+    /// it can't be stopped until the next reset.
+    ///
+    /// Panics if the watchdog is already running from before a soft reset with another config.
+    pub fn start(timeout_ticks: u32, record_starved: fn(u32)) -> Watchdog {
+        unsafe {
+            if RUNSTATUS.read_volatile() & 1 != 0 {
+                if CONFIG.read_volatile() != RUN_DURING_SLEEP
+                    || CRV.read_volatile() != timeout_ticks
+                    || RREN.read_volatile() != 1
+                {
+                    panic!("watchdog already running with a different configuration");
+                }
+            } else {
+                CONFIG.write_volatile(RUN_DURING_SLEEP);
+                CRV.write_volatile(timeout_ticks);
+                RREN.write_volatile(1);
+                TASKS_START.write_volatile(1);
+            }
+        }
+        let mut watchdog = Watchdog {
+            record: record_starved,
+        };
+        super::Watchdog::feed(&mut watchdog);
+        watchdog
+    }
+
+    /// Reads and clears the reset reason, has to be called before the softdevice is enabled.
+    pub fn take_reset_info<T: Task>() -> ResetInfo<T> {
+        unsafe {
+            let resetreas = RESETREAS.read_volatile();
+            let starved = GPREGRET2.read_volatile();
+            // the reset reasons are cleared by writing 1s
+            RESETREAS.write_volatile(resetreas);
+            GPREGRET2.write_volatile(0);
+            ResetInfo::new(ResetReason::from_nrf_resetreas(resetreas), starved)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn overdue() {
+        let mut monitor = TaskMonitor::<TestTask>::new();
+        assert_eq!(monitor.overdue(1000), None);
+        assert_eq!(monitor.overdue(1001), Some(TestTask::Scan));
+
+        monitor.check_in(TestTask::Scan, 1000);
+        assert_eq!(monitor.overdue(2000), None);
+        assert_eq!(monitor.overdue(5001), Some(TestTask::Scan));
+
+        monitor.check_in(TestTask::Scan, 5000);
+        assert_eq!(monitor.overdue(5001), Some(TestTask::Publish));
+
+        monitor.check_in(TestTask::Publish, 5001);
+        assert_eq!(monitor.overdue(5500), None);
+    }
+
+    #[derive(Clone, Copy, Debug, PartialEq)]
+    enum TestTask {
+        Scan,
+        Publish,
+    }
+
+    impl Task for TestTask {
+        const ALL: &'static [Self] = &[TestTask::Scan, TestTask::Publish];
+
+        fn index(self) -> usize {
+            self as usize
+        }
+
+        fn as_str(&self) -> &'static str {
+            match self {
+                TestTask::Scan => "scan",
+                TestTask::Publish => "publish",
+            }
+        }
+
+        fn deadline_ms(self) -> u64 {
+            match self {
+                TestTask::Scan => 1000,
+                TestTask::Publish => 5000,
+            }
+        }
+    }
+
+    #[test]
+    fn starved_task() {
+        let info = ResetInfo::<TestTask>::new(ResetReason::Watchdog, 2);
+        assert_eq!(info.starved_task, Some(TestTask::Publish));
+        let info = ResetInfo::<TestTask>::new(ResetReason::Watchdog, 0);
+        assert_eq!(info.starved_task, None);
+        let info = ResetInfo::<TestTask>::new(ResetReason::Watchdog, 3);
+        assert_eq!(info.starved_task, None);
+        // the value is stale unless the watchdog reset the device
+        let info = ResetInfo::<TestTask>::new(ResetReason::PowerOn, 1);
+        assert_eq!(info.starved_task, None);
+    }
+
+    #[test]
+    fn check_in_from_the_past() {
+        let mut monitor = TaskMonitor::<TestTask>::new();
+        monitor.check_in(TestTask::Scan, 2000);
+        monitor.check_in(TestTask::Publish, 2000);
+        assert_eq!(monitor.overdue(1500), None);
+    }
+}
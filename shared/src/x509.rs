@@ -0,0 +1,164 @@
+//! Public keys of DER encoded X.509 certificates, used to pin the key of the MQTT broker.
+//!
+//! Only the structure leading to the subject public key info is parsed, the signature,
+//! the validity and the names of the certificate are not checked.
+
+const SEQUENCE: u8 = 0x30;
+const INTEGER: u8 = 0x02;
+const BIT_STRING: u8 = 0x03;
+const OBJECT_IDENTIFIER: u8 = 0x06;
+/// Explicitly tagged version of the certificate, `[0]`
+const VERSION: u8 = 0xa0;
+
+/// id-ecPublicKey, 1.2.840.10045.2.1
+const EC_PUBLIC_KEY: &[u8] = &[0x2a, 0x86, 0x48, 0xce, 0x3d, 0x02, 0x01];
+/// prime256v1, 1.2.840.10045.3.1.7
+const PRIME256V1: &[u8] = &[0x2a, 0x86, 0x48, 0xce, 0x3d, 0x03, 0x01, 0x07];
+
+/// Returns the SEC1 encoded P-256 public key of a DER encoded certificate
+/// or subject public key info
+pub fn p256_public_key(der: &[u8]) -> Option<&[u8]> {
+    let (content, rest) = expect(der, SEQUENCE)?;
+    if !rest.is_empty() {
+        return None;
+    }
+    // a subject public key info is the algorithm followed by the key,
+    // a certificate starts with two sequences
+    let (_, after_first) = expect(content, SEQUENCE)?;
+    match after_first.first() {
+        Some(&BIT_STRING) => subject_public_key_info(content),
+        _ => certificate(content),
+    }
+}
+
+/// Finds the subject public key info in the content of a certificate
+fn certificate(content: &[u8]) -> Option<&[u8]> {
+    let (tbs, _) = expect(content, SEQUENCE)?;
+    let tbs = match expect(tbs, VERSION) {
+        Some((_, rest)) => rest,
+        None => tbs,
+    };
+    let (_serial, rest) = expect(tbs, INTEGER)?;
+    // signature algorithm, issuer, validity and subject
+    let rest = (0..4).try_fold(rest, |rest, _| Some(expect(rest, SEQUENCE)?.1))?;
+    let (spki, _) = expect(rest, SEQUENCE)?;
+    subject_public_key_info(spki)
+}
+
+fn subject_public_key_info(content: &[u8]) -> Option<&[u8]> {
+    let (algorithm, rest) = expect(content, SEQUENCE)?;
+    let (key, rest) = expect(rest, BIT_STRING)?;
+    if !rest.is_empty() {
+        return None;
+    }
+
+    let (oid, parameters) = expect(algorithm, OBJECT_IDENTIFIER)?;
+    let (curve, parameters) = expect(parameters, OBJECT_IDENTIFIER)?;
+    if oid != EC_PUBLIC_KEY || curve != PRIME256V1 || !parameters.is_empty() {
+        return None;
+    }
+
+    // the key is a whole number of bytes, without unused bits
+    match key.split_first() {
+        Some((0, key)) => Some(key),
+        _ => None,
+    }
+}
+
+/// Reads an element with the tag, returns its content and the elements following it
+fn expect(der: &[u8], tag: u8) -> Option<(&[u8], &[u8])> {
+    let (&actual, rest) = der.split_first()?;
+    if actual != tag {
+        return None;
+    }
+    let (&first, rest) = rest.split_first()?;
+    let (len, rest) = match first {
+        0..=0x7f => (first as usize, rest),
+        0x81 => {
+            let (&len, rest) = rest.split_first()?;
+            (len as usize, rest)
+        }
+        0x82 => {
+            let len = rest.get(..2)?;
+            (u16::from_be_bytes([len[0], len[1]]) as usize, &rest[2..])
+        }
+        // certificates longer than 64 KiB are not supported
+        _ => return None,
+    };
+    if rest.len() < len {
+        return None;
+    }
+    Some(rest.split_at(len))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn element(tag: u8, content: &[u8]) -> Vec<u8> {
+        let mut der = vec![tag];
+        match content.len() {
+            len @ 0..=0x7f => der.push(len as u8),
+            len @ 0x80..=0xff => der.extend([0x81, len as u8]),
+            len => der.extend([0x82, (len >> 8) as u8, len as u8]),
+        }
+        der.extend(content);
+        der
+    }
+
+    fn spki(curve: &[u8], key: &[u8]) -> Vec<u8> {
+        let algorithm = [
+            element(OBJECT_IDENTIFIER, EC_PUBLIC_KEY),
+            element(OBJECT_IDENTIFIER, curve),
+        ]
+        .concat();
+        let key = [&[0][..], key].concat();
+        element(
+            SEQUENCE,
+            &[element(SEQUENCE, &algorithm), element(BIT_STRING, &key)].concat(),
+        )
+    }
+
+    fn certificate(spki: &[u8]) -> Vec<u8> {
+        let algorithm = element(SEQUENCE, &element(OBJECT_IDENTIFIER, &[0x2a, 0x86]));
+        let name = element(SEQUENCE, &[0x31; 150]);
+        let tbs = [
+            element(VERSION, &element(INTEGER, &[2])),
+            element(INTEGER, &[0x12, 0x34]),
+            algorithm.clone(),
+            name.clone(),
+            element(SEQUENCE, &[0x17; 30]),
+            name,
+            spki.to_vec(),
+        ]
+        .concat();
+        element(
+            SEQUENCE,
+            &[
+                element(SEQUENCE, &tbs),
+                algorithm,
+                element(BIT_STRING, &[0; 72]),
+            ]
+            .concat(),
+        )
+    }
+
+    #[test]
+    fn public_key() {
+        let key = [[0x04].as_slice(), &[0xab; 64]].concat();
+        let p256 = spki(PRIME256V1, &key);
+        assert_eq!(p256_public_key(&p256), Some(key.as_slice()));
+        assert_eq!(p256_public_key(&certificate(&p256)), Some(key.as_slice()));
+
+        // trailing and truncated data
+        let mut cert = certificate(&p256);
+        cert.push(0);
+        assert_eq!(p256_public_key(&cert), None);
+        assert_eq!(p256_public_key(&cert[..cert.len() - 10]), None);
+
+        let secp384r1 = [0x2b, 0x81, 0x04, 0x00, 0x22];
+        let other = spki(&secp384r1, &key);
+        assert_eq!(p256_public_key(&other), None);
+        assert_eq!(p256_public_key(&certificate(&other)), None);
+    }
+}